@@ -86,7 +86,7 @@
             environmentFile = lib.mkOption {
               type = lib.types.nullOr lib.types.path;
               default = null;
-              description = "Path to environment file with MQTT_HOST, MQTT_PORT, MQTT_USERNAME, MQTT_PASSWORD.";
+              description = "Path to environment file with DIALD_MQTT_HOST, DIALD_MQTT_PORT, DIALD_MQTT_USERNAME, DIALD_MQTT_PASSWORD.";
             };
           };
 
@@ -102,10 +102,16 @@
               description = "Surface Dial event daemon";
               wantedBy = [ "multi-user.target" ];
               after = [ "systemd-udev-settle.service" ];
+              # For the optional update check
+              path = [ pkgs.curl ];
               serviceConfig = {
                 ExecStart = "${cfg.package}/bin/diald --device ${cfg.device}";
+                ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
                 Restart = "on-failure";
+                # A remote restart exits with EX_TEMPFAIL
+                RestartForceExitStatus = "75";
                 DynamicUser = true;
+                StateDirectory = "diald";
                 SupplementaryGroups = [ "input" ];
               } // lib.optionalAttrs (cfg.environmentFile != null) {
                 EnvironmentFile = cfg.environmentFile;
@@ -0,0 +1,56 @@
+//! Arbitrary event sequences through the dial state machine: it must never
+//! panic, and the volume must stay within the model's range whatever happens.
+
+#![no_main]
+
+use std::time::{Duration, Instant};
+
+use diald::dial::{Action, Dial, Scale, Tool, VolumeModel};
+use diald::input::InputEvent;
+use libfuzzer_sys::fuzz_target;
+
+const TOOLS: [Tool; 4] = [Tool::Volume, Tool::Scroll, Tool::Zoom, Tool::Undo];
+
+fuzz_target!(|data: &[u8]| {
+    diald::set_log_level(diald::Level::Error);
+    // The first byte picks the volume model: 0 is continuous, else that many steps
+    let Some((&steps, data)) = data.split_first() else {
+        return;
+    };
+    let model = VolumeModel::from(match steps {
+        0 | 1 => Scale::Continuous,
+        steps => Scale::Stepped(steps as i32),
+    });
+    let range = 0..=model.range();
+    let mut dial = Dial::with_model(model);
+    let mut actions = Vec::new();
+    let mut now = Instant::now();
+
+    // Each 3-byte chunk is one step: operation, value, milliseconds since the
+    // previous step (0xff jumps past the idle timeout)
+    for chunk in data.chunks_exact(3) {
+        let (op, value, gap) = (chunk[0], chunk[1] as i8 as i32, chunk[2]);
+        now += match gap {
+            0xff => Duration::from_secs(31),
+            gap => Duration::from_millis(gap as u64),
+        };
+        match op % 8 {
+            0..=2 => dial.handle(InputEvent::Rotate(value), now, &mut actions),
+            3 => dial.handle(InputEvent::Detent(value.signum()), now, &mut actions),
+            4 => dial.handle(InputEvent::Press, now, &mut actions),
+            5 => dial.handle(InputEvent::Release, now, &mut actions),
+            6 => {
+                dial.set_volume(value * 2);
+            }
+            _ => dial.set_tool(TOOLS[value.unsigned_abs() as usize % TOOLS.len()]),
+        }
+        dial.tick(now, &mut actions);
+
+        for action in actions.drain(..) {
+            if let Action::Volume(volume) = action {
+                assert!(range.contains(&volume), "published volume {}", volume);
+            }
+        }
+        assert!(range.contains(&dial.volume()), "volume {}", dial.volume());
+    }
+});
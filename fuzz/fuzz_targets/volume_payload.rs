@@ -0,0 +1,48 @@
+//! Arbitrary payloads for every subscribed topic: parsing must never panic, and
+//! whatever it accepts must leave the dial within 0-100.
+
+#![no_main]
+
+use diald::dial::Dial;
+use diald::payload::{self, Command};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    diald::set_log_level(diald::Level::Error);
+    let mut dial = Dial::new();
+    let mut actions = Vec::new();
+    for name in payload::SUBSCRIBED {
+        match payload::parse(name, data) {
+            Some(Command::SetVolume(volume)) => {
+                let volume = dial.model().position(volume);
+                assert!(dial.set_volume(volume), "idle dial must accept a volume");
+            }
+            Some(Command::AdjustVolume(delta)) => {
+                let delta = dial.model().steps(delta);
+                assert!(dial.adjust_volume(delta, &mut actions), "idle dial must accept a nudge");
+            }
+            Some(Command::Reset(volume)) => {
+                dial.reset();
+                if let Some(volume) = volume {
+                    dial.set_volume(dial.model().position(volume));
+                }
+            }
+            Some(Command::Config(tuning)) => {
+                if let Some(counts) = tuning.counts_per_step {
+                    dial.set_counts_per_step((counts > 0).then_some(counts));
+                }
+            }
+            Some(
+                Command::Haptics(..)
+                | Command::GuestOn(_)
+                | Command::GuestOff
+                | Command::Profile(_)
+                | Command::LogLevel(_)
+                | Command::Restart(_)
+                | Command::Request(_),
+            )
+            | None => {}
+        }
+        assert!((0..=100).contains(&dial.volume()), "volume {}", dial.volume());
+    }
+});
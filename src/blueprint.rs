@@ -0,0 +1,106 @@
+//! `diald ha-blueprint`: print a Home Assistant automation blueprint with one
+//! action slot per gesture diald publishes, so wiring the dial up takes a few
+//! clicks in the HA UI. It follows the current configuration: click counts
+//! taken by guest mode are left out and the idle slot is only there if an
+//! idle message is configured.
+
+use std::fmt::Write;
+
+use crate::guest::GuestMode;
+use crate::hooks::IdleHooks;
+use crate::payload;
+
+const CLICK_NAMES: [&str; 3] = ["Single click", "Double click", "Triple click"];
+
+fn action_input(out: &mut String, id: &str, name: &str, description: &str) {
+    writeln!(out, "    {}:", id).unwrap();
+    writeln!(out, "      name: {}", name).unwrap();
+    writeln!(out, "      description: {}", description).unwrap();
+    writeln!(out, "      default: []").unwrap();
+    writeln!(out, "      selector:").unwrap();
+    writeln!(out, "        action: {{}}").unwrap();
+}
+
+fn trigger(out: &mut String, id: &str, topic: &str) {
+    writeln!(out, "  - platform: mqtt").unwrap();
+    writeln!(out, "    topic: {}", topic).unwrap();
+    writeln!(out, "    id: {}", id).unwrap();
+}
+
+fn choice(out: &mut String, id: &str, payload: Option<&str>, input: &str) {
+    writeln!(out, "      - conditions:").unwrap();
+    writeln!(out, "          - condition: trigger").unwrap();
+    writeln!(out, "            id: {}", id).unwrap();
+    if let Some(payload) = payload {
+        writeln!(out, "          - condition: template").unwrap();
+        writeln!(out, "            value_template: \"{{{{ trigger.payload == '{}' }}}}\"", payload)
+            .unwrap();
+    }
+    writeln!(out, "        sequence: !input {}", input).unwrap();
+}
+
+/// The blueprint for the configuration in the environment.
+pub fn render() -> Result<String, String> {
+    let prefix = payload::prefix();
+    let guest = GuestMode::from_env()?;
+    let hooks = IdleHooks::from_env()?;
+    let clicks: Vec<(u32, &str)> = (1..)
+        .zip(CLICK_NAMES)
+        .filter(|(count, _)| !guest.is_activation(*count))
+        .collect();
+    // The custom message isn't retained, so HA won't replay it on restart the
+    // way it would the snapshot.
+    let idle = match &hooks.publish {
+        Some((topic, payload)) => Some((topic.clone(), Some(payload.as_str()))),
+        None if hooks.snapshot => Some((format!("{}/snapshot", prefix), None)),
+        None => None,
+    };
+
+    let mut out = String::new();
+    writeln!(out, "# Generated by `diald ha-blueprint`. Save it under").unwrap();
+    writeln!(out, "# blueprints/automation/diald/dial.yaml and create an automation from it.")
+        .unwrap();
+    writeln!(out, "blueprint:").unwrap();
+    writeln!(out, "  name: diald dial").unwrap();
+    writeln!(out, "  description: Run actions on the dial's clicks, long press and volume.")
+        .unwrap();
+    writeln!(out, "  domain: automation").unwrap();
+    writeln!(out, "  input:").unwrap();
+    for (count, name) in &clicks {
+        let description = format!("Pressed {} time(s) in a row.", count);
+        action_input(&mut out, &format!("click_{}", count), name, &description);
+    }
+    action_input(&mut out, "long_press", "Long press", "Held for a second.");
+    action_input(
+        &mut out,
+        "volume",
+        "Volume changed",
+        "The new volume is available as '{{ volume }}'.",
+    );
+    if idle.is_some() {
+        action_input(&mut out, "idle", "Went idle", "Nobody has touched the dial for a while.");
+    }
+
+    writeln!(out, "mode: queued").unwrap();
+    writeln!(out, "trigger:").unwrap();
+    trigger(&mut out, "clicks", &format!("{}/clicks", prefix));
+    trigger(&mut out, "long_press", &format!("{}/long_press", prefix));
+    trigger(&mut out, "volume", &format!("{}/volume", prefix));
+    if let Some((topic, _)) = &idle {
+        trigger(&mut out, "idle", topic);
+    }
+    writeln!(out, "variables:").unwrap();
+    writeln!(out, "  volume: \"{{{{ trigger.payload | int(0) }}}}\"").unwrap();
+    writeln!(out, "action:").unwrap();
+    writeln!(out, "  - choose:").unwrap();
+    for (count, _) in &clicks {
+        let payload = count.to_string();
+        choice(&mut out, "clicks", Some(&payload), &format!("click_{}", count));
+    }
+    choice(&mut out, "long_press", None, "long_press");
+    choice(&mut out, "volume", None, "volume");
+    if let Some((_, payload)) = idle {
+        choice(&mut out, "idle", payload, "idle");
+    }
+    Ok(out)
+}
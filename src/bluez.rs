@@ -0,0 +1,511 @@
+//! BlueZ integration over the system D-Bus, plus the kernel's Bluetooth
+//! management socket for link quality, which D-Bus doesn't expose.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zbus::blocking::Connection;
+use zbus::blocking::fdo::ObjectManagerProxy;
+use zbus::zvariant::OwnedObjectPath;
+
+use crate::config;
+use crate::wake::Waker;
+
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const BATTERY_INTERFACE: &str = "org.bluez.Battery1";
+
+struct BluezDevice {
+    path: OwnedObjectPath,
+    paired: bool,
+    connected: bool,
+    /// `public` or `random` for LE devices; absent for classic ones.
+    address_type: Option<String>,
+    /// Only present while BlueZ is discovering.
+    rssi: Option<i16>,
+    /// Percent, from BlueZ's battery service once connected.
+    battery: Option<u8>,
+}
+
+impl BluezDevice {
+    /// The controller index from the object path, e.g. 0 for `/org/bluez/hci0/dev_...`.
+    fn adapter_index(&self) -> Option<u16> {
+        self.path.as_str().strip_prefix("/org/bluez/hci")?.split('/').next()?.parse().ok()
+    }
+}
+
+/// Look up a BlueZ device by its Bluetooth address, on any adapter.
+fn find_device(connection: &Connection, address: &str) -> zbus::Result<Option<BluezDevice>> {
+    let manager = ObjectManagerProxy::builder(connection)
+        .destination("org.bluez")?
+        .path("/")?
+        .build()?;
+
+    for (path, interfaces) in manager.get_managed_objects()? {
+        let Some(props) = interfaces
+            .iter()
+            .find(|(name, _)| name.as_str() == DEVICE_INTERFACE)
+            .map(|(_, props)| props)
+        else {
+            continue;
+        };
+        let matches = props
+            .get("Address")
+            .and_then(|v| <&str>::try_from(v).ok())
+            .is_some_and(|a| a.eq_ignore_ascii_case(address));
+        if !matches {
+            continue;
+        }
+        let battery = interfaces
+            .iter()
+            .find(|(name, _)| name.as_str() == BATTERY_INTERFACE)
+            .and_then(|(_, props)| props.get("Percentage"))
+            .and_then(|v| u8::try_from(v).ok());
+        let flag = |name: &str| {
+            props
+                .get(name)
+                .and_then(|v| bool::try_from(v).ok())
+                .unwrap_or(false)
+        };
+        return Ok(Some(BluezDevice {
+            paired: flag("Paired"),
+            connected: flag("Connected"),
+            address_type: props
+                .get("AddressType")
+                .and_then(|v| <&str>::try_from(v).ok())
+                .map(String::from),
+            rssi: props.get("RSSI").and_then(|v| i16::try_from(v).ok()),
+            battery,
+            path,
+        }));
+    }
+    Ok(None)
+}
+
+/// Runs `query` on the system bus every `interval`, and again whenever
+/// asked to, on a thread of its own: a D-Bus round trip can take a while,
+/// and a `Connect` to a sleeping dial up to the 25s D-Bus timeout, which the
+/// dial's loop can't sit out. Answers come back over a channel and wake the
+/// loop. A failure is logged once until a query works again, and the next
+/// one starts from a fresh bus connection.
+struct Poller<T> {
+    refresh: Sender<()>,
+    answers: Receiver<T>,
+}
+
+impl<T: Send + 'static> Poller<T> {
+    fn spawn<F>(what: &'static str, interval: Duration, waker: Waker, mut query: F) -> Self
+    where
+        F: FnMut(&Connection) -> Result<Option<T>, String> + Send + 'static,
+    {
+        let (refresh, refresh_rx) = mpsc::channel();
+        let (tx, answers) = mpsc::channel();
+        thread::spawn(move || {
+            let mut connection = None;
+            let mut error_logged = false;
+            loop {
+                match ask(&mut connection, &mut query) {
+                    Ok(answer) => {
+                        error_logged = false;
+                        if let Some(answer) = answer {
+                            if tx.send(answer).is_err() {
+                                return;
+                            }
+                            waker.wake();
+                        }
+                    }
+                    Err(err) => {
+                        if !error_logged {
+                            warn!("diald: {} ({})", what, err);
+                            error_logged = true;
+                        }
+                        connection = None;
+                    }
+                }
+                // Gone once the owner is dropped
+                if let Err(RecvTimeoutError::Disconnected) = refresh_rx.recv_timeout(interval) {
+                    return;
+                }
+                while refresh_rx.try_recv().is_ok() {}
+            }
+        });
+        Self { refresh, answers }
+    }
+
+    /// Ask again now rather than at the end of the interval.
+    fn refresh(&self) {
+        let _ = self.refresh.send(());
+    }
+
+    /// The latest answer since the last call, if any came.
+    fn latest(&self) -> Option<T> {
+        self.answers.try_iter().last()
+    }
+}
+
+fn ask<T>(
+    connection: &mut Option<Connection>,
+    query: &mut impl FnMut(&Connection) -> Result<Option<T>, String>,
+) -> Result<Option<T>, String> {
+    if connection.is_none() {
+        *connection = Some(Connection::system().map_err(|err| err.to_string())?);
+    }
+    match connection.as_ref() {
+        Some(connection) => query(connection),
+        None => Ok(None),
+    }
+}
+
+/// Seconds from `var`, else `default`.
+fn interval(var: &str, default: u64) -> Duration {
+    config::var(var)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(default))
+}
+
+/// Periodically asks BlueZ to reconnect a paired-but-disconnected dial while its
+/// input node is missing, so the user doesn't have to press it and wait.
+pub struct WakeNudger {
+    poller: Poller<()>,
+    missing: Arc<AtomicBool>,
+}
+
+impl WakeNudger {
+    /// Enabled by setting `DIALD_BT_ADDRESS` to the dial's Bluetooth address.
+    pub fn from_env(waker: Waker) -> Option<Self> {
+        let address = config::var("DIALD_BT_ADDRESS").ok()?;
+        // Until the device is first opened
+        let missing = Arc::new(AtomicBool::new(true));
+        let wanted = missing.clone();
+        let query = move |connection: &Connection| {
+            if !wanted.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            connect(connection, &address).map_err(|err| err.to_string())
+        };
+        let interval = interval("DIALD_WAKE_INTERVAL", 30);
+        let poller = Poller::spawn("bluez wake nudge failed", interval, waker, query);
+        Some(Self { poller, missing })
+    }
+
+    /// Whether the input node is gone, so nudges are wanted. Going missing
+    /// nudges right away.
+    pub fn set_missing(&self, missing: bool) {
+        if self.missing.swap(missing, Ordering::Relaxed) != missing && missing {
+            self.poller.refresh();
+        }
+    }
+
+    /// Whether a nudge has connected the dial since the last call.
+    pub fn connected(&self) -> bool {
+        self.poller.latest().is_some()
+    }
+}
+
+/// Ask BlueZ to connect the dial if it is paired but not connected; `Some`
+/// when it did.
+fn connect(connection: &Connection, address: &str) -> zbus::Result<Option<()>> {
+    let Some(device) = find_device(connection, address)? else {
+        return Ok(None);
+    };
+    if !device.paired || device.connected {
+        return Ok(None);
+    }
+
+    log!("diald: nudging {} to connect", address);
+    connection.call_method(
+        Some("org.bluez"),
+        &device.path,
+        Some(DEVICE_INTERFACE),
+        "Connect",
+        &(),
+    )?;
+    log!("diald: {} connected", address);
+    Ok(Some(()))
+}
+
+/// Whether the dial is connected, and its battery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub connected: bool,
+    /// Percent; `None` while disconnected or when the dial doesn't say.
+    pub battery: Option<u8>,
+}
+
+/// Asks BlueZ now and then whether the dial is connected and how full its
+/// battery is, for availability and battery sensors.
+pub struct StatusMonitor {
+    poller: Poller<DeviceStatus>,
+    last: Option<DeviceStatus>,
+}
+
+impl StatusMonitor {
+    /// Enabled by `DIALD_BT_ADDRESS`, polled every `DIALD_BT_STATUS_INTERVAL`
+    /// seconds (default 60, 0 turns it off).
+    pub fn from_env(waker: Waker) -> Option<Self> {
+        let address = config::var("DIALD_BT_ADDRESS").ok()?;
+        let interval = interval("DIALD_BT_STATUS_INTERVAL", 60);
+        if interval.is_zero() {
+            return None;
+        }
+        let query = move |connection: &Connection| {
+            let device = find_device(connection, &address).map_err(|err| err.to_string())?;
+            // Unknown to BlueZ (not paired yet, or removed) is as good as gone
+            Ok(Some(match device {
+                Some(device) if device.connected => {
+                    DeviceStatus { connected: true, battery: device.battery }
+                }
+                _ => DeviceStatus { connected: false, battery: None },
+            }))
+        };
+        let poller = Poller::spawn("bluetooth status unavailable", interval, waker, query);
+        Some(Self { poller, last: None })
+    }
+
+    /// Ask again now, e.g. once the input device comes or goes.
+    pub fn refresh(&self) {
+        self.poller.refresh();
+    }
+
+    /// The status, when it has changed.
+    pub fn poll(&mut self) -> Option<DeviceStatus> {
+        let status = self.poller.latest()?;
+        (self.last.replace(status) != Some(status)).then_some(status)
+    }
+}
+
+/// Gaps between events this long in the middle of a gesture are counted as
+/// dropouts; longer ones are more likely the user pausing.
+const DROPOUT_GAP: Duration = Duration::from_millis(150);
+const MAX_DROPOUT_GAP: Duration = Duration::from_secs(1);
+
+/// A reading of the dial's Bluetooth link.
+#[derive(Clone, Copy, Debug)]
+pub struct LinkQuality {
+    pub rssi: i16,
+    pub tx_power: Option<i8>,
+}
+
+/// Reads the dial's signal strength now and then and counts gaps in its
+/// events, logging each next to the latest reading, to help place the
+/// Bluetooth dongle.
+pub struct LinkMonitor {
+    poller: Poller<LinkQuality>,
+    last: Option<LinkQuality>,
+    last_event_at: Option<Instant>,
+    dropouts: u32,
+}
+
+impl LinkMonitor {
+    /// Enabled by `DIALD_BT_ADDRESS`, polled every `DIALD_BT_RSSI_INTERVAL`
+    /// seconds (default 60, 0 turns it off).
+    pub fn from_env(waker: Waker) -> Option<Self> {
+        let address = config::var("DIALD_BT_ADDRESS").ok()?;
+        let interval = interval("DIALD_BT_RSSI_INTERVAL", 60);
+        if interval.is_zero() {
+            return None;
+        }
+        let query = move |connection: &Connection| link_quality(connection, &address);
+        let poller = Poller::spawn("bluetooth link quality unavailable", interval, waker, query);
+        Some(Self { poller, last: None, last_event_at: None, dropouts: 0 })
+    }
+
+    /// The latest reading, when there is a fresh one.
+    pub fn poll(&mut self) -> Option<LinkQuality> {
+        let quality = self.poller.latest()?;
+        self.last = Some(quality);
+        Some(quality)
+    }
+
+    /// Note that input events arrived. A gap since the previous ones in the
+    /// middle of a gesture counts as a dropout.
+    pub fn note_events(&mut self, now: Instant, mid_gesture: bool) {
+        if let Some(last) = self.last_event_at.replace(now)
+            && mid_gesture
+        {
+            let gap = now.duration_since(last);
+            if (DROPOUT_GAP..MAX_DROPOUT_GAP).contains(&gap) {
+                self.dropouts += 1;
+                match self.last {
+                    Some(quality) => log!(
+                        "diald: {}ms gap in events (last rssi {} dBm)",
+                        gap.as_millis(),
+                        quality.rssi
+                    ),
+                    None => log!("diald: {}ms gap in events", gap.as_millis()),
+                }
+            }
+        }
+    }
+
+    /// Dropouts seen so far.
+    pub fn dropouts(&self) -> u32 {
+        self.dropouts
+    }
+}
+
+/// The dial's link while it is connected.
+fn link_quality(connection: &Connection, address: &str) -> Result<Option<LinkQuality>, String> {
+    let device = find_device(connection, address).map_err(|err| err.to_string())?;
+    let Some(device) = device.filter(|device| device.connected) else {
+        return Ok(None);
+    };
+    // BlueZ only knows the RSSI while discovering; otherwise ask the kernel
+    if let Some(rssi) = device.rssi {
+        return Ok(Some(LinkQuality { rssi, tx_power: None }));
+    }
+    let index = device.adapter_index().ok_or("unknown adapter")?;
+    #[cfg(target_os = "linux")]
+    {
+        let (rssi, tx_power) =
+            mgmt::connection_info(index, address, device.address_type.as_deref())
+                .map_err(|err| err.to_string())?;
+        Ok(Some(LinkQuality { rssi: rssi as i16, tx_power }))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = index;
+        Err("not supported on this platform".to_string())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mgmt {
+    use std::io;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const BTPROTO_HCI: libc::c_int = 1;
+    const HCI_DEV_NONE: u16 = 0xffff;
+    const HCI_CHANNEL_CONTROL: u16 = 3;
+    const OP_GET_CONN_INFO: u16 = 0x0031;
+    const EV_CMD_COMPLETE: u16 = 0x0001;
+    const EV_CMD_STATUS: u16 = 0x0002;
+
+    #[repr(C)]
+    struct SockaddrHci {
+        family: libc::sa_family_t,
+        dev: u16,
+        channel: u16,
+    }
+
+    /// `AA:BB:CC:DD:EE:FF` in the little-endian byte order the kernel wants.
+    fn address_bytes(address: &str) -> Option<[u8; 6]> {
+        let mut bytes = [0u8; 6];
+        let mut parts = address.split(':');
+        for byte in bytes.iter_mut().rev() {
+            *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+        }
+        parts.next().is_none().then_some(bytes)
+    }
+
+    fn open() -> io::Result<OwnedFd> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                BTPROTO_HCI,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let addr = SockaddrHci {
+            family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            dev: HCI_DEV_NONE,
+            channel: HCI_CHANNEL_CONTROL,
+        };
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const SockaddrHci as *const libc::sockaddr,
+                mem::size_of::<SockaddrHci>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timeout = libc::timeval { tv_sec: 1, tv_usec: 0 };
+        unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            );
+        }
+        Ok(fd)
+    }
+
+    /// RSSI and TX power of the live connection to `address`, via the
+    /// management interface's Get Connection Information. Needs
+    /// `CAP_NET_ADMIN`.
+    pub fn connection_info(
+        index: u16,
+        address: &str,
+        le_type: Option<&str>,
+    ) -> io::Result<(i8, Option<i8>)> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "bad Bluetooth address");
+        let bdaddr = address_bytes(address).ok_or_else(invalid)?;
+        // BR/EDR, LE public, LE random
+        let address_type: u8 = match le_type {
+            None => 0,
+            Some("random") => 2,
+            Some(_) => 1,
+        };
+
+        let fd = open()?;
+        let mut command = Vec::with_capacity(13);
+        command.extend_from_slice(&OP_GET_CONN_INFO.to_le_bytes());
+        command.extend_from_slice(&index.to_le_bytes());
+        command.extend_from_slice(&7u16.to_le_bytes());
+        command.extend_from_slice(&bdaddr);
+        command.push(address_type);
+        let written = unsafe {
+            libc::write(fd.as_raw_fd(), command.as_ptr() as *const libc::c_void, command.len())
+        };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Other events may be broadcast on the same channel; skip them
+        let mut buf = [0u8; 512];
+        for _ in 0..16 {
+            let read = unsafe {
+                libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let packet = &buf[..read as usize];
+            if packet.len() < 9 {
+                continue;
+            }
+            let event = u16::from_le_bytes([packet[0], packet[1]]);
+            let opcode = u16::from_le_bytes([packet[6], packet[7]]);
+            if !matches!(event, EV_CMD_COMPLETE | EV_CMD_STATUS) || opcode != OP_GET_CONN_INFO {
+                continue;
+            }
+            let status = packet[8];
+            if status != 0 {
+                return Err(io::Error::other(format!("management status {:#04x}", status)));
+            }
+            // Address (6), type, RSSI, TX power, max TX power
+            let info = &packet[9..];
+            if info.len() < 9 {
+                return Err(io::Error::other("short connection info"));
+            }
+            let rssi = info[7] as i8;
+            // 127 means the controller doesn't know
+            let tx_power = Some(info[8] as i8).filter(|power| *power != 127);
+            return Ok((rssi, tx_power));
+        }
+        Err(io::ErrorKind::TimedOut.into())
+    }
+}
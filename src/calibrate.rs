@@ -0,0 +1,117 @@
+//! `diald calibrate`: measure how many raw counts the dial reports in one
+//! full turn and store it as `device.counts_per_revolution`, so anything
+//! measured in degrees (scroll notches, undo steps) turns the same angle on
+//! any hardware.
+
+use std::error::Error;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use toml::Value;
+
+use crate::config;
+use crate::input::{self, InputEvent, InputSource};
+
+/// A turn is over once the dial has been still this long.
+const SETTLE: Duration = Duration::from_secs(2);
+/// How long to wait for the user to start turning.
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+/// Clockwise and counter-clockwise turns further apart than this are
+/// probably not both exactly one turn.
+const TOLERANCE_PERCENT: i64 = 5;
+
+fn wait_for_enter(prompt: &str) -> io::Result<()> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    if io::stdin().lock().read_line(&mut String::new())? == 0 {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "calibration cancelled"));
+    }
+    Ok(())
+}
+
+/// Total raw counts of one turn: from the first rotation until the dial has
+/// been still for `SETTLE`.
+fn measure(device: &mut dyn InputSource) -> Result<i64, Box<dyn Error>> {
+    // Whatever was turned before the prompt doesn't count
+    while device.fetch_events().is_ok_and(|events| !events.is_empty()) {}
+
+    let started = Instant::now();
+    let mut total = 0i64;
+    let mut last_event: Option<Instant> = None;
+    loop {
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        for event in events {
+            match event {
+                InputEvent::Rotate(value) => {
+                    total += value as i64;
+                    last_event = Some(Instant::now());
+                }
+                InputEvent::Detent(_) => {
+                    return Err("this device reports steps rather than rotation counts; \
+                                there is nothing to calibrate"
+                        .into());
+                }
+                InputEvent::Press | InputEvent::Release => {}
+            }
+        }
+        match last_event {
+            Some(at) if at.elapsed() >= SETTLE => return Ok(total),
+            None if started.elapsed() >= START_TIMEOUT => {
+                return Err("no rotation within a minute; check DIALD_AXES".into());
+            }
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// `device` is the one to calibrate, else the first dial found.
+pub fn run(device: Option<PathBuf>, config_path: &Path) -> Result<(), Box<dyn Error>> {
+    let path = match device {
+        Some(path) => path,
+        None => match input::scan()?.into_iter().next() {
+            Some((path, _)) => path,
+            None => return Err("no dial found; pass its path, e.g. `diald calibrate \
+                                /dev/input/event5`"
+                .into()),
+        },
+    };
+    let mut device = input::open(&path)?;
+    let name = device.name().unwrap_or_else(|| "unnamed".to_string());
+    println!("Calibrating {} ({})\n", path.display(), name);
+    println!("Mark where the dial points. Each turn ends once the dial has been still for");
+    println!("{} seconds.\n", SETTLE.as_secs());
+
+    wait_for_enter("Press Enter, then turn exactly one full turn clockwise... ")?;
+    let clockwise = measure(device.as_mut())?;
+    println!("  {} counts", clockwise);
+    wait_for_enter("Press Enter, then turn exactly one full turn back... ")?;
+    let counter = measure(device.as_mut())?;
+    println!("  {} counts\n", counter);
+
+    let (clockwise, counter) = (clockwise.abs(), counter.abs());
+    if clockwise == 0 || counter == 0 {
+        return Err("one of the turns reported no rotation".into());
+    }
+    if (clockwise - counter).abs() * 100 > clockwise.max(counter) * TOLERANCE_PERCENT {
+        return Err(format!(
+            "the two turns differ by more than {}% ({} and {}); try again",
+            TOLERANCE_PERCENT, clockwise, counter
+        )
+        .into());
+    }
+    let counts = (clockwise + counter) / 2;
+    println!("One turn is {} counts ({:.2} per degree).", counts, counts as f64 / 360.0);
+
+    config::set(config_path, "DIALD_COUNTS_PER_REV", Value::Integer(counts))?;
+    println!("Saved device.counts_per_revolution to {}.", config_path.display());
+    if let Some(name) = config::set_in_environment("DIALD_COUNTS_PER_REV") {
+        println!("{} is set in the environment and still takes precedence.", name);
+    }
+    Ok(())
+}
@@ -0,0 +1,795 @@
+//! The config file: `/etc/diald/config.toml` (for users,
+//! `$XDG_CONFIG_HOME/diald/config.toml` if they have one), or whatever
+//! `--config` or `DIALD_CONFIG` points at. Each setting has an environment variable of the
+//! same meaning, and the environment wins when both set one. The variable is
+//! `DIALD_<SECTION>_<KEY>` (`standard_name`); the names settings started out
+//! with (`SETTINGS`), like `MQTT_HOST`, keep working:
+//!
+//! ```toml
+//! version = 2
+//!
+//! [device]
+//! path = ["/dev/input/event5", "/dev/input/event7"]
+//!
+//! [mqtt]
+//! host = "broker.lan"
+//!
+//! [haptics]
+//! triggers = ["wake", "boundary"]
+//! ```
+//!
+//! One process can run several dials: each `[[device]]` section is one,
+//! named, with its own device settings and any other section nested under
+//! it. What a dial's section sets wins over both the environment and the
+//! rest of the file, which the dials share:
+//!
+//! ```toml
+//! [[device]]
+//! name = "office"
+//! path = "/dev/input/by-id/usb-Office_Dial-event-if00"
+//! counts_per_step = 20
+//!
+//! [device.topics]
+//! prefix = "office/dial"
+//! ```
+//!
+//! `[[profile]]` sections are laid out the same way, with every setting in a
+//! nested section. A dial switched to one at runtime reads it first of all.
+//!
+//! Files carry a `version`. Older files are migrated in memory when read
+//! (`MIGRATIONS`), and `diald --migrate-config` rewrites them in place, also
+//! moving settings found in the environment into the file.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env::{self, VarError};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use toml::{Table, Value};
+
+/// The layout this diald reads and writes.
+pub const VERSION: i64 = 2;
+pub const DEFAULT_PATH: &str = "/etc/diald/config.toml";
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/diald";
+
+/// Every setting as `(section, key, environment variable)`.
+pub const SETTINGS: &[(&str, &str, &str)] = &[
+    ("device", "path", "DIALD_DEVICE"),
+    ("device", "axes", "DIALD_AXES"),
+    ("device", "grab", "DIALD_GRAB"),
+    ("device", "mirrored_axes", "DIALD_MIRRORED_AXES"),
+    ("device", "invert_direction", "DIALD_INVERT_DIRECTION"),
+    ("device", "tool", "DIALD_TOOL"),
+    ("device", "volume_steps", "DIALD_VOLUME_STEPS"),
+    ("device", "range", "DIALD_RANGE"),
+    ("device", "range_step", "DIALD_RANGE_STEP"),
+    ("device", "curve", "DIALD_CURVE"),
+    ("device", "counts_per_revolution", "DIALD_COUNTS_PER_REV"),
+    ("device", "counts_per_step", "DIALD_COUNTS_PER_STEP"),
+    ("device", "min_delta", "DIALD_MIN_DELTA"),
+    ("device", "min_delta_window", "DIALD_MIN_DELTA_WINDOW"),
+    ("device", "min_delta_release", "DIALD_MIN_DELTA_RELEASE"),
+    ("mqtt", "host", "MQTT_HOST"),
+    ("mqtt", "port", "MQTT_PORT"),
+    ("mqtt", "username", "MQTT_USERNAME"),
+    ("mqtt", "password", "MQTT_PASSWORD"),
+    ("mqtt", "active_publish", "DIALD_ACTIVE_PUBLISH"),
+    ("mqtt", "command_token", "DIALD_COMMAND_TOKEN"),
+    ("mqtt", "fast", "DIALD_FAST"),
+    ("topics", "prefix", "DIALD_TOPIC_PREFIX"),
+    ("topics", "layout", "DIALD_TOPIC_LAYOUT"),
+    ("topics", "device_id", "DIALD_DEVICE_ID"),
+    ("backlash", "threshold", "DIALD_BACKLASH_THRESHOLD"),
+    ("backlash", "cancel_threshold", "DIALD_BACKLASH_CANCEL"),
+    ("backlash", "time", "DIALD_BACKLASH_TIME"),
+    ("backlash", "filter", "DIALD_BACKLASH_FILTER"),
+    ("backlash", "velocity", "DIALD_BACKLASH_VELOCITY"),
+    ("backlash", "deadband", "DIALD_BACKLASH_DEADBAND"),
+    ("backlash", "release", "DIALD_BACKLASH_RELEASE"),
+    ("timeouts", "idle", "DIALD_IDLE_TIMEOUT"),
+    ("timeouts", "click_window", "DIALD_CLICK_WINDOW"),
+    ("timeouts", "click_max", "DIALD_CLICK_MAX"),
+    ("haptics", "triggers", "DIALD_HAPTICS"),
+    ("haptics", "backend", "DIALD_HAPTIC_BACKEND"),
+    ("haptics", "device", "DIALD_HAPTIC_DEV"),
+    ("haptics", "strength", "DIALD_HAPTIC_STRENGTH"),
+    ("haptics", "piezo", "DIALD_PIEZO"),
+    ("leds", "status", "DIALD_STATUS_LED"),
+    ("display", "oled", "DIALD_OLED"),
+    ("desktop", "app_tools", "DIALD_APP_TOOLS"),
+    ("desktop", "focus_command", "DIALD_FOCUS_COMMAND"),
+    ("desktop", "output_reports", "DIALD_OUTPUT_REPORTS"),
+    ("night", "limit", "DIALD_NIGHT_LIMIT"),
+    ("night", "location", "DIALD_LOCATION"),
+    ("guest", "cap", "DIALD_GUEST_CAP"),
+    ("guest", "duration", "DIALD_GUEST_DURATION"),
+    ("guest", "clicks", "DIALD_GUEST_CLICKS"),
+    ("idle", "snapshot", "DIALD_IDLE_SNAPSHOT"),
+    ("idle", "exec", "DIALD_IDLE_EXEC"),
+    ("idle", "publish", "DIALD_IDLE_PUBLISH"),
+    ("gestures", "rules", "DIALD_GESTURES"),
+    ("gestures", "press_turn", "DIALD_PRESS_TURN"),
+    ("bluetooth", "address", "DIALD_BT_ADDRESS"),
+    ("bluetooth", "wake_interval", "DIALD_WAKE_INTERVAL"),
+    ("bluetooth", "rssi_interval", "DIALD_BT_RSSI_INTERVAL"),
+    ("bluetooth", "status_interval", "DIALD_BT_STATUS_INTERVAL"),
+    ("daemon", "throttle_temp", "DIALD_THROTTLE_TEMP"),
+    ("daemon", "throttle_load", "DIALD_THROTTLE_LOAD"),
+    ("daemon", "latency_budget", "DIALD_LATENCY_BUDGET"),
+    ("daemon", "update_url", "DIALD_UPDATE_URL"),
+    ("daemon", "update_interval", "DIALD_UPDATE_INTERVAL"),
+    ("daemon", "pid_file", "DIALD_PID_FILE"),
+    ("daemon", "log_file", "DIALD_LOG_FILE"),
+    ("daemon", "log_level", "DIALD_LOG_LEVEL"),
+    ("daemon", "control_socket", "DIALD_CONTROL_SOCKET"),
+    ("daemon", "restart_token", "DIALD_RESTART_TOKEN"),
+    ("daemon", "monitor", "DIALD_MONITOR"),
+    ("daemon", "dry_run", "DIALD_DRY_RUN"),
+    ("daemon", "watch_config", "DIALD_WATCH_CONFIG"),
+    ("daemon", "exit_when_idle", "DIALD_EXIT_WHEN_IDLE"),
+    ("daemon", "exit_with_device", "DIALD_EXIT_WITH_DEVICE"),
+    ("daemon", "wait_for_device", "DIALD_WAIT_FOR_DEVICE"),
+    ("daemon", "hotplug", "DIALD_HOTPLUG"),
+    ("daemon", "state_file", "DIALD_STATE_FILE"),
+];
+
+/// `(environment variable, example, help)` for `diald init-config` and
+/// `diald config-schema`. The example is a TOML value, the default where the
+/// setting has one.
+pub const EXAMPLES: &[(&str, &str, &str)] = &[
+    ("DIALD_DEVICE", r#"["/dev/input/event5"]"#, "Input devices, most preferred first"),
+    ("DIALD_AXES", r#""REL_DIAL=rotate""#, "Axis roles, e.g. REL_RZ=rotate:5,REL_Z=push:40"),
+    ("DIALD_GRAB", "false", "Keep the desktop from also seeing the dial's events"),
+    ("DIALD_MIRRORED_AXES", r#""first""#, "With several rotation axes, count first or all"),
+    ("DIALD_INVERT_DIRECTION", "false", "Turn clockwise for down, for a dial mounted upside down"),
+    ("DIALD_TOOL", r#""volume""#, "volume, scroll, zoom or undo"),
+    ("DIALD_VOLUME_STEPS", "10", "Stepped volume with this many steps"),
+    ("DIALD_RANGE", "[-60.0, 0.0]", "Values at the bottom and top, instead of 0-100"),
+    ("DIALD_RANGE_STEP", "0.5", "How far one step of the range goes"),
+    ("DIALD_CURVE", r#""linear""#, "linear, log, or percentages along the turn"),
+    ("DIALD_COUNTS_PER_REV", "3600", "Raw counts in one turn, from `diald calibrate`"),
+    ("DIALD_COUNTS_PER_STEP", "30", "Raw counts per volume step"),
+    ("DIALD_MIN_DELTA", "3", "Counts to move before rotation counts, against chatter"),
+    ("DIALD_MIN_DELTA_WINDOW", "100", "Milliseconds that movement is summed over"),
+    ("DIALD_MIN_DELTA_RELEASE", "1", "Counts below which a turn has stopped, for hysteresis"),
+    ("MQTT_HOST", r#""localhost""#, "Broker address"),
+    ("MQTT_PORT", "1883", "Broker port"),
+    ("MQTT_USERNAME", r#""diald""#, "Broker user"),
+    ("MQTT_PASSWORD", r#""secret""#, "Broker password"),
+    ("DIALD_ACTIVE_PUBLISH", "false", "Publish the starting volume as the dial wakes"),
+    ("DIALD_COMMAND_TOKEN", r#""secret""#, "Token every MQTT command needs"),
+    ("DIALD_FAST", r#"["mqtt"]"#, "Sinks that get every volume change: mqtt, reports"),
+    ("DIALD_TOPIC_PREFIX", r#""home/diald""#, "Topic prefix"),
+    ("DIALD_TOPIC_LAYOUT", r#""legacy""#, "legacy, or acl for diald/<device id>/command/..."),
+    ("DIALD_DEVICE_ID", r#""den""#, "Device id in the acl layout, else the host name"),
+    ("DIALD_BACKLASH_THRESHOLD", "50", "Events before a reversal counts"),
+    ("DIALD_BACKLASH_CANCEL", "10", "Events that cancel a pending reversal"),
+    ("DIALD_BACKLASH_TIME", "300", "Milliseconds in the new direction that also confirm one"),
+    ("DIALD_BACKLASH_FILTER", r#""threshold""#, "threshold, velocity or deadband"),
+    ("DIALD_BACKLASH_VELOCITY", "20", "Counts per second that settle a reversal by velocity"),
+    ("DIALD_BACKLASH_DEADBAND", "20", "Counts dropped after a reversal with the deadband"),
+    ("DIALD_BACKLASH_RELEASE", r#""apply""#, "apply, halve or discard what backlash held back"),
+    ("DIALD_IDLE_TIMEOUT", r#""30s""#, "Time without events before the dial is idle"),
+    ("DIALD_CLICK_WINDOW", "300", "Milliseconds between clicks of a burst, 0 for none"),
+    ("DIALD_CLICK_MAX", "3", "End a burst once it has this many clicks"),
+    ("DIALD_HAPTICS", r#"["wake", "boundary"]"#, "Triggers that buzz, or all / none"),
+    ("DIALD_HAPTIC_BACKEND", r#""auto""#, "auto, hidraw, hidapi or ff"),
+    ("DIALD_HAPTIC_DEV", r#""/dev/hidraw0""#, "Haptics node, else found from the device"),
+    ("DIALD_HAPTIC_STRENGTH", r#""base + past * 20""#, "Intensity from base, volume and past"),
+    ("DIALD_PIEZO", r#""gpiochip0:18""#, "Piezo buzzer line, :active for active buzzers"),
+    ("DIALD_STATUS_LED", r#""gpiochip0:17""#, "Status LED line, :low for active-low"),
+    ("DIALD_OLED", r#""i2c-1""#, "SSD1306 display bus, optionally :address"),
+    ("DIALD_APP_TOOLS", r#"["spotify=volume", "mpv=scroll"]"#, "Tool per focused application"),
+    (
+        "DIALD_FOCUS_COMMAND",
+        r#""xdotool getactivewindow getwindowclassname""#,
+        "Prints the focused app",
+    ),
+    ("DIALD_OUTPUT_REPORTS", r#""volume: 2, volume * 255 / 100""#, "HID output report templates"),
+    ("DIALD_NIGHT_LIMIT", r#"["20:00-23:00 100-40 until 07:00"]"#, "Evening volume cap rules"),
+    ("DIALD_LOCATION", r#""52.52,13.40""#, "Latitude,longitude for sunset and sunrise"),
+    ("DIALD_GUEST_CAP", "60", "Maximum volume in guest mode"),
+    ("DIALD_GUEST_DURATION", r#""4h""#, "How long guest mode lasts"),
+    ("DIALD_GUEST_CLICKS", "5", "Clicks that turn guest mode on"),
+    ("DIALD_IDLE_SNAPSHOT", "false", "Publish a snapshot when the dial goes idle"),
+    ("DIALD_IDLE_EXEC", r#""logger diald idle""#, "Command to run when the dial goes idle"),
+    ("DIALD_IDLE_PUBLISH", r#""home/dial/idle on""#, "Topic and payload for going idle"),
+    (
+        "DIALD_GESTURES",
+        r#"["scroll long_press -> tool volume, publish mode volume"]"#,
+        "Extra effects of clicks and long presses",
+    ),
+    ("DIALD_PRESS_TURN", "false", "Publish turns made with the button held as press_turn"),
+    ("DIALD_BT_ADDRESS", r#""AA:BB:CC:DD:EE:FF""#, "The dial's Bluetooth address"),
+    ("DIALD_WAKE_INTERVAL", "30", "Seconds between reconnect attempts"),
+    ("DIALD_BT_RSSI_INTERVAL", "60", "Seconds between signal readings, 0 for none"),
+    ("DIALD_BT_STATUS_INTERVAL", "60", "Seconds between connection and battery checks"),
+    ("DIALD_THROTTLE_TEMP", r#""80""#, "Back off above this many °C, or off"),
+    ("DIALD_THROTTLE_LOAD", r#""2""#, "Back off above this load per CPU, or off"),
+    ("DIALD_LATENCY_BUDGET", "50", "Warn when handling events takes this many ms"),
+    (
+        "DIALD_UPDATE_URL",
+        r#""https://api.github.com/repos/eljojo/diald/releases/latest""#,
+        "Release feed",
+    ),
+    ("DIALD_UPDATE_INTERVAL", r#""24h""#, "How often to check for updates"),
+    ("DIALD_PID_FILE", r#""/run/diald.pid""#, "PID file"),
+    ("DIALD_LOG_FILE", r#""/var/log/diald.log""#, "Log file when daemonized"),
+    ("DIALD_LOG_LEVEL", r#""info""#, "error, warn, info, debug or trace"),
+    ("DIALD_CONTROL_SOCKET", r#""/run/diald/control.sock""#, "Control socket path"),
+    ("DIALD_RESTART_TOKEN", r#""secret""#, "Token for remote restarts, else the command token"),
+    ("DIALD_MONITOR", "false", "Run as a read-only monitor instance"),
+    ("DIALD_DRY_RUN", "false", "Log what would be published or played instead"),
+    ("DIALD_WATCH_CONFIG", "true", "Reload this file as soon as it changes"),
+    ("DIALD_EXIT_WHEN_IDLE", r#""30m""#, "Exit once no dial has been touched this long"),
+    ("DIALD_EXIT_WITH_DEVICE", "false", "Exit when the device goes away instead of waiting"),
+    ("DIALD_WAIT_FOR_DEVICE", r#""forever""#, "How long to wait for a device at startup"),
+    ("DIALD_HOTPLUG", "true", "Reopen the device when udev adds it, not every second"),
+    ("DIALD_STATE_FILE", r#""/var/lib/diald/state.toml""#, "Where volume and tool are kept, or off"),
+];
+
+/// The settings whose example is their default.
+pub const DEFAULTS: &[&str] = &[
+    "DIALD_AXES",
+    "DIALD_GRAB",
+    "DIALD_MIRRORED_AXES",
+    "DIALD_INVERT_DIRECTION",
+    "DIALD_TOOL",
+    "DIALD_CURVE",
+    "DIALD_COUNTS_PER_REV",
+    "DIALD_MIN_DELTA_WINDOW",
+    "MQTT_HOST",
+    "MQTT_PORT",
+    "DIALD_ACTIVE_PUBLISH",
+    "DIALD_TOPIC_PREFIX",
+    "DIALD_TOPIC_LAYOUT",
+    "DIALD_BACKLASH_THRESHOLD",
+    "DIALD_BACKLASH_CANCEL",
+    "DIALD_BACKLASH_TIME",
+    "DIALD_BACKLASH_FILTER",
+    "DIALD_BACKLASH_VELOCITY",
+    "DIALD_BACKLASH_DEADBAND",
+    "DIALD_BACKLASH_RELEASE",
+    "DIALD_PRESS_TURN",
+    "DIALD_IDLE_TIMEOUT",
+    "DIALD_CLICK_WINDOW",
+    "DIALD_HAPTIC_BACKEND",
+    "DIALD_GUEST_CAP",
+    "DIALD_GUEST_DURATION",
+    "DIALD_IDLE_SNAPSHOT",
+    "DIALD_WAKE_INTERVAL",
+    "DIALD_BT_RSSI_INTERVAL",
+    "DIALD_BT_STATUS_INTERVAL",
+    "DIALD_THROTTLE_TEMP",
+    "DIALD_THROTTLE_LOAD",
+    "DIALD_UPDATE_INTERVAL",
+    "DIALD_MONITOR",
+    "DIALD_DRY_RUN",
+    "DIALD_WATCH_CONFIG",
+    "DIALD_EXIT_WITH_DEVICE",
+    "DIALD_WAIT_FOR_DEVICE",
+    "DIALD_HOTPLUG",
+];
+
+type Migration = fn(&mut Table) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` turns a version `n + 1` file into version `n + 2`. Renaming
+/// a key or a topic means bumping `VERSION` and appending a step here.
+const MIGRATIONS: &[Migration] = &[into_sections];
+
+static LOADED: RwLock<BTreeMap<String, String>> = RwLock::new(BTreeMap::new());
+
+/// A `[[device]]` or `[[profile]]` section: its name and settings, by
+/// environment name.
+struct Named {
+    name: String,
+    settings: BTreeMap<String, String>,
+}
+
+static DIALS: RwLock<Vec<Named>> = RwLock::new(Vec::new());
+static PROFILES: RwLock<Vec<Named>> = RwLock::new(Vec::new());
+
+thread_local! {
+    /// The `[[device]]` section this thread's dial runs from, see `use_dial`.
+    static DIAL: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// The `[[profile]]` this thread's dial is switched to, see `use_profile`.
+    static PROFILE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn names(sections: &RwLock<Vec<Named>>) -> Vec<String> {
+    sections.read().unwrap().iter().map(|section| section.name.clone()).collect()
+}
+
+fn named_var(sections: &RwLock<Vec<Named>>, current: Option<String>, name: &str) -> Option<String> {
+    let current = current?;
+    let sections = sections.read().unwrap();
+    let section = sections.iter().find(|section| section.name == current)?;
+    section.settings.get(name).cloned()
+}
+
+/// The names of the `[[device]]` sections, in file order.
+pub fn dials() -> Vec<String> {
+    names(&DIALS)
+}
+
+/// Have `var` on this thread answer from the `[[device]]` section `name`
+/// first, or from nothing but the environment and file with `None`. It
+/// follows reloads, as the section is looked up on every call.
+pub fn use_dial(name: Option<&str>) {
+    DIAL.with(|dial| *dial.borrow_mut() = name.map(str::to_string));
+}
+
+/// The `[[device]]` section this thread uses, if any.
+pub fn dial_name() -> Option<String> {
+    DIAL.with(|dial| dial.borrow().clone())
+}
+
+/// The names of the `[[profile]]` sections, in file order.
+pub fn profiles() -> Vec<String> {
+    names(&PROFILES)
+}
+
+/// Switch this thread's dial to the `[[profile]]` `name`, which then wins
+/// over everything else, or back to none. False for a profile that isn't
+/// in the file.
+pub fn use_profile(name: Option<&str>) -> bool {
+    if name.is_some_and(|name| !profiles().iter().any(|profile| profile == name)) {
+        return false;
+    }
+    PROFILE.with(|profile| *profile.borrow_mut() = name.map(str::to_string));
+    true
+}
+
+/// The `[[profile]]` this thread's dial is switched to, if any.
+pub fn profile() -> Option<String> {
+    PROFILE.with(|profile| profile.borrow().clone())
+}
+
+/// `DIALD_<SECTION>_<KEY>` for the setting first known as `name`, e.g.
+/// `DIALD_MQTT_HOST` for `MQTT_HOST`, if that is a different name.
+pub fn standard_name(name: &str) -> Option<String> {
+    let (section, key, _) = SETTINGS.iter().find(|(_, _, env)| *env == name)?;
+    let standard = format!("DIALD_{}_{}", section, key).to_uppercase();
+    (standard != name).then_some(standard)
+}
+
+/// A setting from the environment, by either name; the standard one wins.
+fn env_var(name: &str) -> Result<String, VarError> {
+    if let Some(standard) = standard_name(name) {
+        match env::var(standard) {
+            Err(VarError::NotPresent) => {}
+            other => return other,
+        }
+    }
+    env::var(name)
+}
+
+/// The variable that sets `name` in the environment, if any.
+pub fn set_in_environment(name: &str) -> Option<String> {
+    let standard = standard_name(name).filter(|standard| env::var_os(standard).is_some());
+    standard.or_else(|| env::var_os(name).map(|_| name.to_string()))
+}
+
+/// Whether the environment already sets what `name` does, by either name.
+fn in_environment(name: &str) -> bool {
+    let first = SETTINGS
+        .iter()
+        .map(|(_, _, env)| *env)
+        .find(|env| standard_name(env).is_some_and(|standard| standard == name));
+    env::var_os(name).is_some() || set_in_environment(first.unwrap_or(name)).is_some()
+}
+
+/// Fill in the environment from a `.env` file, for development: `NAME=value`
+/// lines, optionally after `export`, with `#` comments and the value perhaps
+/// in quotes. Settings the environment already has, by either name, are left
+/// alone. A missing file is only an error when `required`.
+pub fn load_env_file(path: &Path, required: bool) -> Result<(), String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound && !required => return Ok(()),
+        Err(err) => return Err(format!("{}: {}", path.display(), err)),
+    };
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .filter(|(name, _)| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            })
+            .ok_or_else(|| format!("{}:{}: expected NAME=value", path.display(), number + 1))?;
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        if !in_environment(name) {
+            // Only called at startup, before diald starts any threads
+            unsafe { env::set_var(name, value) };
+        }
+    }
+    Ok(())
+}
+
+/// An on/off setting: `1`, `true` or `on`, `0`, `false` or `off`, else an
+/// error; `default` when it isn't set.
+pub fn flag(name: &str, default: bool) -> Result<bool, String> {
+    let Ok(value) = var(name) else {
+        return Ok(default);
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => Err(format!("{}: expected true or false, got '{}'", name, value)),
+    }
+}
+
+/// Like `env::var`, falling back to the loaded config file. The dial's
+/// `[[profile]]`, then its `[[device]]` section, come before either.
+pub fn var(name: &str) -> Result<String, VarError> {
+    let named = named_var(&PROFILES, profile(), name);
+    if let Some(value) = named.or_else(|| named_var(&DIALS, dial_name(), name)) {
+        return Ok(value);
+    }
+    match env_var(name) {
+        Err(VarError::NotPresent) => {
+            LOADED.read().unwrap().get(name).cloned().ok_or(VarError::NotPresent)
+        }
+        other => other,
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// `$<var>`, else `$HOME/<fallback>`; relative values are ignored, as the
+/// XDG spec asks.
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(fallback)))
+        .filter(|dir| dir.is_absolute())
+}
+
+/// `--config`, else `DIALD_CONFIG`, else the default path. The flag is true
+/// when the path was asked for, so a missing file is an error.
+///
+/// For users the default is their XDG config file if there is one, then the
+/// system-wide file, and otherwise the XDG path again, so that
+/// `diald init-config` and `diald calibrate` create the file where they can.
+pub fn path(explicit: Option<String>) -> (PathBuf, bool) {
+    if let Some(path) = explicit.or_else(|| env::var("DIALD_CONFIG").ok()) {
+        return (PathBuf::from(path), true);
+    }
+    let system = PathBuf::from(DEFAULT_PATH);
+    if is_root() {
+        return (system, false);
+    }
+    match xdg_dir("XDG_CONFIG_HOME", ".config") {
+        Some(dir) => {
+            let user = dir.join("diald").join("config.toml");
+            let path = if user.exists() || !system.exists() { user } else { system };
+            (path, false)
+        }
+        None => (system, false),
+    }
+}
+
+/// Where diald keeps what it remembers between runs: systemd's
+/// `StateDirectory=` when it runs as a service, `/var/lib/diald` for root,
+/// else `$XDG_STATE_HOME/diald`. The directory may not exist yet.
+pub fn state_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("STATE_DIRECTORY") {
+        // Several directories are separated by colons; the first is ours
+        let dir = PathBuf::from(dir);
+        if let Some(first) = dir.to_str().and_then(|dir| dir.split(':').next()) {
+            return PathBuf::from(first);
+        }
+        return dir;
+    }
+    if is_root() {
+        return PathBuf::from(DEFAULT_STATE_DIR);
+    }
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+        .map_or_else(|| PathBuf::from(DEFAULT_STATE_DIR), |dir| dir.join("diald"))
+}
+
+/// Version 1 kept every setting at the top level under its environment name.
+fn into_sections(table: &mut Table) -> Result<(), String> {
+    for (name, value) in std::mem::take(table) {
+        if name == "version" {
+            table.insert(name, value);
+            continue;
+        }
+        let (section, key, _) = SETTINGS
+            .iter()
+            .find(|(_, _, env)| *env == name)
+            .ok_or_else(|| format!("unknown setting '{}'", name))?;
+        section_mut(table, section)?.insert(key.to_string(), value);
+    }
+    Ok(())
+}
+
+fn section_mut<'a>(table: &'a mut Table, section: &str) -> Result<&'a mut Table, String> {
+    table
+        .entry(section)
+        .or_insert_with(|| Value::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format!("'{}' should be a section", section))
+}
+
+/// The file's table, or `None` if there is no file.
+fn read(path: &Path) -> Result<Option<Table>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("{}: {}", path.display(), err)),
+    };
+    let table = text.parse::<Table>().map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(Some(table))
+}
+
+/// Bring `table` up to `VERSION`. Returns the version it started at; a file
+/// without one is taken to be version 1.
+fn migrate(table: &mut Table) -> Result<i64, String> {
+    let version = match table.get("version") {
+        None => 1,
+        Some(Value::Integer(version)) if *version >= 1 => *version,
+        Some(other) => return Err(format!("version: expected a number, got {}", other)),
+    };
+    if version > VERSION {
+        return Err(format!(
+            "version {} was written by a newer diald (this one reads up to {})",
+            version, VERSION
+        ));
+    }
+    for step in &MIGRATIONS[(version - 1) as usize..] {
+        step(table)?;
+    }
+    table.insert("version".to_string(), Value::Integer(VERSION));
+    Ok(version)
+}
+
+/// A value as its environment variable would spell it. Lists are joined the
+/// way the variable separates them.
+fn env_value(env: &str, value: Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value),
+        Value::Boolean(value) => Some(if value { "1" } else { "0" }.to_string()),
+        Value::Integer(_) | Value::Float(_) => Some(value.to_string()),
+        Value::Array(items) => {
+            let separator =
+                if matches!(env, "DIALD_NIGHT_LIMIT" | "DIALD_GESTURES") { ";" } else { "," };
+            let items: Option<Vec<String>> =
+                items.into_iter().map(|item| env_value(env, item)).collect();
+            Some(items?.join(separator))
+        }
+        _ => None,
+    }
+}
+
+/// The settings in a current-version table, by environment name.
+fn flatten(table: Table) -> Result<BTreeMap<String, String>, String> {
+    let mut settings = BTreeMap::new();
+    for (section, values) in table {
+        if section == "version" {
+            continue;
+        }
+        let Value::Table(values) = values else {
+            return Err(format!("unknown setting '{}'", section));
+        };
+        for (key, value) in values {
+            let (_, _, env) = SETTINGS
+                .iter()
+                .find(|(s, k, _)| *s == section && *k == key)
+                .ok_or_else(|| format!("unknown setting '{}.{}'", section, key))?;
+            let value = env_value(env, value).ok_or_else(|| {
+                format!("{}.{}: expected a string, number, boolean or list", section, key)
+            })?;
+            settings.insert(env.to_string(), value);
+        }
+    }
+    Ok(settings)
+}
+
+/// Take the `[[<key>]]` sections out of a current-version table. In each,
+/// tables are sections; plain keys are settings of the `plain` section, if
+/// there is one.
+fn take_named(table: &mut Table, key: &str, plain: Option<&str>) -> Result<Vec<Named>, String> {
+    let sections = match table.remove(key) {
+        Some(Value::Array(sections)) => sections,
+        Some(section) => {
+            table.insert(key.to_string(), section);
+            return Ok(Vec::new());
+        }
+        None => return Ok(Vec::new()),
+    };
+    let mut named: Vec<Named> = Vec::new();
+    for section in sections {
+        let Value::Table(mut section) = section else {
+            return Err(format!("[[{}]]: expected a section", key));
+        };
+        let name = match section.remove("name") {
+            Some(Value::String(name)) if !name.is_empty() => name,
+            _ => return Err(format!("[[{}]]: every section needs a name", key)),
+        };
+        if named.iter().any(|section| section.name == name) {
+            return Err(format!("[[{}]]: '{}' is there twice", key, name));
+        }
+        let mut sections = Table::new();
+        let mut settings = Table::new();
+        for (key, value) in section {
+            match value {
+                Value::Table(values) => sections.insert(key, Value::Table(values)),
+                value => settings.insert(key, value),
+            };
+        }
+        match plain {
+            Some(plain) => {
+                sections.insert(plain.to_string(), Value::Table(settings));
+            }
+            None if !settings.is_empty() => {
+                let keys: Vec<&String> = settings.keys().collect();
+                return Err(format!("[[{}]] {}: '{}' is not in a section", key, name, keys[0]));
+            }
+            None => {}
+        }
+        let settings = flatten(sections).map_err(|err| format!("[[{}]] {}: {}", key, name, err))?;
+        named.push(Named { name, settings });
+    }
+    Ok(named)
+}
+
+/// The `[[device]]` and `[[profile]]` sections of a current-version table.
+fn take_dials_and_profiles(table: &mut Table) -> Result<(Vec<Named>, Vec<Named>), String> {
+    let dials = take_named(table, "device", Some("device"))?;
+    let profiles = take_named(table, "profile", None)?;
+    if profiles.iter().any(|profile| profile.name == "default") {
+        return Err("[[profile]]: 'default' is taken, it means no profile".to_string());
+    }
+    Ok((dials, profiles))
+}
+
+/// Whether diald can read `table`, checked before anything is written over it.
+fn check(table: &Table) -> Result<(), String> {
+    let mut table = table.clone();
+    take_dials_and_profiles(&mut table)?;
+    flatten(table).map(drop)
+}
+
+/// Read the config file, if there is one, so `var` can fall back to it.
+pub fn load(path: &Path, required: bool) -> Result<(), String> {
+    let Some(mut table) = read(path)? else {
+        if required {
+            return Err(format!("{}: no such file", path.display()));
+        }
+        return Ok(());
+    };
+    let version = migrate(&mut table).map_err(|err| format!("{}: {}", path.display(), err))?;
+    if version < VERSION {
+        warn!(
+            "config: {} is version {}, `diald --migrate-config` updates it to {}",
+            path.display(),
+            version,
+            VERSION
+        );
+    }
+    let (dials, profiles) = take_dials_and_profiles(&mut table)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    let settings = flatten(table).map_err(|err| format!("{}: {}", path.display(), err))?;
+    *LOADED.write().unwrap() = settings;
+    *DIALS.write().unwrap() = dials;
+    *PROFILES.write().unwrap() = profiles;
+    Ok(())
+}
+
+/// `diald --migrate-config`: bring the config file up to `VERSION` and add
+/// the settings that are only in the environment. The old file is kept as
+/// `.bak` next to it. Returns what changed, for the user.
+pub fn migrate_file(path: &Path) -> Result<String, String> {
+    let existing = read(path)?;
+    let mut table = existing.clone().unwrap_or_default();
+    let version = migrate(&mut table).map_err(|err| format!("{}: {}", path.display(), err))?;
+    // Nothing is written over a file diald couldn't read
+    check(&table).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut moved = Vec::new();
+    for (section, key, env) in SETTINGS {
+        let Ok(value) = env_var(env) else {
+            continue;
+        };
+        let values = section_mut(&mut table, section)?;
+        if !values.contains_key(*key) {
+            values.insert(key.to_string(), Value::String(value));
+            moved.push(set_in_environment(env).unwrap_or_else(|| env.to_string()));
+        }
+    }
+
+    let mut summary = match &existing {
+        Some(_) if version < VERSION => {
+            format!("{}: migrated from version {} to {}\n", path.display(), version, VERSION)
+        }
+        Some(_) => format!("{}: already version {}\n", path.display(), VERSION),
+        None => format!("{}: created at version {}\n", path.display(), VERSION),
+    };
+    for env in &moved {
+        summary.push_str(&format!("  {} taken from the environment\n", env));
+    }
+    if existing.as_ref() != Some(&table) {
+        write(path, &table, existing.is_some())?;
+    }
+    Ok(summary)
+}
+
+/// The file `diald init-config` writes: every setting, commented out, with
+/// what it does and its environment variable.
+pub fn template() -> String {
+    let mut out = String::new();
+    out.push_str("# diald configuration, written by `diald init-config`. Each setting is\n");
+    out.push_str("# shown at its default, or an example where it has none. Uncomment to\n");
+    out.push_str("# change it; the environment variable in parentheses wins over the file.\n\n");
+    out.push_str(&format!("version = {}\n", VERSION));
+    let mut section = "";
+    for (name, key, env) in SETTINGS {
+        if *name != section {
+            section = name;
+            out.push_str(&format!("\n[{}]\n", section));
+        }
+        let name = standard_name(env).unwrap_or_else(|| env.to_string());
+        match EXAMPLES.iter().find(|(example_env, ..)| example_env == env) {
+            Some((_, example, help)) => {
+                out.push_str(&format!("# {} ({})\n# {} = {}\n", help, name, key, example));
+            }
+            None => out.push_str(&format!("# {} ({})\n", key, name)),
+        }
+    }
+    out
+}
+
+/// `diald init-config`: write `template()` to `path`. An existing file is
+/// left alone; `--migrate-config` is what updates one.
+pub fn init_file(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return Err(format!("{}: already exists", path.display()));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    }
+    fs::write(path, template()).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Store one setting, by environment name, in the config file, creating the
+/// file if there is none.
+pub fn set(path: &Path, env: &str, value: Value) -> Result<(), String> {
+    let existing = read(path)?;
+    let mut table = existing.clone().unwrap_or_default();
+    migrate(&mut table).map_err(|err| format!("{}: {}", path.display(), err))?;
+    check(&table).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let (section, key, _) = SETTINGS
+        .iter()
+        .find(|(_, _, name)| *name == env)
+        .ok_or_else(|| format!("unknown setting '{}'", env))?;
+    section_mut(&mut table, section)?.insert(key.to_string(), value);
+    write(path, &table, existing.is_some())
+}
+
+/// Replace the file with `table`, keeping the old one as `.bak`.
+fn write(path: &Path, table: &Table, exists: bool) -> Result<(), String> {
+    if exists {
+        let backup = path.with_extension("toml.bak");
+        fs::copy(path, &backup).map_err(|err| format!("{}: {}", backup.display(), err))?;
+    } else if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    }
+    let text = toml::to_string(table).map_err(|err| err.to_string())?;
+    let tmp = path.with_extension("toml.tmp");
+    fs::write(&tmp, text).map_err(|err| format!("{}: {}", tmp.display(), err))?;
+    fs::rename(&tmp, path).map_err(|err| format!("{}: {}", path.display(), err))
+}
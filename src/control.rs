@@ -0,0 +1,136 @@
+//! Local control socket: a line-based protocol on a Unix stream socket.
+//!
+//! The socket is either inherited from systemd socket activation (`LISTEN_FDS`)
+//! or bound at the path in `DIALD_CONTROL_SOCKET`. Each line is one command and
+//! gets exactly one line back.
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::wake::Waker;
+
+/// First file descriptor passed by systemd (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+pub struct ControlRequest {
+    pub command: String,
+    reply: Sender<String>,
+}
+
+impl ControlRequest {
+    pub fn reply(self, response: impl Into<String>) {
+        let _ = self.reply.send(response.into());
+    }
+}
+
+/// Sockets handed over by systemd, keyed by their `FileDescriptorName=`.
+/// Unnamed sockets are reported as "unknown", as systemd does.
+fn listen_fds() -> Vec<(String, RawFd)> {
+    let pid_matches = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+    // Don't leak activation state into anything we spawn
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    if !pid_matches {
+        return Vec::new();
+    }
+
+    let mut names = names.split(':');
+    (0..count)
+        .map(|i| {
+            let fd = LISTEN_FDS_START + i;
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let name = names.next().filter(|n| !n.is_empty()).unwrap_or("unknown");
+            (name.to_string(), fd)
+        })
+        .collect()
+}
+
+fn control_listener() -> Option<UnixListener> {
+    let activated = listen_fds();
+    let mut control_fd = None;
+    for (name, fd) in &activated {
+        match name.as_str() {
+            "control" | "unknown" if control_fd.is_none() => control_fd = Some(*fd),
+            _ => warn!("diald: ignoring activated socket {} (fd {})", name, fd),
+        }
+    }
+    if let Some(fd) = control_fd {
+        log!("diald: control socket from systemd (fd {})", fd);
+        return Some(unsafe { UnixListener::from_raw_fd(fd) });
+    }
+
+    let path = config::var("DIALD_CONTROL_SOCKET").ok()?;
+    // A stale socket from a previous run would make bind fail
+    let _ = fs::remove_file(&path);
+    match UnixListener::bind(&path) {
+        Ok(listener) => {
+            log!("diald: control socket at {}", path);
+            Some(listener)
+        }
+        Err(err) => {
+            error!("diald: failed to bind control socket {} ({})", path, err);
+            None
+        }
+    }
+}
+
+fn serve_client(stream: UnixStream, tx: &Sender<ControlRequest>, waker: &Waker) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let command = line.trim().to_string();
+        if command.is_empty() {
+            continue;
+        }
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if tx.send(ControlRequest { command, reply: reply_tx }).is_err() {
+            return;
+        }
+        waker.wake();
+        let response = reply_rx
+            .recv_timeout(Duration::from_secs(2))
+            .unwrap_or_else(|_| "error: timed out".to_string());
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+/// `waker` is woken for each request, so the main loop answers right away.
+pub fn spawn_control(waker: Waker) -> Option<Receiver<ControlRequest>> {
+    let listener = control_listener()?;
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (tx, waker) = (tx.clone(), waker.clone());
+            thread::spawn(move || serve_client(stream, &tx, &waker));
+        }
+    });
+
+    Some(rx)
+}
@@ -0,0 +1,137 @@
+//! Classic double-fork daemonization and PID file handling for init systems
+//! that don't supervise foreground processes (runit, OpenRC, ...), and the
+//! SIGHUP that asks for a config reload. A restart asked for remotely is a
+//! clean exit with `RESTART_EXIT_STATUS`, left to the supervisor to restart.
+//! For `--exit-when-idle`, it keeps track of when any dial was last touched.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// EX_TEMPFAIL; the systemd unit restarts on it (`RestartForceExitStatus=`).
+pub const RESTART_EXIT_STATUS: i32 = 75;
+/// EX_UNAVAILABLE: no device turned up within `--wait-for-device`.
+pub const NO_DEVICE_EXIT_STATUS: i32 = 69;
+
+static RELOADS: AtomicU64 = AtomicU64::new(0);
+static RESTART_REQUESTED: AtomicBool = AtomicBool::new(false);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+/// When a dial last had input, in milliseconds after `STARTED`.
+static LAST_INPUT: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Catch SIGHUP instead of dying from it; see `reloads`.
+pub fn watch_sighup() {
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+/// How many SIGHUPs came in so far. Each dial keeps the count it last acted
+/// on, so every one of them reloads.
+pub fn reloads() -> u64 {
+    RELOADS.load(Ordering::Relaxed)
+}
+
+/// Reload the config as if SIGHUP had come in, e.g. because the file changed.
+pub fn request_reload() {
+    RELOADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Ask the main loop to shut down for a restart; see `restart_requested`.
+pub fn request_restart() {
+    RESTART_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn restart_requested() -> bool {
+    RESTART_REQUESTED.load(Ordering::Relaxed)
+}
+
+fn started() -> Instant {
+    *STARTED.get_or_init(Instant::now)
+}
+
+/// A dial had input just now; shared by all dials, see `idle_for`.
+pub fn note_input() {
+    LAST_INPUT.store(started().elapsed().as_millis() as u64, Ordering::Relaxed);
+}
+
+/// How long since any dial last had input, or since the first call if none
+/// has yet.
+pub fn idle_for() -> Duration {
+    let last = Duration::from_millis(LAST_INPUT.load(Ordering::Relaxed));
+    started().elapsed().saturating_sub(last)
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Open and lock the PID file. The lock is held for the lifetime of the
+/// process (and survives the daemonize forks), so a stale file left behind by a
+/// crash never blocks the next start, but a running instance does.
+pub fn lock_pid_file(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if locked < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(io::Error::other(format!(
+                "{} is locked; is diald already running?",
+                path.display()
+            )));
+        }
+        return Err(err);
+    }
+    Ok(file)
+}
+
+pub fn write_pid(file: &mut File) -> io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())?;
+    file.flush()
+}
+
+/// Detach from the controlling terminal. Stdin is pointed at /dev/null and
+/// stdout/stderr at `log_file` (or /dev/null), so logs keep going somewhere.
+pub fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    // Open everything before forking so errors still reach the terminal
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let log = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => null.try_clone()?,
+    };
+
+    if check(unsafe { libc::fork() })? > 0 {
+        unsafe { libc::_exit(0) };
+    }
+    check(unsafe { libc::setsid() })?;
+    // Fork again so we can never reacquire a controlling terminal
+    if check(unsafe { libc::fork() })? > 0 {
+        unsafe { libc::_exit(0) };
+    }
+
+    std::env::set_current_dir("/")?;
+    unsafe { libc::umask(0o022) };
+
+    check(unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) })?;
+    check(unsafe { libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) })?;
+    check(unsafe { libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) })?;
+    Ok(())
+}
@@ -0,0 +1,249 @@
+//! Desktop output: a uinput virtual mouse and keyboard driven by the scroll,
+//! zoom and undo tools.
+
+use std::time::{Duration, Instant};
+
+use crate::dial::Tool;
+use crate::input;
+
+/// Hi-res wheel units per notch, as defined by the kernel.
+const HI_RES_PER_NOTCH: i32 = 120;
+/// How far a turn is one scroll notch.
+const DEGREES_PER_NOTCH: i32 = 10;
+/// How far a turn is one undo/redo step; coarser so steps are deliberate.
+const DEGREES_PER_UNDO: i32 = 20;
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+
+    use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+    use evdev::{AttributeSet, EventType, InputEvent, Key, RelativeAxisType};
+
+    pub struct VirtualInput(VirtualDevice);
+
+    fn key(key: Key, pressed: bool) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), pressed as i32)
+    }
+
+    impl VirtualInput {
+        pub fn open() -> io::Result<Self> {
+            let mut axes = AttributeSet::<RelativeAxisType>::new();
+            for axis in [
+                RelativeAxisType::REL_WHEEL,
+                RelativeAxisType::REL_HWHEEL,
+                RelativeAxisType::REL_WHEEL_HI_RES,
+                RelativeAxisType::REL_HWHEEL_HI_RES,
+            ] {
+                axes.insert(axis);
+            }
+            // libinput only treats the device as a pointer if it has a button
+            let mut keys = AttributeSet::<Key>::new();
+            for key in [Key::BTN_LEFT, Key::KEY_LEFTCTRL, Key::KEY_LEFTSHIFT, Key::KEY_Z] {
+                keys.insert(key);
+            }
+            let device = VirtualDeviceBuilder::new()?
+                .name("diald virtual wheel")
+                .with_relative_axes(&axes)?
+                .with_keys(&keys)?
+                .build()?;
+            Ok(Self(device))
+        }
+
+        /// Emit one frame with hi-res units and, if any, whole legacy notches.
+        pub fn scroll(&mut self, hi_res: i32, notches: i32, horizontal: bool) -> io::Result<()> {
+            let (axis, hi_res_axis) = if horizontal {
+                (RelativeAxisType::REL_HWHEEL, RelativeAxisType::REL_HWHEEL_HI_RES)
+            } else {
+                (RelativeAxisType::REL_WHEEL, RelativeAxisType::REL_WHEEL_HI_RES)
+            };
+            let mut events = vec![InputEvent::new(EventType::RELATIVE, hi_res_axis.0, hi_res)];
+            if notches != 0 {
+                events.push(InputEvent::new(EventType::RELATIVE, axis.0, notches));
+            }
+            self.0.emit(&events)
+        }
+
+        /// Hold or release Ctrl (and Shift), each in its own frame so
+        /// applications see the modifier before anything else.
+        pub fn modifiers(&mut self, ctrl: bool, shift: bool) -> io::Result<()> {
+            let mut events = vec![key(Key::KEY_LEFTCTRL, ctrl)];
+            if shift || !ctrl {
+                events.push(key(Key::KEY_LEFTSHIFT, shift));
+            }
+            self.0.emit(&events)
+        }
+
+        pub fn tap_z(&mut self) -> io::Result<()> {
+            self.0.emit(&[key(Key::KEY_Z, true)])?;
+            self.0.emit(&[key(Key::KEY_Z, false)])
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+
+    pub struct VirtualInput;
+
+    impl VirtualInput {
+        pub fn open() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "uinput is Linux-only"))
+        }
+
+        pub fn scroll(&mut self, _hi_res: i32, _notches: i32, _horizontal: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn modifiers(&mut self, _ctrl: bool, _shift: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn tap_z(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+/// Turns processed dial rotation into wheel and key events for the desktop
+/// tools. The virtual device is only created once something uses it.
+pub struct DesktopOutput {
+    device: Option<platform::VirtualInput>,
+    open_failed: bool,
+    last_scroll_at: Option<Instant>,
+    // Hi-res units not yet reported as a whole legacy notch, per axis
+    vertical_remainder: i32,
+    horizontal_remainder: i32,
+    // Raw counts towards the next undo/redo step
+    undo_remainder: i32,
+    counts_per_notch: i32,
+    counts_per_undo: i32,
+}
+
+impl Default for DesktopOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DesktopOutput {
+    pub fn new() -> Self {
+        let mut output = Self {
+            device: None,
+            open_failed: false,
+            last_scroll_at: None,
+            vertical_remainder: 0,
+            horizontal_remainder: 0,
+            undo_remainder: 0,
+            counts_per_notch: 0,
+            counts_per_undo: 0,
+        };
+        output.set_counts_per_revolution(input::SURFACE_DIAL_COUNTS_PER_REV);
+        output
+    }
+
+    /// The dial's raw counts per turn (see `diald calibrate`), so notches and
+    /// undo steps are the same angle on any hardware.
+    pub fn set_counts_per_revolution(&mut self, counts: i32) {
+        self.counts_per_notch = (counts * DEGREES_PER_NOTCH / 360).max(1);
+        self.counts_per_undo = (counts * DEGREES_PER_UNDO / 360).max(1);
+    }
+
+    pub fn rotate(&mut self, tool: Tool, delta: i32, horizontal: bool, now: Instant) {
+        if self.device.is_none() && !self.open_failed {
+            match platform::VirtualInput::open() {
+                Ok(device) => {
+                    log!("diald: created virtual wheel");
+                    self.device = Some(device);
+                }
+                Err(err) => {
+                    warn!("diald: failed to create virtual wheel ({})", err);
+                    self.open_failed = true;
+                }
+            }
+        }
+        if self.device.is_none() {
+            return;
+        }
+
+        let result = match tool {
+            Tool::Volume => Ok(()),
+            Tool::Scroll => self.scroll(delta, horizontal, now),
+            // Clockwise zooms in, which is wheel up
+            Tool::Zoom => self.zoom(-delta),
+            Tool::Undo => self.undo(delta),
+        };
+        if let Err(err) = result {
+            warn!("diald: virtual wheel write failed ({})", err);
+            self.device = None;
+            self.open_failed = true;
+        }
+    }
+
+    fn device(&mut self) -> &mut platform::VirtualInput {
+        self.device.as_mut().expect("virtual device is open")
+    }
+
+    /// Hi-res units for a raw delta, plus any whole notches now completed.
+    fn wheel_units(&mut self, delta: i32, horizontal: bool) -> (i32, i32) {
+        // Clockwise scrolls down or right; REL_WHEEL counts up as positive
+        let mut hi_res = delta * HI_RES_PER_NOTCH / self.counts_per_notch;
+        if !horizontal {
+            hi_res = -hi_res;
+        }
+        let remainder = if horizontal {
+            &mut self.horizontal_remainder
+        } else {
+            &mut self.vertical_remainder
+        };
+        *remainder += hi_res;
+        let notches = *remainder / HI_RES_PER_NOTCH;
+        *remainder -= notches * HI_RES_PER_NOTCH;
+        (hi_res, notches)
+    }
+
+    fn scroll(&mut self, delta: i32, horizontal: bool, now: Instant) -> std::io::Result<()> {
+        let gap = self.last_scroll_at.map(|t| now.duration_since(t));
+        self.last_scroll_at = Some(now);
+        let acceleration = match gap {
+            Some(gap) if gap < Duration::from_millis(15) => 3,
+            Some(gap) if gap < Duration::from_millis(40) => 2,
+            _ => 1,
+        };
+
+        let (hi_res, notches) = self.wheel_units(delta * acceleration, horizontal);
+        if hi_res == 0 {
+            return Ok(());
+        }
+        self.device().scroll(hi_res, notches, horizontal)
+    }
+
+    /// Ctrl+wheel. The caller flips `delta` so clockwise is wheel up.
+    fn zoom(&mut self, delta: i32) -> std::io::Result<()> {
+        let (hi_res, notches) = self.wheel_units(delta, false);
+        if hi_res == 0 {
+            return Ok(());
+        }
+        let device = self.device();
+        device.modifiers(true, false)?;
+        device.scroll(hi_res, notches, false)?;
+        device.modifiers(false, false)
+    }
+
+    fn undo(&mut self, delta: i32) -> std::io::Result<()> {
+        self.undo_remainder += delta;
+        let steps = self.undo_remainder / self.counts_per_undo;
+        self.undo_remainder -= steps * self.counts_per_undo;
+        if steps == 0 {
+            return Ok(());
+        }
+        let device = self.device();
+        // Counter-clockwise undoes, clockwise redoes
+        device.modifiers(true, steps > 0)?;
+        for _ in 0..steps.abs() {
+            device.tap_z()?;
+        }
+        device.modifiers(false, false)
+    }
+}
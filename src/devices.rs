@@ -0,0 +1,38 @@
+//! `diald list-devices`: every input device that could be a dial, with what
+//! it reports and where its haptics would go, so picking `--device` doesn't
+//! take `evtest`.
+
+use std::fmt::Write;
+
+use crate::haptics;
+use crate::input;
+
+pub fn render() -> Result<String, String> {
+    let devices = input::list().map_err(|err| err.to_string())?;
+    if devices.is_empty() {
+        return Ok("No input devices with a rotation axis or volume keys found. Is the dial \
+                   connected, and can this user read /dev/input?\n"
+            .to_string());
+    }
+    let mut out = String::new();
+    for device in &devices {
+        let marker = if device.matches { "*" } else { " " };
+        writeln!(out, "{} {}  {}", marker, device.path.display(), device.name).unwrap();
+        if let Some(link) = input::stable_path(&device.path) {
+            writeln!(out, "    by-id   {}", link.display()).unwrap();
+        }
+        if let Some(phys) = device.phys.as_deref().filter(|phys| !phys.is_empty()) {
+            writeln!(out, "    phys    {}", phys).unwrap();
+        }
+        if let Some((vendor, product)) = device.id {
+            writeln!(out, "    id      {:04x}:{:04x}", vendor, product).unwrap();
+        }
+        if !device.axes.is_empty() {
+            writeln!(out, "    axes    {}", device.axes.join(", ")).unwrap();
+        }
+        let hidraw = haptics::hidraw_node(&device.path);
+        writeln!(out, "    hidraw  {}", hidraw.as_deref().unwrap_or("none")).unwrap();
+    }
+    out.push_str("\n* reports a rotation axis from DIALD_AXES (REL_DIAL by default)\n");
+    Ok(out)
+}
@@ -0,0 +1,1250 @@
+//! The dial state machine: turns input events into volume changes (or desktop
+//! tool input) and feedback, independent of where the events come from or where results go.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::haptics::Trigger;
+use crate::input::InputEvent;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum DialMode {
+    Idle,
+    Active,
+    Backlash,
+}
+
+impl DialMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DialMode::Idle => "idle",
+            DialMode::Active => "active",
+            DialMode::Backlash => "backlash",
+        }
+    }
+}
+
+/// What moves the dial from one mode to another; see `TRANSITIONS`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Cause {
+    /// Any input event.
+    Input,
+    /// Rotation against the direction the dial was turning.
+    Reversal,
+    /// Enough events back in the original direction: the reversal was a
+    /// wobble, and everything buffered is released.
+    BacklashCancel,
+    /// Enough events in the new direction: the reversal was real, and the
+    /// buffered events in that direction are released.
+    BacklashConfirm,
+    /// No events for the idle timeout.
+    IdleTimeout,
+    /// A reset, asked for from outside or on (re)attaching a device.
+    Reset,
+}
+
+pub struct Transition {
+    pub from: &'static [DialMode],
+    pub cause: Cause,
+    pub to: DialMode,
+}
+
+/// Every mode change the dial makes. The code decides when a cause applies;
+/// where it leads is only ever looked up here, so `diald dump-fsm` draws
+/// what the dial actually does.
+pub const TRANSITIONS: &[Transition] = &[
+    Transition { from: &[DialMode::Idle], cause: Cause::Input, to: DialMode::Active },
+    Transition { from: &[DialMode::Active], cause: Cause::Reversal, to: DialMode::Backlash },
+    Transition {
+        from: &[DialMode::Backlash],
+        cause: Cause::BacklashCancel,
+        to: DialMode::Active,
+    },
+    Transition {
+        from: &[DialMode::Backlash],
+        cause: Cause::BacklashConfirm,
+        to: DialMode::Active,
+    },
+    Transition {
+        from: &[DialMode::Active, DialMode::Backlash],
+        cause: Cause::IdleTimeout,
+        to: DialMode::Idle,
+    },
+    Transition {
+        from: &[DialMode::Active, DialMode::Backlash],
+        cause: Cause::Reset,
+        to: DialMode::Idle,
+    },
+];
+
+/// What rotation drives, like the Surface Dial's tools on Windows.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Tool {
+    Volume,
+    /// Rotation scrolls a virtual mouse wheel; horizontally while pressed.
+    Scroll,
+    /// Ctrl+scroll, which zooms in most desktop applications.
+    Zoom,
+    /// Ctrl+Z counter-clockwise, Ctrl+Shift+Z clockwise.
+    Undo,
+}
+
+impl Tool {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tool::Volume => "volume",
+            Tool::Scroll => "scroll",
+            Tool::Zoom => "zoom",
+            Tool::Undo => "undo",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Tool> {
+        match name {
+            "volume" => Some(Tool::Volume),
+            "scroll" => Some(Tool::Scroll),
+            "zoom" => Some(Tool::Zoom),
+            "undo" => Some(Tool::Undo),
+            _ => None,
+        }
+    }
+}
+
+/// Side effects requested by the state machine, carried out by the caller.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Play the standard chunky haptic buzz, for the given reason.
+    Buzz(Trigger),
+    /// The volume changed enough to be printed and published.
+    Volume(i32),
+    /// The button was pressed and released.
+    Click,
+    /// The button was held for at least `LONG_PRESS` and released, without
+    /// turning. Takes the place of a click.
+    LongPress,
+    /// The dial was turned this many steps while the button was held, with
+    /// `set_press_turn` on. Takes the place of a click.
+    PressTurn(i32),
+    /// The dial was turned this many steps while the button was held, and it
+    /// was ignored. Only for at least `IGNORED_HINT_STEPS`, on release.
+    RotationIgnored(i32),
+    /// Processed rotation in raw counts, for the desktop tools.
+    Desktop { delta: i32, horizontal: bool },
+    /// The backlash filter held, released or dropped rotation.
+    Backlash(BacklashEvent),
+}
+
+/// What the backlash filter did, in signed raw counts, so a turn the dial
+/// seemed to ignore can be traced to it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BacklashEvent {
+    /// Rotation went against the direction `from`; it is held from here on.
+    Enter { from: i32 },
+    /// The reversal was real: of the `held` counts the new way, `applied`
+    /// moved the value (see `BacklashRelease`), and `dropped` were lost.
+    Exit { held: i32, applied: i32, dropped: i32 },
+    /// It was a wobble: of the `held` counts, `applied` moved the value.
+    Cancel { held: i32, applied: i32 },
+}
+
+impl BacklashEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BacklashEvent::Enter { .. } => "backlash_enter",
+            BacklashEvent::Exit { .. } => "backlash_exit",
+            BacklashEvent::Cancel { .. } => "backlash_cancel",
+        }
+    }
+
+    /// The `<prefix>/backlash` payload, e.g.
+    /// `{"event":"backlash_exit","held":-500,"applied":-500,"dropped":50}`.
+    pub fn payload(&self) -> String {
+        let counts = match *self {
+            BacklashEvent::Enter { from } => format!("\"from\":{}", from),
+            BacklashEvent::Exit { held, applied, dropped } => {
+                format!("\"held\":{},\"applied\":{},\"dropped\":{}", held, applied, dropped)
+            }
+            BacklashEvent::Cancel { held, applied } => {
+                format!("\"held\":{},\"applied\":{}", held, applied)
+            }
+        };
+        format!("{{\"event\":\"{}\",{}}}", self.as_str(), counts)
+    }
+}
+
+/// How the dial tells a reversal from backlash.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BacklashFilter {
+    /// Count consecutive events in the new direction, holding the last
+    /// `threshold` events back meanwhile.
+    Threshold,
+    /// Follow a smoothed velocity, with this much hysteresis in counts per
+    /// second: a reversal holds events until the dial turns that fast the new
+    /// way, and is cancelled once it turns that fast the old way again. A
+    /// slow turn reverses as soon as a fast one, and nothing is held back
+    /// while turning one way.
+    Velocity(f64),
+    /// Drop this many counts after each reversal, the width of the play in
+    /// the mechanism, and pass everything else straight through: a small,
+    /// predictable loss instead of holding events back.
+    Deadband(u32),
+}
+
+/// What becomes of the events held back in backlash once it ends, either
+/// way. Released all at once, they can make the value jump the instant the
+/// mode exits.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BacklashRelease {
+    /// Apply them in full, as if they had never been held.
+    Apply,
+    /// Apply half of them.
+    Halve,
+    /// Drop them; only what comes after counts.
+    Discard,
+}
+
+impl BacklashRelease {
+    /// The part of `buffered` raw counts to apply.
+    fn of(self, buffered: i32) -> i32 {
+        match self {
+            BacklashRelease::Apply => buffered,
+            BacklashRelease::Halve => buffered / 2,
+            BacklashRelease::Discard => 0,
+        }
+    }
+}
+
+/// Time constant of the velocity estimate for `BacklashFilter::Velocity`.
+pub const VELOCITY_SMOOTHING: Duration = Duration::from_millis(150);
+/// Counts per second, for `BacklashFilter::Velocity`.
+pub const VELOCITY_HYSTERESIS: f64 = 20.0;
+/// Counts dropped after a reversal, for `BacklashFilter::Deadband`.
+pub const BACKLASH_DEADBAND: u32 = 20;
+
+/// Delay buffer for backlash compensation.
+/// Events are held for `lookahead` events before being released, giving us time
+/// to detect direction changes before committing potentially-spurious events.
+struct DelayBuffer {
+    events: VecDeque<i32>,
+    lookahead: usize,
+}
+
+impl DelayBuffer {
+    fn new(lookahead: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(lookahead + 1),
+            lookahead,
+        }
+    }
+
+    /// Push an event. Returns the oldest event if buffer exceeds lookahead size
+    /// (their sum, if the lookahead just shrank).
+    fn push(&mut self, value: i32) -> Option<i32> {
+        self.events.push_back(value);
+        let mut released = None;
+        while self.events.len() > self.lookahead {
+            let oldest = self.events.pop_front().unwrap_or(0);
+            released = Some(released.unwrap_or(0) + oldest);
+        }
+        released
+    }
+
+    /// Hold an event without releasing any.
+    fn hold(&mut self, value: i32) {
+        self.events.push_back(value);
+    }
+
+    /// Drain buffer, keeping only events matching the given direction.
+    /// Returns the sum of matching events and of the others, which are
+    /// dropped. Used when exiting confirmed backlash.
+    fn drain_matching(&mut self, direction: i32) -> (i32, i32) {
+        let (matching, others): (Vec<i32>, Vec<i32>) =
+            self.events.drain(..).partition(|v| v.signum() == direction);
+        (matching.iter().sum(), others.iter().sum())
+    }
+
+    /// Drain buffer, returning sum of all events.
+    /// Used when cancelling false-positive backlash.
+    fn drain_all(&mut self) -> i32 {
+        let sum = self.events.iter().sum();
+        self.events.clear();
+        sum
+    }
+
+    fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+/// The range rotation moves through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    /// 0-100, one unit per detent's worth of counts. Intermediate values are
+    /// throttled when published.
+    Continuous,
+    /// Positions `0..=steps`, for targets that only have so many (an AVR in
+    /// 0.5 dB steps, a 24-step dimmer). A full sweep is as far a turn as 0-100,
+    /// and every step is published.
+    Stepped(i32),
+    /// `min..=max` in steps of `step`, for targets that aren't a percentage
+    /// (-60 to 0 dB, 16 to 28 °C). Otherwise like `Stepped`, with a position
+    /// per step; only the values published and accepted differ.
+    Scaled { min: f64, max: f64, step: f64 },
+}
+
+impl Scale {
+    /// Bottom, top and step of the published values.
+    fn bounds(self) -> (f64, f64, f64) {
+        match self {
+            Scale::Continuous => (0.0, 100.0, 1.0),
+            Scale::Stepped(steps) => (0.0, steps as f64, 1.0),
+            Scale::Scaled { min, max, step } => (min, max, step),
+        }
+    }
+}
+
+/// How the published value follows the dial across the range. The dial's
+/// own positions stay evenly spaced; only the values they stand for bend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// An audio taper, 40 dB across the range: fine steps near the bottom,
+    /// coarse ones near the top.
+    Log,
+    /// Shares of the range (0-1, rising) at evenly spaced points of the
+    /// dial's travel, with straight lines in between.
+    Table(Arc<[f64]>),
+}
+
+impl Curve {
+    /// The share of the range at `travel`, both 0-1.
+    fn apply(&self, travel: f64) -> f64 {
+        match self {
+            Curve::Linear => travel,
+            Curve::Log => (10f64.powf(2.0 * travel) - 1.0) / 99.0,
+            Curve::Table(points) => {
+                let at = travel * (points.len() - 1) as f64;
+                let index = (at.floor() as usize).min(points.len() - 2);
+                let (low, high) = (points[index], points[index + 1]);
+                low + (at - index as f64) * (high - low)
+            }
+        }
+    }
+
+    /// The travel at which the curve reaches `share`, the first one where it
+    /// stays flat.
+    fn invert(&self, share: f64) -> f64 {
+        match self {
+            Curve::Linear => share,
+            Curve::Log => (99.0 * share + 1.0).log10() / 2.0,
+            Curve::Table(points) => {
+                let segments = (points.len() - 1) as f64;
+                let Some(index) = points.windows(2).position(|pair| pair[1] >= share) else {
+                    return 1.0;
+                };
+                let (low, high) = (points[index], points[index + 1]);
+                let along = if high > low { ((share - low) / (high - low)).max(0.0) } else { 0.0 };
+                (index as f64 + along) / segments
+            }
+        }
+    }
+}
+
+/// How rotation maps onto volume.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeModel {
+    pub scale: Scale,
+    pub curve: Curve,
+}
+
+impl From<Scale> for VolumeModel {
+    fn from(scale: Scale) -> Self {
+        Self { scale, curve: Curve::Linear }
+    }
+}
+
+impl VolumeModel {
+    /// The highest volume, as a position: the volume everywhere inside
+    /// diald, counted from 0 at the bottom of the range.
+    pub fn range(&self) -> i32 {
+        match self.scale {
+            Scale::Continuous => 100,
+            Scale::Stepped(steps) => steps,
+            Scale::Scaled { min, max, step } => ((max - min) / step).round() as i32,
+        }
+    }
+
+    fn counts_per_unit(&self) -> i32 {
+        match self.scale {
+            Scale::Continuous => DETENT_COUNTS,
+            Scale::Stepped(_) | Scale::Scaled { .. } => (100 * DETENT_COUNTS / self.range()).max(1),
+        }
+    }
+
+    /// The value at `position`, as published.
+    pub fn value(&self, position: i32) -> f64 {
+        let (min, max, step) = self.scale.bounds();
+        if self.curve == Curve::Linear && !matches!(self.scale, Scale::Scaled { .. }) {
+            return position as f64;
+        }
+        // Rounded to the step's decimals, so 0.1 steps don't show as 0.30000000000000004
+        let scale = (0..6).map(|d| 10f64.powi(d)).find(|s| (step * s).fract().abs() < 1e-9);
+        let mut scale = scale.unwrap_or(1e6);
+        let value = match self.curve {
+            Curve::Linear => min + position as f64 * step,
+            ref curve => {
+                // A curve falls between the steps, so one more decimal
+                scale *= 10.0;
+                let travel = (position as f64 / self.range() as f64).clamp(0.0, 1.0);
+                min + curve.apply(travel) * (max - min)
+            }
+        };
+        // Plus zero, so a value rounded up to zero isn't shown as -0
+        (value.min(max) * scale).round() / scale + 0.0
+    }
+
+    /// The position nearest to a value from outside.
+    pub fn position(&self, value: f64) -> i32 {
+        let (min, max, step) = self.scale.bounds();
+        match self.curve {
+            Curve::Linear => ((value - min) / step).round() as i32,
+            ref curve => {
+                let share = ((value - min) / (max - min)).clamp(0.0, 1.0);
+                (curve.invert(share) * self.range() as f64).round() as i32
+            }
+        }
+    }
+
+    /// A change in value from outside, in positions. Along a curve it is as
+    /// far as turning the dial would take a linear range.
+    pub fn steps(&self, delta: f64) -> i32 {
+        let (_, _, step) = self.scale.bounds();
+        (delta / step).round() as i32
+    }
+
+    /// The value at `position`, e.g. `50` or `-23.5`.
+    pub fn format(&self, position: i32) -> String {
+        self.value(position).to_string()
+    }
+
+    /// `percent` of the range, rounded down.
+    fn units_at_percent(&self, percent: i32) -> i32 {
+        percent * self.range() / 100
+    }
+}
+
+struct DialState {
+    mode: DialMode,
+    last_event_at: Option<Instant>,
+    volume: f64,
+    raw_accumulator: i32,
+    last_print_at: Option<Instant>,
+    last_printed_volume: i32,
+    clicking: bool,
+    pressed_at: Option<Instant>,
+    rotated_while_pressed: bool,
+    pressed_rotation: i32,           // counts turned while pressed and not used
+    last_raw_direction: i32,         // -1, 0, or 1
+    consistent_direction_count: u32, // consecutive events in same direction
+    pre_backlash_direction: i32,     // direction before entering backlash
+    direction_since: Option<Instant>, // when the current direction started
+    velocity: f64,                   // smoothed, in counts per second
+    gap_taken: i32,                  // counts into the deadband, away from the last direction
+    backlash_dropped: i32,           // counts aged out of the delay buffer while in backlash
+    last_rotate_at: Option<Instant>,
+    last_detent_at: Option<Instant>,
+    backlash_threshold: u32,
+    backlash_cancel_threshold: u32,
+    backlash_time: Option<Duration>,
+}
+
+/// Events needed to exit backlash mode (also the delay buffer size).
+pub const BACKLASH_THRESHOLD: u32 = 50;
+/// Events back in the original direction that cancel a false-positive backlash.
+pub const BACKLASH_CANCEL_THRESHOLD: u32 = BACKLASH_THRESHOLD / 5;
+/// How long the new direction has to last to confirm a reversal, however
+/// few events it took; slow turns otherwise wait for all of them.
+pub const BACKLASH_TIME: Duration = Duration::from_millis(300);
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Raw counts per detent step (1 volume unit), unless `set_counts_per_step` says otherwise.
+pub const DETENT_COUNTS: i32 = 40;
+pub const LONG_PRESS: Duration = Duration::from_secs(1);
+/// Steps turned while pressed, and ignored, that are worth telling the user.
+pub const IGNORED_HINT_STEPS: i32 = 3;
+pub const PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+
+impl DialState {
+    fn new(volume: i32) -> Self {
+        Self {
+            mode: DialMode::Idle,
+            last_event_at: None,
+            volume: volume as f64,
+            raw_accumulator: 0,
+            last_print_at: None,
+            last_printed_volume: volume,
+            clicking: false,
+            pressed_at: None,
+            rotated_while_pressed: false,
+            pressed_rotation: 0,
+            last_raw_direction: 0,
+            consistent_direction_count: 0,
+            pre_backlash_direction: 0,
+            direction_since: None,
+            velocity: 0.0,
+            gap_taken: 0,
+            backlash_dropped: 0,
+            last_rotate_at: None,
+            last_detent_at: None,
+            backlash_threshold: BACKLASH_THRESHOLD,
+            backlash_cancel_threshold: BACKLASH_CANCEL_THRESHOLD,
+            backlash_time: Some(BACKLASH_TIME),
+        }
+    }
+
+    /// Take the transition `cause` leads to from the current mode; false if
+    /// there is none.
+    fn fire(&mut self, cause: Cause) -> bool {
+        let Some(transition) =
+            TRANSITIONS.iter().find(|t| t.cause == cause && t.from.contains(&self.mode))
+        else {
+            return false;
+        };
+        log!("diald: state -> {}", transition.to.as_str());
+        self.mode = transition.to;
+        true
+    }
+
+    fn reset_to_idle(&mut self) {
+        self.fire(Cause::Reset);
+        self.raw_accumulator = 0;
+        self.last_raw_direction = 0;
+        self.consistent_direction_count = 0;
+        self.pre_backlash_direction = 0;
+        self.direction_since = None;
+        self.velocity = 0.0;
+        self.gap_taken = 0;
+        self.backlash_dropped = 0;
+        self.last_rotate_at = None;
+    }
+}
+
+pub struct Dial {
+    state: DialState,
+    delay_buffer: DelayBuffer,
+    backlash_filter: BacklashFilter,
+    backlash_release: BacklashRelease,
+    tool: Tool,
+    model: VolumeModel,
+    // Percent of the range, so schedules don't depend on the model
+    max_volume: i32,
+    publish_on_wake: bool,
+    idle_timeout: Duration,
+    publish_interval: Duration,
+    counts_per_step: Option<i32>,
+    inverted: bool,
+    press_turn: bool,
+}
+
+impl Default for Dial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dial {
+    pub fn new() -> Self {
+        Self::with_model(Scale::Continuous.into())
+    }
+
+    /// A dial starting halfway up `model`'s range.
+    pub fn with_model(model: VolumeModel) -> Self {
+        Self {
+            state: DialState::new(model.range() / 2),
+            delay_buffer: DelayBuffer::new(BACKLASH_THRESHOLD as usize),
+            backlash_filter: BacklashFilter::Threshold,
+            backlash_release: BacklashRelease::Apply,
+            tool: Tool::Volume,
+            model,
+            max_volume: 100,
+            publish_on_wake: false,
+            idle_timeout: IDLE_TIMEOUT,
+            publish_interval: PUBLISH_INTERVAL,
+            counts_per_step: None,
+            inverted: false,
+            press_turn: false,
+        }
+    }
+
+    /// Hand out the current volume the moment the dial wakes, before any
+    /// change, so displays can show the starting point right away.
+    pub fn set_publish_on_wake(&mut self, enabled: bool) {
+        self.publish_on_wake = enabled;
+    }
+
+    /// How long after the last event the dial goes idle (30s by default).
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Raw counts per volume unit (or per step of a stepped model), for
+    /// dials that move the value too fast or too slowly. `None` goes back to
+    /// the model's own: `DETENT_COUNTS`, or a full sweep as long as 0-100.
+    pub fn set_counts_per_step(&mut self, counts: Option<i32>) {
+        self.counts_per_step = counts;
+    }
+
+    /// Raw counts per volume unit in use.
+    pub fn counts_per_unit(&self) -> i32 {
+        self.counts_per_step.unwrap_or_else(|| self.model.counts_per_unit())
+    }
+
+    /// Flip the direction of rotation, for a dial mounted upside down. It is
+    /// applied first, so backlash and everything after see the corrected sign.
+    pub fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
+    }
+
+    /// Turn rotation while the button is held into `Action::PressTurn` on
+    /// release, instead of ignoring it (the scroll tool always uses it).
+    pub fn set_press_turn(&mut self, enabled: bool) {
+        self.press_turn = enabled;
+    }
+
+    pub fn press_turn(&self) -> bool {
+        self.press_turn
+    }
+
+    /// How often intermediate volumes are published (`PUBLISH_INTERVAL` by
+    /// default).
+    pub fn set_publish_interval(&mut self, interval: Duration) {
+        self.publish_interval = interval;
+    }
+
+    /// Consistent events that confirm a reversal (and how long events are
+    /// held back), and events back in the original direction that cancel one.
+    /// Worn dials with more slop need more.
+    pub fn set_backlash_thresholds(&mut self, threshold: u32, cancel_threshold: u32) {
+        self.state.backlash_threshold = threshold;
+        self.state.backlash_cancel_threshold = cancel_threshold;
+        self.delay_buffer.lookahead = threshold as usize;
+    }
+
+    /// Tell reversals from backlash by event count (the default) or by
+    /// velocity. Anything held back under the old filter is dropped.
+    pub fn set_backlash_filter(&mut self, filter: BacklashFilter) {
+        if self.backlash_filter != filter {
+            self.backlash_filter = filter;
+            self.reset();
+        }
+    }
+
+    pub fn backlash_filter(&self) -> BacklashFilter {
+        self.backlash_filter
+    }
+
+    /// Apply, halve or drop what was held back once backlash ends (applied in
+    /// full by default). The deadband filter holds nothing back.
+    pub fn set_backlash_release(&mut self, release: BacklashRelease) {
+        self.backlash_release = release;
+    }
+
+    pub fn backlash_release(&self) -> BacklashRelease {
+        self.backlash_release
+    }
+
+    /// How long the new direction has to last to confirm a reversal with
+    /// fewer than the threshold's events (`BACKLASH_TIME` by default); `None`
+    /// counts events only.
+    pub fn set_backlash_time(&mut self, time: Option<Duration>) {
+        self.state.backlash_time = time;
+    }
+
+    pub fn backlash_time(&self) -> Option<Duration> {
+        self.state.backlash_time
+    }
+
+    pub fn model(&self) -> &VolumeModel {
+        &self.model
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn publishes_on_wake(&self) -> bool {
+        self.publish_on_wake
+    }
+
+    /// `(threshold, cancel_threshold)`, as in `set_backlash_thresholds`.
+    pub fn backlash_thresholds(&self) -> (u32, u32) {
+        (self.state.backlash_threshold, self.state.backlash_cancel_threshold)
+    }
+
+    /// Raw counts per volume unit, the model's own unless overridden.
+    pub fn counts_per_step(&self) -> i32 {
+        self.counts_per_unit()
+    }
+
+    pub fn mode(&self) -> DialMode {
+        self.state.mode
+    }
+
+    pub fn tool(&self) -> Tool {
+        self.tool
+    }
+
+    /// Switch tools; any half-finished gesture is dropped so it can't leak
+    /// into the new tool.
+    pub fn set_tool(&mut self, tool: Tool) {
+        if self.tool != tool {
+            log!("diald: tool -> {}", tool.as_str());
+            self.tool = tool;
+            self.reset();
+        }
+    }
+
+    pub fn volume(&self) -> i32 {
+        self.state.volume.round() as i32
+    }
+
+    /// Forget any in-flight gesture, e.g. after (re)attaching a device or when
+    /// asked to remotely: accumulators, backlash and a held button.
+    pub fn reset(&mut self) {
+        self.state.reset_to_idle();
+        self.state.clicking = false;
+        self.state.rotated_while_pressed = false;
+        self.state.pressed_rotation = 0;
+        self.delay_buffer.clear();
+    }
+
+    /// Apply a volume set from outside (MQTT, control socket). Ignored while
+    /// the user is turning the dial so we never fight them; returns whether it
+    /// was applied.
+    pub fn set_volume(&mut self, volume: i32) -> bool {
+        if self.state.mode != DialMode::Idle {
+            return false;
+        }
+        let clamped = (volume as f64).clamp(0.0, self.max_units() as f64);
+        self.state.volume = clamped;
+        self.state.last_printed_volume = clamped.round() as i32;
+        true
+    }
+
+    /// The cap, in percent of the range.
+    pub fn max_volume(&self) -> i32 {
+        self.max_volume
+    }
+
+    fn max_units(&self) -> i32 {
+        self.model.units_at_percent(self.max_volume)
+    }
+
+    /// Cap the volume at `max` percent of the range (e.g. the night limiter).
+    /// A volume above the new cap is pulled down and published, but only once
+    /// the dial is idle.
+    pub fn set_max_volume(&mut self, max: i32, actions: &mut Vec<Action>) {
+        self.max_volume = max.clamp(0, 100);
+        if self.state.mode == DialMode::Idle && self.volume() > self.max_units() {
+            self.set_volume(self.max_units());
+            actions.push(Action::Volume(self.volume()));
+        }
+    }
+
+    /// Nudge the volume from outside by `delta`. Same rule as `set_volume`,
+    /// but the result is published since the sender doesn't know it.
+    pub fn adjust_volume(&mut self, delta: i32, actions: &mut Vec<Action>) -> bool {
+        if !self.set_volume(self.volume().saturating_add(delta)) {
+            return false;
+        }
+        actions.push(Action::Volume(self.volume()));
+        true
+    }
+
+    /// The volume last handed out as `Action::Volume` (or set from outside).
+    /// Differs from `volume()` only while a change waits out the throttle.
+    pub fn last_printed_volume(&self) -> i32 {
+        self.state.last_printed_volume
+    }
+
+    /// When `tick` next has something to do without new events: a volume
+    /// held back by the throttle, a reversal old enough to confirm, or the
+    /// idle timeout. Deadlines already past (a reversal is only confirmed by
+    /// the next event) are left out, so the caller never spins on them.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        let state = &self.state;
+        let publish = state
+            .last_print_at
+            .filter(|_| state.volume.round() as i32 != state.last_printed_volume)
+            .map(|at| at + self.publish_interval);
+        let backlash = state
+            .backlash_time
+            .zip(state.direction_since)
+            .filter(|_| state.mode == DialMode::Backlash)
+            .map(|(time, since)| since + time);
+        let idle = state
+            .last_event_at
+            .filter(|_| state.mode != DialMode::Idle)
+            .map(|at| at + self.idle_timeout);
+        [publish, backlash, idle].into_iter().flatten().filter(|&at| at > now).min()
+    }
+
+    /// Time-driven transitions; call regularly even when no events arrive.
+    pub fn tick(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        // A change held back by the throttle goes out once it allows, so the
+        // published volume always catches up even if rotation stops
+        self.publish_volume(false, now, actions);
+
+        if let Some(last_event) = self.state.last_event_at
+            && now.duration_since(last_event) >= self.idle_timeout
+            && self.state.fire(Cause::IdleTimeout)
+        {
+            self.reset();
+        }
+    }
+
+    pub fn handle(&mut self, event: InputEvent, now: Instant, actions: &mut Vec<Action>) {
+        let counts_per_unit = self.counts_per_unit();
+        let state = &mut self.state;
+        if state.fire(Cause::Input) {
+            actions.push(Action::Buzz(Trigger::Wake));
+            if self.publish_on_wake && self.tool == Tool::Volume {
+                actions.push(Action::Volume(state.last_printed_volume));
+            }
+        }
+        state.last_event_at = Some(now);
+
+        let sign = if self.inverted { -1 } else { 1 };
+        match event {
+            InputEvent::Rotate(value) => self.rotate(sign * value, now, actions),
+            InputEvent::Detent(steps) => self.detent(sign * steps, now, actions),
+            InputEvent::Press => {
+                state.clicking = true;
+                state.pressed_at = Some(now);
+                state.rotated_while_pressed = false;
+                state.pressed_rotation = 0;
+            }
+            InputEvent::Release => {
+                let steps = std::mem::take(&mut state.pressed_rotation) / counts_per_unit;
+                if self.press_turn && state.clicking && steps != 0 {
+                    actions.push(Action::PressTurn(steps));
+                    state.rotated_while_pressed = true;
+                } else if steps.abs() >= IGNORED_HINT_STEPS {
+                    actions.push(Action::RotationIgnored(steps));
+                }
+                // Turning while pressed was a horizontal scroll or a press
+                // turn, not a click
+                if state.clicking && !state.rotated_while_pressed {
+                    let held = state.pressed_at.map(|at| now.duration_since(at));
+                    if held.is_some_and(|held| held >= LONG_PRESS) {
+                        actions.push(Action::LongPress);
+                    } else {
+                        actions.push(Action::Click);
+                    }
+                }
+                state.clicking = false;
+            }
+        }
+    }
+
+    /// Discrete steps skip the backlash buffer entirely; quick successive
+    /// steps are accelerated so a fast spin covers more range.
+    fn detent(&mut self, steps: i32, now: Instant, actions: &mut Vec<Action>) {
+        let counts_per_unit = self.counts_per_unit();
+        if !self.accepts_rotation() {
+            self.state.pressed_rotation += steps * counts_per_unit;
+            return;
+        }
+        let state = &mut self.state;
+        let gap = state.last_detent_at.map(|t| now.duration_since(t));
+        state.last_detent_at = Some(now);
+        let acceleration = match gap {
+            Some(gap) if gap < Duration::from_millis(40) => 4,
+            Some(gap) if gap < Duration::from_millis(80) => 2,
+            _ => 1,
+        };
+        state.raw_accumulator += steps * counts_per_unit * acceleration;
+        self.apply_accumulator(now, actions);
+    }
+
+    /// Rotation while pressed is ignored (or saved for a press turn), except
+    /// that the scroll tool uses it for horizontal scrolling.
+    fn accepts_rotation(&mut self) -> bool {
+        if !self.state.clicking {
+            return true;
+        }
+        if self.tool == Tool::Scroll {
+            self.state.rotated_while_pressed = true;
+            return true;
+        }
+        false
+    }
+
+    fn rotate(&mut self, value: i32, now: Instant, actions: &mut Vec<Action>) {
+        if !self.accepts_rotation() {
+            self.state.pressed_rotation += value;
+            return;
+        }
+        match self.backlash_filter {
+            BacklashFilter::Threshold => {}
+            BacklashFilter::Velocity(hysteresis) => {
+                return self.rotate_by_velocity(value, hysteresis, now, actions);
+            }
+            BacklashFilter::Deadband(width) => {
+                return self.rotate_by_deadband(value, width as i32, now, actions);
+            }
+        }
+        let state = &mut self.state;
+        let delay_buffer = &mut self.delay_buffer;
+        let release = self.backlash_release;
+
+        // Track direction for backlash detection
+        let direction = value.signum();
+        let direction_changed = state.last_raw_direction != 0
+            && direction != state.last_raw_direction;
+
+        if direction_changed {
+            // Direction changed - enter backlash mode
+            let previous = state.last_raw_direction;
+            if state.fire(Cause::Reversal) {
+                debug!("diald: entering backlash (direction {} -> {})", previous, direction);
+                state.pre_backlash_direction = previous;
+                state.backlash_dropped = 0;
+                actions.push(Action::Backlash(BacklashEvent::Enter { from: previous }));
+            }
+            state.consistent_direction_count = 1;
+            state.direction_since = Some(now);
+        } else if direction == state.last_raw_direction {
+            state.consistent_direction_count += 1;
+        } else {
+            state.direction_since = Some(now);
+        }
+        state.last_raw_direction = direction;
+
+        // Push event to delay buffer - returns aged-out event (if any)
+        let delayed = delay_buffer.push(value);
+
+        // Handle based on mode
+        if state.mode == DialMode::Backlash {
+            // In backlash mode: don't commit delayed events, wait for stability
+            state.backlash_dropped += delayed.unwrap_or(0);
+
+            // Check for exit conditions
+            if direction == state.pre_backlash_direction
+                && state.consistent_direction_count >= state.backlash_cancel_threshold
+            {
+                // False positive - cancel backlash, release ALL buffered events
+                let buffered = delay_buffer.drain_all();
+                debug!("diald: canceling backlash (buffered={})", buffered);
+                let applied = release.of(buffered);
+                state.raw_accumulator += applied;
+                state.fire(Cause::BacklashCancel);
+                let cancel = BacklashEvent::Cancel { held: buffered, applied };
+                actions.push(Action::Backlash(cancel));
+            } else if state.consistent_direction_count >= state.backlash_threshold
+                || (direction != state.pre_backlash_direction
+                    && state.backlash_time.zip(state.direction_since).is_some_and(
+                        |(time, since)| now.duration_since(since) >= time,
+                    ))
+            {
+                // Confirmed direction change - release only matching events
+                let (buffered, dropped) = delay_buffer.drain_matching(direction);
+                debug!(
+                    "diald: exiting backlash (stable for {} events, buffered={})",
+                    state.consistent_direction_count,
+                    buffered
+                );
+                let applied = release.of(buffered);
+                state.raw_accumulator += applied;
+                state.fire(Cause::BacklashConfirm);
+                actions.push(Action::Buzz(Trigger::Backlash));
+                let dropped = dropped + state.backlash_dropped;
+                let exit = BacklashEvent::Exit { held: buffered, applied, dropped };
+                actions.push(Action::Backlash(exit));
+            }
+            // else: stay in backlash mode, continue buffering
+        } else {
+            // Normal mode: commit delayed events as they age out
+            if let Some(value) = delayed {
+                state.raw_accumulator += value;
+            }
+        }
+
+        self.apply_accumulator(now, actions);
+    }
+
+    /// `BacklashFilter::Velocity`: rotation the way the dial is turning goes
+    /// straight through; rotation against it is held until the velocity
+    /// settles which way the dial is going.
+    fn rotate_by_velocity(
+        &mut self,
+        value: i32,
+        hysteresis: f64,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) {
+        let state = &mut self.state;
+        let delay_buffer = &mut self.delay_buffer;
+        let release = self.backlash_release;
+
+        // An exponential moving average over time. Gaps are capped at the
+        // time constant, so a slow turn still counts as moving
+        let gap = state.last_rotate_at.map_or(VELOCITY_SMOOTHING, |at| now.duration_since(at));
+        let gap = gap.clamp(Duration::from_millis(1), VELOCITY_SMOOTHING).as_secs_f64();
+        state.last_rotate_at = Some(now);
+        let weight = 1.0 - (-gap / VELOCITY_SMOOTHING.as_secs_f64()).exp();
+        state.velocity += weight * (value as f64 / gap - state.velocity);
+
+        let direction = value.signum();
+        if state.mode == DialMode::Active
+            && state.last_raw_direction != 0
+            && direction == -state.last_raw_direction
+            && state.fire(Cause::Reversal)
+        {
+            debug!("diald: entering backlash (velocity {:.0})", state.velocity);
+            state.pre_backlash_direction = state.last_raw_direction;
+            let from = state.last_raw_direction;
+            actions.push(Action::Backlash(BacklashEvent::Enter { from }));
+        }
+
+        if state.mode == DialMode::Backlash {
+            delay_buffer.hold(value);
+            let along = state.velocity * state.pre_backlash_direction as f64;
+            if along >= hysteresis {
+                let buffered = delay_buffer.drain_all();
+                debug!("diald: canceling backlash (buffered={})", buffered);
+                let applied = release.of(buffered);
+                state.raw_accumulator += applied;
+                state.fire(Cause::BacklashCancel);
+                let cancel = BacklashEvent::Cancel { held: buffered, applied };
+                actions.push(Action::Backlash(cancel));
+            } else if -along >= hysteresis {
+                let direction = -state.pre_backlash_direction;
+                let (buffered, dropped) = delay_buffer.drain_matching(direction);
+                debug!(
+                    "diald: exiting backlash (velocity {:.0}, buffered={})",
+                    state.velocity, buffered
+                );
+                let applied = release.of(buffered);
+                state.raw_accumulator += applied;
+                state.last_raw_direction = direction;
+                state.fire(Cause::BacklashConfirm);
+                actions.push(Action::Buzz(Trigger::Backlash));
+                let exit = BacklashEvent::Exit { held: buffered, applied, dropped };
+                actions.push(Action::Backlash(exit));
+            }
+        } else {
+            state.raw_accumulator += value;
+            if direction != 0 {
+                state.last_raw_direction = direction;
+            }
+        }
+
+        self.apply_accumulator(now, actions);
+    }
+
+    /// `BacklashFilter::Deadband`: rotation against the last direction first
+    /// crosses the gap, `width` counts wide, and only what goes past it
+    /// counts. Turning back inside the gap closes it again without moving.
+    fn rotate_by_deadband(
+        &mut self,
+        value: i32,
+        width: i32,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) {
+        let state = &mut self.state;
+        let direction = value.signum();
+        if state.last_raw_direction == 0 {
+            state.last_raw_direction = direction;
+        }
+
+        if direction == state.last_raw_direction || direction == 0 {
+            // Back toward the side last pushed on
+            let closed = state.gap_taken.min(value.abs());
+            state.gap_taken -= closed;
+            state.raw_accumulator += value - closed * direction;
+            if closed > 0 && state.gap_taken == 0 && state.fire(Cause::BacklashCancel) {
+                debug!("diald: back out of the deadband");
+                let cancel = BacklashEvent::Cancel { held: 0, applied: 0 };
+                actions.push(Action::Backlash(cancel));
+            }
+        } else {
+            if state.gap_taken == 0 && state.fire(Cause::Reversal) {
+                debug!("diald: entering the deadband ({} counts)", width);
+                let from = state.last_raw_direction;
+                actions.push(Action::Backlash(BacklashEvent::Enter { from }));
+            }
+            state.gap_taken += value.abs();
+            let past = state.gap_taken - width;
+            if past >= 0 {
+                state.raw_accumulator += past * direction;
+                state.gap_taken = 0;
+                state.last_raw_direction = direction;
+                let confirmed = state.fire(Cause::BacklashConfirm);
+                actions.push(Action::Buzz(Trigger::Backlash));
+                if confirmed {
+                    let dropped = width * direction;
+                    let exit = BacklashEvent::Exit { held: 0, applied: 0, dropped };
+                    actions.push(Action::Backlash(exit));
+                }
+            }
+        }
+
+        self.apply_accumulator(now, actions);
+    }
+
+    /// Turn whole volume units out of the raw accumulator into volume changes.
+    fn apply_accumulator(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        let max_volume = self.max_units() as f64;
+        let counts_per_unit = self.counts_per_unit();
+        let state = &mut self.state;
+        if self.tool != Tool::Volume {
+            if state.raw_accumulator != 0 {
+                actions.push(Action::Desktop {
+                    delta: state.raw_accumulator,
+                    horizontal: state.clicking,
+                });
+                state.raw_accumulator = 0;
+            }
+            return;
+        }
+
+        // Continuous: 40 raw = 1 volume unit (400 raw = 10 volume)
+        let volume_delta = state.raw_accumulator / counts_per_unit;
+        if volume_delta != 0 {
+            state.raw_accumulator -= volume_delta * counts_per_unit;
+
+            let unclamped = state.volume + volume_delta as f64;
+            state.volume = unclamped.clamp(0.0, max_volume);
+
+            // Buzz at boundaries (trying to go past 0 or the max, normally 100)
+            if !(0.0..=max_volume).contains(&unclamped) {
+                actions.push(Action::Buzz(Trigger::Boundary));
+            }
+
+            // Steps are coarse enough that each one matters to the target
+            let stepped = self.model.scale != Scale::Continuous;
+            self.publish_volume(stepped, now, actions);
+        }
+    }
+
+    /// Print and publish the volume if it changed, throttled to one update per
+    /// publish interval except when it crosses a multiple of ten (or
+    /// `immediately`).
+    fn publish_volume(&mut self, immediately: bool, now: Instant, actions: &mut Vec<Action>) {
+        let state = &mut self.state;
+        let current_volume = state.volume.round() as i32;
+        if current_volume == state.last_printed_volume {
+            return;
+        }
+        let crossed_ten = state.last_printed_volume / 10 != current_volume / 10;
+        let time_to_print = state
+            .last_print_at
+            .is_none_or(|t| now.duration_since(t) >= self.publish_interval);
+
+        if crossed_ten || time_to_print || immediately {
+            debug!("diald: volume {}", current_volume);
+            state.last_print_at = Some(now);
+            state.last_printed_volume = current_volume;
+            actions.push(Action::Volume(current_volume));
+        }
+    }
+}
+
+/// Golden-file regression tests: every `tests/golden/*.trace` is replayed
+/// through the state machine and the resulting action stream is compared with
+/// the matching `.expected` file. After an intentional behavior change, run
+/// with `DIALD_BLESS=1` to rewrite the expected files and review the diff.
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::input::replay;
+
+    fn log_actions(out: &mut String, actions: &mut Vec<Action>, ms: u128) {
+        for action in actions.drain(..) {
+            writeln!(out, "{} {:?}", ms, action).unwrap();
+        }
+    }
+
+    /// Timestamped actions and mode changes, one per line.
+    fn replay_trace(trace: &str) -> String {
+        let events = replay::parse(trace).unwrap();
+        let start = Instant::now();
+        let mut dial = Dial::new();
+        let mut actions = Vec::new();
+        let mut mode = dial.mode();
+        let mut out = String::new();
+        let mut last = Duration::ZERO;
+
+        let mut log_mode = |out: &mut String, dial: &Dial, ms: u128| {
+            if dial.mode() != mode {
+                mode = dial.mode();
+                writeln!(out, "{} mode {}", ms, mode.as_str()).unwrap();
+            }
+        };
+        for (at, event) in events {
+            let now = start + at;
+            let ms = at.as_millis();
+            dial.tick(now, &mut actions);
+            log_actions(&mut out, &mut actions, ms);
+            log_mode(&mut out, &dial, ms);
+            dial.handle(event, now, &mut actions);
+            log_actions(&mut out, &mut actions, ms);
+            log_mode(&mut out, &dial, ms);
+            last = at;
+        }
+        // Let the throttle and the idle timeout run out
+        for after in [PUBLISH_INTERVAL, IDLE_TIMEOUT] {
+            let at = last + after;
+            dial.tick(start + at, &mut actions);
+            log_actions(&mut out, &mut actions, at.as_millis());
+            log_mode(&mut out, &dial, at.as_millis());
+        }
+        out
+    }
+
+    #[test]
+    fn golden_traces() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let bless = std::env::var_os("DIALD_BLESS").is_some();
+        let mut traces: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "trace"))
+            .collect();
+        traces.sort();
+        assert!(!traces.is_empty(), "no traces in {}", dir.display());
+
+        let mut failures = Vec::new();
+        for trace in &traces {
+            let actual = replay_trace(&fs::read_to_string(trace).unwrap());
+            let expected_path = trace.with_extension("expected");
+            if bless {
+                fs::write(&expected_path, &actual).unwrap();
+                continue;
+            }
+            let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+            if actual != expected {
+                let line = actual
+                    .lines()
+                    .zip(expected.lines())
+                    .position(|(a, e)| a != e)
+                    .unwrap_or(actual.lines().count().min(expected.lines().count()));
+                failures.push(format!(
+                    "{}: first difference at line {}\n  actual:   {:?}\n  expected: {:?}",
+                    trace.display(),
+                    line + 1,
+                    actual.lines().nth(line),
+                    expected.lines().nth(line)
+                ));
+            }
+        }
+        assert!(failures.is_empty(), "golden traces differ:\n{}", failures.join("\n"));
+    }
+}
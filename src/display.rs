@@ -0,0 +1,267 @@
+//! A small SSD1306 OLED (128x64, I2C) showing the volume, what the dial is
+//! doing and whether the broker is reachable, for DIY boxes with a spare I2C
+//! header.
+//!
+//! `DIALD_OLED` = the I2C bus, e.g. `i2c-1`, optionally followed by the
+//! display's address (`i2c-1:0x3d`; the default is `0x3c`). The user needs
+//! access to `/dev/i2c-1` (usually the `i2c` group).
+
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use crate::config;
+use crate::dial::DialMode;
+use crate::font;
+
+const WIDTH: usize = 128;
+const PAGES: usize = 8;
+const DEFAULT_ADDRESS: u16 = 0x3c;
+
+/// What the display shows.
+#[derive(Clone, PartialEq)]
+struct Screen {
+    /// The volume as published, e.g. `-23.5` with a range.
+    value: String,
+    /// How far up the range it is, from 0 to 1, for the bar.
+    share: f64,
+    mode: DialMode,
+    connected: bool,
+    guest: bool,
+}
+
+pub struct StatusDisplay {
+    screen: Screen,
+    shown: Option<Screen>,
+    frames: Sender<Screen>,
+}
+
+impl StatusDisplay {
+    /// `None` when no display is configured or it doesn't answer (which is
+    /// logged).
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(spec) = config::var("DIALD_OLED") else {
+            return Ok(None);
+        };
+        let invalid =
+            || format!("DIALD_OLED: expected e.g. 'i2c-1' or 'i2c-1:0x3d', got '{}'", spec);
+        let (bus, address) = match spec.trim().split_once(':') {
+            Some((bus, address)) => {
+                let address = address.trim_start_matches("0x");
+                (bus, u16::from_str_radix(address, 16).map_err(|_| invalid())?)
+            }
+            None => (spec.trim(), DEFAULT_ADDRESS),
+        };
+        if bus.is_empty() {
+            return Err(invalid());
+        }
+        let bus = if bus.starts_with('/') { bus.to_string() } else { format!("/dev/{}", bus) };
+
+        let mut panel = match i2c::Panel::open(&bus, address).and_then(|mut panel| {
+            panel.command(INIT)?;
+            Ok(panel)
+        }) {
+            Ok(panel) => panel,
+            Err(err) => {
+                warn!("diald: no display at {} 0x{:02x} ({})", bus, address, err);
+                return Ok(None);
+            }
+        };
+        // Drawn off the main loop: a full frame is ~25ms of I2C
+        let (frames, queue) = mpsc::channel::<Screen>();
+        thread::spawn(move || {
+            while let Ok(mut screen) = queue.recv() {
+                // Only the latest state matters
+                while let Ok(newer) = queue.try_recv() {
+                    screen = newer;
+                }
+                if let Err(err) = panel.draw(&render(&screen)) {
+                    warn!("diald: display stopped ({})", err);
+                    return;
+                }
+            }
+        });
+        let screen = Screen {
+            value: String::new(),
+            share: 0.0,
+            mode: DialMode::Idle,
+            connected: false,
+            guest: false,
+        };
+        Ok(Some(Self { screen, shown: None, frames }))
+    }
+
+    /// `value` as published, and how far up the range it is for the bar.
+    pub fn set_volume(&mut self, value: &str, share: f64) {
+        if self.screen.value != value {
+            self.screen.value = value.to_string();
+        }
+        self.screen.share = share;
+    }
+
+    pub fn set_status(&mut self, mode: DialMode, connected: bool, guest: bool) {
+        self.screen.mode = mode;
+        self.screen.connected = connected;
+        self.screen.guest = guest;
+    }
+
+    /// Send the screen to the display if anything changed.
+    pub fn flush(&mut self) {
+        if self.shown.as_ref() != Some(&self.screen) {
+            self.shown = Some(self.screen.clone());
+            let _ = self.frames.send(self.screen.clone());
+        }
+    }
+}
+
+/// 128x64 with the internal charge pump, horizontal addressing.
+const INIT: &[u8] = &[
+    0xae, // display off
+    0xd5, 0x80, // clock
+    0xa8, 0x3f, // 64 rows
+    0xd3, 0x00, // no offset
+    0x40, // start line 0
+    0x8d, 0x14, // charge pump on
+    0x20, 0x00, // horizontal addressing
+    0xa1, // columns mirrored
+    0xc8, // rows mirrored
+    0xda, 0x12, // COM pins
+    0x81, 0xcf, // contrast
+    0xd9, 0xf1, // precharge
+    0xdb, 0x40, // VCOMH
+    0xa4, // show RAM
+    0xa6, // not inverted
+    0xaf, // display on
+];
+
+type Frame = [u8; WIDTH * PAGES];
+
+fn set_pixel(frame: &mut Frame, x: usize, y: usize) {
+    if x < WIDTH && y < PAGES * 8 {
+        frame[(y / 8) * WIDTH + x] |= 1 << (y % 8);
+    }
+}
+
+/// `text` at `(x, y)`, each font pixel drawn as a `scale` x `scale` block.
+fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str, scale: usize) {
+    for (index, c) in text.chars().enumerate() {
+        let left = x + index * 6 * scale;
+        for (column, bits) in font::glyph(c).iter().enumerate() {
+            for row in 0..7 {
+                if bits & (1 << row) == 0 {
+                    continue;
+                }
+                for dx in 0..scale {
+                    for dy in 0..scale {
+                        set_pixel(frame, left + column * scale + dx, y + row * scale + dy);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn text_width(text: &str, scale: usize) -> usize {
+    (text.chars().count() * 6).saturating_sub(1) * scale
+}
+
+fn render(screen: &Screen) -> Frame {
+    let mut frame = [0; WIDTH * PAGES];
+    draw_text(&mut frame, 0, 0, "diald", 1);
+    let link = if screen.connected { "online" } else { "OFFLINE" };
+    draw_text(&mut frame, WIDTH - text_width(link, 1), 0, link, 1);
+
+    // Values too wide for the large digits, like `-100.25`, get smaller ones
+    let (scale, top) = if text_width(&screen.value, 3) <= WIDTH { (3, 14) } else { (2, 17) };
+    let left = WIDTH.saturating_sub(text_width(&screen.value, scale)) / 2;
+    draw_text(&mut frame, left, top, &screen.value, scale);
+
+    // Volume bar
+    for x in 0..WIDTH {
+        set_pixel(&mut frame, x, 42);
+        set_pixel(&mut frame, x, 49);
+    }
+    let filled = ((WIDTH - 4) as f64 * screen.share.clamp(0.0, 1.0)) as usize;
+    for x in 0..WIDTH {
+        for y in 43..49 {
+            if x == 0 || x == WIDTH - 1 || ((2..2 + filled).contains(&x) && (44..48).contains(&y)) {
+                set_pixel(&mut frame, x, y);
+            }
+        }
+    }
+
+    draw_text(&mut frame, 0, 56, screen.mode.as_str(), 1);
+    if screen.guest {
+        draw_text(&mut frame, WIDTH - text_width("guest", 1), 56, "guest", 1);
+    }
+    frame
+}
+
+#[cfg(target_os = "linux")]
+mod i2c {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Write};
+    use std::os::fd::AsRawFd;
+
+    use super::Frame;
+
+    const I2C_SLAVE: u64 = 0x0703;
+    const CONTROL_COMMAND: u8 = 0x00;
+    const CONTROL_DATA: u8 = 0x40;
+
+    pub struct Panel {
+        bus: File,
+    }
+
+    impl Panel {
+        pub fn open(bus: &str, address: u16) -> io::Result<Self> {
+            let bus = OpenOptions::new().read(true).write(true).open(bus)?;
+            let result =
+                unsafe { libc::ioctl(bus.as_raw_fd(), I2C_SLAVE as _, address as libc::c_ulong) };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { bus })
+        }
+
+        pub fn command(&mut self, bytes: &[u8]) -> io::Result<()> {
+            let mut message = vec![CONTROL_COMMAND];
+            message.extend_from_slice(bytes);
+            self.bus.write_all(&message)
+        }
+
+        pub fn draw(&mut self, frame: &Frame) -> io::Result<()> {
+            // Whole screen: columns 0-127, pages 0-7
+            self.command(&[0x21, 0, 127, 0x22, 0, 7])?;
+            // Small chunks, for adapters with short transfer limits
+            for chunk in frame.chunks(32) {
+                let mut message = vec![CONTROL_DATA];
+                message.extend_from_slice(chunk);
+                self.bus.write_all(&message)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod i2c {
+    use std::io;
+
+    use super::Frame;
+
+    pub struct Panel;
+
+    impl Panel {
+        pub fn open(_bus: &str, _address: u16) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "I2C needs Linux"))
+        }
+
+        pub fn command(&mut self, _bytes: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn draw(&mut self, _frame: &Frame) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
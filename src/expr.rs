@@ -0,0 +1,265 @@
+//! Tiny integer expression language for user-supplied byte values, e.g.
+//! `volume * 255 / 100` or `0x80 | (value & 0x7f)`.
+//!
+//! Supports `+ - * / % & | << >>`, unary minus, parentheses, decimal/hex
+//! literals and named variables. Division by zero evaluates to 0.
+
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(i64),
+    Var(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Expr {
+    /// Parse `source`, rejecting variables not listed in `vars`.
+    pub fn parse(source: &str, vars: &[&str]) -> Result<Expr, ParseError> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            vars,
+        };
+        let expr = parser.parse_or()?;
+        parser.skip_ws();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> i64) -> i64 {
+        match self {
+            Expr::Literal(value) => *value,
+            Expr::Var(name) => lookup(name),
+            Expr::Neg(inner) => inner.eval(lookup).wrapping_neg(),
+            Expr::Binary(op, lhs, rhs) => {
+                let (a, b) = (lhs.eval(lookup), rhs.eval(lookup));
+                match op {
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Mul => a.wrapping_mul(b),
+                    Op::Div => a.checked_div(b).unwrap_or(0),
+                    Op::Rem => a.checked_rem(b).unwrap_or(0),
+                    Op::And => a & b,
+                    Op::Or => a | b,
+                    Op::Shl => a.wrapping_shl(b as u32),
+                    Op::Shr => a.wrapping_shr(b as u32),
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    vars: &'a [&'a str],
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError(format!("{} at position {}", message, self.pos))
+    }
+
+    fn skip_ws(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `token` if it comes next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        let matches = token
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if matches {
+            self.pos += token.len();
+        }
+        matches
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, Op)],
+        next: fn(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Expr, ParseError> {
+        let mut lhs = next(self)?;
+        'outer: loop {
+            for (token, op) in ops {
+                if self.eat(token) {
+                    let rhs = next(self)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&[("|", Op::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&[("&", Op::And)], Self::parse_shift)
+    }
+
+    fn parse_shift(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&[("<<", Op::Shl), (">>", Op::Shr)], Self::parse_sum)
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Self::parse_product)
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, ParseError> {
+        self.binary(
+            &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+            Self::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+        }
+        if self.eat("(") {
+            let inner = self.parse_or()?;
+            if !self.eat(")") {
+                return Err(self.error("expected ')'"));
+            }
+            return Ok(inner);
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr, ParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            self.pos += 1;
+        }
+        let word: String = self.chars[start..self.pos].iter().collect();
+        if word.is_empty() {
+            return Err(self.error("expected a number or variable"));
+        }
+
+        let number = if let Some(hex) = word.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else {
+            word.parse().ok()
+        };
+        if let Some(value) = number {
+            return Ok(Expr::Literal(value));
+        }
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(ParseError(format!("invalid number '{}'", word)));
+        }
+        if !self.vars.contains(&word.as_str()) {
+            return Err(ParseError(format!(
+                "unknown variable '{}' (expected one of: {})",
+                word,
+                self.vars.join(", ")
+            )));
+        }
+        Ok(Expr::Var(word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> i64 {
+        let lookup = |name: &str| match name {
+            "a" => 1,
+            "b" => 2,
+            "c" => 4,
+            _ => 0,
+        };
+        Expr::parse(source, &["a", "b", "c"]).unwrap().eval(&lookup)
+    }
+
+    fn error(source: &str) -> String {
+        Expr::parse(source, &["volume"]).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("10 - 4 - 3"), 3);
+        // `&` binds tighter than `|`: 1 | (2 & 4), not (1 | 2) & 4
+        assert_eq!(eval("a | b & c"), 1);
+        assert_eq!(eval("1 << 2 + 1"), 8);
+    }
+
+    #[test]
+    fn hex_literals() {
+        assert_eq!(eval("0x1f"), 31);
+        assert_eq!(eval("0x80 | (0xff & 0x7f)"), 0xff);
+        assert_eq!(error("0xzz"), "invalid number '0xzz'");
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-3 * 2"), -6);
+        assert_eq!(eval("--4"), 4);
+        assert_eq!(eval("2 - -a"), 3);
+        assert_eq!(eval("-(b + c)"), -6);
+    }
+
+    #[test]
+    fn division_by_zero_is_zero() {
+        assert_eq!(eval("10 / 0"), 0);
+        assert_eq!(eval("10 % 0"), 0);
+        assert_eq!(eval("c / (b - b) + 1"), 1);
+        assert_eq!(eval("7 % 4"), 3);
+    }
+
+    #[test]
+    fn unknown_variables() {
+        assert_eq!(error("volume + level"), "unknown variable 'level' (expected one of: volume)");
+    }
+
+    #[test]
+    fn trailing_input() {
+        assert_eq!(error("1 2"), "unexpected input at position 2");
+        assert_eq!(error("volume )"), "unexpected input at position 7");
+        assert_eq!(error("(volume"), "expected ')' at position 7");
+        assert_eq!(error("volume +"), "expected a number or variable at position 8");
+    }
+}
@@ -0,0 +1,181 @@
+//! Per-application tools: watch which window has focus and switch the dial's
+//! tool to match, like the Wheel settings on Windows.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::dial::Tool;
+use crate::wake::Waker;
+
+/// How often `DIALD_FOCUS_COMMAND` is run.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to wait before following the focus again once `xprop` exits.
+const RESPAWN_DELAY: Duration = Duration::from_secs(5);
+
+/// `DIALD_APP_TOOLS` maps application names to tools, e.g.
+/// `spotify=volume,mpv=scroll`. Names are matched case-insensitively against
+/// the focused window's WM_CLASS (X11) or app id.
+pub struct AppTools {
+    rules: Vec<(String, Tool)>,
+}
+
+impl AppTools {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(spec) = config::var("DIALD_APP_TOOLS") else {
+            return Ok(None);
+        };
+        let mut rules = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (app, tool) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("DIALD_APP_TOOLS: expected app=tool in '{}'", entry))?;
+            let tool = Tool::parse(tool.trim())
+                .ok_or_else(|| format!("DIALD_APP_TOOLS: unknown tool '{}'", tool.trim()))?;
+            rules.push((app.trim().to_lowercase(), tool));
+        }
+        Ok(Some(Self { rules }))
+    }
+
+    /// Tool for the first rule matching any of the window's names.
+    pub fn tool_for(&self, names: &[String]) -> Option<Tool> {
+        self.rules
+            .iter()
+            .find(|(app, _)| names.iter().any(|name| name.eq_ignore_ascii_case(app)))
+            .map(|(_, tool)| *tool)
+    }
+}
+
+/// The window id in an `xprop` line, e.g.
+/// `_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007`; `None` for no window.
+fn active_window(line: &str) -> Option<&str> {
+    let window = line.split_whitespace().last()?;
+    (window.starts_with("0x") && window != "0x0").then_some(window)
+}
+
+/// Names of `window` from its `WM_CLASS`.
+fn window_classes(window: &str) -> Option<Vec<String>> {
+    let output = Command::new("xprop").args(["-id", window, "WM_CLASS"]).output().ok()?;
+    // WM_CLASS(STRING) = "spotify", "Spotify"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (_, classes) = stdout.split_once('=')?;
+    Some(
+        classes
+            .split(',')
+            .map(|class| class.trim().trim_matches('"').to_string())
+            .filter(|class| !class.is_empty())
+            .collect(),
+    )
+}
+
+/// Follow the focused window via EWMH: `xprop -spy` prints the root window's
+/// `_NET_ACTIVE_WINDOW` again each time it changes, and `WM_CLASS` is only
+/// looked up for a new window. Returns when `xprop` exits (e.g. the X server
+/// went away), or false once nobody is listening.
+fn watch_x11(focus: &mut Focus) -> bool {
+    let spawned = Command::new("xprop")
+        .args(["-root", "-spy", "_NET_ACTIVE_WINDOW"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let Ok(mut child) = spawned else {
+        return focus.report(None);
+    };
+    let mut listening = true;
+    if let Some(stdout) = child.stdout.take() {
+        let mut window: Option<String> = None;
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let active = active_window(&line).map(str::to_string);
+            if window.is_some() && active == window {
+                continue;
+            }
+            let names = match &active {
+                Some(active) => window_classes(active),
+                None => Some(Vec::new()),
+            };
+            window = active;
+            if !focus.report(names) {
+                listening = false;
+                break;
+            }
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    listening && focus.report(None)
+}
+
+/// Wayland has no portable way to ask for the focused window, so compositor
+/// specific commands (e.g. `hyprctl activewindow -j | jq -r .class`) are run
+/// through `DIALD_FOCUS_COMMAND`; each output line is one name.
+fn command_focused(command: &str) -> Option<Vec<String>> {
+    let output = Command::new("sh").args(["-c", command]).output().ok()?;
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Where the watcher thread sends the focused window's names.
+struct Focus {
+    tx: Sender<Vec<String>>,
+    waker: Waker,
+    last: Option<Vec<String>>,
+    error_logged: bool,
+}
+
+impl Focus {
+    /// Pass `names` on if focus moved, or log once that they couldn't be
+    /// found out; false once nobody is listening.
+    fn report(&mut self, names: Option<Vec<String>>) -> bool {
+        let Some(names) = names else {
+            if !self.error_logged {
+                warn!("diald: failed to query focused window");
+                self.error_logged = true;
+            }
+            return true;
+        };
+        self.error_logged = false;
+        if self.last.as_ref() == Some(&names) {
+            return true;
+        }
+        if self.tx.send(names.clone()).is_err() {
+            return false;
+        }
+        self.waker.wake();
+        self.last = Some(names);
+        true
+    }
+}
+
+/// Watch the focused window and send its names whenever focus changes.
+pub fn spawn_focus_watcher(waker: Waker) -> Receiver<Vec<String>> {
+    let command = config::var("DIALD_FOCUS_COMMAND").ok();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut focus = Focus { tx, waker, last: None, error_logged: false };
+        match command {
+            Some(command) => {
+                while focus.report(command_focused(&command)) {
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+            None => {
+                while watch_x11(&mut focus) {
+                    thread::sleep(RESPAWN_DELAY);
+                }
+            }
+        }
+    });
+
+    rx
+}
@@ -0,0 +1,138 @@
+//! `diald dump-fsm`: the dial's state machine as a Graphviz or Mermaid
+//! diagram, drawn from `dial::TRANSITIONS` with the thresholds and gesture
+//! rules of the current configuration, so the effect of tuning them can be
+//! seen.
+
+use std::fmt::Write;
+
+use crate::dial::{self, BacklashFilter, Cause, Dial, DialMode, TRANSITIONS};
+use crate::gestures::Gestures;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Dot,
+    Mermaid,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "dot" | "graphviz" => Some(Format::Dot),
+            "mermaid" => Some(Format::Mermaid),
+            _ => None,
+        }
+    }
+}
+
+const MODES: [DialMode; 3] = [DialMode::Idle, DialMode::Active, DialMode::Backlash];
+
+/// When `cause` applies with `dial`'s settings, and what else happens then.
+fn label(cause: Cause, dial: &Dial) -> String {
+    match cause {
+        Cause::Input if dial.publishes_on_wake() => {
+            "any event / wake buzz, publish volume".to_string()
+        }
+        Cause::Input => "any event / wake buzz".to_string(),
+        Cause::Reversal | Cause::BacklashCancel | Cause::BacklashConfirm => {
+            backlash_label(cause, dial)
+        }
+        Cause::IdleTimeout => format!("no events for {}s", dial.idle_timeout().as_secs()),
+        Cause::Reset => "reset".to_string(),
+    }
+}
+
+/// The backlash transitions, which depend on the filter in use.
+fn backlash_label(cause: Cause, dial: &Dial) -> String {
+    let (threshold, cancel_threshold) = dial.backlash_thresholds();
+    match (dial.backlash_filter(), cause) {
+        (BacklashFilter::Threshold, Cause::Reversal) => {
+            format!("direction reverses / hold the last {} events", threshold)
+        }
+        (BacklashFilter::Threshold, Cause::BacklashCancel) => {
+            format!("{} events back / release all held events", cancel_threshold)
+        }
+        (BacklashFilter::Threshold, _) => match dial.backlash_time() {
+            Some(time) => format!(
+                "{} events or {}ms in the new direction / release those, buzz",
+                threshold,
+                time.as_millis()
+            ),
+            None => format!("{} events in the new direction / release those, buzz", threshold),
+        },
+        (BacklashFilter::Velocity(_), Cause::Reversal) => {
+            "direction reverses / hold events against the turn".to_string()
+        }
+        (BacklashFilter::Velocity(velocity), Cause::BacklashCancel) => {
+            format!("{} counts/s back / release all held events", velocity)
+        }
+        (BacklashFilter::Velocity(velocity), _) => {
+            format!("{} counts/s the new way / release those, buzz", velocity)
+        }
+        (BacklashFilter::Deadband(width), Cause::Reversal) => {
+            format!("direction reverses / drop up to {} counts", width)
+        }
+        (BacklashFilter::Deadband(_), Cause::BacklashCancel) => {
+            "back where it reversed / carry on".to_string()
+        }
+        (BacklashFilter::Deadband(width), _) => {
+            format!("{} counts the new way / pass the rest, buzz", width)
+        }
+    }
+}
+
+/// What the dial does without changing modes, with the configured gesture
+/// rules last.
+fn gestures(dial: &Dial, rules: &Gestures) -> Vec<(DialMode, String)> {
+    let long_press = dial::LONG_PRESS.as_secs();
+    let mut gestures = vec![
+        (DialMode::Active, format!("{} counts / one volume step", dial.counts_per_step())),
+        (DialMode::Active, format!("press, release within {}s / click", long_press)),
+        (DialMode::Active, format!("press, hold {}s / long press", long_press)),
+        (DialMode::Active, if dial.press_turn() {
+            "press, turn, release / press turn".to_string()
+        } else {
+            "turn while pressed / ignore".to_string()
+        }),
+        (DialMode::Backlash, match dial.backlash_filter() {
+            BacklashFilter::Deadband(_) => "within the gap / drop".to_string(),
+            _ => "same direction / keep holding".to_string(),
+        }),
+    ];
+    gestures.extend(rules.describe().into_iter().map(|rule| (DialMode::Active, rule)));
+    gestures
+}
+
+pub fn render(dial: &Dial, rules: &Gestures, format: Format) -> String {
+    let mut edges = Vec::new();
+    for transition in TRANSITIONS {
+        for from in transition.from {
+            edges.push((*from, transition.to, label(transition.cause, dial)));
+        }
+    }
+    edges.extend(gestures(dial, rules).into_iter().map(|(mode, label)| (mode, mode, label)));
+
+    let mut out = String::new();
+    match format {
+        Format::Dot => {
+            writeln!(out, "digraph diald {{").unwrap();
+            writeln!(out, "  rankdir=LR;").unwrap();
+            for mode in MODES {
+                let shape = if mode == DialMode::Idle { "doublecircle" } else { "circle" };
+                writeln!(out, "  {} [shape={}];", mode.as_str(), shape).unwrap();
+            }
+            for (from, to, label) in edges {
+                writeln!(out, "  {} -> {} [label=\"{}\"];", from.as_str(), to.as_str(), label)
+                    .unwrap();
+            }
+            writeln!(out, "}}").unwrap();
+        }
+        Format::Mermaid => {
+            writeln!(out, "stateDiagram-v2").unwrap();
+            writeln!(out, "  [*] --> {}", DialMode::Idle.as_str()).unwrap();
+            for (from, to, label) in edges {
+                writeln!(out, "  {} --> {}: {}", from.as_str(), to.as_str(), label).unwrap();
+            }
+        }
+    }
+    out
+}
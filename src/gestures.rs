@@ -0,0 +1,158 @@
+//! Gesture rules: extra things a click or long press does, declared in the
+//! config instead of built in. They come on top of what diald always does
+//! (publishing `clicks` and `long_press`), so existing automations keep
+//! working.
+//!
+//! `DIALD_GESTURES` is a `;`-separated list of `<when> -> <effects>` rules,
+//! e.g. `scroll long_press -> tool volume, publish mode volume`. `<when>` is
+//! `click`, `double_click`, `triple_click`, `long_press` or `press_turn`
+//! (with `DIALD_PRESS_TURN`), optionally after
+//! the tool it applies to. The effects, separated by commas, are
+//! `tool <name>`, `publish <topic> <payload>` (under the topic prefix) and
+//! `buzz <trigger>`. The first rule that matches is the one used.
+
+use crate::config;
+use crate::dial::Tool;
+use crate::haptics::Trigger;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Gesture {
+    /// A burst of this many clicks.
+    Clicks(u32),
+    LongPress,
+    /// Turned while held down, however far.
+    PressTurn,
+}
+
+impl Gesture {
+    fn parse(name: &str) -> Option<Gesture> {
+        match name {
+            "click" => Some(Gesture::Clicks(1)),
+            "double_click" => Some(Gesture::Clicks(2)),
+            "triple_click" => Some(Gesture::Clicks(3)),
+            "long_press" => Some(Gesture::LongPress),
+            "press_turn" => Some(Gesture::PressTurn),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Gesture::Clicks(1) => "click".to_string(),
+            Gesture::Clicks(2) => "double click".to_string(),
+            Gesture::Clicks(count) => format!("{} clicks", count),
+            Gesture::LongPress => "long press".to_string(),
+            Gesture::PressTurn => "press turn".to_string(),
+        }
+    }
+}
+
+pub enum Effect {
+    Tool(Tool),
+    Publish { topic: String, payload: String },
+    Buzz(Trigger),
+}
+
+impl Effect {
+    fn parse(spec: &str) -> Result<Effect, String> {
+        let (verb, rest) = spec.split_once(' ').unwrap_or((spec, ""));
+        let rest = rest.trim();
+        match verb {
+            "tool" => Tool::parse(rest)
+                .map(Effect::Tool)
+                .ok_or_else(|| format!("DIALD_GESTURES: unknown tool '{}'", rest)),
+            "publish" => {
+                let (topic, payload) = rest.split_once(' ').unwrap_or((rest, ""));
+                if topic.is_empty() || topic.contains(['+', '#']) {
+                    return Err(format!("DIALD_GESTURES: bad topic in '{}'", spec));
+                }
+                let payload = payload.trim().to_string();
+                Ok(Effect::Publish { topic: topic.trim_matches('/').to_string(), payload })
+            }
+            "buzz" => Trigger::parse(rest)
+                .map(Effect::Buzz)
+                .ok_or_else(|| format!("DIALD_GESTURES: unknown trigger '{}'", rest)),
+            _ => Err(format!("DIALD_GESTURES: expected tool, publish or buzz in '{}'", spec)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Effect::Tool(tool) => format!("tool {}", tool.as_str()),
+            Effect::Publish { topic, .. } => format!("publish {}", topic),
+            Effect::Buzz(trigger) => format!("buzz {}", trigger.as_str()),
+        }
+    }
+}
+
+struct Rule {
+    tool: Option<Tool>,
+    gesture: Gesture,
+    effects: Vec<Effect>,
+}
+
+#[derive(Default)]
+pub struct Gestures {
+    rules: Vec<Rule>,
+}
+
+impl Gestures {
+    /// No rules unless `DIALD_GESTURES` is set.
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(spec) = config::var("DIALD_GESTURES") else {
+            return Ok(Self::default());
+        };
+        let mut rules = Vec::new();
+        for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (when, effects) = entry
+                .split_once("->")
+                .ok_or_else(|| format!("DIALD_GESTURES: expected when -> effects in '{}'", entry))?;
+            let mut words = when.split_whitespace().rev();
+            let gesture = words.next().unwrap_or("");
+            let gesture = Gesture::parse(gesture)
+                .ok_or_else(|| format!("DIALD_GESTURES: unknown gesture '{}'", gesture))?;
+            let tool = match (words.next(), words.next()) {
+                (None, _) => None,
+                (Some(tool), None) => Some(
+                    Tool::parse(tool)
+                        .ok_or_else(|| format!("DIALD_GESTURES: unknown tool '{}'", tool))?,
+                ),
+                (Some(_), Some(_)) => {
+                    return Err(format!("DIALD_GESTURES: expected [tool] gesture in '{}'", when));
+                }
+            };
+            let effects = effects
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(Effect::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            if effects.is_empty() {
+                return Err(format!("DIALD_GESTURES: nothing to do in '{}'", entry));
+            }
+            rules.push(Rule { tool, gesture, effects });
+        }
+        Ok(Self { rules })
+    }
+
+    /// The effects of the first rule for `gesture` with `tool` selected.
+    pub fn effects(&self, tool: Tool, gesture: Gesture) -> &[Effect] {
+        self.rules
+            .iter()
+            .find(|rule| rule.gesture == gesture && rule.tool.is_none_or(|t| t == tool))
+            .map_or(&[], |rule| &rule.effects)
+    }
+
+    /// Each rule as `when / effects`, for `diald dump-fsm`.
+    pub fn describe(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let tool =
+                    rule.tool.map_or(String::new(), |tool| format!(" with {}", tool.as_str()));
+                let effects: Vec<String> = rule.effects.iter().map(Effect::describe).collect();
+                format!("{}{} / {}", rule.gesture.describe(), tool, effects.join(", "))
+            })
+            .collect()
+    }
+}
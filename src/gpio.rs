@@ -0,0 +1,125 @@
+//! Output lines through the GPIO character device (the v2 uAPI), for the
+//! status LED and the piezo. No sysfs export is needed, only access to
+//! `/dev/gpiochipN`.
+
+pub use platform::Line;
+
+/// `<chip>:<line>[:<flag>]`, e.g. `gpiochip0:17:low`. A bare chip name is
+/// looked up in `/dev`. `name` is the setting, for the error message.
+pub fn parse_spec<'a>(name: &str, spec: &'a str) -> Result<(String, u32, Option<&'a str>), String> {
+    let invalid = || format!("{}: expected e.g. 'gpiochip0:17', got '{}'", name, spec);
+    let mut parts = spec.trim().split(':');
+    let chip = parts.next().filter(|chip| !chip.is_empty()).ok_or_else(invalid)?;
+    let line = parts.next().and_then(|line| line.parse().ok()).ok_or_else(invalid)?;
+    let flag = parts.next();
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    let chip = if chip.starts_with('/') { chip.to_string() } else { format!("/dev/{}", chip) };
+    Ok((chip, line, flag))
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+    const LINE_FLAG_OUTPUT: u64 = 1 << 3;
+    // _IOWR(0xB4, 0x07, struct gpio_v2_line_request)
+    const GET_LINE_IOCTL: u64 = 0xc250_b407;
+    // _IOWR(0xB4, 0x0F, struct gpio_v2_line_values)
+    const SET_VALUES_IOCTL: u64 = 0xc010_b40f;
+
+    #[repr(C)]
+    struct LineAttribute {
+        id: u32,
+        padding: u32,
+        value: u64,
+    }
+
+    #[repr(C)]
+    struct LineConfigAttribute {
+        attr: LineAttribute,
+        mask: u64,
+    }
+
+    #[repr(C)]
+    struct LineConfig {
+        flags: u64,
+        num_attrs: u32,
+        padding: [u32; 5],
+        attrs: [LineConfigAttribute; 10],
+    }
+
+    /// `struct gpio_v2_line_request` from `linux/gpio.h`.
+    #[repr(C)]
+    struct LineRequest {
+        offsets: [u32; 64],
+        consumer: [u8; 32],
+        config: LineConfig,
+        num_lines: u32,
+        event_buffer_size: u32,
+        padding: [u32; 5],
+        fd: i32,
+    }
+
+    const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+
+    #[repr(C)]
+    struct LineValues {
+        bits: u64,
+        mask: u64,
+    }
+
+    /// One output line, held until dropped.
+    pub struct Line {
+        fd: OwnedFd,
+    }
+
+    impl Line {
+        pub fn request(chip: &str, line: u32, active_low: bool) -> io::Result<Self> {
+            let chip = File::open(chip)?;
+            let mut request: LineRequest = unsafe { std::mem::zeroed() };
+            request.offsets[0] = line;
+            request.consumer[..5].copy_from_slice(b"diald");
+            request.config.flags =
+                LINE_FLAG_OUTPUT | if active_low { LINE_FLAG_ACTIVE_LOW } else { 0 };
+            request.num_lines = 1;
+            let result =
+                unsafe { libc::ioctl(chip.as_raw_fd(), GET_LINE_IOCTL as _, &mut request) };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(request.fd) } })
+        }
+
+        pub fn set(&self, on: bool) -> io::Result<()> {
+            let mut values = LineValues { bits: on as u64, mask: 1 };
+            let result =
+                unsafe { libc::ioctl(self.fd.as_raw_fd(), SET_VALUES_IOCTL as _, &mut values) };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+
+    pub struct Line;
+
+    impl Line {
+        pub fn request(_chip: &str, _line: u32, _active_low: bool) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "GPIO needs Linux"))
+        }
+
+        pub fn set(&self, _on: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Guest mode: temporary restrictions for parties and rentals. While it is on
+//! the volume is capped, the tool can't be changed and the night limit can't
+//! be overridden. It switches itself off after a while.
+
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::payload;
+
+const DEFAULT_CAP: i32 = 60;
+const DEFAULT_DURATION: Duration = Duration::from_secs(4 * 60 * 60);
+
+pub struct GuestMode {
+    cap: i32,
+    duration: Duration,
+    activation_clicks: Option<u32>,
+    until: Option<Instant>,
+}
+
+impl GuestMode {
+    /// `DIALD_GUEST_CAP` (default 60), `DIALD_GUEST_DURATION` (default `4h`)
+    /// and `DIALD_GUEST_CLICKS`, the click count that turns guest mode on
+    /// from the dial itself (off by default).
+    pub fn from_env() -> Result<Self, String> {
+        let cap = match config::var("DIALD_GUEST_CAP") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|cap| (0..=100).contains(cap))
+                .ok_or_else(|| format!("DIALD_GUEST_CAP: expected 0-100, got '{}'", value))?,
+            Err(_) => DEFAULT_CAP,
+        };
+        let duration = match config::var("DIALD_GUEST_DURATION") {
+            Ok(value) => payload::parse_duration(&value).ok_or_else(|| {
+                format!("DIALD_GUEST_DURATION: expected e.g. '90m' or '4h', got '{}'", value)
+            })?,
+            Err(_) => DEFAULT_DURATION,
+        };
+        let activation_clicks = match config::var("DIALD_GUEST_CLICKS") {
+            Ok(value) => Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|clicks| *clicks > 1)
+                    .ok_or_else(|| {
+                        format!("DIALD_GUEST_CLICKS: expected 2 or more, got '{}'", value)
+                    })?,
+            ),
+            Err(_) => None,
+        };
+        Ok(Self { cap, duration, activation_clicks, until: None })
+    }
+
+    /// Take the settings from `settings`, staying on (or off) as before.
+    pub fn reconfigure(&mut self, settings: GuestMode) {
+        self.cap = settings.cap;
+        self.duration = settings.duration;
+        self.activation_clicks = settings.activation_clicks;
+    }
+
+    /// Turn guest mode on for `duration`, or the configured default.
+    pub fn start(&mut self, duration: Option<Duration>, now: Instant) {
+        self.until = Some(now + duration.unwrap_or(self.duration));
+    }
+
+    pub fn stop(&mut self) {
+        self.until = None;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until.is_some()
+    }
+
+    /// Expire guest mode once its time is up; returns true if it just ended.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.until.is_some_and(|until| now >= until) {
+            self.until = None;
+            return true;
+        }
+        false
+    }
+
+    /// The volume cap while guest mode is on.
+    pub fn cap(&self) -> Option<i32> {
+        self.is_active().then_some(self.cap)
+    }
+
+    /// Whether `count` batched clicks should turn guest mode on. Turning it
+    /// off takes MQTT or the control socket, so guests can't.
+    pub fn is_activation(&self, count: u32) -> bool {
+        self.activation_clicks == Some(count)
+    }
+
+    /// `on (1h30m left)` or `off`, for status replies.
+    pub fn describe(&self, now: Instant) -> String {
+        match self.until {
+            Some(until) => {
+                let left = until.saturating_duration_since(now).as_secs();
+                format!("on ({}h{:02}m left)", left / 3600, left / 60 % 60)
+            }
+            None => "off".to_string(),
+        }
+    }
+}
@@ -0,0 +1,1114 @@
+//! Haptic feedback, either through the dial's HID output reports or, for
+//! devices that have it, the evdev force-feedback interface.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::expr::Expr;
+use crate::piezo::Piezo;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    Chunky,
+    /// Longer, softer triple pulse played when the dial comes back after a wake nudge.
+    Greeting,
+    /// A single light pulse, for when the dial is touched after idling.
+    Tick,
+    /// A rapid rattle that doesn't feel like any of the others: the input is
+    /// going nowhere (e.g. the broker is down).
+    Error,
+}
+
+impl Pattern {
+    const ALL: [Pattern; 4] = [Pattern::Chunky, Pattern::Greeting, Pattern::Tick, Pattern::Error];
+
+    /// Surface Dial output report: report ID 1, repeat count, manual waveform, retrigger period.
+    fn report(self) -> [u8; 5] {
+        match self {
+            Pattern::Chunky => [1, 2, 3, 70, 0],
+            Pattern::Greeting => [1, 3, 3, 120, 0],
+            Pattern::Tick => [1, 0, 3, 0, 0],
+            Pattern::Error => [1, 6, 3, 25, 0],
+        }
+    }
+
+    /// The intensity (retrigger) byte of the report, which `Strength` can
+    /// override.
+    fn intensity(self) -> u8 {
+        self.report()[3]
+    }
+}
+
+/// What a buzz is for, so each kind can be switched on or off on its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// The dial woke up from idle.
+    Wake,
+    /// Turning past 0 or 100.
+    Boundary,
+    /// A real direction change was confirmed after backlash.
+    Backlash,
+    /// The volume was set from outside (MQTT, control socket).
+    Remote,
+    /// The dial came back after a wake nudge.
+    Greeting,
+    /// The dial was turned while the MQTT broker is unreachable.
+    Offline,
+    /// Each volume step while turning, so the dial feels notched.
+    Detent,
+}
+
+impl Trigger {
+    pub const ALL: [Trigger; 7] = [
+        Trigger::Wake,
+        Trigger::Boundary,
+        Trigger::Backlash,
+        Trigger::Remote,
+        Trigger::Greeting,
+        Trigger::Offline,
+        Trigger::Detent,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Wake => "wake",
+            Trigger::Boundary => "boundary",
+            Trigger::Backlash => "backlash",
+            Trigger::Remote => "remote",
+            Trigger::Greeting => "greeting",
+            Trigger::Offline => "offline",
+            Trigger::Detent => "detent",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Trigger> {
+        Trigger::ALL.into_iter().find(|trigger| trigger.as_str() == name)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The set of triggers that buzz.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Triggers(u8);
+
+impl Triggers {
+    /// Everything but `remote`, which never buzzed before it was configurable,
+    /// and `detent`, which is a matter of taste.
+    fn default_set() -> Self {
+        let all = Trigger::ALL.iter().map(|t| t.bit()).sum::<u8>();
+        Triggers(all & !Trigger::Remote.bit() & !Trigger::Detent.bit())
+    }
+
+    /// `DIALD_HAPTICS` lists the triggers that buzz, e.g. `wake,boundary`, or
+    /// `all` / `none`.
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(spec) = config::var("DIALD_HAPTICS") else {
+            return Ok(Self::default_set());
+        };
+        let mut triggers = Triggers(0);
+        for name in spec.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "all" => Trigger::ALL.iter().for_each(|t| triggers.set(*t, true)),
+                "none" => {}
+                name => {
+                    let trigger = Trigger::parse(name)
+                        .ok_or_else(|| format!("DIALD_HAPTICS: unknown trigger '{}'", name))?;
+                    triggers.set(trigger, true);
+                }
+            }
+        }
+        Ok(triggers)
+    }
+
+    pub fn contains(self, trigger: Trigger) -> bool {
+        self.0 & trigger.bit() != 0
+    }
+
+    pub fn set(&mut self, trigger: Trigger, enabled: bool) {
+        if enabled {
+            self.0 |= trigger.bit();
+        } else {
+            self.0 &= !trigger.bit();
+        }
+    }
+
+    /// e.g. `wake=on boundary=on backlash=off remote=off greeting=on offline=on`
+    pub fn describe(self) -> String {
+        Trigger::ALL
+            .iter()
+            .map(|t| format!("{}={}", t.as_str(), if self.contains(*t) { "on" } else { "off" }))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Boundary buzzes closer together than this count as one push past the
+/// end, for `past`.
+const BOUNDARY_STREAK: Duration = Duration::from_secs(1);
+
+/// Buzzes asked for closer together than this are felt as one anyway, so
+/// only the first is played; a burst of them would queue up on a slow link.
+const COALESCE_WINDOW: Duration = Duration::from_millis(40);
+
+/// A failed write is tried once more after this long before the output is
+/// given up on; Bluetooth links drop the odd report without being gone.
+const RETRY_DELAY: Duration = Duration::from_millis(15);
+
+/// A missing output is looked for again this often; hidraw nodes can show up
+/// a little after the input node.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A busy output (held by another process, e.g. a desktop Surface Dial
+/// driver) is only tried this often, since it tends to stay that way.
+const BUSY_REOPEN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Detent ticks are played at most this often, which is about as fast as the
+/// dial can play them apart; a fast spin is felt as a run of ticks rather
+/// than one.
+const DETENT_PACE: Duration = Duration::from_millis(25);
+
+/// Ticks still to play are capped at this many, so they never trail the
+/// dial by more than `DETENT_PACE` times as much; the rest are dropped.
+const DETENT_BACKLOG: u32 = 6;
+
+const STRENGTH_VARIABLES: &[&str] = &["base", "volume", "past"];
+
+/// `DIALD_HAPTIC_STRENGTH`: an expression for each buzz's intensity byte,
+/// clamped to 0-255, e.g. `base + past * 20` to buzz harder the longer the
+/// user keeps pushing past 0 or 100. It sees `base` (the pattern's own
+/// intensity), `volume` and `past` (boundary buzzes in a row, 0 for other
+/// triggers).
+pub struct Strength(Expr);
+
+impl Strength {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(source) = config::var("DIALD_HAPTIC_STRENGTH") else {
+            return Ok(None);
+        };
+        Expr::parse(&source, STRENGTH_VARIABLES)
+            .map(|expr| Some(Strength(expr)))
+            .map_err(|err| format!("DIALD_HAPTIC_STRENGTH: {}", err))
+    }
+
+    fn eval(&self, base: u8, volume: i32, past: u32) -> u8 {
+        let lookup = |name: &str| match name {
+            "base" => base as i64,
+            "volume" => volume as i64,
+            "past" => past as i64,
+            _ => 0,
+        };
+        self.0.eval(&lookup).clamp(0, 255) as u8
+    }
+}
+
+/// Whether diald has a haptics output, published on `home/diald/haptics`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HapticState {
+    Ok,
+    /// Another process holds the device.
+    Busy,
+    /// diald isn't allowed to open the device.
+    Denied,
+    /// No output found, or it went away.
+    Unavailable,
+    /// Never opened, for monitor instances.
+    Off,
+}
+
+impl HapticState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HapticState::Ok => "ok",
+            HapticState::Busy => "busy",
+            HapticState::Denied => "denied",
+            HapticState::Unavailable => "unavailable",
+            HapticState::Off => "off",
+        }
+    }
+
+    fn of(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ResourceBusy => HapticState::Busy,
+            io::ErrorKind::PermissionDenied => HapticState::Denied,
+            _ => HapticState::Unavailable,
+        }
+    }
+}
+
+trait Output: Send {
+    /// Play `pattern` with `strength` as its intensity byte.
+    fn play(&mut self, pattern: Pattern, strength: u8) -> io::Result<()>;
+
+    /// Send an arbitrary output report; only raw HID backends can.
+    fn write_report(&mut self, _report: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Raw HID output reports written to anything that takes bytes (hidraw, hidapi).
+struct ReportOutput<W>(W);
+
+impl<W: Write + Send> Output for ReportOutput<W> {
+    fn play(&mut self, pattern: Pattern, strength: u8) -> io::Result<()> {
+        let mut report = pattern.report();
+        report[3] = strength;
+        self.0.write_all(&report)
+    }
+
+    fn write_report(&mut self, report: &[u8]) -> io::Result<()> {
+        self.0.write_all(report)
+    }
+}
+
+type BoxedOutput = Box<dyn Output>;
+
+/// Output reports through hidapi, used on macOS and as a Linux fallback.
+#[cfg(any(target_os = "macos", feature = "hidapi"))]
+mod hid {
+    use std::io::{self, Write};
+
+    use hidapi::{HidApi, HidDevice};
+
+    use super::BoxedOutput;
+
+    struct HidWriter(HidDevice);
+
+    impl Write for HidWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf).map_err(io::Error::other)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub fn open(vendor_id: u16, product_id: u16) -> io::Result<BoxedOutput> {
+        let device = HidApi::new()
+            .and_then(|api| api.open(vendor_id, product_id))
+            .map_err(|err| io::Error::other(format!("hidapi: {}", err)))?;
+        log!("diald: opened haptics via hidapi {:04x}:{:04x}", vendor_id, product_id);
+        Ok(Box::new(super::ReportOutput(HidWriter(device))))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::{self, OpenOptions};
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use super::{BoxedOutput, HapticState};
+    use crate::config;
+
+    #[derive(PartialEq)]
+    enum Backend {
+        Auto,
+        Hidraw,
+        Hidapi,
+        ForceFeedback,
+    }
+
+    /// `DIALD_HAPTIC_BACKEND` = `auto` (default), `hidraw`, `hidapi` or `ff`.
+    fn backend() -> Backend {
+        match config::var("DIALD_HAPTIC_BACKEND").as_deref() {
+            Ok("hidraw") => Backend::Hidraw,
+            Ok("hidapi") => Backend::Hidapi,
+            Ok("ff") => Backend::ForceFeedback,
+            _ => Backend::Auto,
+        }
+    }
+
+    /// Find the hidraw device that shares the same HID parent as the given event device.
+    pub fn find_hidraw_for_event_device(event_path: &Path) -> Option<String> {
+        // /dev/input/event2 -> event2
+        let event_name = event_path.file_name()?;
+        // /sys/class/input/event2/device -> canonical path to input device
+        let event_sysfs = PathBuf::from("/sys/class/input").join(event_name);
+        let event_device_path = fs::canonicalize(event_sysfs.join("device")).ok()?;
+
+        // Check each hidraw to see if it's an ancestor of our event device
+        let hidraw_dir = fs::read_dir("/sys/class/hidraw").ok()?;
+        for entry in hidraw_dir.flatten() {
+            let hidraw_device_link = entry.path().join("device");
+            if let Ok(hidraw_device_path) = fs::canonicalize(&hidraw_device_link) {
+                // The hidraw's device should be an ancestor of the event's device
+                if event_device_path.starts_with(&hidraw_device_path) {
+                    let name = entry.file_name();
+                    return Some(format!("/dev/{}", name.to_string_lossy()));
+                }
+            }
+        }
+        None
+    }
+
+    fn open_hidraw(event_path: &Path) -> io::Result<BoxedOutput> {
+        let path = config::var("DIALD_HAPTIC_DEV")
+            .ok()
+            .or_else(|| find_hidraw_for_event_device(event_path))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no hidraw node"))?;
+
+        // The kind is kept, so a busy node is still told apart
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))?;
+        log!("diald: opened haptics {}", path);
+        Ok(Box::new(super::ReportOutput(file)))
+    }
+
+    /// Vendor and product ID of the input device, from sysfs.
+    #[cfg(feature = "hidapi")]
+    fn device_ids(event_path: &Path) -> Option<(u16, u16)> {
+        let id_dir = PathBuf::from("/sys/class/input")
+            .join(event_path.file_name()?)
+            .join("device/id");
+        let read_id = |name: &str| {
+            let raw = fs::read_to_string(id_dir.join(name)).ok()?;
+            u16::from_str_radix(raw.trim(), 16).ok()
+        };
+        Some((read_id("vendor")?, read_id("product")?))
+    }
+
+    #[cfg(feature = "hidapi")]
+    fn open_hidapi(event_path: &Path) -> io::Result<BoxedOutput> {
+        let (vendor_id, product_id) = device_ids(event_path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no USB ids in sysfs"))?;
+        super::hid::open(vendor_id, product_id)
+    }
+
+    #[cfg(not(feature = "hidapi"))]
+    fn open_hidapi(_event_path: &Path) -> io::Result<BoxedOutput> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without hidapi support (enable the `hidapi` feature)",
+        ))
+    }
+
+    /// The first backend that opens; otherwise why not, preferring a busy
+    /// device over one that isn't there.
+    pub fn open(event_path: &Path) -> io::Result<BoxedOutput> {
+        let backend = backend();
+        // Devices that advertise force feedback have a kernel driver that knows
+        // how to drive their actuator, so prefer that over raw reports
+        if matches!(backend, Backend::Auto | Backend::ForceFeedback)
+            && let Some(output) = super::ff::open(event_path)
+        {
+            return Ok(output);
+        }
+        let mut failed = io::Error::new(io::ErrorKind::NotFound, "no force feedback");
+        if matches!(backend, Backend::Auto | Backend::Hidraw) {
+            match open_hidraw(event_path) {
+                Ok(output) => return Ok(output),
+                Err(err) => failed = err,
+            }
+        }
+        // Locked-down hidraw nodes are common; libusb may still reach the device
+        let fallback = backend == Backend::Auto && cfg!(feature = "hidapi");
+        if backend == Backend::Hidapi || fallback {
+            match open_hidapi(event_path) {
+                Ok(output) => return Ok(output),
+                Err(err) if HapticState::of(&failed) == HapticState::Unavailable => failed = err,
+                Err(_) => {}
+            }
+        }
+        Err(failed)
+    }
+}
+
+/// evdev force feedback (FF_RUMBLE / FF_PERIODIC).
+#[cfg(target_os = "linux")]
+mod ff {
+    use std::io;
+    use std::path::Path;
+
+    use evdev::{
+        Device, FFEffect, FFEffectData, FFEffectKind, FFEffectType, FFEnvelope, FFReplay,
+        FFTrigger, FFWaveform,
+    };
+
+    use super::{Output, Pattern, BoxedOutput};
+
+    struct ForceFeedback {
+        // Effects are removed from the device when dropped, so keep it open
+        _device: Device,
+        rumble: bool,
+        /// Each pattern's effect and the strength it was uploaded with.
+        effects: Vec<(Pattern, FFEffect, u8)>,
+    }
+
+    impl Output for ForceFeedback {
+        fn play(&mut self, pattern: Pattern, strength: u8) -> io::Result<()> {
+            let Some((_, effect, uploaded)) = self.effects.iter_mut().find(|(p, ..)| *p == pattern)
+            else {
+                return Ok(());
+            };
+            if *uploaded != strength {
+                effect.update(effect_data(pattern, self.rumble, strength))?;
+                *uploaded = strength;
+            }
+            effect.play(1)
+        }
+    }
+
+    /// The effect for `pattern`, its magnitude scaled by `strength` over the
+    /// pattern's own intensity (patterns with none keep theirs).
+    fn effect_data(pattern: Pattern, rumble: bool, strength: u8) -> FFEffectData {
+        let (length, delay, magnitude) = match pattern {
+            Pattern::Chunky => (40, 0, 0xc000u16),
+            Pattern::Greeting => (120, 0, 0x6000u16),
+            Pattern::Tick => (15, 0, 0x5000u16),
+            Pattern::Error => (300, 0, 0xffffu16),
+        };
+        let base = pattern.intensity() as u32;
+        let magnitude = match base {
+            0 => magnitude,
+            _ => (magnitude as u32 * strength as u32 / base).min(0xffff) as u16,
+        };
+        let kind = if rumble {
+            FFEffectKind::Rumble {
+                strong_magnitude: magnitude,
+                weak_magnitude: magnitude / 2,
+            }
+        } else {
+            FFEffectKind::Periodic {
+                waveform: FFWaveform::Sine,
+                period: 20,
+                magnitude: (magnitude / 2) as i16,
+                offset: 0,
+                phase: 0,
+                envelope: FFEnvelope {
+                    attack_length: 0,
+                    attack_level: 0,
+                    fade_length: 0,
+                    fade_level: 0,
+                },
+            }
+        };
+        FFEffectData {
+            direction: 0,
+            trigger: FFTrigger { button: 0, interval: 0 },
+            replay: FFReplay { length, delay },
+            kind,
+        }
+    }
+
+    pub fn open(event_path: &Path) -> Option<BoxedOutput> {
+        let mut device = Device::open(event_path).ok()?;
+        let supported = device.supported_ff()?;
+        let rumble = supported.contains(FFEffectType::FF_RUMBLE);
+        let periodic = supported.contains(FFEffectType::FF_PERIODIC);
+        if !rumble && !periodic {
+            return None;
+        }
+
+        let mut effects = Vec::new();
+        for pattern in Pattern::ALL {
+            let strength = pattern.intensity();
+            match device.upload_ff_effect(effect_data(pattern, rumble, strength)) {
+                Ok(effect) => effects.push((pattern, effect, strength)),
+                Err(err) => {
+                    warn!("diald: failed to upload ff effect ({})", err);
+                    return None;
+                }
+            }
+        }
+        log!("diald: opened force-feedback haptics {}", event_path.display());
+        Some(Box::new(ForceFeedback {
+            _device: device,
+            rumble,
+            effects,
+        }))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::io;
+    use std::path::Path;
+
+    use super::BoxedOutput;
+    use crate::input::hid::{SURFACE_DIAL_PID, SURFACE_DIAL_VID};
+
+    /// IOKit allows a second non-exclusive handle next to the input one.
+    pub fn open(_event_path: &Path) -> io::Result<BoxedOutput> {
+        super::hid::open(SURFACE_DIAL_VID, SURFACE_DIAL_PID)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod platform {
+    use std::io;
+    use std::path::Path;
+
+    use super::BoxedOutput;
+
+    pub fn open(_event_path: &Path) -> io::Result<BoxedOutput> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// The hidraw node haptic reports go to for `event_path`, if it has one.
+pub fn hidraw_node(event_path: &Path) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    return platform::find_hidraw_for_event_device(event_path);
+    #[cfg(not(target_os = "linux"))]
+    return None;
+}
+
+/// The HID report descriptor of the node haptics are written to, from sysfs.
+fn report_descriptor(event_path: &Path) -> Option<Vec<u8>> {
+    let node = config::var("DIALD_HAPTIC_DEV").ok().or_else(|| hidraw_node(event_path))?;
+    let name = Path::new(&node).file_name()?;
+    fs::read(Path::new("/sys/class/hidraw").join(name).join("device/report_descriptor")).ok()
+}
+
+/// Each output report in `descriptor` and its length as written to hidraw,
+/// report ID included (0 when the device doesn't number its reports).
+fn output_reports(descriptor: &[u8]) -> Vec<(u8, usize)> {
+    let (mut id, mut size, mut count) = (0u8, 0u32, 0u32);
+    let mut pushed = Vec::new();
+    let mut bits: Vec<(u8, u32)> = Vec::new();
+    let mut at = 0;
+    while let Some(&prefix) = descriptor.get(at) {
+        // Long items carry their size in the next byte and nothing we need
+        if prefix == 0xfe {
+            at += 3 + descriptor.get(at + 1).copied().unwrap_or(0) as usize;
+            continue;
+        }
+        let len = [0, 1, 2, 4][(prefix & 3) as usize];
+        let Some(data) = descriptor.get(at + 1..at + 1 + len) else {
+            break;
+        };
+        let value = data.iter().rev().fold(0u32, |value, &byte| value << 8 | byte as u32);
+        match prefix & 0xfc {
+            0x84 => id = value as u8,
+            0x74 => size = value,
+            0x94 => count = value,
+            0xa4 => pushed.push((id, size, count)),
+            0xb4 => (id, size, count) = pushed.pop().unwrap_or((id, size, count)),
+            // Output main item
+            0x90 => match bits.iter_mut().find(|(report, _)| *report == id) {
+                Some((_, total)) => *total += size * count,
+                None => bits.push((id, size * count)),
+            },
+            _ => {}
+        }
+        at += 1 + len;
+    }
+    bits.into_iter().map(|(id, bits)| (id, 1 + bits.div_ceil(8) as usize)).collect()
+}
+
+pub struct HapticDevice {
+    output: Option<BoxedOutput>,
+    state: HapticState,
+    last_retry: Option<Instant>,
+    event_path: PathBuf,
+    triggers: Triggers,
+    enabled: bool,
+    /// Switched off at runtime, over `config/set`.
+    muted: bool,
+    piezo: Option<Piezo>,
+    strength: Option<Strength>,
+    volume: i32,
+    past: u32,
+    last_boundary: Option<Instant>,
+    /// When the last pattern was played, for coalescing.
+    last_played: Option<Instant>,
+    /// Output reports defined at runtime, by name. One named after a trigger
+    /// plays instead of that trigger's own pattern.
+    patterns: Vec<(String, Vec<u8>)>,
+    /// Detent ticks still to play, and when the next one may.
+    detents: u32,
+    next_detent: Option<Instant>,
+    write_time: Duration,
+}
+
+impl HapticDevice {
+    pub fn new(event_path: PathBuf) -> Self {
+        let mut device = Self {
+            output: None,
+            state: HapticState::Unavailable,
+            last_retry: None,
+            event_path,
+            triggers: Triggers::default_set(),
+            enabled: true,
+            muted: false,
+            piezo: None,
+            strength: None,
+            volume: 0,
+            past: 0,
+            last_boundary: None,
+            last_played: None,
+            patterns: Vec::new(),
+            detents: 0,
+            next_detent: None,
+            write_time: Duration::ZERO,
+        };
+        device.open();
+        device
+    }
+
+    /// A device that never opens an output, for monitor instances.
+    pub fn disabled(event_path: PathBuf) -> Self {
+        Self {
+            output: None,
+            state: HapticState::Off,
+            last_retry: None,
+            event_path,
+            triggers: Triggers::default_set(),
+            enabled: false,
+            muted: false,
+            piezo: None,
+            strength: None,
+            volume: 0,
+            past: 0,
+            last_boundary: None,
+            last_played: None,
+            patterns: Vec::new(),
+            detents: 0,
+            next_detent: None,
+            write_time: Duration::ZERO,
+        }
+    }
+
+    pub fn triggers(&self) -> Triggers {
+        self.triggers
+    }
+
+    pub fn set_triggers(&mut self, triggers: Triggers) {
+        self.triggers = triggers;
+    }
+
+    /// Whether anything is played at all, whatever the triggers say.
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn set_strength(&mut self, strength: Option<Strength>) {
+        self.strength = strength;
+    }
+
+    /// The current volume, for `Strength`.
+    pub fn set_volume(&mut self, volume: i32) {
+        self.volume = volume;
+    }
+
+    /// Also play every pattern on a piezo buzzer.
+    pub fn set_piezo(&mut self, piezo: Piezo) {
+        self.piezo = Some(piezo);
+    }
+
+    /// Define, or redefine, the pattern `name` as a raw output report. It has
+    /// to be one the device takes, as its report descriptor says; without one
+    /// to go by, it has to look like the dial's own haptic report.
+    pub fn define_pattern(&mut self, name: &str, report: Vec<u8>) -> Result<(), String> {
+        let Some(&id) = report.first() else {
+            return Err("empty report".to_string());
+        };
+        match report_descriptor(&self.event_path).map(|d| output_reports(&d)) {
+            Some(reports) => match reports.iter().find(|(report_id, _)| *report_id == id) {
+                Some(&(_, len)) if len == report.len() => {}
+                Some(&(_, len)) => {
+                    return Err(format!("report {} is {} bytes, got {}", id, len, report.len()));
+                }
+                None => return Err(format!("the device has no output report {}", id)),
+            },
+            None if id == 1 && report.len() == Pattern::Chunky.report().len() => {}
+            None => return Err("no report descriptor; expected 5 bytes starting with 1".into()),
+        }
+        match self.patterns.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, existing)) => *existing = report,
+            None => self.patterns.push((name.to_string(), report)),
+        }
+        Ok(())
+    }
+
+    /// Forget the pattern `name`; false if there is none.
+    pub fn remove_pattern(&mut self, name: &str) -> bool {
+        let before = self.patterns.len();
+        self.patterns.retain(|(existing, _)| existing != name);
+        self.patterns.len() != before
+    }
+
+    pub fn patterns(&self) -> &[(String, Vec<u8>)] {
+        &self.patterns
+    }
+
+    fn pattern(&self, name: &str) -> Option<Vec<u8>> {
+        let (_, report) = self.patterns.iter().find(|(existing, _)| existing == name)?;
+        Some(report.clone())
+    }
+
+    /// Play the pattern `name` whatever the triggers say, to try it out;
+    /// false if there is none.
+    pub fn play_pattern(&mut self, name: &str) -> bool {
+        let Some(report) = self.pattern(name) else {
+            return false;
+        };
+        self.play_report(&report);
+        true
+    }
+
+    /// The dial turned the volume by `steps`. With the `detent` trigger on,
+    /// each step is a tick, paced by `pace_detents`; otherwise the piezo, if
+    /// there is one, clicks once.
+    pub fn turned(&mut self, steps: i32) {
+        if steps == 0 {
+            return;
+        }
+        if self.triggers.contains(Trigger::Detent) {
+            self.detents = (self.detents + steps.unsigned_abs()).min(DETENT_BACKLOG);
+        } else {
+            self.detent();
+        }
+    }
+
+    /// Play the next detent tick if one is waiting and due; called on every
+    /// pass of the main loop.
+    pub fn pace_detents(&mut self, now: Instant) {
+        if self.detents == 0 || self.next_detent.is_some_and(|t| now < t) {
+            return;
+        }
+        self.detents -= 1;
+        self.next_detent = Some(now + DETENT_PACE);
+        self.detent();
+    }
+
+    /// When `pace_detents` has the next tick to play, if any are waiting.
+    pub fn next_detent(&self, now: Instant) -> Option<Instant> {
+        (self.detents > 0).then(|| self.next_detent.unwrap_or(now))
+    }
+
+    /// A click on the piezo and, with the `detent` trigger on, a tick on the
+    /// dial. A tick doesn't hold back the buzzes after it, and gives way to
+    /// one just played.
+    fn detent(&mut self) {
+        if self.muted {
+            return;
+        }
+        let started = Instant::now();
+        if let Some(piezo) = &self.piezo {
+            piezo.detent();
+        }
+        if self.triggers.contains(Trigger::Detent)
+            && self.last_played.is_none_or(|t| started - t >= COALESCE_WINDOW)
+        {
+            match self.pattern(Trigger::Detent.as_str()) {
+                Some(report) => self.write_report(&report),
+                None => self.write(Pattern::Tick, 0),
+            }
+        }
+        self.write_time += started.elapsed();
+    }
+
+    /// Time spent writing to the dial and the piezo so far, for the latency
+    /// budget.
+    pub fn write_time(&self) -> Duration {
+        self.write_time
+    }
+
+    /// Play the pattern for `trigger`, unless that trigger is switched off.
+    pub fn buzz(&mut self, trigger: Trigger) {
+        if !self.triggers.contains(trigger) {
+            return;
+        }
+        if let Some(report) = self.pattern(trigger.as_str()) {
+            self.play_report(&report);
+            return;
+        }
+        let mut past = 0;
+        if trigger == Trigger::Boundary {
+            let now = Instant::now();
+            let streak = self.last_boundary.is_some_and(|t| now - t < BOUNDARY_STREAK);
+            self.past = if streak { self.past + 1 } else { 1 };
+            self.last_boundary = Some(now);
+            past = self.past;
+        }
+        match trigger {
+            Trigger::Greeting => self.play(Pattern::Greeting, past),
+            Trigger::Wake => self.play(Pattern::Tick, past),
+            Trigger::Offline => self.play(Pattern::Error, past),
+            _ => self.play(Pattern::Chunky, past),
+        }
+    }
+
+    /// Like `buzz`, but with the error pattern: what `trigger` would confirm
+    /// isn't working.
+    pub fn buzz_error(&mut self, trigger: Trigger) {
+        if self.triggers.contains(trigger) {
+            self.play(Pattern::Error, 0);
+        }
+    }
+
+    pub fn reconnect(&mut self, event_path: &Path) {
+        self.event_path = event_path.to_path_buf();
+        if !self.enabled {
+            return;
+        }
+        self.open();
+        self.last_retry = None;
+    }
+
+    pub fn try_reconnect_if_needed(&mut self) {
+        if self.output.is_some() || !self.enabled {
+            return;
+        }
+        let interval = match self.state {
+            HapticState::Busy => BUSY_REOPEN_INTERVAL,
+            _ => REOPEN_INTERVAL,
+        };
+        let now = Instant::now();
+        if let Some(last) = self.last_retry
+            && now.duration_since(last) < interval
+        {
+            return;
+        }
+        self.last_retry = Some(now);
+        self.open();
+    }
+
+    /// Open the output, saying why not only when that changes, so a retry
+    /// every second doesn't fill the log.
+    fn open(&mut self) {
+        match platform::open(&self.event_path) {
+            Ok(output) => {
+                self.output = Some(output);
+                self.state = HapticState::Ok;
+            }
+            Err(err) => {
+                let state = HapticState::of(&err);
+                if state == self.state {
+                    debug!("diald: haptics still {} ({})", state.as_str(), err);
+                } else if state == HapticState::Busy {
+                    warn!(
+                        "diald: haptics busy ({}); another process may hold the dial, \
+                         carrying on without haptics and trying again every {}s",
+                        err,
+                        BUSY_REOPEN_INTERVAL.as_secs()
+                    );
+                } else if state == HapticState::Denied {
+                    warn!(
+                        "diald: haptics denied ({}); `diald udev-rule` writes a rule that lets \
+                         the input group at the dial's hidraw node, carrying on without haptics",
+                        err
+                    );
+                } else {
+                    log!("diald: no haptics ({})", err);
+                }
+                self.output = None;
+                self.state = state;
+            }
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.output.is_some()
+    }
+
+    pub fn state(&self) -> HapticState {
+        self.state
+    }
+
+    /// The standard buzz regardless of triggers, e.g. to test the hardware.
+    pub fn send_chunky(&mut self) {
+        self.play(Pattern::Chunky, 0);
+    }
+
+    fn play(&mut self, pattern: Pattern, past: u32) {
+        let started = Instant::now();
+        self.play_now(pattern, past);
+        self.write_time += started.elapsed();
+    }
+
+    fn play_now(&mut self, pattern: Pattern, past: u32) {
+        let now = Instant::now();
+        if self.muted || self.last_played.is_some_and(|t| now - t < COALESCE_WINDOW) {
+            return;
+        }
+        self.last_played = Some(now);
+        if let Some(piezo) = &self.piezo {
+            piezo.play(pattern);
+        }
+        self.write(pattern, past);
+    }
+
+    /// Write `pattern` to the dial, retrying once.
+    fn write(&mut self, pattern: Pattern, past: u32) {
+        if crate::DRY_RUN.load(Ordering::Relaxed) {
+            log!("diald: would play {:?}", pattern);
+            return;
+        }
+        let Some(output) = self.output.as_mut() else {
+            return;
+        };
+        let strength = match &self.strength {
+            Some(strength) => strength.eval(pattern.intensity(), self.volume, past),
+            None => pattern.intensity(),
+        };
+        let played = output.play(pattern, strength).or_else(|_| {
+            thread::sleep(RETRY_DELAY);
+            output.play(pattern, strength)
+        });
+        if let Err(err) = played {
+            warn!("diald: haptics write failed ({})", err);
+            self.output = None;
+            self.state = HapticState::of(&err);
+        }
+    }
+
+    /// A pattern defined at runtime, coalesced like the built-in ones.
+    fn play_report(&mut self, report: &[u8]) {
+        let now = Instant::now();
+        if self.muted || self.last_played.is_some_and(|t| now - t < COALESCE_WINDOW) {
+            return;
+        }
+        self.last_played = Some(now);
+        self.send_report(report);
+    }
+
+    /// Send a user-defined output report (see `report.rs`).
+    pub fn send_report(&mut self, report: &[u8]) {
+        let started = Instant::now();
+        self.write_report(report);
+        self.write_time += started.elapsed();
+    }
+
+    fn write_report(&mut self, report: &[u8]) {
+        if crate::DRY_RUN.load(Ordering::Relaxed) {
+            log!("diald: would send report {:02x?}", report);
+            return;
+        }
+        let Some(output) = self.output.as_mut() else {
+            return;
+        };
+        let written = output.write_report(report).or_else(|err| {
+            if err.kind() == io::ErrorKind::Unsupported {
+                return Err(err);
+            }
+            thread::sleep(RETRY_DELAY);
+            output.write_report(report)
+        });
+        match written {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {}
+            Err(err) => {
+                warn!("diald: output report write failed ({})", err);
+                self.output = None;
+                self.state = HapticState::of(&err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Surface Dial's report descriptor (045e:091b): the puck's input
+    /// report 1, then the haptics controller with its feature report 1 and
+    /// the 4-byte output report 1 the patterns are written as.
+    const SURFACE_DIAL: &[u8] = &[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x0e, // Usage (System Multi-Axis Controller)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x01, //   Report ID (1)
+        0x05, 0x0d, //   Usage Page (Digitizer)
+        0x09, 0x21, //   Usage (Puck)
+        0xa1, 0x00, //   Collection (Physical)
+        0x05, 0x09, //     Usage Page (Button)
+        0x09, 0x01, //     Usage (Button 1)
+        0x95, 0x01, //     Report Count (1)
+        0x75, 0x01, //     Report Size (1)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x25, 0x01, //     Logical Maximum (1)
+        0x81, 0x02, //     Input (Data, Variable, Absolute)
+        0x05, 0x01, //     Usage Page (Generic Desktop)
+        0x09, 0x37, //     Usage (Dial)
+        0x95, 0x01, //     Report Count (1)
+        0x75, 0x0f, //     Report Size (15)
+        0x55, 0x0f, //     Unit Exponent (-1)
+        0x65, 0x14, //     Unit (Degrees)
+        0x36, 0xf0, 0xf1, //     Physical Minimum (-3600)
+        0x46, 0x10, 0x0e, //     Physical Maximum (3600)
+        0x16, 0xf0, 0xf1, //     Logical Minimum (-3600)
+        0x26, 0x10, 0x0e, //     Logical Maximum (3600)
+        0x81, 0x06, //     Input (Data, Variable, Relative)
+        0xc0, //   End Collection
+        0x05, 0x0e, //   Usage Page (Haptics)
+        0x09, 0x01, //   Usage (Simple Haptic Controller)
+        0xa1, 0x02, //   Collection (Logical)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x27, 0xff, 0xff, 0x00, 0x00, //     Logical Maximum (65535)
+        0x09, 0x25, //     Usage (Retrigger Period)
+        0x75, 0x10, //     Report Size (16)
+        0x95, 0x01, //     Report Count (1)
+        0xb1, 0x02, //     Feature (Data, Variable, Absolute)
+        0x26, 0xff, 0x00, //     Logical Maximum (255)
+        0x09, 0x24, //     Usage (Repeat Count)
+        0x09, 0x20, //     Usage (Auto Trigger)
+        0x09, 0x28, //     Usage (Waveform Cutoff Time)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x03, //     Report Count (3)
+        0xb1, 0x02, //     Feature (Data, Variable, Absolute)
+        0xa4, //     Push
+        0x09, 0x24, //     Usage (Repeat Count)
+        0x09, 0x21, //     Usage (Manual Trigger)
+        0x95, 0x02, //     Report Count (2)
+        0x91, 0x02, //     Output (Data, Variable, Absolute)
+        0xb4, //     Pop
+        0x27, 0xff, 0xff, 0x00, 0x00, //     Logical Maximum (65535)
+        0x09, 0x25, //     Usage (Retrigger Period)
+        0x75, 0x10, //     Report Size (16)
+        0x95, 0x01, //     Report Count (1)
+        0x91, 0x02, //     Output (Data, Variable, Absolute)
+        0xc0, //   End Collection
+        0xc0, // End Collection
+    ];
+
+    #[test]
+    fn surface_dial_output_report() {
+        assert_eq!(output_reports(SURFACE_DIAL), [(1, Pattern::Chunky.report().len())]);
+    }
+
+    #[test]
+    fn output_reports_by_id() {
+        let descriptor = [
+            0x85, 0x02, // Report ID (2)
+            0x75, 0x08, // Report Size (8)
+            0x95, 0x03, // Report Count (3)
+            0x91, 0x02, // Output
+            0xfe, 0x02, 0x00, 0xaa, 0xbb, // Long item, skipped
+            0x85, 0x05, // Report ID (5)
+            0x95, 0x01, // Report Count (1)
+            0x81, 0x02, // Input, not counted
+            0x75, 0x01, // Report Size (1)
+            0x95, 0x04, // Report Count (4)
+            0x91, 0x02, // Output: 4 bits still take a byte
+        ];
+        assert_eq!(output_reports(&descriptor), [(2, 4), (5, 2)]);
+        // Cut short mid-item: what came before still counts
+        assert_eq!(output_reports(&descriptor[..9]), [(2, 4)]);
+        assert_eq!(output_reports(&[]), []);
+    }
+
+    #[test]
+    fn permission_errors_are_not_busy() {
+        let state = |kind: io::ErrorKind| HapticState::of(&kind.into());
+        assert_eq!(state(io::ErrorKind::ResourceBusy), HapticState::Busy);
+        assert_eq!(state(io::ErrorKind::PermissionDenied), HapticState::Denied);
+        assert_eq!(state(io::ErrorKind::NotFound), HapticState::Unavailable);
+    }
+}
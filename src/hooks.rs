@@ -0,0 +1,51 @@
+//! What to do when the dial goes idle, for setups that use it as a presence
+//! hint: publish a snapshot, run a command, send a message of your own.
+
+use std::process::Command;
+use std::thread;
+
+use crate::config;
+
+/// Configured from `DIALD_IDLE_SNAPSHOT=1`, `DIALD_IDLE_EXEC` (run through
+/// `sh -c`) and `DIALD_IDLE_PUBLISH` (`topic payload`, e.g.
+/// `home/office/display off`).
+pub struct IdleHooks {
+    pub snapshot: bool,
+    pub exec: Option<String>,
+    pub publish: Option<(String, String)>,
+}
+
+impl IdleHooks {
+    pub fn from_env() -> Result<Self, String> {
+        let snapshot = config::flag("DIALD_IDLE_SNAPSHOT", false)?;
+        let exec = config::var("DIALD_IDLE_EXEC").ok().filter(|command| !command.is_empty());
+        let publish = match config::var("DIALD_IDLE_PUBLISH") {
+            Ok(spec) => {
+                let (topic, payload) = spec.trim().split_once(' ').ok_or_else(|| {
+                    format!("DIALD_IDLE_PUBLISH: expected 'topic payload', got '{}'", spec)
+                })?;
+                Some((topic.to_string(), payload.trim().to_string()))
+            }
+            Err(_) => None,
+        };
+        Ok(Self { snapshot, exec, publish })
+    }
+}
+
+/// Run `command` through the shell without waiting for it; `vars` are added
+/// to its environment. The child is reaped on a thread of its own.
+pub fn spawn_command(command: &str, vars: &[(&str, String)]) {
+    let mut child = Command::new("sh");
+    child.args(["-c", command]);
+    for (name, value) in vars {
+        child.env(name, value);
+    }
+    match child.spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || {
+                let _ = child.wait();
+            });
+        }
+        Err(err) => warn!("diald: failed to run '{}' ({})", command, err),
+    }
+}
@@ -0,0 +1,159 @@
+//! Notice input devices as they are added, from the kernel's uevents, so a
+//! Bluetooth dial that wakes up is opened right away rather than by a retry
+//! loop probing every device once a second while it sleeps.
+//!
+//! The events come after udev has set the device node up (permissions and
+//! `/dev/input/by-id` links included) when udev runs, else straight from the
+//! kernel. Only `add` events for `eventN` nodes count; which one appeared
+//! doesn't matter, since every candidate is tried again. On by default on
+//! Linux; `DIALD_HOTPLUG=0` goes back to retrying every second.
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::config;
+
+pub struct Hotplug {
+    monitor: platform::Monitor,
+}
+
+impl Hotplug {
+    /// A monitor for added input devices, unless turned off or unavailable
+    /// (logged), in which case the caller retries on its own.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        if !config::flag("DIALD_HOTPLUG", true)? {
+            return Ok(None);
+        }
+        Ok(match platform::Monitor::new() {
+            Ok(monitor) => Some(Self { monitor }),
+            Err(err) if err.kind() == ErrorKind::Unsupported => None,
+            Err(err) => {
+                warn!("diald: no hotplug events, retrying every second ({})", err);
+                None
+            }
+        })
+    }
+
+    /// Wait up to `timeout` for an input device to be added; whether one was.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        match self.monitor.wait(timeout) {
+            Ok(added) => added,
+            Err(err) => {
+                debug!("diald: hotplug: {}", err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Multicast groups: the kernel's own events, and udev's once it has
+    /// handled them.
+    const KERNEL_EVENTS: u32 = 1;
+    const UDEV_EVENTS: u32 = 2;
+
+    pub struct Monitor {
+        fd: OwnedFd,
+        buf: Vec<u8>,
+    }
+
+    impl Monitor {
+        pub fn new() -> io::Result<Self> {
+            let flags = libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK;
+            let fd = unsafe { libc::socket(libc::AF_NETLINK, flags, libc::NETLINK_KOBJECT_UEVENT) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            // Without udev, nodes are ready as soon as the kernel says so
+            let udev = Path::new("/run/udev/control").exists();
+            addr.nl_groups = if udev { UDEV_EVENTS } else { KERNEL_EVENTS };
+            let bound = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    (&addr as *const libc::sockaddr_nl).cast(),
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if bound < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd, buf: vec![0; 8192] })
+        }
+
+        /// Whether any of the pending messages announces a new device.
+        fn drain(&mut self) -> io::Result<bool> {
+            let mut added = false;
+            loop {
+                let read = unsafe {
+                    libc::recv(self.fd.as_raw_fd(), self.buf.as_mut_ptr().cast(), self.buf.len(), 0)
+                };
+                if read < 0 {
+                    let err = io::Error::last_os_error();
+                    return match err.kind() {
+                        io::ErrorKind::WouldBlock => Ok(added),
+                        io::ErrorKind::Interrupted => continue,
+                        _ => Err(err),
+                    };
+                }
+                added |= input_added(&self.buf[..read as usize]);
+            }
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let fd = self.fd.as_raw_fd();
+            let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                // SIGHUP cuts the wait short, which is as good as a timeout
+                return if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) };
+            }
+            if ready == 0 {
+                return Ok(false);
+            }
+            self.drain()
+        }
+    }
+
+    /// Whether a uevent, `KEY=value` fields separated by NULs, announces a
+    /// new event device.
+    fn input_added(message: &[u8]) -> bool {
+        let fields: Vec<&[u8]> = message.split(|byte| *byte == 0).collect();
+        let has = |field: &[u8]| fields.contains(&field);
+        // `input/event5` from the kernel, `/dev/input/event5` from udev
+        let event_node = fields
+            .iter()
+            .filter_map(|field| field.strip_prefix(b"DEVNAME="))
+            .filter_map(|name| name.rsplit(|byte| *byte == b'/').next())
+            .any(|node| node.starts_with(b"event"));
+        has(b"ACTION=add") && has(b"SUBSYSTEM=input") && event_node
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+    use std::time::Duration;
+
+    pub struct Monitor;
+
+    impl Monitor {
+        pub fn new() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "hotplug events need Linux"))
+        }
+
+        pub fn wait(&mut self, _timeout: Duration) -> io::Result<bool> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "hotplug events need Linux"))
+        }
+    }
+}
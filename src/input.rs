@@ -0,0 +1,269 @@
+//! OS abstraction over the input layer. The rest of diald only ever sees
+//! [`InputEvent`]s from an [`InputSource`]; each platform provides a backend.
+
+use std::fs;
+use std::io;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config;
+
+#[cfg(target_os = "linux")]
+mod evdev;
+mod filter;
+#[cfg(target_os = "macos")]
+pub mod hid;
+pub mod replay;
+#[cfg(target_os = "linux")]
+mod streamdeck;
+
+/// The Surface Dial's USB (and Bluetooth) vendor and product ids.
+pub const SURFACE_DIAL_ID: (u16, u16) = (0x045e, 0x091b);
+
+/// The device path that stands for whichever dial is connected.
+pub const AUTO: &str = "auto";
+
+/// The Surface Dial reports a tenth of a degree per count.
+pub const SURFACE_DIAL_COUNTS_PER_REV: i32 = 3600;
+
+/// Set by a monitor instance, which must never take the device away from the
+/// instance doing the real work, whatever `DIALD_GRAB` says.
+pub static NEVER_GRAB: AtomicBool = AtomicBool::new(false);
+
+/// `DIALD_GRAB`: take the device exclusively, so the desktop (GNOME scrolling,
+/// keyboard volume keys) doesn't also act on its events.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn grab() -> io::Result<bool> {
+    let grab = config::flag("DIALD_GRAB", false)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    Ok(grab && !NEVER_GRAB.load(Ordering::Relaxed))
+}
+
+/// Input normalized across backends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    /// Relative rotation in raw encoder counts; positive is clockwise.
+    Rotate(i32),
+    /// One discrete step (+1/-1) from a knob that only reports detents, such
+    /// as a keyboard volume knob. These have no backlash to filter.
+    Detent(i32),
+    Press,
+    Release,
+}
+
+/// What `diald list-devices` shows about an input device.
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub phys: Option<String>,
+    /// `(vendor, product)`.
+    pub id: Option<(u16, u16)>,
+    /// Relative axes and knob keys it reports, e.g. `REL_DIAL`.
+    pub axes: Vec<String>,
+    /// Whether `scan` would pick it with the current `DIALD_AXES`.
+    pub matches: bool,
+}
+
+pub trait InputSource {
+    fn name(&self) -> Option<String>;
+
+    /// Explain why this device can't work as configured (e.g. it lacks the
+    /// rotation axis), so a wrong `--device` doesn't go unnoticed.
+    fn sanity_warning(&self) -> Option<String> {
+        None
+    }
+
+    /// Read whatever is pending without blocking. Returns an error of kind
+    /// `WouldBlock` when there is nothing to read, and any other error when the
+    /// device is gone.
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>>;
+
+    /// A file descriptor that turns readable when there are events, for the
+    /// main loop to sleep on; without one it looks every 10ms.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Show the dial's value on the device's own screen, if it has one.
+    /// `share` is how far up the range it is, from 0 to 1.
+    fn show_value(&mut self, _value: &str, _share: f64) {}
+}
+
+/// Open the input device at `path` with the platform's backend, a Stream
+/// Deck+ dial for `streamdeck:<node>:<dial>`, or replay a recorded trace for
+/// `replay:<file>`, behind the minimum-delta filter if one is configured.
+pub fn open(path: &Path) -> io::Result<Box<dyn InputSource>> {
+    filter::MinDelta::wrap(open_backend(path)?)
+}
+
+fn open_backend(path: &Path) -> io::Result<Box<dyn InputSource>> {
+    if let Some(trace) = path.to_str().and_then(|p| p.strip_prefix("replay:")) {
+        return replay::open(Path::new(trace));
+    }
+    if let Some(spec) = path.to_str().and_then(|p| p.strip_prefix("streamdeck:")) {
+        #[cfg(target_os = "linux")]
+        return streamdeck::open(spec);
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Stream Deck dials need hidraw, which only Linux has ({})", spec),
+        ));
+    }
+    #[cfg(target_os = "linux")]
+    return evdev::open(path);
+    #[cfg(target_os = "macos")]
+    return hid::open(path);
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("no input backend for this platform ({})", path.display()),
+    ));
+}
+
+/// `DIALD_COUNTS_PER_REV`: raw counts in one full turn, as measured by
+/// `diald calibrate`. Defaults to the Surface Dial's.
+pub fn counts_per_revolution() -> Result<i32, String> {
+    let Ok(counts) = config::var("DIALD_COUNTS_PER_REV") else {
+        return Ok(SURFACE_DIAL_COUNTS_PER_REV);
+    };
+    counts.parse().ok().filter(|counts| *counts > 0).ok_or_else(|| {
+        format!("DIALD_COUNTS_PER_REV: expected a positive number, got '{}'", counts)
+    })
+}
+
+/// Every device that could drive diald: anything with a relative axis or
+/// volume keys, dials or not, and each Stream Deck+ dial.
+pub fn list() -> io::Result<Vec<DeviceInfo>> {
+    #[cfg(target_os = "linux")]
+    return Ok(evdev::list()?.into_iter().chain(streamdeck::list()).collect());
+    #[cfg(target_os = "macos")]
+    return Ok(hid::scan()?
+        .into_iter()
+        .map(|(path, name)| DeviceInfo {
+            path,
+            name,
+            phys: None,
+            id: Some((hid::SURFACE_DIAL_VID, hid::SURFACE_DIAL_PID)),
+            axes: Vec::new(),
+            matches: true,
+        })
+        .collect());
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    return Ok(Vec::new());
+}
+
+/// The `/dev/input/by-id` link for `path`, which survives reboots and
+/// replugging unlike `eventN` numbers.
+pub fn stable_path(path: &Path) -> Option<PathBuf> {
+    link_in(Path::new("/dev/input/by-id"), path)
+}
+
+/// A link in `dir` that points at the same node as `path`.
+fn link_in(dir: &Path, path: &Path) -> Option<PathBuf> {
+    let target = fs::canonicalize(path).ok()?;
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|link| fs::canonicalize(link).is_ok_and(|resolved| resolved == target))
+}
+
+/// What was at an `eventN` path when diald first opened it, so the same
+/// device is found again once it comes back under another number.
+enum Identity {
+    /// Its `/dev/input/by-id` or `by-path` link.
+    Link(PathBuf),
+    /// Bluetooth devices get neither; their name, physical path and ids.
+    Info { name: String, phys: Option<String>, id: Option<(u16, u16)> },
+}
+
+impl Identity {
+    fn of(path: &Path) -> Option<Identity> {
+        let link = stable_path(path).or_else(|| link_in(Path::new("/dev/input/by-path"), path));
+        if let Some(link) = link {
+            return Some(Identity::Link(link));
+        }
+        let device = list().ok()?.into_iter().find(|device| device.path == path)?;
+        Some(Identity::Info { name: device.name, phys: device.phys, id: device.id })
+    }
+
+    /// Where the device is now, if it's there.
+    fn find(&self) -> Option<PathBuf> {
+        match self {
+            Identity::Link(link) => fs::canonicalize(link).ok(),
+            Identity::Info { name, phys, id } => list()
+                .ok()?
+                .into_iter()
+                .find(|device| device.name == *name && device.phys == *phys && device.id == *id)
+                .map(|device| device.path),
+        }
+    }
+}
+
+/// The identity of each `eventN` path opened so far.
+static IDENTITIES: Mutex<Vec<(PathBuf, Identity)>> = Mutex::new(Vec::new());
+
+/// A plain `eventN` node, whose number can change when the device comes back.
+fn is_event_node(path: &Path) -> bool {
+    path.starts_with("/dev/input")
+        && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("event"))
+        && !fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+}
+
+/// Where the device first found at the `eventN` node `path` is now. The first
+/// time, that is `path` itself, and what is there is remembered.
+fn follow(path: &Path) -> io::Result<PathBuf> {
+    let mut identities = IDENTITIES.lock().unwrap_or_else(|err| err.into_inner());
+    let Some((_, identity)) = identities.iter().find(|(known, _)| known == path) else {
+        if let Some(identity) = Identity::of(path) {
+            identities.push((path.to_path_buf(), identity));
+        }
+        return Ok(path.to_path_buf());
+    };
+    // Whatever else took its number is not the dial
+    let found = identity.find().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "the device first found there is gone")
+    })?;
+    if found != path {
+        log!("diald: {} is now {}", path.display(), found.display());
+    }
+    Ok(found)
+}
+
+/// The device `path` stands for. On Linux, `auto` is the first Surface Dial
+/// (by its ids or name) that reports a rotation axis, else the first device
+/// that does, so nothing depends on `eventN` numbering; hidapi on macOS picks
+/// the Surface Dial itself.
+pub fn resolve(path: &Path) -> io::Result<PathBuf> {
+    if cfg!(not(target_os = "linux")) {
+        return Ok(path.to_path_buf());
+    }
+    if is_event_node(path) {
+        return follow(path);
+    }
+    if path != Path::new(AUTO) {
+        return Ok(path.to_path_buf());
+    }
+    let devices: Vec<DeviceInfo> = list()?.into_iter().filter(|device| device.matches).collect();
+    let known = |device: &&DeviceInfo| {
+        device.id == Some(SURFACE_DIAL_ID) || device.name.contains("Surface Dial")
+    };
+    devices
+        .iter()
+        .find(known)
+        .or_else(|| devices.first())
+        .map(|device| device.path.clone())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no dial found"))
+}
+
+/// Devices that look like dials, with a human-readable name each.
+pub fn scan() -> io::Result<Vec<(PathBuf, String)>> {
+    #[cfg(target_os = "linux")]
+    return evdev::scan();
+    #[cfg(target_os = "macos")]
+    return hid::scan();
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    return Ok(Vec::new());
+}
@@ -0,0 +1,304 @@
+//! Linux evdev backend.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use evdev::{Device, InputEventKind, Key, RelativeAxisType, Synchronization};
+
+use super::{DeviceInfo, InputEvent, InputSource};
+use crate::config;
+
+/// A push axis re-arms after this long without events, for devices that stop
+/// reporting instead of sending a final zero.
+const PUSH_REARM: Duration = Duration::from_millis(300);
+
+fn set_nonblock(device: &Device) -> io::Result<()> {
+    let fd = device.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AxisRole {
+    /// Feeds the rotation pipeline, like the Dial's REL_DIAL.
+    Rotate,
+    /// Acts as the dial button: pushing past the dead zone is one click.
+    Push,
+}
+
+struct AxisMapping {
+    axis: RelativeAxisType,
+    role: AxisRole,
+    dead_zone: i32,
+    latched: bool,
+    last_event: Option<SystemTime>,
+    /// Whether it was seen mirroring an earlier rotation axis, for the log.
+    mirrored: bool,
+}
+
+/// `DIALD_AXES` is a comma-separated list of `AXIS=role[:dead_zone]`, e.g.
+/// `REL_RZ=rotate:5,REL_Z=push:40` for a SpaceMouse. Defaults to `REL_DIAL=rotate`.
+fn axis_mappings() -> io::Result<Vec<AxisMapping>> {
+    let spec = config::var("DIALD_AXES").unwrap_or_else(|_| "REL_DIAL=rotate".to_string());
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    let mut mappings = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (axis, rest) = entry
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("DIALD_AXES: expected AXIS=role in '{}'", entry)))?;
+        let axis: RelativeAxisType = axis
+            .trim()
+            .parse()
+            .map_err(|_| invalid(format!("DIALD_AXES: unknown axis '{}'", axis)))?;
+        let (role, dead_zone) = rest.split_once(':').unwrap_or((rest, "0"));
+        let role = match role.trim() {
+            "rotate" => AxisRole::Rotate,
+            "push" => AxisRole::Push,
+            other => return Err(invalid(format!("DIALD_AXES: unknown role '{}'", other))),
+        };
+        let dead_zone = dead_zone
+            .trim()
+            .parse()
+            .map_err(|_| invalid(format!("DIALD_AXES: invalid dead zone in '{}'", entry)))?;
+        mappings.push(AxisMapping {
+            axis,
+            role,
+            dead_zone,
+            latched: false,
+            last_event: None,
+            mirrored: false,
+        });
+    }
+    Ok(mappings)
+}
+
+/// `DIALD_MIRRORED_AXES`: with several rotation axes mapped, `first` (the
+/// default) only counts the one listed first in each report, for firmwares
+/// that report the same turn as both `REL_DIAL` and `REL_WHEEL`; `all` counts
+/// every one.
+fn first_axis_only() -> io::Result<bool> {
+    match config::var("DIALD_MIRRORED_AXES").as_deref() {
+        Err(_) | Ok("first") => Ok(true),
+        Ok("all") => Ok(false),
+        Ok(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("DIALD_MIRRORED_AXES: expected first or all, got '{}'", other),
+        )),
+    }
+}
+
+impl AxisMapping {
+    fn map(&mut self, value: i32, time: SystemTime, out: &mut Vec<InputEvent>) {
+        let outside = value.abs() > self.dead_zone;
+        match self.role {
+            AxisRole::Rotate => {
+                if outside {
+                    out.push(InputEvent::Rotate(value));
+                }
+            }
+            AxisRole::Push => {
+                let stale = self
+                    .last_event
+                    .and_then(|last| time.duration_since(last).ok())
+                    .is_some_and(|gap| gap >= PUSH_REARM);
+                if !outside || stale {
+                    self.latched = false;
+                }
+                if outside && !self.latched {
+                    self.latched = true;
+                    out.push(InputEvent::Press);
+                    out.push(InputEvent::Release);
+                }
+                self.last_event = Some(time);
+            }
+        }
+    }
+}
+
+struct EvdevSource {
+    device: Device,
+    axes: Vec<AxisMapping>,
+    first_axis_only: bool,
+    /// Rotation in the report being read, as `(index into axes, value, time)`;
+    /// held until the report ends so a mirror can be told from the original.
+    report: Vec<(usize, i32, SystemTime)>,
+}
+
+/// A report is complete: pass its rotation on, only the first mapped axis's
+/// if mirrors are ignored.
+fn end_report(
+    report: &mut Vec<(usize, i32, SystemTime)>,
+    axes: &mut [AxisMapping],
+    first_axis_only: bool,
+    out: &mut Vec<InputEvent>,
+) {
+    let first = report.iter().map(|(index, ..)| *index).min();
+    for (index, value, time) in report.drain(..) {
+        let mapping = &mut axes[index];
+        if Some(index) == first || !first_axis_only {
+            mapping.map(value, time, out);
+        } else if !mapping.mirrored {
+            mapping.mirrored = true;
+            log!("diald: ignoring {:?}, which mirrors another rotation axis", mapping.axis);
+        }
+    }
+}
+
+pub fn open(path: &Path) -> io::Result<Box<dyn InputSource>> {
+    let axes = axis_mappings()?;
+    let first_axis_only = first_axis_only()?;
+    let mut device = Device::open(path)?;
+    set_nonblock(&device)?;
+    // Keep the desktop from also acting on the events (e.g. keyboard volume keys)
+    if super::grab()? {
+        device.grab()?;
+        log!("diald: grabbed {}", path.display());
+    }
+    Ok(Box::new(EvdevSource { device, axes, first_axis_only, report: Vec::new() }))
+}
+
+/// Event devices that report at least one of the given axes, with their names.
+fn find_devices(axes: &[RelativeAxisType]) -> Vec<(PathBuf, String)> {
+    let mut found: Vec<_> = evdev::enumerate()
+        .filter(|(_, device)| {
+            device
+                .supported_relative_axes()
+                .is_some_and(|supported| axes.iter().any(|axis| supported.contains(*axis)))
+        })
+        .map(|(path, device)| (path, device.name().unwrap_or("unnamed").to_string()))
+        .collect();
+    found.sort();
+    found
+}
+
+fn rotate_axes() -> io::Result<Vec<RelativeAxisType>> {
+    Ok(axis_mappings()?
+        .iter()
+        .filter(|mapping| mapping.role == AxisRole::Rotate)
+        .map(|mapping| mapping.axis)
+        .collect())
+}
+
+/// Devices that look like dials: they report one of the configured rotation
+/// axes (REL_DIAL by default).
+pub fn scan() -> io::Result<Vec<(PathBuf, String)>> {
+    Ok(find_devices(&rotate_axes()?))
+}
+
+pub fn list() -> io::Result<Vec<DeviceInfo>> {
+    let rotate = rotate_axes()?;
+    let mut found = Vec::new();
+    for (path, device) in evdev::enumerate() {
+        let relative = device.supported_relative_axes();
+        let mut axes: Vec<String> =
+            relative.iter().flat_map(|set| set.iter()).map(|a| format!("{:?}", a)).collect();
+        if device.supported_keys().is_some_and(|keys| keys.contains(Key::KEY_VOLUMEUP)) {
+            axes.push("KEY_VOLUMEUP".to_string());
+        }
+        if axes.is_empty() {
+            continue;
+        }
+        let id = device.input_id();
+        found.push(DeviceInfo {
+            path,
+            name: device.name().unwrap_or("unnamed").to_string(),
+            phys: device.physical_path().map(str::to_string),
+            id: Some((id.vendor(), id.product())),
+            axes,
+            matches: relative.is_some_and(|set| rotate.iter().any(|axis| set.contains(*axis))),
+        });
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(found)
+}
+
+impl InputSource for EvdevSource {
+    fn name(&self) -> Option<String> {
+        self.device.name().map(str::to_string)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.device.as_raw_fd())
+    }
+
+    fn sanity_warning(&self) -> Option<String> {
+        let supported = self.device.supported_relative_axes();
+        let missing: Vec<RelativeAxisType> = self
+            .axes
+            .iter()
+            .map(|mapping| mapping.axis)
+            .filter(|axis| !supported.is_some_and(|s| s.contains(*axis)))
+            .collect();
+        // Keyboard knobs rotate through volume keys rather than an axis
+        let has_volume_keys = self
+            .device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(Key::KEY_VOLUMEUP));
+        if missing.is_empty() || has_volume_keys {
+            return None;
+        }
+
+        let names: Vec<String> = missing.iter().map(|axis| format!("{:?}", axis)).collect();
+        let mut warning = format!(
+            "device does not report {}, so it will never produce rotation",
+            names.join(" or ")
+        );
+        let axes: Vec<RelativeAxisType> = self.axes.iter().map(|mapping| mapping.axis).collect();
+        let found: Vec<String> = find_devices(&axes)
+            .iter()
+            .map(|(path, name)| format!("{} ({})", path.display(), name))
+            .collect();
+        if found.is_empty() {
+            warning.push_str("; no other device reports it either");
+        } else {
+            warning.push_str("; devices that do: ");
+            warning.push_str(&found.join(", "));
+        }
+        Some(warning)
+    }
+
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        let mut out = Vec::new();
+        for event in self.device.fetch_events()? {
+            match event.kind() {
+                InputEventKind::RelAxis(axis) => {
+                    let Some(index) = self.axes.iter().position(|m| m.axis == axis) else {
+                        continue;
+                    };
+                    if self.axes[index].role == AxisRole::Rotate {
+                        self.report.push((index, event.value(), event.timestamp()));
+                    } else {
+                        self.axes[index].map(event.value(), event.timestamp(), &mut out);
+                    }
+                }
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) => {
+                    end_report(&mut self.report, &mut self.axes, self.first_axis_only, &mut out)
+                }
+                InputEventKind::Key(Key::BTN_0 | Key::KEY_MUTE) => out.push(if event.value() == 1 {
+                    InputEvent::Press
+                } else {
+                    InputEvent::Release
+                }),
+                // Keyboard knobs: presses and autorepeats (value 2) are detents
+                InputEventKind::Key(Key::KEY_VOLUMEUP) if event.value() != 0 => {
+                    out.push(InputEvent::Detent(1))
+                }
+                InputEventKind::Key(Key::KEY_VOLUMEDOWN) if event.value() != 0 => {
+                    out.push(InputEvent::Detent(-1))
+                }
+                _ => {}
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,139 @@
+//! Minimum-delta filter for cheap encoders that chatter ±1 while resting
+//! between detents. Unlike backlash compensation, which only looks at
+//! reversals mid-gesture, it keeps the chatter from waking the dial at all.
+//!
+//! `DIALD_MIN_DELTA` is the net movement, in raw counts, the dial has to make
+//! within `DIALD_MIN_DELTA_WINDOW` (milliseconds, default 100) before its
+//! rotation is let through. Nothing is lost once it is: the counts held back
+//! until then are released with it. It lets go again once the net movement
+//! over the window falls below `DIALD_MIN_DELTA_RELEASE`, the threshold by
+//! default; a lower one keeps a slow turn going once it has started without
+//! letting resting chatter start one.
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
+
+use super::{InputEvent, InputSource};
+use crate::config;
+
+const DEFAULT_WINDOW: Duration = Duration::from_millis(100);
+
+pub struct MinDelta {
+    inner: Box<dyn InputSource>,
+    threshold: i32,
+    /// Net movement below which the dial is at rest again.
+    release: i32,
+    window: Duration,
+    moving: bool,
+    /// Rotation held back while resting.
+    pending: VecDeque<(Instant, i32)>,
+    /// All rotation within the window, to tell when the dial has come to rest.
+    recent: VecDeque<(Instant, i32)>,
+}
+
+fn net(events: &VecDeque<(Instant, i32)>) -> i32 {
+    events.iter().map(|(_, value)| value).sum()
+}
+
+fn prune(events: &mut VecDeque<(Instant, i32)>, since: Instant) {
+    while events.front().is_some_and(|(at, _)| *at < since) {
+        events.pop_front();
+    }
+}
+
+impl MinDelta {
+    /// `source` as is unless `DIALD_MIN_DELTA` is set.
+    pub fn wrap(source: Box<dyn InputSource>) -> io::Result<Box<dyn InputSource>> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let Ok(threshold) = config::var("DIALD_MIN_DELTA") else {
+            return Ok(source);
+        };
+        let threshold = threshold.parse().ok().filter(|counts| *counts > 0).ok_or_else(|| {
+            invalid(format!("DIALD_MIN_DELTA: expected a positive number, got '{}'", threshold))
+        })?;
+        let window = match config::var("DIALD_MIN_DELTA_WINDOW") {
+            Ok(ms) => ms
+                .parse()
+                .ok()
+                .filter(|ms| (10..=2000).contains(ms))
+                .map(Duration::from_millis)
+                .ok_or_else(|| {
+                    invalid(format!("DIALD_MIN_DELTA_WINDOW: expected 10-2000 (ms), got '{}'", ms))
+                })?,
+            Err(_) => DEFAULT_WINDOW,
+        };
+        let release = match config::var("DIALD_MIN_DELTA_RELEASE") {
+            Ok(counts) => {
+                counts.parse().ok().filter(|c| (1..=threshold).contains(c)).ok_or_else(|| {
+                    invalid(format!(
+                        "DIALD_MIN_DELTA_RELEASE: expected 1-{} (counts), got '{}'",
+                        threshold, counts
+                    ))
+                })?
+            }
+            Err(_) => threshold,
+        };
+        Ok(Box::new(Self {
+            inner: source,
+            threshold,
+            release,
+            window,
+            moving: false,
+            pending: VecDeque::new(),
+            recent: VecDeque::new(),
+        }))
+    }
+
+    fn filter(&mut self, events: Vec<InputEvent>, now: Instant) -> Vec<InputEvent> {
+        let since = now.checked_sub(self.window).unwrap_or(now);
+        prune(&mut self.recent, since);
+        prune(&mut self.pending, since);
+        if self.moving && net(&self.recent).abs() < self.release {
+            self.moving = false;
+        }
+
+        let mut out = Vec::with_capacity(events.len());
+        for event in events {
+            let InputEvent::Rotate(value) = event else {
+                out.push(event);
+                continue;
+            };
+            self.recent.push_back((now, value));
+            if self.moving {
+                out.push(event);
+                continue;
+            }
+            self.pending.push_back((now, value));
+            if net(&self.pending).abs() >= self.threshold {
+                self.moving = true;
+                out.extend(self.pending.drain(..).map(|(_, value)| InputEvent::Rotate(value)));
+            }
+        }
+        out
+    }
+}
+
+impl InputSource for MinDelta {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn sanity_warning(&self) -> Option<String> {
+        self.inner.sanity_warning()
+    }
+
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        let events = self.inner.fetch_events()?;
+        Ok(self.filter(events, Instant::now()))
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.inner.raw_fd()
+    }
+
+    fn show_value(&mut self, value: &str, share: f64) {
+        self.inner.show_value(value, share)
+    }
+}
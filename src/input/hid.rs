@@ -0,0 +1,94 @@
+//! macOS backend: reads the Surface Dial's HID input reports through hidapi
+//! (IOKit underneath), since there is no evdev.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use hidapi::{HidApi, HidDevice};
+
+use super::{InputEvent, InputSource};
+
+pub const SURFACE_DIAL_VID: u16 = 0x045e;
+pub const SURFACE_DIAL_PID: u16 = 0x091b;
+
+struct HidSource {
+    device: HidDevice,
+    name: Option<String>,
+    pressed: bool,
+}
+
+/// `path` is either an IOKit registry path as reported by hidapi, or `auto` to
+/// pick the first Surface Dial.
+pub fn open(path: &Path) -> io::Result<Box<dyn InputSource>> {
+    let api = HidApi::new().map_err(io::Error::other)?;
+    // hidapi seizes devices on macOS by default, which would keep the dial
+    // from the rest of the system even without `DIALD_GRAB`
+    api.set_open_exclusive(super::grab()?);
+    let device = if path == Path::new("auto") {
+        api.open(SURFACE_DIAL_VID, SURFACE_DIAL_PID)
+    } else {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        api.open_path(&path)
+    }
+    .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?;
+    device.set_blocking_mode(false).map_err(io::Error::other)?;
+    let name = device.get_product_string().ok().flatten();
+    Ok(Box::new(HidSource {
+        device,
+        name,
+        pressed: false,
+    }))
+}
+
+/// Surface Dials known to hidapi, by IOKit path.
+pub fn scan() -> io::Result<Vec<(PathBuf, String)>> {
+    let api = HidApi::new().map_err(io::Error::other)?;
+    Ok(api
+        .device_list()
+        .filter(|info| {
+            info.vendor_id() == SURFACE_DIAL_VID && info.product_id() == SURFACE_DIAL_PID
+        })
+        .map(|info| {
+            let path = PathBuf::from(info.path().to_string_lossy().into_owned());
+            (path, info.product_string().unwrap_or("Surface Dial").to_string())
+        })
+        .collect())
+}
+
+impl InputSource for HidSource {
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        let mut events = Vec::new();
+        let mut report = [0u8; 64];
+        loop {
+            let len = self.device.read(&mut report).map_err(io::Error::other)?;
+            if len == 0 {
+                break;
+            }
+            // Report ID 1: 1-bit button followed by a 15-bit signed dial delta
+            if len < 3 || report[0] != 1 {
+                continue;
+            }
+            let bits = u16::from_le_bytes([report[1], report[2]]);
+            let pressed = bits & 1 != 0;
+            let delta = (bits as i16) >> 1;
+            if pressed != self.pressed {
+                self.pressed = pressed;
+                events.push(if pressed { InputEvent::Press } else { InputEvent::Release });
+            }
+            if delta != 0 {
+                events.push(InputEvent::Rotate(delta as i32));
+            }
+        }
+        if events.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(events)
+    }
+}
@@ -0,0 +1,95 @@
+//! Replays a recorded event trace in real time, for reproducing bug reports
+//! and for tests. Open it as `--device replay:/path/to/trace`.
+//!
+//! One event per line, with its time in milliseconds since the trace started:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! 0 rotate 10
+//! 4 rotate -3
+//! 500 press
+//! 560 release
+//! 900 detent 1
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::{InputEvent, InputSource};
+
+pub fn parse(text: &str) -> Result<Vec<(Duration, InputEvent)>, String> {
+    let mut events = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| format!("line {}: {} in '{}'", index + 1, msg, line);
+        let mut parts = line.split_whitespace();
+        let at = parts
+            .next()
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .ok_or_else(|| error("expected a time in milliseconds"))?;
+        let kind = parts.next();
+        let mut value = || {
+            parts
+                .next()
+                .and_then(|v| v.parse::<i32>().ok())
+                .ok_or_else(|| error("expected an integer value"))
+        };
+        let event = match kind {
+            Some("rotate") => InputEvent::Rotate(value()?),
+            Some("detent") => InputEvent::Detent(value()?),
+            Some("press") => InputEvent::Press,
+            Some("release") => InputEvent::Release,
+            _ => return Err(error("unknown event")),
+        };
+        events.push((Duration::from_millis(at), event));
+    }
+    Ok(events)
+}
+
+struct ReplaySource {
+    events: Vec<(Duration, InputEvent)>,
+    next: usize,
+    started: Instant,
+}
+
+pub fn open(path: &Path) -> io::Result<Box<dyn InputSource>> {
+    let text = fs::read_to_string(path)?;
+    let events = parse(&text).map_err(|msg| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+    })?;
+    Ok(Box::new(ReplaySource {
+        events,
+        next: 0,
+        started: Instant::now(),
+    }))
+}
+
+impl InputSource for ReplaySource {
+    fn name(&self) -> Option<String> {
+        Some("replay".to_string())
+    }
+
+    /// Everything that is due; once the trace is over the "device" stays
+    /// attached but quiet.
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        let elapsed = self.started.elapsed();
+        let due = self.events[self.next..]
+            .iter()
+            .take_while(|(at, _)| *at <= elapsed)
+            .count();
+        if due == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let events = self.events[self.next..self.next + due]
+            .iter()
+            .map(|(_, event)| *event)
+            .collect();
+        self.next += due;
+        Ok(events)
+    }
+}
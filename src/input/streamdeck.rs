@@ -0,0 +1,353 @@
+//! Elgato Stream Deck+ over hidraw. Each of its four dials is a dial of its
+//! own (e.g. one `[[device]]` section each), and its quarter of the touch strip
+//! above shows that dial's value.
+//!
+//! The device path is `streamdeck:<hidraw node>:<dial>`, dials numbered 1-4
+//! from the left: `streamdeck:/dev/hidraw3:2`. A detent is one step, with no
+//! backlash to filter; pushing the dial is the button. A tap on the dial's
+//! part of the strip is a click, and a swipe across it turns the dial one step
+//! every 20 pixels.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use super::{DeviceInfo, InputEvent, InputSource};
+use crate::font;
+use crate::jpeg;
+
+pub const PREFIX: &str = "streamdeck:";
+
+/// The Stream Deck+'s USB vendor and product ids.
+pub const STREAM_DECK_PLUS_ID: (u16, u16) = (0x0fd9, 0x0084);
+
+const DIALS: usize = 4;
+const STRIP_WIDTH: usize = 800;
+const STRIP_HEIGHT: usize = 100;
+/// The width of the strip above each dial.
+const SEGMENT: usize = STRIP_WIDTH / DIALS;
+/// Pixels of swipe per step.
+const SWIPE_STEP: i32 = 20;
+
+/// Strip images go out in output reports of this size, each a 16-byte
+/// header and a slice of the JPEG.
+const IMAGE_REPORT_LEN: usize = 1024;
+const IMAGE_HEADER_LEN: usize = 16;
+
+/// What one input report says.
+#[derive(Debug, PartialEq)]
+enum Report {
+    /// Detents turned by each dial since the last report, clockwise positive.
+    Turn([i8; DIALS]),
+    /// Whether each dial is pushed in.
+    Push([bool; DIALS]),
+    /// A tap (short or long) on the strip, at `x`.
+    Tap(usize),
+    /// A swipe along the strip.
+    Swipe { from: usize, to: usize },
+    /// Keys, or something this doesn't know.
+    Other,
+}
+
+fn parse(report: &[u8]) -> Report {
+    let at = |index: usize| report.get(index).copied().unwrap_or(0);
+    let x = |index: usize| u16::from_le_bytes([at(index), at(index + 1)]) as usize;
+    if at(0) != 0x01 {
+        return Report::Other;
+    }
+    match (at(1), at(4)) {
+        (0x02, 0x01 | 0x02) => Report::Tap(x(6)),
+        (0x02, 0x03) => Report::Swipe { from: x(6), to: x(10) },
+        (0x03, 0x00) => Report::Push(std::array::from_fn(|dial| at(5 + dial) != 0)),
+        (0x03, 0x01) => Report::Turn(std::array::from_fn(|dial| at(5 + dial) as i8)),
+        _ => Report::Other,
+    }
+}
+
+/// The hidraw node and the dial (from 0) in a `streamdeck:` path.
+fn split(spec: &str) -> Option<(PathBuf, usize)> {
+    let (node, dial) = spec.rsplit_once(':')?;
+    let dial: usize = dial.parse().ok().filter(|dial| (1..=DIALS).contains(dial))?;
+    Some((PathBuf::from(node), dial - 1))
+}
+
+/// `(vendor, product)` and name of a hidraw node, from sysfs.
+fn identify(node: &Path) -> Option<((u16, u16), String)> {
+    let name = node.file_name()?;
+    let uevent = Path::new("/sys/class/hidraw").join(name).join("device/uevent");
+    let uevent = fs::read_to_string(uevent).ok()?;
+    let field = |key: &str| {
+        uevent.lines().find_map(|line| line.strip_prefix(key)).map(str::to_string)
+    };
+    // HID_ID=0003:00000FD9:00000084
+    let ids = field("HID_ID=")?;
+    let mut ids = ids.split(':').skip(1).map(|id| u32::from_str_radix(id, 16).ok());
+    let (vendor, product) = (ids.next()??, ids.next()??);
+    let name = field("HID_NAME=").unwrap_or_else(|| "Stream Deck+".to_string());
+    Some(((vendor as u16, product as u16), name))
+}
+
+/// A dial for each of the four on every Stream Deck+.
+pub fn list() -> Vec<DeviceInfo> {
+    let Ok(entries) = fs::read_dir("/sys/class/hidraw") else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<PathBuf> =
+        entries.flatten().map(|entry| Path::new("/dev").join(entry.file_name())).collect();
+    nodes.sort();
+    let mut found = Vec::new();
+    for node in nodes {
+        let Some((STREAM_DECK_PLUS_ID, name)) = identify(&node) else {
+            continue;
+        };
+        for dial in 1..=DIALS {
+            found.push(DeviceInfo {
+                path: PathBuf::from(format!("{}{}:{}", PREFIX, node.display(), dial)),
+                name: format!("{} dial {}", name, dial),
+                phys: None,
+                id: Some(STREAM_DECK_PLUS_ID),
+                axes: vec!["dial".to_string(), "touch strip".to_string()],
+                matches: false,
+            });
+        }
+    }
+    found
+}
+
+struct StreamDeck {
+    node: File,
+    path: PathBuf,
+    dial: usize,
+    pressed: bool,
+    name: Option<String>,
+    /// The value and how far up the range it is, to the drawing thread.
+    strip: Sender<(String, f64)>,
+    shown: Option<(String, f64)>,
+}
+
+/// `spec` is what follows `streamdeck:` in the device path.
+pub fn open(spec: &str) -> io::Result<Box<dyn InputSource>> {
+    let (path, dial) = split(spec).ok_or_else(|| {
+        let expected = format!("{}/dev/hidraw3:1' (dials 1-{})", PREFIX, DIALS);
+        let message = format!("expected e.g. '{}, got '{}{}'", expected, PREFIX, spec);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    })?;
+    let node = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path)?;
+    let mut output = node.try_clone()?;
+    // Drawn off the main loop: a frame is a few output reports
+    let (strip, queue) = mpsc::channel::<(String, f64)>();
+    thread::spawn(move || {
+        while let Ok(mut shown) = queue.recv() {
+            // Only the latest value matters
+            while let Ok(newer) = queue.try_recv() {
+                shown = newer;
+            }
+            let jpeg = jpeg::encode(SEGMENT, STRIP_HEIGHT, &render(&shown.0, shown.1));
+            if let Err(err) = write_frame(&mut output, &pages(dial * SEGMENT, &jpeg)) {
+                warn!("diald: touch strip stopped ({})", err);
+                return;
+            }
+        }
+    });
+    let name = identify(&path).map(|(_, name)| format!("{} dial {}", name, dial + 1));
+    Ok(Box::new(StreamDeck { node, path, dial, pressed: false, name, strip, shown: None }))
+}
+
+/// All four dials draw through the same node, from one diald or several, so
+/// the node is locked while the pages of a frame go out.
+fn write_frame(output: &mut File, pages: &[[u8; IMAGE_REPORT_LEN]]) -> io::Result<()> {
+    output.lock()?;
+    let written = pages.iter().try_for_each(|page| output.write_all(page));
+    output.unlock()?;
+    written
+}
+
+/// The dial's part of the strip: the value, as large as it fits, over a bar.
+fn render(value: &str, share: f64) -> Vec<u8> {
+    let mut pixels = vec![0u8; SEGMENT * STRIP_HEIGHT];
+    let mut set = |x: usize, y: usize| {
+        if x < SEGMENT && y < STRIP_HEIGHT {
+            pixels[y * SEGMENT + x] = 0xff;
+        }
+    };
+    let columns = (value.chars().count() * 6).max(1);
+    let scale = ((SEGMENT - 16) / columns).clamp(1, 8);
+    let width = (columns - 1) * scale;
+    let left = (SEGMENT - width.min(SEGMENT)) / 2;
+    let top = (72 - 7 * scale) / 2;
+    for (index, c) in value.chars().enumerate() {
+        for (column, bits) in font::glyph(c).iter().enumerate() {
+            for row in 0..7 {
+                if bits & (1 << row) == 0 {
+                    continue;
+                }
+                for dx in 0..scale {
+                    for dy in 0..scale {
+                        set(left + (index * 6 + column) * scale + dx, top + row * scale + dy);
+                    }
+                }
+            }
+        }
+    }
+    // Outlined bar, filled up to the value
+    let (bar_left, bar_right, bar_top, bar_bottom) = (8, SEGMENT - 8, 80, 92);
+    let length = (bar_right - bar_left - 4) as f64 * share.clamp(0.0, 1.0);
+    let filled = bar_left + 2 + length as usize;
+    for x in bar_left..bar_right {
+        for y in bar_top..bar_bottom {
+            let edge = x == bar_left || x == bar_right - 1 || y == bar_top || y == bar_bottom - 1;
+            let inside = x >= bar_left + 2 && x < filled && y >= bar_top + 2 && y < bar_bottom - 2;
+            if edge || inside {
+                set(x, y);
+            }
+        }
+    }
+    pixels
+}
+
+/// `jpeg` as the output reports that put it on the strip at `x`, full height.
+fn pages(x: usize, jpeg: &[u8]) -> Vec<[u8; IMAGE_REPORT_LEN]> {
+    let chunks = jpeg.chunks(IMAGE_REPORT_LEN - IMAGE_HEADER_LEN);
+    let last = chunks.len().saturating_sub(1);
+    chunks
+        .enumerate()
+        .map(|(page, chunk)| {
+            let mut report = [0u8; IMAGE_REPORT_LEN];
+            report[0] = 0x02;
+            report[1] = 0x0c;
+            report[2..4].copy_from_slice(&(x as u16).to_le_bytes());
+            report[4..6].copy_from_slice(&0u16.to_le_bytes());
+            report[6..8].copy_from_slice(&(SEGMENT as u16).to_le_bytes());
+            report[8..10].copy_from_slice(&(STRIP_HEIGHT as u16).to_le_bytes());
+            report[10] = (page == last) as u8;
+            report[11..13].copy_from_slice(&(page as u16).to_le_bytes());
+            report[13..15].copy_from_slice(&(chunk.len() as u16).to_le_bytes());
+            report[IMAGE_HEADER_LEN..IMAGE_HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+            report
+        })
+        .collect()
+}
+
+/// `steps` detents, one event each.
+fn detents(events: &mut Vec<InputEvent>, steps: i32) {
+    events.extend((0..steps.abs()).map(|_| InputEvent::Detent(steps.signum())));
+}
+
+impl StreamDeck {
+    fn events(&mut self, report: Report, events: &mut Vec<InputEvent>) {
+        let ours = |x: usize| x / SEGMENT == self.dial;
+        match report {
+            Report::Turn(turned) => detents(events, turned[self.dial] as i32),
+            Report::Push(pushed) if pushed[self.dial] != self.pressed => {
+                self.pressed = pushed[self.dial];
+                events.push(if self.pressed { InputEvent::Press } else { InputEvent::Release });
+            }
+            Report::Tap(x) if ours(x) => events.extend([InputEvent::Press, InputEvent::Release]),
+            Report::Swipe { from, to } if ours(from) => {
+                detents(events, (to as i32 - from as i32) / SWIPE_STEP)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl InputSource for StreamDeck {
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn sanity_warning(&self) -> Option<String> {
+        match identify(&self.path) {
+            Some((STREAM_DECK_PLUS_ID, _)) => None,
+            Some(((vendor, product), name)) => Some(format!(
+                "{} ({:04x}:{:04x}) is not a Stream Deck+; its reports will be ignored",
+                name, vendor, product
+            )),
+            None => None,
+        }
+    }
+
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        let mut events = Vec::new();
+        let mut report = [0u8; 512];
+        loop {
+            match self.node.read(&mut report) {
+                Ok(0) => break,
+                Ok(len) => self.events(parse(&report[..len]), &mut events),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        if events.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(events)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.node.as_raw_fd())
+    }
+
+    fn show_value(&mut self, value: &str, share: f64) {
+        let shown = (value.to_string(), share);
+        if self.shown.as_ref() != Some(&shown) {
+            let _ = self.strip.send(shown.clone());
+            self.shown = Some(shown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(bytes: &[u8]) -> Vec<u8> {
+        let mut report = bytes.to_vec();
+        report.resize(14, 0);
+        report
+    }
+
+    #[test]
+    fn parses_reports() {
+        assert_eq!(
+            parse(&report(&[1, 3, 5, 0, 1, 1, 0xfe, 0, 0])),
+            Report::Turn([1, -2, 0, 0])
+        );
+        assert_eq!(
+            parse(&report(&[1, 3, 5, 0, 0, 0, 0, 1, 0])),
+            Report::Push([false, false, true, false])
+        );
+        // x = 0x0190 = 400
+        assert_eq!(parse(&report(&[1, 2, 14, 0, 1, 0, 0x90, 1, 50, 0])), Report::Tap(400));
+        assert_eq!(
+            parse(&report(&[1, 2, 14, 0, 3, 0, 10, 0, 50, 0, 80, 0, 50, 0])),
+            Report::Swipe { from: 10, to: 80 }
+        );
+        assert_eq!(parse(&report(&[1, 0, 8, 0, 1])), Report::Other);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(split("/dev/hidraw3:2"), Some((PathBuf::from("/dev/hidraw3"), 1)));
+        assert_eq!(split("/dev/hidraw3:5"), None);
+        assert_eq!(split("/dev/hidraw3"), None);
+    }
+
+    #[test]
+    fn image_pages() {
+        let jpeg: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let pages = pages(400, &jpeg);
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0][..16], [2, 0x0c, 0x90, 1, 0, 0, 200, 0, 100, 0, 0, 0, 0, 0xf0, 3, 0]);
+        assert_eq!(pages[2][10..15], [1, 2, 0, 0xe4, 1]);
+        assert_eq!(pages[1][16], jpeg[1008]);
+    }
+}
@@ -0,0 +1,271 @@
+//! A small baseline JPEG encoder, for screens that only take JPEG frames
+//! (the Stream Deck+ touch strip). Grayscale in, written as YCbCr with flat
+//! colour planes like the colour JPEGs the device's own software sends. One
+//! quantization table and the standard luminance Huffman tables serve all
+//! three components.
+
+use std::f32::consts::PI;
+
+/// Standard luminance quantization table (JPEG Annex K.1), in row order.
+const LUMINANCE: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, //
+    12, 12, 14, 19, 26, 58, 60, 55, //
+    14, 13, 16, 24, 40, 57, 69, 56, //
+    14, 17, 22, 29, 51, 87, 80, 62, //
+    18, 22, 37, 56, 68, 109, 103, 77, //
+    24, 35, 55, 64, 81, 104, 113, 92, //
+    49, 64, 78, 87, 103, 121, 120, 101, //
+    72, 92, 95, 98, 112, 100, 103, 99, //
+];
+
+/// Row-order index of each coefficient in zigzag order.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+    20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
+    59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Standard luminance DC Huffman table (Annex K.3): codes per length, values.
+const DC_COUNTS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+/// Standard luminance AC Huffman table (Annex K.5).
+const AC_COUNTS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+    0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52,
+    0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25,
+    0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64,
+    0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83,
+    0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+    0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+    0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3,
+    0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8,
+    0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+/// Text on a small screen rings badly at the tables' own quality (50).
+const QUALITY: u32 = 90;
+
+/// `(code, length)` for each symbol of a table in the JPEG's own form.
+type Codes = [(u16, u8); 256];
+
+fn codes(counts: &[u8; 16], values: &[u8]) -> Codes {
+    let mut codes = [(0, 0); 256];
+    let mut values = values.iter();
+    let mut code = 0u16;
+    for (length, &count) in (1..=16).zip(counts) {
+        for _ in 0..count {
+            if let Some(&value) = values.next() {
+                codes[value as usize] = (code, length);
+            }
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// The quantization table at `QUALITY`, in row order.
+fn quantization() -> [u16; 64] {
+    let scale = 200 - 2 * QUALITY;
+    LUMINANCE.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// Collects the entropy-coded bits, stuffing a zero after every 0xff byte.
+struct Bits {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits {
+    fn put(&mut self, bits: u16, length: u8) {
+        self.buffer = (self.buffer << length) | (bits as u32 & ((1 << length) - 1));
+        self.count += length as u32;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.buffer >> self.count) as u8;
+            self.out.push(byte);
+            if byte == 0xff {
+                self.out.push(0);
+            }
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    /// Pads the last byte with ones.
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let pad = 8 - self.count as u8;
+            self.put((1 << pad) - 1, pad);
+        }
+        self.out
+    }
+}
+
+/// The bit length of `value`'s magnitude and its bits as JPEG stores them
+/// (negative values one less, in ones' complement).
+fn magnitude(value: i32) -> (u16, u8) {
+    let length = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    ((bits & ((1 << length) - 1)) as u16, length)
+}
+
+/// The 2D DCT of one level-shifted 8x8 block, quantized, in row order.
+fn transform(block: &[f32; 64], table: &[u16; 64], cosines: &[[f32; 8]; 8]) -> [i32; 64] {
+    let mut rows = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| block[y * 8 + x] * cosines[u][x]).sum();
+        }
+    }
+    let mut out = [0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let sum: f32 = (0..8).map(|y| rows[y * 8 + u] * cosines[v][y]).sum();
+            let scale = |k: usize| if k == 0 { 0.5f32.sqrt() } else { 1.0 };
+            let coefficient = sum * scale(u) * scale(v) / 4.0;
+            out[v * 8 + u] = (coefficient / table[v * 8 + u] as f32).round() as i32;
+        }
+    }
+    out
+}
+
+fn encode_block(
+    bits: &mut Bits,
+    coefficients: &[i32; 64],
+    previous_dc: &mut i32,
+    (dc, ac): (&Codes, &Codes),
+) {
+    let (value, length) = magnitude(coefficients[0] - *previous_dc);
+    *previous_dc = coefficients[0];
+    let (code, code_length) = dc[length as usize];
+    bits.put(code, code_length);
+    bits.put(value, length);
+
+    let mut zeros = 0;
+    for &index in &ZIGZAG[1..] {
+        let coefficient = coefficients[index];
+        if coefficient == 0 {
+            zeros += 1;
+            continue;
+        }
+        while zeros >= 16 {
+            let (code, code_length) = ac[0xf0];
+            bits.put(code, code_length);
+            zeros -= 16;
+        }
+        let (value, length) = magnitude(coefficient);
+        let (code, code_length) = ac[(zeros << 4) | length as usize];
+        bits.put(code, code_length);
+        bits.put(value, length);
+        zeros = 0;
+    }
+    if zeros > 0 {
+        let (code, code_length) = ac[0x00];
+        bits.put(code, code_length);
+    }
+}
+
+fn segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+/// `pixels` (`width` x `height` gray levels, row by row) as a baseline JPEG.
+pub fn encode(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let table = quantization();
+    let mut out = vec![0xff, 0xd8];
+    segment(&mut out, 0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+
+    let mut dqt = vec![0];
+    dqt.extend(ZIGZAG.iter().map(|&index| table[index] as u8));
+    segment(&mut out, 0xdb, &dqt);
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.push(3);
+    for component in 1..=3 {
+        sof.extend_from_slice(&[component, 0x11, 0]);
+    }
+    segment(&mut out, 0xc0, &sof);
+
+    let mut dht = vec![0x00];
+    dht.extend_from_slice(&DC_COUNTS);
+    dht.extend_from_slice(&DC_VALUES);
+    dht.push(0x10);
+    dht.extend_from_slice(&AC_COUNTS);
+    dht.extend_from_slice(&AC_VALUES);
+    segment(&mut out, 0xc4, &dht);
+
+    segment(&mut out, 0xda, &[3, 1, 0x00, 2, 0x00, 3, 0x00, 0, 63, 0]);
+
+    let (dc, ac) = (codes(&DC_COUNTS, &DC_VALUES), codes(&AC_COUNTS, &AC_VALUES));
+    let huffman = (&dc, &ac);
+    let cosines: [[f32; 8]; 8] = std::array::from_fn(|u| {
+        std::array::from_fn(|x| ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos())
+    });
+    let mut bits = Bits { out: Vec::new(), buffer: 0, count: 0 };
+    let mut previous = [0; 3];
+    // Flat colour: every chroma block is all zero after the level shift
+    let flat = [0; 64];
+    for top in (0..height).step_by(8) {
+        for left in (0..width).step_by(8) {
+            // Edge pixels repeated to fill the blocks past the picture
+            let block: [f32; 64] = std::array::from_fn(|index| {
+                let y = (top + index / 8).min(height - 1);
+                let x = (left + index % 8).min(width - 1);
+                pixels[y * width + x] as f32 - 128.0
+            });
+            let luma = transform(&block, &table, &cosines);
+            encode_block(&mut bits, &luma, &mut previous[0], huffman);
+            encode_block(&mut bits, &flat, &mut previous[1], huffman);
+            encode_block(&mut bits, &flat, &mut previous[2], huffman);
+        }
+    }
+    out.extend(bits.finish());
+    out.extend_from_slice(&[0xff, 0xd9]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_codes() {
+        let dc = codes(&DC_COUNTS, &DC_VALUES);
+        assert_eq!(dc[0], (0b00, 2));
+        assert_eq!(dc[1], (0b010, 3));
+        assert_eq!(dc[11], (0b111111110, 9));
+        let ac = codes(&AC_COUNTS, &AC_VALUES);
+        assert_eq!(ac[0x00], (0b1010, 4));
+        assert_eq!(ac[0x01], (0b00, 2));
+        assert_eq!(ac[0xf0], (0b11111111001, 11));
+    }
+
+    #[test]
+    fn magnitudes() {
+        assert_eq!(magnitude(0), (0, 0));
+        assert_eq!(magnitude(1), (1, 1));
+        assert_eq!(magnitude(-1), (0, 1));
+        assert_eq!(magnitude(5), (0b101, 3));
+        assert_eq!(magnitude(-5), (0b010, 3));
+    }
+
+    #[test]
+    fn frame_is_well_formed() {
+        let pixels: Vec<u8> = (0..20 * 12).map(|i| if i % 7 < 3 { 255 } else { 0 }).collect();
+        let jpeg = encode(20, 12, &pixels);
+        assert_eq!(jpeg[..2], [0xff, 0xd8]);
+        assert_eq!(jpeg[jpeg.len() - 2..], [0xff, 0xd9]);
+        // Inside the scan, every 0xff is stuffed
+        let scan = jpeg.windows(2).position(|pair| pair == [0xff, 0xda]).unwrap();
+        let data = &jpeg[scan + 14..jpeg.len() - 2];
+        assert!(data.windows(2).all(|pair| pair[0] != 0xff || pair[1] == 0));
+    }
+}
@@ -0,0 +1,125 @@
+//! Latency budget, for "the dial feels laggy" reports: how long diald takes
+//! from reading the dial's events to having handed off everything they
+//! caused, and which stage that time went to: haptics writes, handing
+//! messages to the broker connection, or starting an exec hook.
+//!
+//! `DIALD_LATENCY_BUDGET` is the budget in milliseconds (off by default). When
+//! it is exceeded `STRIKES` times in a row, diald logs a warning naming the
+//! slowest stage and publishes it on `<prefix>/latency`, at most once a
+//! minute.
+
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Handlings over the budget in a row before it is worth a warning; one slow
+/// write now and then isn't.
+const STRIKES: u32 = 3;
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stage {
+    Haptics,
+    Broker,
+    Hook,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Haptics => "haptics",
+            Stage::Broker => "broker",
+            Stage::Hook => "exec hook",
+        }
+    }
+}
+
+pub struct Warning {
+    pub total: Duration,
+    /// The stage that took longest, or `None` when most of the time went to
+    /// diald's own handling.
+    pub stage: Option<(Stage, Duration)>,
+    pub budget: Duration,
+}
+
+impl Warning {
+    /// For the log, e.g. `84 ms from event to publish, 71 ms of it broker`.
+    pub fn describe(&self) -> String {
+        let stage = match self.stage {
+            Some((stage, took)) => format!("{} ms of it {}", took.as_millis(), stage.as_str()),
+            None => "most of it in diald itself".to_string(),
+        };
+        format!(
+            "{} ms from event to publish, {} ({} times over the {} ms budget)",
+            self.total.as_millis(),
+            stage,
+            STRIKES,
+            self.budget.as_millis()
+        )
+    }
+
+    /// The `<prefix>/latency` payload, e.g.
+    /// `{"total_ms":84,"stage":"broker","stage_ms":71,"budget_ms":50}`.
+    pub fn payload(&self) -> String {
+        let (stage, stage_ms) = match self.stage {
+            Some((stage, took)) => (format!("\"{}\"", stage.as_str()), took.as_millis()),
+            None => ("null".to_string(), 0),
+        };
+        format!(
+            "{{\"total_ms\":{},\"stage\":{},\"stage_ms\":{},\"budget_ms\":{}}}",
+            self.total.as_millis(),
+            stage,
+            stage_ms,
+            self.budget.as_millis()
+        )
+    }
+}
+
+pub struct LatencyBudget {
+    budget: Duration,
+    strikes: u32,
+    last_warning: Option<Instant>,
+}
+
+impl LatencyBudget {
+    /// `None` unless `DIALD_LATENCY_BUDGET` is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(ms) = config::var("DIALD_LATENCY_BUDGET") else {
+            return Ok(None);
+        };
+        let budget = ms
+            .parse()
+            .ok()
+            .filter(|ms| (1..=10_000).contains(ms))
+            .map(Duration::from_millis)
+            .ok_or_else(|| format!("DIALD_LATENCY_BUDGET: expected 1-10000 (ms), got '{}'", ms))?;
+        Ok(Some(Self { budget, strikes: 0, last_warning: None }))
+    }
+
+    /// Note one handling that took `total`, `stages` of it in the stages
+    /// named. Returns a warning once the budget has been exceeded often
+    /// enough.
+    pub fn record(
+        &mut self,
+        total: Duration,
+        stages: &[(Stage, Duration)],
+        now: Instant,
+    ) -> Option<Warning> {
+        if total <= self.budget {
+            self.strikes = 0;
+            return None;
+        }
+        self.strikes += 1;
+        if self.strikes < STRIKES
+            || self.last_warning.is_some_and(|t| now.duration_since(t) < WARNING_INTERVAL)
+        {
+            return None;
+        }
+        self.strikes = 0;
+        self.last_warning = Some(now);
+        let staged: Duration = stages.iter().map(|(_, took)| *took).sum();
+        let own = total.saturating_sub(staged);
+        let stage = stages.iter().copied().max_by_key(|(_, took)| *took).filter(|(_, t)| *t > own);
+        Some(Warning { total, stage, budget: self.budget })
+    }
+}
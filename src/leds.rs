@@ -0,0 +1,120 @@
+//! A status LED on a GPIO line, for headless boxes: solid while everything
+//! is up, a slow blink while the broker is unreachable, a double blink while
+//! the dial is missing.
+//!
+//! `DIALD_STATUS_LED` = `<chip>:<line>`, e.g. `gpiochip0:17`, with a `:low`
+//! suffix for LEDs wired active-low.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::gpio;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Status {
+    Starting,
+    Ok,
+    BrokerDown,
+    DeviceMissing,
+}
+
+impl Status {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Status::Ok,
+            2 => Status::BrokerDown,
+            3 => Status::DeviceMissing,
+            _ => Status::Starting,
+        }
+    }
+
+    /// `(on, off)` steps in milliseconds, repeated.
+    fn pattern(self) -> &'static [(u64, u64)] {
+        match self {
+            Status::Starting => &[(0, 1000)],
+            Status::Ok => &[(1000, 0)],
+            Status::BrokerDown => &[(500, 500)],
+            Status::DeviceMissing => &[(100, 100), (100, 700)],
+        }
+    }
+
+    /// Whether the LED is lit `elapsed` into the pattern.
+    fn lit_at(self, elapsed: Duration) -> bool {
+        let pattern = self.pattern();
+        let period: u64 = pattern.iter().map(|(on, off)| on + off).sum();
+        let mut at = elapsed.as_millis() as u64 % period;
+        for (on, off) in pattern {
+            if at < *on {
+                return true;
+            }
+            if at < on + off {
+                return false;
+            }
+            at -= on + off;
+        }
+        false
+    }
+}
+
+/// Handle to the LED thread. Setting the same status again is cheap.
+pub struct StatusLed {
+    status: Arc<AtomicU8>,
+}
+
+impl StatusLed {
+    /// `None` when no LED is configured or the line can't be claimed (which
+    /// is logged).
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(spec) = config::var("DIALD_STATUS_LED") else {
+            return Ok(None);
+        };
+        let (chip, line, flag) = gpio::parse_spec("DIALD_STATUS_LED", &spec)?;
+        let active_low = match flag {
+            None => false,
+            Some("low") => true,
+            Some(flag) => return Err(format!("DIALD_STATUS_LED: unknown flag '{}'", flag)),
+        };
+
+        let output = match gpio::Line::request(&chip, line, active_low) {
+            Ok(output) => output,
+            Err(err) => {
+                warn!("diald: can't claim {} line {} for the status LED ({})", chip, line, err);
+                return Ok(None);
+            }
+        };
+        let status = Arc::new(AtomicU8::new(Status::Starting as u8));
+        let shared = status.clone();
+        thread::spawn(move || blink(output, &shared));
+        Ok(Some(Self { status }))
+    }
+
+    pub fn set(&self, status: Status) {
+        self.status.store(status as u8, Ordering::Relaxed);
+    }
+}
+
+fn blink(output: gpio::Line, status: &AtomicU8) {
+    let mut current = Status::Starting;
+    let mut since = Instant::now();
+    let mut lit = None;
+    loop {
+        let wanted = Status::from_u8(status.load(Ordering::Relaxed));
+        if wanted != current {
+            current = wanted;
+            since = Instant::now();
+        }
+        let on = current.lit_at(since.elapsed());
+        if lit != Some(on) {
+            if let Err(err) = output.set(on) {
+                warn!("diald: status LED stopped ({})", err);
+                return;
+            }
+            lit = Some(on);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
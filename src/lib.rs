@@ -0,0 +1,140 @@
+//! The pieces of diald: the dial state machine and the inputs and outputs
+//! around it. The daemon's main loop lives in `main.rs`; keeping the rest in
+//! a library lets the fuzz targets in `fuzz/` drive it directly.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// How much diald logs; each level includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] =
+        [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn parse(name: &str) -> Option<Level> {
+        Self::ALL.into_iter().find(|level| level.as_str() == name)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static LOG_LEVEL_CHOSEN: AtomicBool = AtomicBool::new(false);
+/// Set while the host is too hot or busy (see `thermal.rs`): only warnings
+/// and errors get through.
+pub static LOGGING_THROTTLED: AtomicBool = AtomicBool::new(false);
+/// Set by `--dry-run`: the dial is handled as usual, but what would be
+/// written to it or published is logged instead.
+pub static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn log_level() -> Level {
+    Level::ALL[LOG_LEVEL.load(Ordering::Relaxed) as usize - 1]
+}
+
+/// Log at `level` from now on, e.g. from `--log-level` or the control socket.
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    LOG_LEVEL_CHOSEN.store(true, Ordering::Relaxed);
+}
+
+/// After a while diald goes quiet to spare the SD card, down to warnings,
+/// unless a level has been asked for.
+pub fn quiet_down() {
+    if !LOG_LEVEL_CHOSEN.load(Ordering::Relaxed) {
+        LOG_LEVEL.store(Level::Warn as u8, Ordering::Relaxed);
+    }
+}
+
+/// Whether a message at `level` is printed.
+pub fn log_enabled(level: Level) -> bool {
+    level <= log_level() && (level <= Level::Warn || !LOGGING_THROTTLED.load(Ordering::Relaxed))
+}
+
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log_enabled($level) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// What diald is doing, at `Level::Info`.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => { $crate::log_at!($crate::Level::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log_at!($crate::Level::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log_at!($crate::Level::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log_at!($crate::Level::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log_at!($crate::Level::Trace, $($arg)*) };
+}
+
+pub mod blueprint;
+pub mod bluez;
+pub mod calibrate;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod desktop;
+pub mod devices;
+pub mod dial;
+pub mod display;
+pub mod expr;
+pub mod focus;
+pub mod font;
+pub mod fsm;
+pub mod gestures;
+mod gpio;
+pub mod guest;
+pub mod haptics;
+pub mod hooks;
+pub mod hotplug;
+pub mod input;
+pub mod jpeg;
+pub mod latency;
+pub mod leds;
+pub mod memory;
+pub mod mqtt_test;
+pub mod payload;
+pub mod piezo;
+pub mod record;
+pub mod report;
+pub mod schedule;
+pub mod schema;
+pub mod setup;
+pub mod thermal;
+pub mod unit;
+pub mod update;
+pub mod wake;
+pub mod watch;
@@ -1,357 +1,799 @@
-use std::collections::VecDeque;
+use std::cell::Cell;
 use std::env;
-use std::fs::{self, File, OpenOptions};
-use std::io::{ErrorKind, Write};
-use std::os::unix::io::AsRawFd;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use evdev::{Device, InputEventKind, Key, RelativeAxisType};
-use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
-
-static LOGGING_ENABLED: AtomicBool = AtomicBool::new(true);
-
-macro_rules! log {
-    ($($arg:tt)*) => {
-        if LOGGING_ENABLED.load(Ordering::Relaxed) {
-            println!($($arg)*);
+use rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, QoS};
+
+use diald::blueprint;
+use diald::bluez::{DeviceStatus, LinkMonitor, StatusMonitor, WakeNudger};
+use diald::calibrate;
+use diald::config;
+use diald::control::{self, ControlRequest};
+use diald::daemon;
+use diald::desktop::DesktopOutput;
+use diald::devices;
+use diald::dial::{
+    self, Action, BacklashFilter, BacklashRelease, Curve, Dial, DialMode, Scale, Tool, VolumeModel,
+};
+use diald::display::StatusDisplay;
+use diald::focus::{self, AppTools};
+use diald::fsm;
+use diald::gestures::{Effect, Gesture, Gestures};
+use diald::guest::GuestMode;
+use diald::haptics::{self, HapticDevice, HapticState, Strength, Trigger, Triggers};
+use diald::hooks::{self, IdleHooks};
+use diald::hotplug::Hotplug;
+use diald::input::{self, InputEvent, InputSource};
+use diald::latency::{LatencyBudget, Stage};
+use diald::leds::{Status, StatusLed};
+use diald::memory::StateFile;
+use diald::mqtt_test;
+use diald::payload::{self, Command, Query, Request, Tuning};
+use diald::piezo::Piezo;
+use diald::record;
+use diald::report::{OutputReports, ReportEvent};
+use diald::schedule::{LocalTime, NightLimit};
+use diald::schema;
+use diald::setup;
+use diald::thermal::{self, Throttle};
+use diald::update::{self, UpdateCheck};
+use diald::unit;
+use diald::wake::{self, Sleeper};
+use diald::watch;
+use diald::{DRY_RUN, Level, LOGGING_THROTTLED, debug, error, log, trace, warn};
+
+/// Collect every `--device` argument, in order. The order is the priority order
+/// used for warm standby: earlier devices win over later ones when present.
+fn parse_device_args() -> Vec<PathBuf> {
+    let mut devices = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--device"
+            && let Some(path) = args.next()
+        {
+            devices.push(PathBuf::from(path));
         }
-    };
-}
-
-fn set_nonblock(device: &Device) -> std::io::Result<()> {
-    let fd = device.as_raw_fd();
-    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
-    if flags < 0 {
-        return Err(std::io::Error::last_os_error());
     }
-    let result = unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
-    if result < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-    Ok(())
+    devices
 }
 
-fn parse_device_arg() -> Option<PathBuf> {
+/// Value of a `--name value` style argument.
+fn arg_value(name: &str) -> Option<String> {
     let mut args = env::args().skip(1);
     while let Some(arg) = args.next() {
-        if arg == "--device" {
-            return args.next().map(PathBuf::from);
+        if arg == name {
+            return args.next();
         }
     }
     None
 }
 
-/// Find the hidraw device that shares the same HID parent as the given event device.
-fn find_hidraw_for_event_device(event_path: &Path) -> Option<String> {
-    // /dev/input/event2 -> event2
-    let event_name = event_path.file_name()?;
-    // /sys/class/input/event2/device -> canonical path to input device
-    let event_sysfs = PathBuf::from("/sys/class/input").join(event_name);
-    let event_device_path = fs::canonicalize(event_sysfs.join("device")).ok()?;
+fn has_flag(name: &str) -> bool {
+    env::args().skip(1).any(|arg| arg == name)
+}
 
-    // Check each hidraw to see if it's an ancestor of our event device
-    let hidraw_dir = fs::read_dir("/sys/class/hidraw").ok()?;
-    for entry in hidraw_dir.flatten() {
-        let hidraw_device_link = entry.path().join("device");
-        if let Ok(hidraw_device_path) = fs::canonicalize(&hidraw_device_link) {
-            // The hidraw's device should be an ancestor of the event's device
-            if event_device_path.starts_with(&hidraw_device_path) {
-                let name = entry.file_name();
-                return Some(format!("/dev/{}", name.to_string_lossy()));
-            }
-        }
-    }
-    None
+/// `DIALD_DEVICE` accepts a comma-separated list of candidate devices.
+fn parse_device_env() -> Vec<PathBuf> {
+    config::var("DIALD_DEVICE")
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
-struct HapticDevice {
-    file: Option<File>,
-    last_retry: Option<Instant>,
-    event_path: PathBuf,
+fn volume_model() -> Result<VolumeModel, String> {
+    Ok(VolumeModel { scale: volume_scale()?, curve: volume_curve()? })
 }
 
-impl HapticDevice {
-    fn new(event_path: PathBuf) -> Self {
-        let file = Self::try_open(&event_path);
-        Self { file, last_retry: None, event_path }
+/// `DIALD_CURVE`: `linear`, `log`, or percentages of the range at evenly
+/// spaced points of the dial's travel, e.g. `0,5,15,40,100`.
+fn volume_curve() -> Result<Curve, String> {
+    let curve = match config::var("DIALD_CURVE") {
+        Ok(curve) => curve,
+        Err(_) => return Ok(Curve::Linear),
+    };
+    match curve.trim() {
+        "linear" => return Ok(Curve::Linear),
+        "log" => return Ok(Curve::Log),
+        _ => {}
     }
-
-    fn try_open(event_path: &Path) -> Option<File> {
-        let path = env::var("DIALD_HAPTIC_DEV")
-            .ok()
-            .or_else(|| find_hidraw_for_event_device(event_path))?;
-
-        match OpenOptions::new().write(true).open(&path) {
-            Ok(file) => {
-                log!("diald: opened haptics {}", path);
-                Some(file)
-            }
-            Err(err) => {
-                log!("diald: failed to open haptics {} ({})", path, err);
-                None
-            }
+    let points: Option<Vec<f64>> = curve
+        .split(',')
+        .map(|point| point.trim().parse().ok().filter(|p| (0.0..=100.0).contains(p)))
+        .collect();
+    match points {
+        Some(points)
+            if points.len() >= 2 && points.windows(2).all(|pair| pair[0] <= pair[1]) =>
+        {
+            Ok(Curve::Table(points.iter().map(|point| point / 100.0).collect()))
         }
+        _ => Err(format!(
+            "DIALD_CURVE: expected linear, log or rising percentages like 0,5,15,40,100, got '{}'",
+            curve
+        )),
     }
+}
 
-    fn reconnect(&mut self) {
-        self.file = Self::try_open(&self.event_path);
-        self.last_retry = None;
-    }
-
-    fn try_reconnect_if_needed(&mut self) {
-        if self.file.is_some() {
-            return;
-        }
-        let now = Instant::now();
-        if let Some(last) = self.last_retry {
-            if now.duration_since(last) < Duration::from_secs(1) {
-                return;
-            }
+fn volume_scale() -> Result<Scale, String> {
+    let steps = match config::var("DIALD_VOLUME_STEPS") {
+        Ok(steps) => Some(
+            steps
+                .parse::<i32>()
+                .ok()
+                .filter(|steps| (2..=1000).contains(steps))
+                .ok_or_else(|| format!("DIALD_VOLUME_STEPS: expected 2-1000, got '{}'", steps))?,
+        ),
+        Err(_) => None,
+    };
+    let Ok(range) = config::var("DIALD_RANGE") else {
+        if config::var("DIALD_RANGE_STEP").is_ok() {
+            return Err("DIALD_RANGE_STEP: needs DIALD_RANGE".to_string());
         }
-        self.last_retry = Some(now);
-        self.file = Self::try_open(&self.event_path);
+        return Ok(steps.map_or(Scale::Continuous, Scale::Stepped));
+    };
+    let ends: Vec<f64> = range.split(',').filter_map(|end| end.trim().parse().ok()).collect();
+    let [min, max] = ends[..] else {
+        return Err(format!("DIALD_RANGE: expected min,max like -60,0, got '{}'", range));
+    };
+    if !(min.is_finite() && max.is_finite() && min < max) {
+        return Err(format!("DIALD_RANGE: expected min below max, got '{}'", range));
     }
-
-    fn send_chunky(&mut self) {
-        let Some(file) = self.file.as_mut() else {
-            return;
-        };
-        // Report ID 1 output: repeat=2, manual=3, retrigger=70 (chunky)
-        let payload = [1u8, 2u8, 3u8, 70u8, 0u8];
-        if let Err(err) = file.write_all(&payload) {
-            log!("diald: haptics write failed ({})", err);
-            self.file = None;
+    let step = match (config::var("DIALD_RANGE_STEP"), steps) {
+        (Ok(_), Some(_)) => {
+            return Err("DIALD_RANGE_STEP and DIALD_VOLUME_STEPS: set one or the other".into());
         }
+        (Ok(step), None) => {
+            step.parse().ok().filter(|step: &f64| *step > 0.0 && step.is_finite()).ok_or_else(
+                || format!("DIALD_RANGE_STEP: expected a positive number, got '{}'", step),
+            )?
+        }
+        (Err(_), Some(steps)) => (max - min) / steps as f64,
+        (Err(_), None) => 1.0,
+    };
+    let scale = Scale::Scaled { min, max, step };
+    let model = VolumeModel::from(scale);
+    if !(2..=1000).contains(&model.range()) {
+        return Err(format!(
+            "DIALD_RANGE: {} steps of {} from {} to {}, expected 2-1000",
+            model.range(),
+            step,
+            min,
+            max
+        ));
     }
+    Ok(scale)
 }
 
-#[derive(PartialEq, Clone, Copy)]
-enum DialMode {
-    Idle,
-    Active,
-    Backlash,
+/// `--log-level`, else `DIALD_LOG_LEVEL`.
+fn log_level() -> Result<Option<Level>, String> {
+    match arg_value("--log-level").or_else(|| config::var("DIALD_LOG_LEVEL").ok()) {
+        Some(name) => Level::parse(&name).map(Some).ok_or_else(|| {
+            format!("DIALD_LOG_LEVEL: expected error, warn, info, debug or trace, got '{}'", name)
+        }),
+        None => Ok(None),
+    }
 }
 
-impl DialMode {
-    fn as_str(&self) -> &'static str {
-        match self {
-            DialMode::Idle => "idle",
-            DialMode::Active => "active",
-            DialMode::Backlash => "backlash",
-        }
+/// `--wait-for-device`, else `DIALD_WAIT_FOR_DEVICE`: how long to wait for a
+/// device at startup before giving up; `None` waits forever.
+fn wait_for_device() -> Result<Option<Duration>, String> {
+    match arg_value("--wait-for-device").or_else(|| config::var("DIALD_WAIT_FOR_DEVICE").ok()) {
+        Some(wait) if wait == "forever" => Ok(None),
+        Some(wait) => payload::parse_duration(&wait).map(Some).ok_or_else(|| {
+            format!("DIALD_WAIT_FOR_DEVICE: expected e.g. 30s, 5m or forever, got '{}'", wait)
+        }),
+        None => Ok(None),
     }
 }
 
-/// Delay buffer for backlash compensation.
-/// Events are held for `lookahead` events before being released, giving us time
-/// to detect direction changes before committing potentially-spurious events.
-struct DelayBuffer {
-    events: VecDeque<i32>,
-    lookahead: usize,
+/// `--exit-when-idle`, else `DIALD_EXIT_WHEN_IDLE`: how long no dial may be
+/// touched before diald exits.
+fn exit_when_idle() -> Result<Option<Duration>, String> {
+    match arg_value("--exit-when-idle").or_else(|| config::var("DIALD_EXIT_WHEN_IDLE").ok()) {
+        Some(after) => payload::parse_duration(&after)
+            .filter(|after| *after >= Duration::from_secs(1))
+            .map(Some)
+            .ok_or_else(|| {
+                format!("DIALD_EXIT_WHEN_IDLE: expected e.g. 90s or 30m, got '{}'", after)
+            }),
+        None => Ok(None),
+    }
 }
 
-impl DelayBuffer {
-    fn new(lookahead: usize) -> Self {
-        Self {
-            events: VecDeque::with_capacity(lookahead + 1),
-            lookahead,
+fn default_tool() -> Result<Tool, String> {
+    match config::var("DIALD_TOOL") {
+        Ok(name) => {
+            Tool::parse(&name).ok_or_else(|| format!("DIALD_TOOL: unknown tool '{}'", name))
         }
+        Err(_) => Ok(Tool::Volume),
     }
+}
 
-    /// Push an event. Returns the oldest event if buffer exceeds lookahead size.
-    fn push(&mut self, value: i32) -> Option<i32> {
-        self.events.push_back(value);
-        if self.events.len() > self.lookahead {
-            self.events.pop_front()
-        } else {
-            None
+/// How often turning the dial while the broker is down plays the error buzz.
+const OFFLINE_BUZZ_INTERVAL: Duration = Duration::from_secs(10);
+
+/// While the host is throttled: how often intermediate volumes are published
+/// and how long the loop sleeps when there's nothing to read.
+const THROTTLED_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+const THROTTLED_POLL_INTERVAL: Duration = Duration::from_millis(30);
+
+/// The longest the loop sleeps while the dial is idle and nothing wakes it;
+/// the rest of the loop's duties are checked that often.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How often a missing device is tried again, and how often the loop looks
+/// after everything else meanwhile.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a missing device is tried again when hotplug events say when
+/// one is added.
+const HOTPLUG_RETRY: Duration = Duration::from_secs(30);
+
+/// How long startup waits for the broker to hand over the retained volume.
+const SEED_WAIT: Duration = Duration::from_secs(1);
+
+/// Below this the dial's Bluetooth link is logged as weak.
+const WEAK_RSSI: i16 = -80;
+
+type OpenErrors<'a> = Vec<(&'a Path, std::io::Error)>;
+
+/// Open the highest-priority candidate that is currently present, with the
+/// path it was found at (`auto` stands for the dial it found).
+fn open_first_available(
+    paths: &[PathBuf],
+) -> Result<(usize, PathBuf, Box<dyn InputSource>), OpenErrors<'_>> {
+    let mut errors = Vec::new();
+    for (index, path) in paths.iter().enumerate() {
+        match input::resolve(path).and_then(|found| Ok((input::open(&found)?, found))) {
+            Ok((device, found)) => return Ok((index, found, device)),
+            Err(err) => errors.push((path.as_path(), err)),
         }
     }
+    Err(errors)
+}
+
+/// Groups clicks into bursts: a burst ends once no click has come for
+/// `window`, so a double or triple click is always counted as one. It also
+/// ends as soon as it reaches `max` clicks; a zero window sends every click
+/// on its own.
+struct ClickBurst {
+    count: u32,
+    deadline: Option<Instant>,
+    window: Duration,
+    max: Option<u32>,
+}
 
-    /// Drain buffer, keeping only events matching the given direction.
-    /// Returns the sum of matching events. Used when exiting confirmed backlash.
-    fn drain_matching(&mut self, direction: i32) -> i32 {
-        let sum = self.events.iter()
-            .filter(|v| v.signum() == direction)
-            .sum();
-        self.events.clear();
-        sum
+impl ClickBurst {
+    fn new(window: Duration, max: Option<u32>) -> Self {
+        Self {
+            count: 0,
+            deadline: None,
+            window,
+            max,
+        }
     }
 
-    /// Drain buffer, returning sum of all events.
-    /// Used when cancelling false-positive backlash.
-    fn drain_all(&mut self) -> i32 {
-        let sum = self.events.iter().sum();
-        self.events.clear();
-        sum
+    fn push(&mut self, now: Instant) {
+        self.count += 1;
+        let full = self.max.is_some_and(|max| self.count >= max);
+        self.deadline = Some(if full { now } else { now + self.window });
     }
 
-    fn clear(&mut self) {
-        self.events.clear();
+    /// The click count once the burst is over.
+    fn try_finish(&mut self, now: Instant) -> Option<u32> {
+        if now < self.deadline? {
+            return None;
+        }
+        self.deadline = None;
+        Some(std::mem::take(&mut self.count))
     }
 }
 
-struct DialState {
-    mode: DialMode,
-    last_event_at: Option<Instant>,
-    volume: f64,
-    raw_accumulator: i32,
-    last_print_at: Option<Instant>,
-    last_printed_volume: i32,
-    clicking: bool,
-    last_raw_direction: i32,         // -1, 0, or 1
-    consistent_direction_count: u32, // consecutive events in same direction
-    pre_backlash_direction: i32,     // direction before entering backlash
+/// The volume sinks that get every change as it happens, for machines (a
+/// fader bridge) rather than people, instead of the throttled values.
+#[derive(Clone, Copy, Default)]
+struct FastSinks {
+    /// `<prefix>/volume/fast`, next to the throttled `<prefix>/volume`.
+    mqtt: bool,
+    /// The output reports sent for the volume.
+    reports: bool,
 }
 
-const BACKLASH_THRESHOLD: usize = 50; // events needed to exit backlash mode (also delay buffer size)
-const BACKLASH_CANCEL_THRESHOLD: u32 = (BACKLASH_THRESHOLD / 5) as u32; // events to cancel false-positive backlash
-
-impl DialState {
-    fn new() -> Self {
-        Self {
-            mode: DialMode::Idle,
-            last_event_at: None,
-            volume: 50.0,
-            raw_accumulator: 0,
-            last_print_at: None,
-            last_printed_volume: 50,
-            clicking: false,
-            last_raw_direction: 0,
-            consistent_direction_count: 0,
-            pre_backlash_direction: 0,
+impl FastSinks {
+    /// `DIALD_FAST`: a comma-separated list of `mqtt` and `reports`.
+    fn from_env() -> Result<Self, String> {
+        let mut sinks = FastSinks::default();
+        let Ok(spec) = config::var("DIALD_FAST") else {
+            return Ok(sinks);
+        };
+        for sink in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match sink {
+                "mqtt" => sinks.mqtt = true,
+                "reports" => sinks.reports = true,
+                _ => return Err(format!("DIALD_FAST: expected mqtt or reports, got '{}'", sink)),
+            }
         }
+        Ok(sinks)
     }
+}
 
-    fn set_mode(&mut self, mode: DialMode) {
-        if self.mode != mode {
-            log!("diald: state -> {}", mode.as_str());
-            self.mode = mode;
-        }
+/// Hand the volume to the fast sinks each time it differs from `published`.
+fn publish_fast(
+    fast: FastSinks,
+    published: &mut i32,
+    dial: &Dial,
+    reports: &OutputReports,
+    haptic: &mut HapticDevice,
+    mqtt: &Option<MqttHandle>,
+) {
+    if dial.volume() == *published {
+        return;
     }
-
-    fn reset_to_idle(&mut self) {
-        self.set_mode(DialMode::Idle);
-        self.raw_accumulator = 0;
-        self.last_raw_direction = 0;
-        self.consistent_direction_count = 0;
-        self.pre_backlash_direction = 0;
+    *published = dial.volume();
+    if fast.mqtt
+        && let Some(handle) = mqtt
+    {
+        handle.publish("volume/fast", false, dial.model().format(*published));
+    }
+    if fast.reports {
+        let value = *published as i64;
+        for report in reports.render(ReportEvent::Volume, value, value) {
+            haptic.send_report(&report);
+        }
     }
 }
 
-struct EventBatcher {
-    events: Vec<&'static str>,
-    deadline: Option<Instant>,
-    window: Duration,
+fn emit_clicks(
+    count: u32,
+    mqtt: &Option<MqttHandle>,
+    haptic: &mut HapticDevice,
+    reports: &OutputReports,
+    volume: i32,
+) {
+    debug!("diald: click count={}", count);
+    for report in reports.render(ReportEvent::Click, count as i64, volume as i64) {
+        haptic.send_report(&report);
+    }
+    if let Some(handle) = mqtt {
+        handle.publish("clicks", false, count.to_string());
+        // The original topic, kept for existing automations
+        handle.publish("click", false, count.to_string());
+    }
 }
 
-impl EventBatcher {
-    fn new(window: Duration) -> Self {
-        Self {
-            events: Vec::new(),
-            deadline: None,
-            window,
+/// Carry out what a `DIALD_GESTURES` rule adds to a click or long press.
+fn apply_gesture(
+    effects: &[Effect],
+    dial: &mut Dial,
+    haptic: &mut HapticDevice,
+    mqtt: &Option<MqttHandle>,
+) {
+    for effect in effects {
+        match effect {
+            Effect::Tool(tool) => {
+                log!("diald: tool -> {}", tool.as_str());
+                dial.set_tool(*tool);
+            }
+            Effect::Publish { topic, payload } => {
+                if let Some(handle) = mqtt {
+                    handle.publish(topic, false, payload.as_str());
+                }
+            }
+            Effect::Buzz(trigger) => haptic.buzz(*trigger),
         }
     }
+}
 
-    fn push(&mut self, event: &'static str) {
-        if self.deadline.is_none() {
-            self.deadline = Some(Instant::now() + self.window);
-        }
-        self.events.push(event);
+/// The settings a SIGHUP re-reads. All of them are read before any is
+/// applied, so a broken config leaves the running ones alone.
+struct Settings {
+    triggers: Triggers,
+    strength: Option<Strength>,
+    counts_per_revolution: i32,
+    counts_per_step: Option<i32>,
+    invert_direction: bool,
+    active_publish: bool,
+    idle_timeout: Duration,
+    click_window: Duration,
+    click_max: Option<u32>,
+    backlash_threshold: u32,
+    backlash_cancel_threshold: u32,
+    backlash_filter: BacklashFilter,
+    backlash_time: Option<Duration>,
+    backlash_release: BacklashRelease,
+    press_turn: bool,
+    fast: FastSinks,
+    reports: OutputReports,
+    night_limit: Option<NightLimit>,
+    guest: GuestMode,
+    idle_hooks: IdleHooks,
+    throttle: Throttle,
+    restart_token: Option<String>,
+    gestures: Gestures,
+    latency: Option<LatencyBudget>,
+    log_level: Option<Level>,
+    exit_when_idle: Option<Duration>,
+    /// Exit when the device goes away instead of waiting for it, for a unit
+    /// bound to the device (`diald systemd-unit`).
+    exit_with_device: bool,
+    /// How long to wait for a device at startup; `None` for forever.
+    wait_for_device: Option<Duration>,
+}
+
+impl Settings {
+    fn from_env() -> Result<Self, String> {
+        // Only checked here: a typo should fail the start or the reload rather
+        // than quietly keep the old topics
+        payload::layout()?;
+        let idle_timeout = match config::var("DIALD_IDLE_TIMEOUT") {
+            Ok(timeout) => payload::parse_duration(&timeout)
+                .filter(|timeout| *timeout >= Duration::from_secs(1))
+                .ok_or_else(|| {
+                    format!("DIALD_IDLE_TIMEOUT: expected e.g. 30s or 2m, got '{}'", timeout)
+                })?,
+            Err(_) => dial::IDLE_TIMEOUT,
+        };
+        // 0 turns batching off
+        let click_window = match config::var("DIALD_CLICK_WINDOW") {
+            Ok(ms) => ms
+                .parse()
+                .ok()
+                .filter(|ms| *ms == 0 || (50..=2000).contains(ms))
+                .map(Duration::from_millis)
+                .ok_or_else(|| {
+                    format!("DIALD_CLICK_WINDOW: expected 0 or 50-2000 (ms), got '{}'", ms)
+                })?,
+            Err(_) => Duration::from_millis(300),
+        };
+        let click_max = match config::var("DIALD_CLICK_MAX") {
+            Ok(clicks) => Some(
+                clicks.parse().ok().filter(|clicks| (1..=20).contains(clicks)).ok_or_else(
+                    || format!("DIALD_CLICK_MAX: expected 1-20 (clicks), got '{}'", clicks),
+                )?,
+            ),
+            Err(_) => None,
+        };
+        let counts_per_step = match config::var("DIALD_COUNTS_PER_STEP") {
+            Ok(counts) => Some(
+                counts.parse().ok().filter(|counts| (1..=10_000).contains(counts)).ok_or_else(
+                    || format!("DIALD_COUNTS_PER_STEP: expected 1-10000, got '{}'", counts),
+                )?,
+            ),
+            Err(_) => None,
+        };
+        // Event counts; the flags win over the environment and the file
+        let events = |flag: &str, name: &str, max: u32| match arg_value(flag)
+            .or_else(|| config::var(name).ok())
+        {
+            Some(events) => events
+                .parse()
+                .ok()
+                .filter(|events| (1..=max).contains(events))
+                .map(Some)
+                .ok_or_else(|| format!("{}: expected 1-{} (events), got '{}'", name, max, events)),
+            None => Ok(None),
+        };
+        let backlash_threshold =
+            events("--backlash-threshold", "DIALD_BACKLASH_THRESHOLD", 1000)?
+                .unwrap_or(dial::BACKLASH_THRESHOLD);
+        let backlash_cancel_threshold =
+            events("--backlash-cancel", "DIALD_BACKLASH_CANCEL", backlash_threshold)?
+                .unwrap_or((backlash_threshold / 5).max(1));
+        let hysteresis = match config::var("DIALD_BACKLASH_VELOCITY") {
+            Ok(velocity) => velocity
+                .parse()
+                .ok()
+                .filter(|velocity: &f64| *velocity > 0.0 && velocity.is_finite())
+                .ok_or_else(|| {
+                    format!("DIALD_BACKLASH_VELOCITY: expected counts/s, got '{}'", velocity)
+                })?,
+            Err(_) => dial::VELOCITY_HYSTERESIS,
+        };
+        // 0 confirms reversals by event count only
+        let backlash_time = match config::var("DIALD_BACKLASH_TIME") {
+            Ok(ms) => ms
+                .parse()
+                .ok()
+                .filter(|ms| *ms <= 5000)
+                .map(|ms| Some(Duration::from_millis(ms)).filter(|time| !time.is_zero()))
+                .ok_or_else(|| {
+                    format!("DIALD_BACKLASH_TIME: expected 0-5000 (ms), got '{}'", ms)
+                })?,
+            Err(_) => Some(dial::BACKLASH_TIME),
+        };
+        let deadband = match config::var("DIALD_BACKLASH_DEADBAND") {
+            Ok(counts) => counts.parse().ok().filter(|counts| *counts <= 10_000).ok_or_else(
+                || format!("DIALD_BACKLASH_DEADBAND: expected 0-10000 (counts), got '{}'", counts),
+            )?,
+            Err(_) => dial::BACKLASH_DEADBAND,
+        };
+        let backlash_filter = match config::var("DIALD_BACKLASH_FILTER").as_deref() {
+            Ok("threshold") | Err(_) => BacklashFilter::Threshold,
+            Ok("velocity") => BacklashFilter::Velocity(hysteresis),
+            Ok("deadband") => BacklashFilter::Deadband(deadband),
+            Ok(filter) => {
+                return Err(format!(
+                    "DIALD_BACKLASH_FILTER: expected threshold, velocity or deadband, got '{}'",
+                    filter
+                ));
+            }
+        };
+        let backlash_release = match config::var("DIALD_BACKLASH_RELEASE").as_deref() {
+            Ok("apply") | Err(_) => BacklashRelease::Apply,
+            Ok("halve") => BacklashRelease::Halve,
+            Ok("discard") => BacklashRelease::Discard,
+            Ok(release) => {
+                return Err(format!(
+                    "DIALD_BACKLASH_RELEASE: expected apply, halve or discard, got '{}'",
+                    release
+                ));
+            }
+        };
+        Ok(Self {
+            triggers: Triggers::from_env()?,
+            strength: Strength::from_env()?,
+            counts_per_revolution: input::counts_per_revolution()?,
+            counts_per_step,
+            invert_direction: config::flag("DIALD_INVERT_DIRECTION", false)?,
+            active_publish: config::flag("DIALD_ACTIVE_PUBLISH", false)?,
+            idle_timeout,
+            click_window,
+            click_max,
+            backlash_threshold,
+            backlash_cancel_threshold,
+            backlash_filter,
+            backlash_time,
+            backlash_release,
+            press_turn: config::flag("DIALD_PRESS_TURN", false)?,
+            fast: FastSinks::from_env()?,
+            reports: OutputReports::from_env()?,
+            night_limit: NightLimit::from_env()?,
+            guest: GuestMode::from_env()?,
+            idle_hooks: IdleHooks::from_env()?,
+            throttle: Throttle::from_env()?,
+            restart_token: config::var("DIALD_RESTART_TOKEN")
+                .or_else(|_| config::var("DIALD_COMMAND_TOKEN"))
+                .ok()
+                .filter(|token| !token.is_empty()),
+            gestures: Gestures::from_env()?,
+            latency: LatencyBudget::from_env()?,
+            log_level: log_level()?,
+            exit_when_idle: exit_when_idle()?,
+            exit_with_device: has_flag("--exit-with-device")
+                || config::flag("DIALD_EXIT_WITH_DEVICE", false)?,
+            wait_for_device: wait_for_device()?,
+        })
     }
+}
 
-    fn try_flush(&mut self) -> Option<Vec<&'static str>> {
-        let deadline = self.deadline?;
-        if Instant::now() < deadline {
-            return None;
+/// Apply the settings that shape how the dial turns events into actions.
+fn configure_dial(dial: &mut Dial, settings: &Settings) {
+    dial.set_publish_on_wake(settings.active_publish);
+    dial.set_idle_timeout(settings.idle_timeout);
+    dial.set_counts_per_step(settings.counts_per_step);
+    dial.set_inverted(settings.invert_direction);
+    dial.set_backlash_thresholds(settings.backlash_threshold, settings.backlash_cancel_threshold);
+    dial.set_backlash_filter(settings.backlash_filter);
+    dial.set_backlash_time(settings.backlash_time);
+    dial.set_backlash_release(settings.backlash_release);
+    dial.set_press_turn(settings.press_turn);
+}
+
+/// What the MQTT connection is made with, and the token its protected topics
+/// need; a reload only reconnects when this changes.
+#[derive(PartialEq)]
+struct BrokerSettings {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    prefix: String,
+    command_prefix: String,
+    command_token: Option<String>,
+}
+
+impl BrokerSettings {
+    fn from_env() -> Self {
+        Self {
+            host: config::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            port: config::var("MQTT_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(1883),
+            username: config::var("MQTT_USERNAME").ok(),
+            password: config::var("MQTT_PASSWORD").ok(),
+            prefix: payload::prefix(),
+            command_prefix: payload::command_prefix(&payload::prefix()),
+            command_token: config::var("DIALD_COMMAND_TOKEN").ok().filter(|t| !t.is_empty()),
         }
-        self.deadline = None;
-        Some(std::mem::take(&mut self.events))
     }
 }
 
-fn emit_batch(events: Vec<&'static str>, mqtt: &Option<MqttHandle>) {
-    // Count occurrences of each event type
-    let mut counts: Vec<(&'static str, u32)> = Vec::new();
-    for event in events {
-        if let Some((_, count)) = counts.iter_mut().find(|(e, _)| *e == event) {
-            *count += 1;
-        } else {
-            counts.push((event, 1));
-        }
+struct MqttHandle {
+    client: Client,
+    incoming_rx: Receiver<Command>,
+    /// The volume retained on `<prefix>/volume`, until it has been taken.
+    seed_rx: Option<Receiver<f64>>,
+    prefix: String,
+    connected: Arc<AtomicBool>,
+    broker: BrokerSettings,
+    /// Time spent handing messages to the connection, for the latency budget.
+    publish_time: Cell<Duration>,
+}
+
+impl MqttHandle {
+    /// Whether the broker has accepted us and the connection hasn't failed
+    /// since.
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
     }
-    for (event, count) in &counts {
-        log!("diald: {} count={}", event, count);
+
+    /// Publish on `<prefix>/<name>`, or under `<prefix>/monitor/` for a
+    /// monitor instance so it never shadows the real one.
+    fn publish(&self, name: &str, retain: bool, payload: impl Into<Vec<u8>>) {
+        self.publish_to(format!("{}/{}", self.prefix, name), retain, payload);
     }
 
-    // Publish clicks to MQTT
-    if let Some(handle) = mqtt {
-        for (event, count) in counts {
-            if event == "click" {
-                let _ = handle.client.publish(
-                    "home/diald/click",
-                    QoS::AtLeastOnce,
-                    false,
-                    count.to_string(),
-                );
-            }
+    /// Publish on `topic` as is. Blocks while the connection is behind on
+    /// sending.
+    fn publish_to(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            let payload = payload.into();
+            let payload = String::from_utf8_lossy(&payload);
+            let retained = if retain { " (retained)" } else { "" };
+            log!("diald: would publish {}{}: {}", topic, retained, payload);
+            return;
         }
+        let started = Instant::now();
+        let _ = self.client.publish(topic, QoS::AtLeastOnce, retain, payload);
+        self.publish_time.set(self.publish_time.get() + started.elapsed());
     }
 }
 
-struct MqttHandle {
-    client: Client,
-    incoming_rx: Receiver<i32>,
+fn publish_time(mqtt: &Option<MqttHandle>) -> Duration {
+    mqtt.as_ref().map_or(Duration::ZERO, |handle| handle.publish_time.get())
 }
 
-fn spawn_mqtt() -> Option<MqttHandle> {
-    let host = env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let port: u16 = env::var("MQTT_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(1883);
-    let username = env::var("MQTT_USERNAME").ok();
-    let password = env::var("MQTT_PASSWORD").ok();
+/// Note how long handling something took against the latency budget, and
+/// warn once it is over it too often.
+fn check_latency(
+    latency: &mut Option<LatencyBudget>,
+    took: Duration,
+    stages: &[(Stage, Duration)],
+    mqtt: &Option<MqttHandle>,
+) {
+    let Some(warning) = latency.as_mut().and_then(|l| l.record(took, stages, Instant::now()))
+    else {
+        return;
+    };
+    warn!("diald: slow: {}", warning.describe());
+    if let Some(handle) = mqtt {
+        handle.publish("latency", false, warning.payload());
+    }
+}
 
-    let mut opts = MqttOptions::new("diald", &host, port);
+/// A monitor instance connects under its own client ID (a second "diald"
+/// would kick the real one off the broker) and subscribes to nothing. So
+/// does a dry run, which then publishes nothing either. With `seed`, the
+/// volume retained on the broker is offered on `seed_rx`, for `seed_volume`.
+/// `waker` is woken for each command.
+fn spawn_mqtt(monitor: bool, seed: bool, waker: wake::Waker) -> Option<MqttHandle> {
+    let broker = BrokerSettings::from_env();
+    let (host, port) = (broker.host.clone(), broker.port);
+
+    let dry_run = DRY_RUN.load(Ordering::Relaxed);
+    let client_id = match (dry_run, monitor) {
+        (true, _) => "diald-dry-run",
+        (false, true) => "diald-monitor",
+        (false, false) => "diald",
+    };
+    // Dials sharing a broker each need an ID of their own
+    let client_id = match config::dial_name() {
+        Some(name) => format!("{}-{}", client_id, name),
+        None => client_id.to_string(),
+    };
+    let mut opts = MqttOptions::new(client_id, &host, port);
     opts.set_keep_alive(Duration::from_secs(30));
 
-    if let (Some(user), Some(pass)) = (&username, &password) {
+    if let (Some(user), Some(pass)) = (&broker.username, &broker.password) {
         opts.set_credentials(user, pass);
     }
 
-    let (client, mut connection) = Client::new(opts, 10);
-
-    if let Err(err) = client.subscribe("home/diald/volume/set", QoS::AtLeastOnce) {
-        log!("diald: mqtt subscribe failed ({})", err);
+    // Every subscription is queued before the connection thread starts
+    // draining, so they must all fit
+    let (client, mut connection) = Client::new(opts, payload::SUBSCRIBED.len() + 10);
+
+    let mut prefix = broker.prefix.clone();
+    let seed = seed && !monitor;
+    let seed_topic = format!("{}/volume", broker.prefix);
+    // First, so its retained message has come by the time the last
+    // subscription is acknowledged
+    if seed && let Err(err) = client.subscribe(&seed_topic, QoS::AtLeastOnce) {
+        error!("diald: mqtt subscribe failed ({})", err);
         return None;
     }
+    if !monitor {
+        for name in payload::SUBSCRIBED {
+            let topic = format!("{}/{}", broker.command_prefix, name);
+            if let Err(err) = client.subscribe(topic, QoS::AtLeastOnce) {
+                error!("diald: mqtt subscribe failed ({})", err);
+                return None;
+            }
+        }
+    }
 
-    let (tx, rx): (Sender<i32>, Receiver<i32>) = mpsc::channel();
+    let (tx, rx): (Sender<Command>, Receiver<Command>) = mpsc::channel();
+    let (seed_tx, seed_rx) = mpsc::channel();
+    let mut seed_tx = seed.then_some(seed_tx);
+    let subscriptions = payload::SUBSCRIBED.len() + 1;
+    let mut acknowledged = 0;
+    let connected = Arc::new(AtomicBool::new(false));
 
+    let connected_flag = connected.clone();
+    let subscribed_prefix = format!("{}/", broker.command_prefix);
+    let command_token = broker.command_token.clone();
     thread::spawn(move || {
         let mut last_error_log: Option<Instant> = None;
         for event in connection.iter() {
             match event {
+                // Only what the broker kept; our own publishes come back too
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == seed_topic => {
+                    if let Some(tx) = &seed_tx
+                        && publish.retain
+                        && let Some(volume) = std::str::from_utf8(&publish.payload)
+                            .ok()
+                            .and_then(payload::parse_number)
+                    {
+                        let _ = tx.send(volume);
+                    }
+                }
+                // Nothing retained then; closing the channel says so
+                Ok(Event::Incoming(Packet::SubAck(_))) => {
+                    acknowledged += 1;
+                    if acknowledged >= subscriptions {
+                        seed_tx = None;
+                    }
+                }
                 Ok(Event::Incoming(Packet::Publish(publish))) => {
-                    if let Ok(payload) = std::str::from_utf8(&publish.payload) {
-                        if let Ok(volume) = payload.trim().parse::<i32>() {
-                            let _ = tx.send(volume);
-                        }
+                    let Some(name) = publish.topic.strip_prefix(&subscribed_prefix) else {
+                        continue;
+                    };
+                    if let Some(expected) = &command_token
+                        && payload::is_protected(name)
+                        && !payload::token(&publish.payload)
+                            .is_some_and(|token| payload::token_matches(expected, token))
+                    {
+                        warn!("diald: mqtt {} refused, missing or wrong token", name);
+                        continue;
+                    }
+                    if let Some(command) = payload::parse(name, &publish.payload) {
+                        let _ = tx.send(command);
+                        waker.wake();
                     }
                 }
+                // Only sent when a reload replaces this connection; carrying
+                // on would reconnect under the same client ID and kick the
+                // new one off
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
                 Ok(Event::Incoming(Packet::ConnAck(_))) => {
                     log!("diald: mqtt connected to {}:{}", host, port);
+                    connected_flag.store(true, Ordering::Relaxed);
                 }
                 Err(err) => {
+                    connected_flag.store(false, Ordering::Relaxed);
                     let now = Instant::now();
                     let should_log = last_error_log
                         .map(|t| now.duration_since(t) >= Duration::from_secs(10))
                         .unwrap_or(true);
                     if should_log {
-                        log!("diald: mqtt error ({})", err);
+                        warn!("diald: mqtt error ({})", err);
                         last_error_log = Some(now);
                     }
                 }
@@ -360,81 +802,1011 @@ fn spawn_mqtt() -> Option<MqttHandle> {
         }
     });
 
-    Some(MqttHandle { client, incoming_rx: rx })
+    if monitor && !dry_run {
+        prefix.push_str("/monitor");
+    }
+    let publish_time = Cell::new(Duration::ZERO);
+    let seed_rx = seed.then_some(seed_rx);
+    Some(MqttHandle { client, incoming_rx: rx, seed_rx, prefix, connected, broker, publish_time })
+}
+
+/// Start from the volume the broker kept on `<prefix>/volume` rather than the
+/// model's default, so a restart doesn't jump back to 50. Waits until the
+/// broker has acknowledged the subscriptions, at most `SEED_WAIT`.
+fn seed_volume(mqtt: &mut Option<MqttHandle>, dial: &mut Dial) {
+    let Some(handle) = mqtt.as_mut() else {
+        return;
+    };
+    let Some(seed_rx) = handle.seed_rx.take() else {
+        return;
+    };
+    match seed_rx.recv_timeout(SEED_WAIT) {
+        Ok(volume) if dial.set_volume(dial.model().position(volume)) => {
+            log!("diald: volume -> {} (retained)", dial.model().format(dial.volume()));
+        }
+        Ok(_) => {}
+        Err(_) => debug!("diald: no retained volume"),
+    }
+    let _ = handle.client.unsubscribe(format!("{}/volume", handle.broker.prefix));
+}
+
+/// Apply a command from MQTT or the control socket. `source` is only for the
+/// log.
+fn apply_command(
+    command: Command,
+    source: &str,
+    restart_token: Option<&str>,
+    dial: &mut Dial,
+    haptic: &mut HapticDevice,
+    guest: &mut GuestMode,
+    actions: &mut Vec<Action>,
+) -> Result<(), &'static str> {
+    match command {
+        Command::SetVolume(volume) => {
+            // Never fight the user while they're turning
+            if !dial.set_volume(dial.model().position(volume)) {
+                return Err("dial is active");
+            }
+            log!("diald: {} volume -> {}", source, dial.model().format(dial.volume()));
+            actions.push(Action::Buzz(Trigger::Remote));
+        }
+        Command::AdjustVolume(delta) => {
+            if !dial.adjust_volume(dial.model().steps(delta), actions) {
+                return Err("dial is active");
+            }
+            log!("diald: {} volume -> {}", source, dial.model().format(dial.volume()));
+            actions.push(Action::Buzz(Trigger::Remote));
+        }
+        Command::Reset(volume) => {
+            // For when the state machine got into a weird place; the resulting
+            // volume is published so everyone agrees again
+            dial.reset();
+            if let Some(volume) = volume {
+                dial.set_volume(dial.model().position(volume));
+            }
+            log!("diald: {} reset (volume {})", source, dial.model().format(dial.volume()));
+            actions.push(Action::Volume(dial.volume()));
+        }
+        Command::Haptics(trigger, enabled) => {
+            let mut triggers = haptic.triggers();
+            triggers.set(trigger, enabled);
+            haptic.set_triggers(triggers);
+            log!("diald: {} haptics -> {}", source, triggers.describe());
+        }
+        Command::GuestOn(duration) => {
+            guest.start(duration, Instant::now());
+            log!("diald: {} guest mode -> {}", source, guest.describe(Instant::now()));
+        }
+        Command::GuestOff => {
+            guest.stop();
+            log!("diald: {} guest mode -> off", source);
+        }
+        Command::Profile(name) => {
+            // Applied by the main loop, which notices the switch
+            if !config::use_profile(name.as_deref()) {
+                return Err("unknown profile");
+            }
+            log!("diald: {} profile -> {}", source, name.as_deref().unwrap_or("default"));
+        }
+        Command::LogLevel(level) => {
+            diald::set_log_level(level);
+            log!("diald: {} log level -> {}", source, level.as_str());
+        }
+        Command::Restart(token) => {
+            let Some(expected) = restart_token else {
+                warn!("diald: {} restart ignored, DIALD_RESTART_TOKEN is not set", source);
+                return Err("restart is disabled");
+            };
+            if !payload::token_matches(expected, &token) {
+                warn!("diald: {} restart refused, wrong token", source);
+                return Err("wrong token");
+            }
+            log!("diald: {} restart", source);
+            daemon::request_restart();
+        }
+        // Needs the click burst, so the main loop applies it
+        Command::Config(_) => return Err("not supported here"),
+        // Answered on the broker by the main loop
+        Command::Request(_) => return Err("not supported here"),
+    }
+    Ok(())
+}
+
+/// Apply a `config/set` message. Until the next reload or profile switch,
+/// which go back to the configured settings.
+fn apply_tuning(
+    tuning: Tuning,
+    dial: &mut Dial,
+    haptic: &mut HapticDevice,
+    clicks: &mut ClickBurst,
+) {
+    if let Some(counts) = tuning.counts_per_step {
+        dial.set_counts_per_step((counts > 0).then_some(counts));
+    }
+    if let Some(window) = tuning.click_window {
+        clicks.window = window;
+    }
+    if let Some(enabled) = tuning.haptics {
+        haptic.set_muted(!enabled);
+    }
+    if let Some(update) = tuning.pattern
+        && let Err(err) = update_pattern(haptic, "mqtt", update)
+    {
+        warn!("diald: mqtt pattern refused ({})", err);
+    }
+    log!("diald: mqtt config -> {}", effective_config(dial, haptic, clicks));
+}
+
+/// The `<prefix>/response` payload for a `request`: its `id` and the
+/// answer, or an `error`.
+fn answer(
+    request: &Request,
+    device: &Path,
+    dial: &Dial,
+    haptic: &HapticDevice,
+    clicks: &ClickBurst,
+    guest: &GuestMode,
+) -> String {
+    let id = payload::json_string(&request.id);
+    let Some(query) = request.query else {
+        return format!("{{\"id\":{},\"error\":\"unknown query\"}}", id);
+    };
+    let volume = dial.model().format(dial.volume());
+    let answer = match query {
+        Query::Volume => format!("\"volume\":{}", volume),
+        Query::Config => format!("\"config\":{}", effective_config(dial, haptic, clicks)),
+        Query::Diagnostics => format!(
+            concat!(
+                "\"version\":\"{}\",\"device\":{},\"mode\":\"{}\",\"tool\":\"{}\",",
+                "\"volume\":{},\"guest\":{},\"haptics\":\"{}\",\"profile\":{},",
+                "\"log_level\":\"{}\""
+            ),
+            update::VERSION,
+            payload::json_string(&device.display().to_string()),
+            dial.mode().as_str(),
+            dial.tool().as_str(),
+            volume,
+            guest.is_active(),
+            haptic.state().as_str(),
+            config::profile().map_or("null".to_string(), |name| payload::json_string(&name)),
+            diald::log_level().as_str()
+        ),
+    };
+    format!("{{\"id\":{},{}}}", id, answer)
+}
+
+/// Define a haptic pattern, or remove it when there is no report.
+fn update_pattern(
+    haptic: &mut HapticDevice,
+    source: &str,
+    (name, report): (String, Option<Vec<u8>>),
+) -> Result<(), String> {
+    match report {
+        Some(report) => {
+            let bytes = format!("{:?}", report);
+            haptic.define_pattern(&name, report)?;
+            log!("diald: {} pattern {} -> {}", source, name, bytes);
+        }
+        None if haptic.remove_pattern(&name) => log!("diald: {} pattern {} removed", source, name),
+        None => return Err(format!("no pattern {}", name)),
+    }
+    Ok(())
+}
+
+/// The `<prefix>/config` payload: what `config/set` can change, as in use.
+fn effective_config(dial: &Dial, haptic: &HapticDevice, clicks: &ClickBurst) -> String {
+    format!(
+        "{{\"counts_per_step\":{},\"click_window\":{},\"haptics\":{}}}",
+        dial.counts_per_unit(),
+        clicks.window.as_millis(),
+        !haptic.is_muted()
+    )
+}
+
+fn handle_control(
+    request: ControlRequest,
+    restart_token: Option<&str>,
+    dial: &mut Dial,
+    haptic: &mut HapticDevice,
+    guest: &mut GuestMode,
+    actions: &mut Vec<Action>,
+) {
+    let parts: Vec<&str> = request.command.split_whitespace().collect();
+    let negative = matches!(dial.model().scale, Scale::Scaled { min, .. } if min < 0.0);
+    let command = match parts.as_slice() {
+        ["status"] => {
+            let status = format!(
+                "mode={} tool={} volume={} published={} guest={}",
+                dial.mode().as_str(),
+                dial.tool().as_str(),
+                dial.model().format(dial.volume()),
+                dial.model().format(dial.last_printed_volume()),
+                if guest.is_active() { "on" } else { "off" }
+            );
+            return request.reply(status);
+        }
+        ["tool"] => return request.reply(dial.tool().as_str()),
+        ["tool", _] if guest.is_active() => return request.reply("error: guest mode"),
+        ["tool", name] => match Tool::parse(name) {
+            Some(tool) => {
+                dial.set_tool(tool);
+                return request.reply("ok");
+            }
+            None => return request.reply("error: unknown tool"),
+        },
+        ["volume"] => return request.reply(dial.model().format(dial.volume())),
+        ["haptics"] => return request.reply(haptic.triggers().describe()),
+        ["pattern"] => {
+            let patterns: Vec<String> = haptic
+                .patterns()
+                .iter()
+                .map(|(name, report)| format!("{} {:?}", name, report))
+                .collect();
+            if patterns.is_empty() {
+                return request.reply("none");
+            }
+            return request.reply(patterns.join(", "));
+        }
+        ["pattern", words @ ..] => match payload::parse_pattern(words) {
+            Some(update) => match update_pattern(haptic, "control", update) {
+                Ok(()) => return request.reply("ok"),
+                Err(err) => return request.reply(format!("error: {}", err)),
+            },
+            None => return request.reply("error: invalid argument"),
+        },
+        ["play", name] if haptic.play_pattern(name) => return request.reply("ok"),
+        ["play", _] => return request.reply("error: unknown pattern"),
+        ["guest"] => return request.reply(guest.describe(Instant::now())),
+        ["reset"] => Some(Command::Reset(None)),
+        ["reset", value] => payload::parse_number(value).map(|volume| Command::Reset(Some(volume))),
+        ["volume", "adjust", delta] => payload::parse_number(delta).map(Command::AdjustVolume),
+        // A negative volume is only set when a range goes below zero
+        ["volume", value] if value.starts_with('+') || (value.starts_with('-') && !negative) => {
+            payload::parse_number(value).map(Command::AdjustVolume)
+        }
+        ["volume", value] => payload::parse_number(value).map(Command::SetVolume),
+        ["haptics", trigger, state] => payload::parse_haptics(trigger, state),
+        ["guest", words @ ..] => payload::parse_guest(words),
+        ["profile"] => return request.reply(config::profile().unwrap_or("default".to_string())),
+        ["profile", name] => payload::parse_profile(name),
+        ["log-level"] => return request.reply(diald::log_level().as_str()),
+        ["log-level", name] => Level::parse(name).map(Command::LogLevel),
+        ["restart", token] => Some(Command::Restart(token.to_string())),
+        _ => return request.reply("error: unknown command"),
+    };
+    let Some(command) = command else {
+        return request.reply("error: invalid argument");
+    };
+    match apply_command(command, "control", restart_token, dial, haptic, guest, actions) {
+        Ok(()) => request.reply("ok"),
+        Err(err) => request.reply(format!("error: {}", err)),
+    }
+}
+
+/// Shut down for a remote restart: the service manager brings diald back up on
+/// `RESTART_EXIT_STATUS`.
+fn restart(mqtt: Option<MqttHandle>) -> ! {
+    log!("diald: exiting for a restart");
+    shut_down(mqtt, daemon::RESTART_EXIT_STATUS);
+}
+
+/// Leave the broker cleanly and exit with `status`.
+fn shut_down(mqtt: Option<MqttHandle>, status: i32) -> ! {
+    if let Some(handle) = mqtt {
+        let _ = handle.client.disconnect();
+    }
+    // Give the control socket's "ok" and the disconnect a moment to go out
+    thread::sleep(Duration::from_millis(200));
+    std::process::exit(status);
+}
+
+/// Exit once no dial has been touched for `after`, if set; a dial in the
+/// middle of a gesture holds it off.
+fn exit_if_idle(after: Option<Duration>, dial: &Dial, mqtt: &mut Option<MqttHandle>) {
+    let Some(after) = after else {
+        return;
+    };
+    if dial.mode() == DialMode::Idle && daemon::idle_for() >= after {
+        log!("diald: untouched for {}s, exiting", after.as_secs());
+        shut_down(mqtt.take(), 0);
+    }
+}
+
+/// The dial just went idle: run whatever `DIALD_IDLE_*` asks for. Commands
+/// are skipped for guests and for monitor instances. Returns how long
+/// starting the command took.
+fn on_idle(
+    hooks: &IdleHooks,
+    dial: &Dial,
+    guest: &GuestMode,
+    monitor: bool,
+    mqtt: &Option<MqttHandle>,
+) -> Duration {
+    if let Some(handle) = mqtt {
+        if hooks.snapshot {
+            let snapshot = format!(
+                "{{\"volume\":{},\"tool\":\"{}\",\"max_volume\":{},\"guest\":{}}}",
+                dial.model().format(dial.volume()),
+                dial.tool().as_str(),
+                dial.max_volume(),
+                guest.is_active()
+            );
+            handle.publish("snapshot", true, snapshot);
+        }
+        if let Some((topic, payload)) = &hooks.publish {
+            handle.publish_to(topic.clone(), false, payload.as_str());
+        }
+    }
+    let started = Instant::now();
+    if let Some(command) = &hooks.exec
+        && !guest.is_active()
+        && !monitor
+    {
+        let vars = [
+            ("DIALD_VOLUME", dial.model().format(dial.volume())),
+            ("DIALD_TOOL", dial.tool().as_str().to_string()),
+        ];
+        hooks::spawn_command(command, &vars);
+    }
+    started.elapsed()
+}
+
+/// Called whenever a device is (re)attached, including warm-standby switches.
+fn attach_device(
+    device: &dyn InputSource,
+    path: &Path,
+    dial: &mut Dial,
+    haptic: &mut HapticDevice,
+    mqtt: &Option<MqttHandle>,
+) {
+    log!("diald: opened {}", path.display());
+    debug!("diald: name={:?}", device.name());
+    dial.reset();
+    haptic.reconnect(path);
+
+    let warning = device.sanity_warning();
+    if let Some(ref warning) = warning {
+        warn!("diald: {}: {}", path.display(), warning);
+    }
+    if let Some(handle) = mqtt {
+        let state = if warning.is_some() { "misconfigured" } else { "ok" };
+        handle.publish("state", true, state);
+    }
+}
+
+/// The dial's Bluetooth connection, and its battery when known.
+fn publish_bluetooth(status: DeviceStatus, mqtt: &Option<MqttHandle>) {
+    let state = if status.connected { "connected" } else { "disconnected" };
+    match status.battery {
+        Some(battery) => log!("diald: bluetooth {} (battery {}%)", state, battery),
+        None => log!("diald: bluetooth {}", state),
+    }
+    if let Some(handle) = mqtt {
+        handle.publish("bluetooth", true, state);
+        // The last reading stays while disconnected
+        if let Some(battery) = status.battery {
+            handle.publish("battery", true, battery.to_string());
+        }
+    }
+}
+
+/// `--check-config`: parse every setting, look for the devices and their
+/// hidraw nodes, and connect to the broker once. Returns whether all passed.
+fn check_config(config_path: &Path, device_paths: &[PathBuf]) -> bool {
+    let mut passed = true;
+    let mut check = |what: &str, result: Result<String, String>| {
+        let (status, detail) = match result {
+            Ok(detail) => ("ok", detail),
+            Err(err) => {
+                passed = false;
+                ("FAIL", err)
+            }
+        };
+        println!("{:<5} {:<9} {}", status, what, detail);
+    };
+
+    let file = if config_path.exists() {
+        config_path.display().to_string()
+    } else {
+        format!("{} (none, environment only)", config_path.display())
+    };
+    check("config", Ok(file));
+    let settings = Settings::from_env()
+        .and_then(|_| volume_model())
+        .and_then(|_| default_tool())
+        .and_then(|_| AppTools::from_env())
+        .and_then(|_| input::scan().map_err(|err| err.to_string()));
+    check("settings", settings.map(|_| "parsed".to_string()));
+
+    // Warm standby lists devices that are only sometimes there, so one is enough
+    let mut present = Vec::new();
+    for path in device_paths {
+        let Ok(found) = input::resolve(path) else {
+            println!("{:<5} {:<9} {} found no dial", "-", "device", path.display());
+            continue;
+        };
+        let file = found.to_str().and_then(|p| p.strip_prefix("replay:")).map(Path::new);
+        if file.unwrap_or(&found).exists() {
+            present.push(found);
+        } else {
+            println!("{:<5} {:<9} {} not present", "-", "device", path.display());
+        }
+    }
+    if present.is_empty() {
+        check("device", Err("none of the listed devices is present".to_string()));
+    }
+    for path in &present {
+        let haptics = match haptics::hidraw_node(path) {
+            Some(node) => format!("(haptics {})", node),
+            None => "(no hidraw node; haptics need ff or hidapi)".to_string(),
+        };
+        check("device", Ok(format!("{} {}", path.display(), haptics)));
+    }
+
+    let broker = BrokerSettings::from_env();
+    let credentials = broker.username.as_deref().zip(broker.password.as_deref());
+    let address = format!("{}:{}", broker.host, broker.port);
+    let connected = setup::test_broker("diald-check", &broker.host, broker.port, credentials)
+        .map(|()| address.clone())
+        .map_err(|err| format!("{} ({})", address, err));
+    check("mqtt", connected);
+    passed
+}
+
+/// A turn counts as one gesture until the dial has been still this long.
+const ONESHOT_TURN_SETTLE: Duration = Duration::from_millis(500);
+
+/// `--oneshot`: wait for one gesture on the dial, print it as JSON and exit,
+/// so a script can wait for the user to confirm on the dial. With
+/// `--timeout` it gives up after that long and exits with 1.
+fn oneshot(device_paths: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    let timeout = match arg_value("--timeout") {
+        Some(timeout) => Some(payload::parse_duration(&timeout).ok_or_else(|| {
+            format!("--timeout: expected a duration like 30s or 5m, got '{}'", timeout)
+        })?),
+        None => None,
+    };
+    let settings = Settings::from_env()?;
+    let mut dial = Dial::with_model(volume_model()?);
+    configure_dial(&mut dial, &settings);
+    let mut device = match open_first_available(device_paths) {
+        Ok((_, _, device)) => device,
+        Err(errors) => {
+            let (path, err) = &errors[0];
+            return Err(format!("can't open {} ({})", path.display(), err).into());
+        }
+    };
+
+    let started = Instant::now();
+    let start = dial.volume();
+    let mut volume = start;
+    let mut turned_at: Option<Instant> = None;
+    let mut clicks = ClickBurst::new(settings.click_window, settings.click_max);
+    let mut actions = Vec::new();
+    let sleeper = Sleeper::new();
+    let gesture = loop {
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let now = Instant::now();
+        for event in events {
+            dial.handle(event, now, &mut actions);
+        }
+        if dial.volume() != volume {
+            volume = dial.volume();
+            turned_at = Some(now);
+        }
+        let mut long_press = false;
+        let mut press_turn = None;
+        for action in actions.drain(..) {
+            match action {
+                Action::Click => clicks.push(now),
+                Action::LongPress => long_press = true,
+                Action::PressTurn(steps) => press_turn = Some(steps),
+                _ => {}
+            }
+        }
+
+        if long_press {
+            break "{\"gesture\":\"long_press\"}".to_string();
+        }
+        if let Some(steps) = press_turn {
+            break format!("{{\"gesture\":\"press_turn\",\"steps\":{}}}", steps);
+        }
+        if let Some(count) = clicks.try_finish(now) {
+            break format!("{{\"gesture\":\"click\",\"count\":{}}}", count);
+        }
+        if turned_at.is_some_and(|at| now - at >= ONESHOT_TURN_SETTLE) {
+            // Turned and back again is no gesture
+            if volume != start {
+                break format!("{{\"gesture\":\"turn\",\"steps\":{}}}", volume - start);
+            }
+            turned_at = None;
+        }
+        if timeout.is_some_and(|timeout| now - started >= timeout) {
+            println!("{{\"gesture\":\"timeout\"}}");
+            std::process::exit(1);
+        }
+        // Asleep until the dial sends something, or a click burst, a turn or
+        // the timeout is due
+        let settled = turned_at.map(|at| at + ONESHOT_TURN_SETTLE);
+        let given_up = timeout.map(|timeout| started + timeout);
+        let deadline = [clicks.deadline, settled, given_up].into_iter().flatten().min();
+        let wait = deadline.map_or(IDLE_WAIT, |at| at.saturating_duration_since(now));
+        sleeper.sleep(device.raw_fd(), wait.min(IDLE_WAIT));
+    };
+    println!("{}", gesture);
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let device_path = parse_device_arg()
-        .or_else(|| env::var_os("DIALD_DEVICE").map(PathBuf::from))
-        .ok_or("missing device path; pass --device or set DIALD_DEVICE")?;
+    if has_flag("--oneshot") {
+        // Only the gesture goes to stdout
+        diald::set_log_level(Level::Error);
+    }
+    // For development: `--env-file`, else a `.env` where diald is started,
+    // fills in what the environment leaves out
+    match arg_value("--env-file") {
+        Some(path) => config::load_env_file(Path::new(&path), true)?,
+        None => config::load_env_file(Path::new(".env"), false)?,
+    }
+    let (config_path, config_required) = config::path(arg_value("--config"));
+    if has_flag("--migrate-config") {
+        print!("{}", config::migrate_file(&config_path)?);
+        return Ok(());
+    }
+    // Calibrating or init-config may be what creates the file
+    let creating = matches!(env::args().nth(1).as_deref(), Some("calibrate" | "init-config"));
+    config::load(&config_path, config_required && !creating)?;
 
-    let mut haptic = HapticDevice::new(device_path.clone());
-    let mut state = DialState::new();
-    let mut delay_buffer = DelayBuffer::new(BACKLASH_THRESHOLD);
-    let mut batcher = EventBatcher::new(Duration::from_millis(250));
-    let mut mqtt = spawn_mqtt();
+    match env::args().nth(1).as_deref() {
+        Some("setup") => {
+            return setup::run(env::args().nth(2).as_deref()).map_err(|err| err.to_string().into());
+        }
+        Some("ha-blueprint") => {
+            print!("{}", blueprint::render()?);
+            return Ok(());
+        }
+        Some("systemd-unit") => {
+            print!("{}", unit::render_unit());
+            return Ok(());
+        }
+        Some("udev-rule") => {
+            print!("{}", unit::render_rule()?);
+            return Ok(());
+        }
+        Some("calibrate") => {
+            let device = env::args()
+                .nth(2)
+                .filter(|arg| !arg.starts_with('-'))
+                .map(PathBuf::from)
+                .or_else(|| parse_device_args().into_iter().next())
+                .or_else(|| parse_device_env().into_iter().next());
+            return calibrate::run(device, &config_path).map_err(|err| err.to_string().into());
+        }
+        Some("record") => {
+            let settings = Settings::from_env()?;
+            let dataset = env::args()
+                .nth(2)
+                .filter(|arg| !arg.starts_with('-'))
+                .unwrap_or_else(|| record::DEFAULT_PATH.to_string());
+            let device = parse_device_args().into_iter().next();
+            let device = device.or_else(|| parse_device_env().into_iter().next());
+            let recorded = [
+                ("counts_per_revolution", settings.counts_per_revolution.to_string()),
+                ("backlash_threshold", settings.backlash_threshold.to_string()),
+                ("backlash_cancel", settings.backlash_cancel_threshold.to_string()),
+                ("backlash_filter", format!("{:?}", settings.backlash_filter)),
+                ("backlash_time", format!("{:?}", settings.backlash_time)),
+                ("backlash_release", format!("{:?}", settings.backlash_release)),
+            ];
+            return record::run(device, Path::new(&dataset), &recorded)
+                .map_err(|err| err.to_string().into());
+        }
+        Some("init-config") => {
+            match env::args().nth(2).filter(|arg| !arg.starts_with("--")).as_deref() {
+                Some("-") => print!("{}", config::template()),
+                Some(path) => config::init_file(Path::new(path))?,
+                None => config::init_file(&config_path)?,
+            }
+            return Ok(());
+        }
+        Some("config-schema") => {
+            print!("{}", schema::render());
+            return Ok(());
+        }
+        Some("dump-fsm") => {
+            let format = match env::args().nth(2) {
+                None => fsm::Format::Dot,
+                Some(name) => fsm::Format::parse(&name).ok_or_else(|| {
+                    format!("dump-fsm: unknown format '{}', expected dot or mermaid", name)
+                })?,
+            };
+            let settings = Settings::from_env()?;
+            let mut dial = Dial::with_model(volume_model()?);
+            configure_dial(&mut dial, &settings);
+            print!("{}", fsm::render(&dial, &settings.gestures, format));
+            return Ok(());
+        }
+        Some("mqtt-test") => {
+            let broker = BrokerSettings::from_env();
+            let credentials = broker.username.as_deref().zip(broker.password.as_deref());
+            let (prefix, command_prefix) = (&broker.prefix, &broker.command_prefix);
+            let passed =
+                mqtt_test::run(&broker.host, broker.port, credentials, prefix, command_prefix);
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some("list-devices") => {
+            print!("{}", devices::render()?);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // A dial and its devices in priority order, for each `[[device]]` section
+    let mut dials: Vec<(Option<String>, Vec<PathBuf>)> = Vec::new();
+    for name in config::dials() {
+        config::use_dial(Some(&name));
+        let device_paths = parse_device_env();
+        if device_paths.is_empty() {
+            return Err(format!("[[device]] {}: missing path", name).into());
+        }
+        dials.push((Some(name), device_paths));
+    }
+    config::use_dial(None);
+    if dials.is_empty() {
+        let mut device_paths = parse_device_args();
+        if device_paths.is_empty() {
+            device_paths = parse_device_env();
+        }
+        if device_paths.is_empty() {
+            device_paths.push(PathBuf::from(input::AUTO));
+        }
+        dials.push((None, device_paths));
+    }
+    if has_flag("--check-config") {
+        let mut passed = true;
+        for (name, device_paths) in &dials {
+            config::use_dial(name.as_deref());
+            if let Some(name) = name {
+                println!("[[device]] {}", name);
+            }
+            passed &= check_config(&config_path, device_paths);
+        }
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if has_flag("--oneshot") {
+        config::use_dial(dials[0].0.as_deref());
+        return oneshot(&dials[0].1);
+    }
+
+    if let Some(level) = log_level()? {
+        diald::set_log_level(level);
+    }
+    let pid_file = arg_value("--pid-file").or_else(|| config::var("DIALD_PID_FILE").ok());
+    let log_file = arg_value("--log-file").or_else(|| config::var("DIALD_LOG_FILE").ok());
+    let mut pid_lock = pid_file
+        .as_deref()
+        .map(|path| daemon::lock_pid_file(Path::new(path)))
+        .transpose()
+        .map_err(|err| err.to_string())?;
+    if has_flag("--daemonize") {
+        daemon::daemonize(log_file.as_deref().map(Path::new))?;
+    }
+    // Written after forking so the file holds the daemon's own PID
+    if let Some(file) = pid_lock.as_mut() {
+        daemon::write_pid(file)?;
+    }
+
+    // A second, read-only instance next to the real one, for diagnostics. A
+    // dry run is one too, that only logs what it would publish
+    let dry_run = has_flag("--dry-run") || config::flag("DIALD_DRY_RUN", false)?;
+    let monitor = dry_run || has_flag("--monitor") || config::flag("DIALD_MONITOR", false)?;
+    if dry_run {
+        DRY_RUN.store(true, Ordering::Relaxed);
+        input::NEVER_GRAB.store(true, Ordering::Relaxed);
+        log!("diald: dry run, logging what would be published or played");
+    } else if monitor {
+        input::NEVER_GRAB.store(true, Ordering::Relaxed);
+        log!("diald: monitor mode, no haptics, grabs, control or desktop output");
+    }
 
-    // Disable logging after 30 minutes to preserve SD card
+    // Down to warnings after 30 minutes to spare the SD card, unless a level
+    // was asked for
     thread::spawn(|| {
         thread::sleep(Duration::from_secs(30 * 60));
-        LOGGING_ENABLED.store(false, Ordering::Relaxed);
+        diald::quiet_down();
     });
 
-    let idle_timeout = Duration::from_secs(30);
+    daemon::watch_sighup();
+    watch::spawn(&config_path)?;
+
+    // Each `[[device]]` section is a dial of its own; all but the first run on
+    // threads of their own, and only the first answers on the control socket
+    let mut dials = dials.into_iter();
+    let (name, device_paths) = dials.next().expect("at least one dial");
+    for (name, device_paths) in dials {
+        let config_path = config_path.clone();
+        thread::spawn(move || {
+            config::use_dial(name.as_deref());
+            let paths = &device_paths;
+            if let Err(err) = run_dial(&config_path, config_required, paths, monitor, false) {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        });
+    }
+    config::use_dial(name.as_deref());
+    run_dial(&config_path, config_required, &device_paths, monitor, true)
+}
+
+/// Run one dial from `device_paths`, in priority order. It never returns
+/// but with an error; a restart exits the process.
+fn run_dial(
+    config_path: &Path,
+    config_required: bool,
+    device_paths: &[PathBuf],
+    monitor: bool,
+    with_control: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut haptic = if monitor {
+        HapticDevice::disabled(device_paths[0].clone())
+    } else {
+        HapticDevice::new(device_paths[0].clone())
+    };
+    let settings = Settings::from_env()?;
+    let mut dial = Dial::with_model(volume_model()?);
+    configure_dial(&mut dial, &settings);
+    haptic.set_triggers(settings.triggers);
+    haptic.set_strength(settings.strength);
+    if !monitor && let Some(piezo) = Piezo::from_env()? {
+        haptic.set_piezo(piezo);
+    }
+    let default_tool = default_tool()?;
+    dial.set_tool(default_tool);
+    // A monitor instance only mirrors the real one
+    let mut state_file = if monitor { None } else { StateFile::from_env() };
+    if let Some(state_file) = state_file.as_mut() {
+        let saved = state_file.load();
+        if let Some(volume) = saved.volume {
+            dial.set_volume(dial.model().position(volume));
+            log!("diald: volume -> {} (saved)", dial.model().format(dial.volume()));
+        }
+        if let Some(tool) = saved.tool {
+            dial.set_tool(tool);
+        }
+        for (name, report) in saved.patterns {
+            if let Err(err) = haptic.define_pattern(&name, report) {
+                warn!("diald: dropping saved pattern {} ({})", name, err);
+            }
+        }
+    }
+    let app_tools = AppTools::from_env()?;
+    let mut desktop = DesktopOutput::new();
+    desktop.set_counts_per_revolution(settings.counts_per_revolution);
+    let mut actions = Vec::new();
+    let mut clicks = ClickBurst::new(settings.click_window, settings.click_max);
+    let mut reports = settings.reports;
+    let mut fast = settings.fast;
+    let sleeper = Sleeper::new();
+    let focus = app_tools
+        .as_ref()
+        .filter(|_| !monitor)
+        .map(|_| focus::spawn_focus_watcher(sleeper.waker()));
+    let mut mqtt = spawn_mqtt(monitor, true, sleeper.waker());
+    seed_volume(&mut mqtt, &mut dial);
+    let mut fast_volume = dial.volume();
+    let control =
+        if monitor || !with_control { None } else { control::spawn_control(sleeper.waker()) };
+    let nudger = if monitor { None } else { WakeNudger::from_env(sleeper.waker()) };
+    let mut greeting_pending = false;
+    let mut night_limit = settings.night_limit;
+    let mut last_schedule_check: Option<Instant> = None;
+    let mut guest = settings.guest;
+    let mut guest_published: Option<bool> = None;
+    let mut idle_hooks = settings.idle_hooks;
+    let mut throttle = settings.throttle;
+    let mut throttle_published: Option<bool> = None;
+    let mut haptics_published: Option<HapticState> = None;
+    let mut restart_token = settings.restart_token;
+    let mut gestures = settings.gestures;
+    let mut latency = settings.latency;
+    let mut exit_when_idle = settings.exit_when_idle;
+    let mut exit_with_device = settings.exit_with_device;
+    let mut log_level = settings.log_level;
+    let mut updates = if monitor { None } else { UpdateCheck::from_env()? };
+    let mut info_published: Option<Option<String>> = None;
+    let mut last_offline_buzz: Option<Instant> = None;
+    let mut link = LinkMonitor::from_env(sleeper.waker());
+    let mut bluetooth = StatusMonitor::from_env(sleeper.waker());
+    let status_led = if monitor { None } else { StatusLed::from_env()? };
+    let mut display = if monitor { None } else { StatusDisplay::from_env()? };
+    let mut last_mode = dial.mode();
+
+    let standby_check_interval = Duration::from_secs(2);
+    let mut reloads = daemon::reloads();
+    // The profile the running settings were read with
+    let mut profile = config::profile();
+    let mut profile_published: Option<Option<String>> = None;
+    let mut config_published: Option<String> = None;
 
     log!("diald: state -> disconnected");
 
     let mut open_error_logged = false;
+    let mut hotplug = Hotplug::from_env()?;
+    // Only the first open is bounded; once seen, the device is waited for
+    let mut give_up_at = settings.wait_for_device.map(|wait| Instant::now() + wait);
     loop {
-        let mut device = loop {
-            match Device::open(&device_path) {
-                Ok(dev) => {
-                    set_nonblock(&dev)?;
-                    log!("diald: opened {}", device_path.display());
-                    log!("diald: name={:?}", dev.name());
-                    open_error_logged = false;
-                    state.reset_to_idle();
-                    delay_buffer.clear();
-                    haptic.reconnect();
-                    break dev;
+        // With hotplug events the devices are tried when one is added, and
+        // only now and then otherwise, in case an event was missed
+        let mut retry_at = Instant::now();
+        let (mut active_index, mut active_path, mut device) = loop {
+            if Instant::now() >= retry_at {
+                match open_first_available(device_paths) {
+                    Ok(opened) => break opened,
+                    Err(errors) => {
+                        if exit_with_device {
+                            for (path, err) in errors {
+                                error!("diald: failed to open {} ({})", path.display(), err);
+                            }
+                            shut_down(mqtt.take(), 1);
+                        }
+                        if give_up_at.is_some_and(|at| Instant::now() >= at) {
+                            for (path, err) in errors {
+                                error!("diald: failed to open {} ({})", path.display(), err);
+                            }
+                            error!("diald: no device turned up, giving up");
+                            shut_down(mqtt.take(), daemon::NO_DEVICE_EXIT_STATUS);
+                        }
+                        if let Some(led) = &status_led {
+                            led.set(Status::DeviceMissing);
+                        }
+                        if !open_error_logged {
+                            let retrying = if hotplug.is_some() { "waiting" } else { "retrying" };
+                            for (path, err) in errors {
+                                error!(
+                                    "diald: failed to open {} ({}), {}...",
+                                    path.display(),
+                                    err,
+                                    retrying
+                                );
+                            }
+                            open_error_logged = true;
+                        }
+                    }
                 }
-                Err(err) => {
-                    if !open_error_logged {
-                        println!(
-                            "diald: failed to open {} ({}), retrying...",
-                            device_path.display(),
-                            err
-                        );
-                        open_error_logged = true;
+                let interval = if hotplug.is_some() { HOTPLUG_RETRY } else { RETRY_INTERVAL };
+                retry_at = Instant::now() + interval;
+                // Tried once more right at the deadline, not up to 30s after
+                if let Some(at) = give_up_at {
+                    retry_at = retry_at.min(at);
+                }
+            }
+            if nudger.as_ref().is_some_and(|nudger| nudger.connected()) {
+                greeting_pending = true;
+                if let Some(bluetooth) = &bluetooth {
+                    bluetooth.refresh();
+                }
+            }
+            if let Some(status) = bluetooth.as_mut().and_then(|bluetooth| bluetooth.poll()) {
+                publish_bluetooth(status, &mqtt);
+            }
+            if let Some(ref control) = control {
+                while let Ok(request) = control.try_recv() {
+                    let token = restart_token.as_deref();
+                    let (guest, actions) = (&mut guest, &mut actions);
+                    handle_control(request, token, &mut dial, &mut haptic, guest, actions);
+                }
+            }
+            if daemon::restart_requested() {
+                restart(mqtt.take());
+            }
+            if let Some(state_file) = state_file.as_mut() {
+                state_file.poll(&dial, haptic.patterns(), Instant::now());
+            }
+            exit_if_idle(exit_when_idle, &dial, &mut mqtt);
+            match hotplug.as_mut() {
+                Some(hotplug) => {
+                    if hotplug.wait(RETRY_INTERVAL) {
+                        debug!("diald: input device added");
+                        retry_at = Instant::now();
                     }
-                    thread::sleep(Duration::from_secs(1));
                 }
+                None => thread::sleep(RETRY_INTERVAL),
             }
         };
+        open_error_logged = false;
+        give_up_at = None;
+        attach_device(device.as_ref(), &active_path, &mut dial, &mut haptic, &mqtt);
+        if let Some(nudger) = &nudger {
+            nudger.set_missing(false);
+        }
+        if let Some(bluetooth) = &bluetooth {
+            bluetooth.refresh();
+        }
+        let mut last_standby_check = Instant::now();
 
         loop {
+            // Warm standby: switch over as soon as a higher-priority device shows up
+            if active_index > 0 && last_standby_check.elapsed() >= standby_check_interval {
+                last_standby_check = Instant::now();
+                if let Ok((index, path, preferred)) =
+                    open_first_available(&device_paths[..active_index])
+                {
+                    log!(
+                        "diald: switching from {} to higher-priority {}",
+                        active_path.display(),
+                        path.display()
+                    );
+                    device = preferred;
+                    (active_index, active_path) = (index, path);
+                    attach_device(device.as_ref(), &active_path, &mut dial, &mut haptic, &mqtt);
+                }
+            }
+
             haptic.try_reconnect_if_needed();
+            if haptics_published != Some(haptic.state()) {
+                haptics_published = Some(haptic.state());
+                if let Some(ref handle) = mqtt {
+                    handle.publish("haptics", true, haptic.state().as_str());
+                }
+            }
+
+            // The connect can still be finishing when the input node shows up
+            if nudger.as_ref().is_some_and(|nudger| nudger.connected()) {
+                greeting_pending = true;
+            }
+            // hidraw can show up a little after the input node, so wait for it
+            if greeting_pending && haptic.is_connected() {
+                haptic.buzz(Trigger::Greeting);
+                greeting_pending = false;
+            }
 
-            // Flush batched events if deadline passed
-            if let Some(events) = batcher.try_flush() {
-                emit_batch(events, &mqtt);
+            if let Some(count) = clicks.try_finish(Instant::now()) {
+                if !guest.is_active() && guest.is_activation(count) {
+                    guest.start(None, Instant::now());
+                    log!("diald: guest mode -> {}", guest.describe(Instant::now()));
+                }
+                emit_clicks(count, &mqtt, &mut haptic, &reports, dial.volume());
+                let effects = gestures.effects(dial.tool(), Gesture::Clicks(count));
+                apply_gesture(effects, &mut dial, &mut haptic, &mqtt);
             }
 
             // Check for incoming MQTT volume updates (only when idle)
             if let Some(ref handle) = mqtt {
                 loop {
                     match handle.incoming_rx.try_recv() {
-                        Ok(volume) => {
-                            if state.mode == DialMode::Idle {
-                                let clamped = (volume as f64).clamp(0.0, 100.0);
-                                state.volume = clamped;
-                                state.last_printed_volume = clamped.round() as i32;
-                                log!("diald: mqtt volume -> {}", state.last_printed_volume);
-                            }
+                        Ok(Command::Config(tuning)) => {
+                            apply_tuning(tuning, &mut dial, &mut haptic, &mut clicks);
+                        }
+                        Ok(Command::Request(request)) => {
+                            let answer = answer(
+                                &request,
+                                &active_path,
+                                &dial,
+                                &haptic,
+                                &clicks,
+                                &guest,
+                            );
+                            debug!("diald: mqtt request -> {}", answer);
+                            handle.publish("response", false, answer);
+                        }
+                        Ok(command) => {
+                            let _ = apply_command(
+                                command,
+                                "mqtt",
+                                restart_token.as_deref(),
+                                &mut dial,
+                                &mut haptic,
+                                &mut guest,
+                                &mut actions,
+                            );
                         }
                         Err(TryRecvError::Empty) => break,
                         Err(TryRecvError::Disconnected) => {
-                            log!("diald: mqtt disconnected");
+                            warn!("diald: mqtt disconnected");
                             mqtt = None;
                             break;
                         }
@@ -442,153 +1814,371 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
-            // Transition to idle after timeout
-            if state.mode == DialMode::Active || state.mode == DialMode::Backlash {
-                if let Some(last_event) = state.last_event_at {
-                    if Instant::now().duration_since(last_event) >= idle_timeout {
-                        state.reset_to_idle();
-                        delay_buffer.clear();
+            if let Some(ref control) = control {
+                while let Ok(request) = control.try_recv() {
+                    let token = restart_token.as_deref();
+                    let (guest, actions) = (&mut guest, &mut actions);
+                    handle_control(request, token, &mut dial, &mut haptic, guest, actions);
+                }
+            }
+
+            if daemon::restart_requested() {
+                restart(mqtt.take());
+            }
+            if let Some(state_file) = state_file.as_mut() {
+                state_file.poll(&dial, haptic.patterns(), Instant::now());
+            }
+            exit_if_idle(exit_when_idle, &dial, &mut mqtt);
+
+            // A profile switch re-reads the settings just like a reload
+            let reload = daemon::reloads() != reloads;
+            let switched = config::profile() != profile;
+            if reload || switched {
+                reloads = daemon::reloads();
+                let loaded =
+                    if reload { config::load(config_path, config_required) } else { Ok(()) };
+                match loaded.and_then(|()| Settings::from_env()) {
+                    Ok(settings) => {
+                        configure_dial(&mut dial, &settings);
+                        haptic.set_triggers(settings.triggers);
+                        haptic.set_strength(settings.strength);
+                        haptic.set_muted(false);
+                        desktop.set_counts_per_revolution(settings.counts_per_revolution);
+                        clicks.window = settings.click_window;
+                        clicks.max = settings.click_max;
+                        reports = settings.reports;
+                        fast = settings.fast;
+                        night_limit = settings.night_limit;
+                        last_schedule_check = None;
+                        guest.reconfigure(settings.guest);
+                        idle_hooks = settings.idle_hooks;
+                        throttle.reconfigure(settings.throttle);
+                        restart_token = settings.restart_token;
+                        gestures = settings.gestures;
+                        latency = settings.latency;
+                        exit_when_idle = settings.exit_when_idle;
+                        exit_with_device = settings.exit_with_device;
+                        // Only when the setting changed, so a level picked at
+                        // runtime survives unrelated reloads
+                        if settings.log_level != log_level {
+                            if let Some(level) = settings.log_level {
+                                diald::set_log_level(level);
+                            }
+                            log_level = settings.log_level;
+                        }
+                        let broker = BrokerSettings::from_env();
+                        if mqtt.as_ref().is_none_or(|handle| handle.broker != broker) {
+                            if let Some(handle) = mqtt.take() {
+                                let _ = handle.client.disconnect();
+                            }
+                            mqtt = spawn_mqtt(monitor, false, sleeper.waker());
+                            guest_published = None;
+                            throttle_published = None;
+                            haptics_published = None;
+                            info_published = None;
+                            profile_published = None;
+                            config_published = None;
+                        }
+                        profile = config::profile();
+                        if reload {
+                            log!("diald: configuration reloaded");
+                        }
+                    }
+                    Err(err) if reload => {
+                        config::use_profile(profile.as_deref());
+                        warn!("diald: reload failed, keeping the running settings ({})", err)
                     }
+                    Err(err) => {
+                        let name = config::profile().unwrap_or_default();
+                        config::use_profile(profile.as_deref());
+                        warn!(
+                            "diald: profile {} doesn't apply, keeping the old one ({})",
+                            name,
+                            err
+                        )
+                    }
+                }
+            }
+            if profile_published.as_ref() != Some(&profile) {
+                if let Some(ref handle) = mqtt {
+                    handle.publish("profile", true, profile.as_deref().unwrap_or("default"));
+                }
+                profile_published = Some(profile.clone());
+            }
+            let effective = effective_config(&dial, &haptic, &clicks);
+            if config_published.as_ref() != Some(&effective) {
+                if let Some(ref handle) = mqtt {
+                    handle.publish("config", true, effective.clone());
+                }
+                config_published = Some(effective);
+            }
+
+            if let (Some(focus), Some(app_tools)) = (&focus, &app_tools) {
+                while let Ok(names) = focus.try_recv() {
+                    // Guests keep whatever tool the dial was left on
+                    if !guest.is_active() {
+                        dial.set_tool(app_tools.tool_for(&names).unwrap_or(default_tool));
+                    }
+                }
+            }
+
+            if guest.poll(Instant::now()) {
+                log!("diald: guest mode expired");
+            }
+            if guest_published != Some(guest.is_active()) {
+                guest_published = Some(guest.is_active());
+                last_schedule_check = None;
+                if let Some(ref handle) = mqtt {
+                    let state = if guest.is_active() { "on" } else { "off" };
+                    handle.publish("guest", true, state);
+                }
+            }
+
+            match throttle.poll(Instant::now()) {
+                Some(thermal::Change::Throttled(reason)) => {
+                    warn!("diald: host too hot or busy ({}), throttling", reason);
+                    LOGGING_THROTTLED.store(true, Ordering::Relaxed);
+                    dial.set_publish_interval(THROTTLED_PUBLISH_INTERVAL);
+                }
+                Some(thermal::Change::Recovered) => {
+                    LOGGING_THROTTLED.store(false, Ordering::Relaxed);
+                    log!("diald: host recovered, no longer throttling");
+                    dial.set_publish_interval(dial::PUBLISH_INTERVAL);
+                }
+                None => {}
+            }
+            if throttle_published != Some(throttle.is_throttled()) {
+                throttle_published = Some(throttle.is_throttled());
+                if let Some(ref handle) = mqtt {
+                    let state = if throttle.is_throttled() { "on" } else { "off" };
+                    handle.publish("throttled", true, state);
+                }
+            }
+
+            if let Some(updates) = updates.as_mut() {
+                updates.poll();
+            }
+            let available = updates.as_ref().and_then(|u| u.available()).map(str::to_string);
+            if info_published.as_ref() != Some(&available) {
+                if let Some(ref handle) = mqtt {
+                    handle.publish("info", true, update::info(available.as_deref()));
+                }
+                info_published = Some(available);
+            }
+
+            if last_schedule_check.is_none_or(|t| t.elapsed() >= Duration::from_secs(1)) {
+                last_schedule_check = Some(Instant::now());
+                let night_cap = night_limit.as_mut().and_then(|limit| limit.cap(LocalTime::now()));
+                let cap = night_cap.into_iter().chain(guest.cap()).min().unwrap_or(100);
+                if cap != dial.max_volume() {
+                    log!("diald: max volume -> {}", cap);
                 }
+                // Also re-applied while unchanged, to pull the volume down once idle
+                dial.set_max_volume(cap, &mut actions);
+            }
+
+            if let Some(link) = link.as_mut()
+                && let Some(quality) = link.poll()
+            {
+                if quality.rssi < WEAK_RSSI {
+                    warn!("diald: weak bluetooth signal ({} dBm)", quality.rssi);
+                }
+                if let Some(ref handle) = mqtt {
+                    let tx_power = quality.tx_power.map_or("null".to_string(), |p| p.to_string());
+                    let payload = format!(
+                        "{{\"rssi\":{},\"tx_power\":{},\"dropouts\":{}}}",
+                        quality.rssi,
+                        tx_power,
+                        link.dropouts()
+                    );
+                    handle.publish("link", false, payload);
+                }
+            }
+            if let Some(status) = bluetooth.as_mut().and_then(|bluetooth| bluetooth.poll()) {
+                publish_bluetooth(status, &mqtt);
             }
 
+            dial.tick(Instant::now(), &mut actions);
+
+            // Checked before new events so an idle edge from a reset or the
+            // timeout is seen even if the dial is turned again right away
+            if dial.mode() != last_mode {
+                if dial.mode() == DialMode::Idle {
+                    let (started, published) = (Instant::now(), publish_time(&mqtt));
+                    let hook = on_idle(&idle_hooks, &dial, &guest, monitor, &mqtt);
+                    let stages = [
+                        (Stage::Broker, publish_time(&mqtt).saturating_sub(published)),
+                        (Stage::Hook, hook),
+                    ];
+                    check_latency(&mut latency, started.elapsed(), &stages, &mqtt);
+                }
+                last_mode = dial.mode();
+            }
+
+            let mut would_block = false;
             let events = match device.fetch_events() {
                 Ok(events) => events,
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(10));
-                    continue;
+                    would_block = true;
+                    Vec::new()
                 }
                 Err(err) => {
-                    log!("diald: lost device {} ({})", device_path.display(), err);
+                    warn!("diald: lost device {} ({})", active_path.display(), err);
+                    if exit_with_device {
+                        log!("diald: device gone, exiting");
+                        shut_down(mqtt.take(), 0);
+                    }
                     log!("diald: state -> disconnected");
+                    if let Some(nudger) = &nudger {
+                        nudger.set_missing(true);
+                    }
+                    if let Some(bluetooth) = &bluetooth {
+                        bluetooth.refresh();
+                    }
                     break;
                 }
             };
+            // Handling the events is measured from here for the latency budget
+            let read_at = Instant::now();
+            let (written, published) = (haptic.write_time(), publish_time(&mqtt));
 
-            for event in events {
-                if state.mode == DialMode::Idle {
-                    state.set_mode(DialMode::Active);
-                    haptic.send_chunky();
-                }
-                state.last_event_at = Some(Instant::now());
+            let broker_up = mqtt.as_ref().is_some_and(|handle| handle.is_connected());
+            if let Some(led) = &status_led {
+                led.set(if broker_up { Status::Ok } else { Status::BrokerDown });
+            }
+            let turned = events
+                .iter()
+                .any(|event| matches!(event, InputEvent::Rotate(_) | InputEvent::Detent(_)));
+            if let Some(link) = link.as_mut()
+                && !events.is_empty()
+            {
+                link.note_events(Instant::now(), dial.mode() != DialMode::Idle);
+            }
 
-                match event.kind() {
-                    InputEventKind::RelAxis(RelativeAxisType::REL_DIAL) => {
-                        if state.clicking {
-                            continue;
+            let handled = !events.is_empty();
+            if handled {
+                daemon::note_input();
+            }
+            let volume_before = dial.volume();
+            for event in events {
+                trace!("diald: event {:?}", event);
+                dial.handle(event, Instant::now(), &mut actions);
+                publish_fast(fast, &mut fast_volume, &dial, &reports, &mut haptic, &mqtt);
+            }
+            // Changes from commands and the night limit too
+            publish_fast(fast, &mut fast_volume, &dial, &reports, &mut haptic, &mqtt);
+            haptic.turned(dial.volume() - volume_before);
+            haptic.set_volume(dial.volume());
+
+            for action in actions.drain(..) {
+                match action {
+                    // Let the user know before they commit to a big turn that
+                    // it won't reach anything
+                    Action::Buzz(Trigger::Wake) if !broker_up => {
+                        haptic.buzz_error(Trigger::Wake);
+                        last_offline_buzz = Some(Instant::now());
+                    }
+                    Action::Buzz(trigger) => haptic.buzz(trigger),
+                    Action::Volume(volume) => {
+                        let value = volume as i64;
+                        if !fast.reports {
+                            for report in reports.render(ReportEvent::Volume, value, value) {
+                                haptic.send_report(&report);
+                            }
                         }
 
-                        // Track direction for backlash detection
-                        let direction = event.value().signum();
-                        let direction_changed = state.last_raw_direction != 0
-                            && direction != state.last_raw_direction;
-
-                        if direction_changed {
-                            // Direction changed - enter backlash mode
-                            if state.mode != DialMode::Backlash {
-                                log!(
-                                    "diald: entering backlash (direction {} -> {})",
-                                    state.last_raw_direction,
-                                    direction
-                                );
-                                state.pre_backlash_direction = state.last_raw_direction;
-                                state.mode = DialMode::Backlash;
-                            }
-                            state.consistent_direction_count = 1;
-                        } else if direction == state.last_raw_direction {
-                            state.consistent_direction_count += 1;
+                        // Publish to MQTT
+                        if let Some(ref handle) = mqtt {
+                            handle.publish("volume", true, dial.model().format(volume));
                         }
-                        state.last_raw_direction = direction;
-
-                        // Push event to delay buffer - returns aged-out event (if any)
-                        let delayed = delay_buffer.push(event.value());
-
-                        // Handle based on mode
-                        if state.mode == DialMode::Backlash {
-                            // In backlash mode: don't commit delayed events, wait for stability
-
-                            // Check for exit conditions
-                            if direction == state.pre_backlash_direction
-                                && state.consistent_direction_count >= BACKLASH_CANCEL_THRESHOLD
-                            {
-                                // False positive - cancel backlash, release ALL buffered events
-                                let buffered = delay_buffer.drain_all();
-                                log!("diald: canceling backlash (buffered={})", buffered);
-                                state.raw_accumulator += buffered;
-                                state.mode = DialMode::Active;
-                            } else if state.consistent_direction_count >= BACKLASH_THRESHOLD as u32 {
-                                // Confirmed direction change - release only matching events
-                                let buffered = delay_buffer.drain_matching(direction);
-                                log!(
-                                    "diald: exiting backlash (stable for {} events, buffered={})",
-                                    state.consistent_direction_count,
-                                    buffered
-                                );
-                                state.raw_accumulator += buffered;
-                                state.mode = DialMode::Active;
-                                haptic.send_chunky();
-                            }
-                            // else: stay in backlash mode, continue buffering
-                        } else {
-                            // Normal mode: commit delayed events as they age out
-                            // 40 raw = 1 volume unit (400 raw = 10 volume)
-                            if let Some(value) = delayed {
-                                state.raw_accumulator += value;
-                            }
+                    }
+                    Action::Click => clicks.push(Instant::now()),
+                    Action::LongPress => {
+                        if let Some(limit) = night_limit.as_mut()
+                            && !guest.is_active()
+                            && limit.override_tonight(LocalTime::now())
+                        {
+                            log!("diald: night limit overridden until morning");
+                            last_schedule_check = None;
                         }
-
-                        let volume_delta = state.raw_accumulator / 40;
-                        if volume_delta != 0 {
-                            state.raw_accumulator -= volume_delta * 40;
-
-                            let unclamped = state.volume + volume_delta as f64;
-                            state.volume = unclamped.clamp(0.0, 100.0);
-
-                            // Buzz at boundaries (trying to go past 0 or 100)
-                            if unclamped < 0.0 || unclamped > 100.0 {
-                                haptic.send_chunky();
-                            }
-
-                            // Check if we should print
-                            let current_volume = state.volume.round() as i32;
-                            let old_tens = state.last_printed_volume / 10;
-                            let new_tens = current_volume / 10;
-                            let crossed_ten = old_tens != new_tens;
-
-                            let now = Instant::now();
-                            let time_to_print = state
-                                .last_print_at
-                                .map(|t| now.duration_since(t) >= Duration::from_millis(250))
-                                .unwrap_or(true);
-
-                            let volume_changed = current_volume != state.last_printed_volume;
-
-                            if crossed_ten || (volume_changed && time_to_print) {
-                                log!("diald: volume {}", current_volume);
-                                state.last_print_at = Some(now);
-                                state.last_printed_volume = current_volume;
-
-                                // Publish to MQTT
-                                if let Some(ref handle) = mqtt {
-                                    let _ = handle.client.publish(
-                                        "home/diald/volume",
-                                        QoS::AtLeastOnce,
-                                        false,
-                                        current_volume.to_string(),
-                                    );
-                                }
-                            }
+                        if let Some(ref handle) = mqtt {
+                            handle.publish("long_press", false, "1");
+                        }
+                        let effects = gestures.effects(dial.tool(), Gesture::LongPress);
+                        apply_gesture(effects, &mut dial, &mut haptic, &mqtt);
+                    }
+                    Action::PressTurn(steps) => {
+                        if let Some(ref handle) = mqtt {
+                            handle.publish("press_turn", false, steps.to_string());
                         }
+                        let effects = gestures.effects(dial.tool(), Gesture::PressTurn);
+                        apply_gesture(effects, &mut dial, &mut haptic, &mqtt);
                     }
-                    InputEventKind::Key(Key::BTN_0) => {
-                        if event.value() == 1 {
-                            state.clicking = true;
-                        } else if state.clicking {
-                            state.clicking = false;
-                            batcher.push("click");
+                    Action::RotationIgnored(steps) => {
+                        warn!(
+                            "diald: ignored {} steps turned with the button held \
+                             (DIALD_PRESS_TURN=1 makes them a press_turn)",
+                            steps
+                        );
+                        if let Some(ref handle) = mqtt {
+                            handle.publish("ignored_rotation", false, steps.to_string());
                         }
                     }
-                    _ => {}
+                    Action::Desktop { .. } if monitor => {}
+                    Action::Desktop { delta, horizontal } => {
+                        desktop.rotate(dial.tool(), delta, horizontal, Instant::now())
+                    }
+                    Action::Backlash(event) => {
+                        let payload = event.payload();
+                        log!("diald: {}", payload);
+                        if let Some(ref handle) = mqtt {
+                            handle.publish("backlash", false, payload);
+                        }
+                    }
+                }
+            }
+
+            let value = dial.model().format(dial.volume());
+            let share = dial.volume() as f64 / dial.model().range().max(1) as f64;
+            device.show_value(&value, share);
+            if let Some(display) = display.as_mut() {
+                display.set_volume(&value, share);
+                display.set_status(dial.mode(), broker_up, guest.is_active());
+                display.flush();
+            }
+
+            // Rate-limited so a long turn doesn't rattle all the way through
+            if turned
+                && !broker_up
+                && last_offline_buzz.is_none_or(|t| t.elapsed() >= OFFLINE_BUZZ_INTERVAL)
+            {
+                last_offline_buzz = Some(Instant::now());
+                haptic.buzz(Trigger::Offline);
+            }
+
+            haptic.pace_detents(Instant::now());
+
+            if handled {
+                let stages = [
+                    (Stage::Haptics, haptic.write_time() - written),
+                    (Stage::Broker, publish_time(&mqtt).saturating_sub(published)),
+                ];
+                check_latency(&mut latency, read_at.elapsed(), &stages, &mqtt);
+            }
+
+            if would_block {
+                if throttle.is_throttled() {
+                    thread::sleep(THROTTLED_POLL_INTERVAL);
+                } else {
+                    // Sleep until something is due; the device, a command or
+                    // a control request wakes the loop early
+                    let now = Instant::now();
+                    let due = [dial.next_deadline(now), clicks.deadline, haptic.next_detent(now)];
+                    let timeout = match due.into_iter().flatten().min() {
+                        Some(at) => at.saturating_duration_since(now),
+                        None => IDLE_WAIT,
+                    };
+                    sleeper.sleep(device.raw_fd(), timeout.min(IDLE_WAIT));
                 }
             }
         }
@@ -0,0 +1,138 @@
+//! What a dial remembers across restarts: its volume, tool and the haptic
+//! patterns defined at runtime, in a small TOML file in the state directory,
+//! so a power blip doesn't put it back to 50. The file is only written once
+//! the dial is at rest and at most every 30 seconds, to spare SD cards, and
+//! replaced in one rename so a crash never leaves half of it behind.
+//!
+//! `DIALD_STATE_FILE` moves it or, with `off`, switches it off. By default
+//! it is `state.toml`, or `state-<name>.toml` for a `[[device]]` section.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use toml::{Table, Value};
+
+use crate::config;
+use crate::dial::{Dial, DialMode, Tool};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What was saved, as the configured range's value (e.g. `42` or `-23.5`).
+pub struct Saved {
+    pub volume: Option<f64>,
+    pub tool: Option<Tool>,
+    pub patterns: Vec<(String, Vec<u8>)>,
+}
+
+pub struct StateFile {
+    path: PathBuf,
+    /// The file's contents as last written or read, else the state diald
+    /// started in.
+    written: Option<String>,
+    last_write: Option<Instant>,
+}
+
+impl StateFile {
+    pub fn from_env() -> Option<Self> {
+        let path = match config::var("DIALD_STATE_FILE") {
+            Ok(path) if path == "off" => return None,
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let name = match config::dial_name() {
+                    Some(name) => format!("state-{}.toml", name),
+                    None => "state.toml".to_string(),
+                };
+                config::state_dir().join(name)
+            }
+        };
+        Some(Self { path, written: None, last_write: None })
+    }
+
+    /// What the last run left; nothing on the first one. A file that can't
+    /// be read is logged and otherwise ignored.
+    pub fn load(&mut self) -> Saved {
+        let mut saved = Saved { volume: None, tool: None, patterns: Vec::new() };
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return saved,
+            Err(err) => {
+                warn!("diald: failed to read {} ({})", self.path.display(), err);
+                return saved;
+            }
+        };
+        let table = match text.parse::<Table>() {
+            Ok(table) => table,
+            Err(err) => {
+                warn!("diald: ignoring {} ({})", self.path.display(), err.message());
+                return saved;
+            }
+        };
+        saved.volume = match table.get("volume") {
+            Some(Value::Integer(volume)) => Some(*volume as f64),
+            Some(Value::Float(volume)) => Some(*volume),
+            _ => None,
+        };
+        saved.tool = table.get("tool").and_then(Value::as_str).and_then(Tool::parse);
+        let patterns = table.get("patterns").and_then(Value::as_table);
+        for (name, report) in patterns.into_iter().flatten() {
+            let report = report.as_array().and_then(|bytes| {
+                bytes.iter().map(|byte| u8::try_from(byte.as_integer()?).ok()).collect()
+            });
+            match report {
+                Some(report) => saved.patterns.push((name.clone(), report)),
+                None => warn!("diald: ignoring saved pattern {}, expected bytes", name),
+            }
+        }
+        self.written = Some(text);
+        saved
+    }
+
+    /// Save the dial's volume, tool and `patterns` if they changed, once it
+    /// is at rest and the last write is long enough ago.
+    pub fn poll(&mut self, dial: &Dial, patterns: &[(String, Vec<u8>)], now: Instant) {
+        if dial.mode() != DialMode::Idle
+            || self.last_write.is_some_and(|t| now - t < SAVE_INTERVAL)
+        {
+            return;
+        }
+        let mut text = format!(
+            "volume = {}\ntool = \"{}\"\n",
+            dial.model().format(dial.volume()),
+            dial.tool().as_str()
+        );
+        if !patterns.is_empty() {
+            text.push_str("\n[patterns]\n");
+            for (name, report) in patterns {
+                text.push_str(&format!("{} = {:?}\n", name, report));
+            }
+        }
+        // Without a file, what diald started with is nothing worth a write
+        if self.written.is_none() {
+            self.written = Some(text);
+            return;
+        }
+        if self.written.as_ref() == Some(&text) {
+            return;
+        }
+        self.last_write = Some(now);
+        if crate::DRY_RUN.load(Ordering::Relaxed) {
+            log!("diald: would save {}", self.path.display());
+        } else if let Err(err) = self.write(&text) {
+            warn!("diald: failed to save {} ({})", self.path.display(), err);
+            return;
+        }
+        self.written = Some(text);
+    }
+
+    fn write(&self, text: &str) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("toml.tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
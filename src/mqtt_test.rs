@@ -0,0 +1,186 @@
+//! `diald mqtt-test`: try the broker on its own, with the configured address,
+//! credentials and topics, to tell broker problems from dial problems during
+//! setup. It subscribes to the command topics, echoes a message through the
+//! broker and checks that retained messages are kept, which diald's state
+//! topics rely on.
+
+use std::time::{Duration, Instant};
+
+use rumqttc::{
+    Client, Connection, Event, MqttOptions, Outgoing, Packet, QoS, SubscribeFilter,
+    SubscribeReasonCode,
+};
+
+use crate::payload;
+
+/// How long each step may wait for the broker.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn millis(elapsed: Duration) -> String {
+    format!("{:.1} ms", elapsed.as_secs_f64() * 1000.0)
+}
+
+/// Wait until `found` picks something out of an incoming packet.
+fn wait_for<T>(
+    connection: &mut Connection,
+    mut found: impl FnMut(&Packet) -> Option<T>,
+) -> Result<T, String> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match connection.recv_timeout(left) {
+            Ok(Ok(Event::Incoming(packet))) => {
+                if let Some(value) = found(&packet) {
+                    return Ok(value);
+                }
+            }
+            Ok(Ok(Event::Outgoing(_))) => {}
+            Ok(Err(err)) => return Err(err.to_string()),
+            Err(_) => return Err(format!("no answer within {} seconds", TIMEOUT.as_secs())),
+        }
+    }
+}
+
+/// Publish `payload` at QoS 1 and wait for the broker's ack as well as for
+/// it to come back on `topic`. Returns how long each took.
+fn echo(
+    client: &Client,
+    connection: &mut Connection,
+    topic: &str,
+    retain: bool,
+    payload: &str,
+) -> Result<(Duration, Duration), String> {
+    let sent = Instant::now();
+    client.publish(topic, QoS::AtLeastOnce, retain, payload).map_err(|err| err.to_string())?;
+    let (mut acked, mut echoed) = (None, None);
+    wait_for(connection, |packet| {
+        match packet {
+            Packet::PubAck(_) => acked = acked.or(Some(sent.elapsed())),
+            Packet::Publish(publish) if publish.topic == topic && publish.payload == payload => {
+                echoed = Some(sent.elapsed())
+            }
+            _ => {}
+        }
+        acked.zip(echoed)
+    })
+}
+
+/// Run the checks and print each result; true when all passed.
+pub fn run(
+    host: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+    prefix: &str,
+    command_prefix: &str,
+) -> bool {
+    let mut passed = true;
+    let mut check = |what: &str, result: Result<String, String>| {
+        let (status, detail) = match result {
+            Ok(detail) => ("ok", detail),
+            Err(err) => {
+                passed = false;
+                ("FAIL", err)
+            }
+        };
+        println!("{:<5} {:<9} {}", status, what, detail);
+    };
+
+    let mut opts = MqttOptions::new("diald-mqtt-test", host, port);
+    opts.set_keep_alive(Duration::from_secs(30));
+    if let Some((user, pass)) = credentials {
+        opts.set_credentials(user, pass);
+    }
+    let (client, mut connection) = Client::new(opts, 10);
+    let address = format!("{}:{}", host, port);
+    let started = Instant::now();
+    let connected = wait_for(&mut connection, |packet| {
+        matches!(packet, Packet::ConnAck(_)).then(|| started.elapsed())
+    });
+    match connected {
+        Ok(elapsed) => check("connect", Ok(format!("{} in {}", address, millis(elapsed)))),
+        Err(err) => {
+            check("connect", Err(format!("{} ({})", address, err)));
+            return false;
+        }
+    }
+
+    // The broker's ACL shows in the QoS it grants each filter, or refuses
+    let echo_topic = format!("{}/mqtt-test", prefix);
+    let mut topics: Vec<String> =
+        payload::SUBSCRIBED.iter().map(|name| format!("{}/{}", command_prefix, name)).collect();
+    topics.push(echo_topic.clone());
+    let filters = topics.iter().map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtLeastOnce));
+    let granted = client
+        .subscribe_many(filters)
+        .map_err(|err| err.to_string())
+        .and_then(|()| {
+            wait_for(&mut connection, |packet| match packet {
+                Packet::SubAck(ack) => Some(ack.return_codes.clone()),
+                _ => None,
+            })
+        });
+    match granted {
+        Ok(codes) => {
+            for (topic, code) in topics.iter().zip(codes) {
+                let result = match code {
+                    SubscribeReasonCode::Success(qos) => {
+                        Ok(format!("{} (QoS {})", topic, qos as u8))
+                    }
+                    SubscribeReasonCode::Failure => {
+                        Err(format!("{} refused; check the broker's ACL", topic))
+                    }
+                };
+                check("subscribe", result);
+            }
+        }
+        Err(err) => check("subscribe", Err(err)),
+    }
+
+    let nonce = format!("diald {} {}", std::process::id(), started.elapsed().as_nanos());
+    let echoed = echo(&client, &mut connection, &echo_topic, false, &nonce);
+    check(
+        "echo",
+        echoed.map(|(acked, echoed)| {
+            format!("round trip {} (acknowledged in {})", millis(echoed), millis(acked))
+        }),
+    );
+
+    // Published first and subscribed to afterwards, so it only comes back if
+    // the broker kept it
+    let retained_topic = format!("{}/mqtt-test/retained", prefix);
+    let kept = client
+        .publish(&retained_topic, QoS::AtLeastOnce, true, nonce.clone())
+        .map_err(|err| err.to_string())
+        .and_then(|()| wait_for(&mut connection, |p| matches!(p, Packet::PubAck(_)).then_some(())))
+        .and_then(|()| {
+            client
+                .subscribe(&retained_topic, QoS::AtLeastOnce)
+                .map_err(|err| err.to_string())
+        })
+        .and_then(|()| {
+            wait_for(&mut connection, |packet| match packet {
+                Packet::Publish(publish) => (publish.topic == retained_topic
+                    && publish.retain
+                    && publish.payload == nonce)
+                    .then_some(()),
+                _ => None,
+            })
+        });
+    check(
+        "retain",
+        kept.map(|()| "retained messages are kept".to_string()).map_err(|err| {
+            format!("not kept ({}); the state, guest and info topics rely on them", err)
+        }),
+    );
+    // An empty retained message clears it again
+    let _ = client.publish(&retained_topic, QoS::AtLeastOnce, true, "");
+    let _ = wait_for(&mut connection, |packet| matches!(packet, Packet::PubAck(_)).then_some(()));
+
+    let _ = client.disconnect();
+    while let Ok(Ok(event)) = connection.recv_timeout(Duration::from_millis(500)) {
+        if matches!(event, Event::Outgoing(Outgoing::Disconnect)) {
+            break;
+        }
+    }
+    passed
+}
@@ -0,0 +1,404 @@
+//! Parsing of inbound MQTT payloads, kept apart from the client so it can be
+//! fuzzed without a broker.
+
+use std::time::Duration;
+
+use crate::config;
+use crate::Level;
+use crate::haptics::Trigger;
+
+pub const DEFAULT_PREFIX: &str = "home/diald";
+
+/// Topics diald subscribes to, under the prefix.
+pub const SUBSCRIBED: &[&str] = &[
+    "volume/set",
+    "volume/adjust",
+    "reset",
+    "haptics/set",
+    "guest/set",
+    "profile/set",
+    "log_level/set",
+    "config/set",
+    "restart",
+    "request",
+];
+
+/// Whether messages on a subscribed topic need `DIALD_COMMAND_TOKEN`, when
+/// it's set: all of them but `restart`, which checks its own token.
+pub fn is_protected(name: &str) -> bool {
+    name != "restart"
+}
+
+/// Settings `home/diald/config/set` can change at runtime; `None` leaves one
+/// as it is.
+#[derive(Debug, Default, PartialEq)]
+pub struct Tuning {
+    /// Raw counts per volume step, or 0 for the volume model's own.
+    pub counts_per_step: Option<i32>,
+    /// How long a click burst stays open; zero sends every click on its own.
+    pub click_window: Option<Duration>,
+    /// Whether the dial buzzes at all.
+    pub haptics: Option<bool>,
+    /// A haptic pattern to define or, without a report, remove.
+    pub pattern: Option<(String, Option<Vec<u8>>)>,
+}
+
+/// What a `request` message asks for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Query {
+    Volume,
+    Config,
+    Diagnostics,
+}
+
+impl Query {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "volume" => Some(Query::Volume),
+            "config" => Some(Query::Config),
+            "diagnostics" => Some(Query::Diagnostics),
+            _ => None,
+        }
+    }
+}
+
+/// A question asked over MQTT; the answer goes to `home/diald/response`
+/// with the same `id`, so the asker can pick its own out.
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub id: String,
+    /// `None` for a query diald doesn't know, which is answered with an error.
+    pub query: Option<Query>,
+}
+
+/// What an inbound message asks diald to do.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `home/diald/volume/set`: absolute volume, in the configured range.
+    SetVolume(f64),
+    /// `home/diald/volume/adjust`: relative change, e.g. `+5` or `-2`.
+    AdjustVolume(f64),
+    /// `home/diald/reset`: drop any in-flight gesture, optionally re-syncing
+    /// the volume from the payload (empty payload keeps the current volume).
+    /// `{"token": ..., "volume": 30}` works too.
+    Reset(Option<f64>),
+    /// `home/diald/haptics/set`: switch one haptic trigger, e.g. `boundary off`.
+    Haptics(Trigger, bool),
+    /// `home/diald/guest/set`: `on`, `on 2h` or `off`.
+    GuestOn(Option<Duration>),
+    GuestOff,
+    /// `home/diald/profile/set`: a `[[profile]]` name, or `default` for none.
+    Profile(Option<String>),
+    /// `home/diald/log_level/set`: `error`, `warn`, `info`, `debug` or
+    /// `trace`.
+    LogLevel(Level),
+    /// `home/diald/config/set`: e.g.
+    /// `{"counts_per_step": 8, "click_window": 400, "haptics": false}`, or
+    /// `{"pattern": "boundary 1 2 3 90 0"}`.
+    Config(Tuning),
+    /// `home/diald/restart`: exit so the service manager restarts diald. The
+    /// payload is the token from `DIALD_RESTART_TOKEN`, bare or as
+    /// `{"token": ...}`.
+    Restart(String),
+    /// `home/diald/request`: e.g. `{"id": "a1", "query": "volume"}`, for
+    /// `volume`, `config` or `diagnostics`.
+    Request(Request),
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of a guess
+/// was right.
+pub fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// `text` as a JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON string without escapes, and what follows it.
+fn parse_string(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix('"')?;
+    let end = text.find('"')?;
+    let string = &text[..end];
+    (!string.contains('\\')).then_some((string, &text[end + 1..]))
+}
+
+/// The fields of a flat JSON object, e.g. `{"token": "s3cret", "volume": 30}`.
+/// Values are returned as written, minus the quotes around strings.
+fn parse_object(text: &str) -> Option<Vec<(&str, &str)>> {
+    let mut rest = text.trim().strip_prefix('{')?.strip_suffix('}')?.trim();
+    let mut fields = Vec::new();
+    while !rest.is_empty() {
+        let (key, after) = parse_string(rest)?;
+        let after = after.trim_start().strip_prefix(':')?.trim_start();
+        let (value, after) = if after.starts_with('"') {
+            parse_string(after)?
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].trim(), &after[end..])
+        };
+        fields.push((key, value));
+        rest = after.trim_start();
+        if let Some(next) = rest.strip_prefix(',') {
+            rest = next.trim_start();
+            if rest.is_empty() {
+                return None;
+            }
+        } else if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(fields)
+}
+
+fn field<'a>(fields: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    fields.iter().find(|(key, _)| *key == name).map(|(_, value)| *value)
+}
+
+/// The `token` field of a JSON payload.
+pub fn token(payload: &[u8]) -> Option<&str> {
+    field(&parse_object(std::str::from_utf8(payload).ok()?)?, "token")
+}
+
+/// A plain number, e.g. `30` or `-23.5`, surrounding whitespace allowed.
+/// Range checking is left to the dial, which clamps.
+pub fn parse_number(text: &str) -> Option<f64> {
+    text.trim().parse().ok().filter(|number: &f64| number.is_finite())
+}
+
+/// A trigger name and `on`/`off`.
+pub fn parse_haptics(trigger: &str, state: &str) -> Option<Command> {
+    let enabled = match state {
+        "on" => true,
+        "off" => false,
+        _ => return None,
+    };
+    Some(Command::Haptics(Trigger::parse(trigger)?, enabled))
+}
+
+/// A `config/set` payload. An unknown setting or a value out of range
+/// refuses the whole message, so a typo never applies half of it.
+fn parse_tuning(text: &str) -> Option<Tuning> {
+    let mut tuning = Tuning::default();
+    for (key, value) in parse_object(text)? {
+        match key {
+            "counts_per_step" => {
+                let counts = value.parse().ok().filter(|c| (0..=10_000).contains(c))?;
+                tuning.counts_per_step = Some(counts);
+            }
+            "click_window" => {
+                let ms = value.parse().ok().filter(|ms| *ms == 0 || (50..=2000).contains(ms))?;
+                tuning.click_window = Some(Duration::from_millis(ms));
+            }
+            "haptics" => {
+                tuning.haptics = Some(match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return None,
+                })
+            }
+            "pattern" => {
+                let words: Vec<&str> = value.split_whitespace().collect();
+                tuning.pattern = Some(parse_pattern(&words)?);
+            }
+            "token" => {}
+            _ => return None,
+        }
+    }
+    Some(tuning)
+}
+
+/// `90s`, `30m` or `2h`; a bare number is minutes.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "m"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.parse::<u64>().ok()?.checked_mul(seconds)?))
+}
+
+/// `on`, `on DURATION` or `off`.
+pub fn parse_guest(words: &[&str]) -> Option<Command> {
+    match words {
+        ["on"] => Some(Command::GuestOn(None)),
+        ["on", duration] => parse_duration(duration).map(|d| Command::GuestOn(Some(d))),
+        ["off"] => Some(Command::GuestOff),
+        _ => None,
+    }
+}
+
+/// `<name> <byte>...` for a haptic pattern as a raw output report, report ID
+/// first (e.g. `boundary 1 2 3 90 0`, bytes decimal or `0x..`), or
+/// `<name> off` to remove it.
+pub fn parse_pattern(words: &[&str]) -> Option<(String, Option<Vec<u8>>)> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    let (name, bytes) = words.split_first()?;
+    if name.is_empty() || !name.chars().all(valid) {
+        return None;
+    }
+    let report = match bytes {
+        [] => return None,
+        ["off"] => None,
+        bytes => Some(bytes.iter().map(|byte| parse_byte(byte)).collect::<Option<Vec<u8>>>()?),
+    };
+    Some((name.to_string(), report))
+}
+
+fn parse_byte(text: &str) -> Option<u8> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// A profile name; `default` switches back to none.
+pub fn parse_profile(name: &str) -> Option<Command> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    match name {
+        "default" => Some(Command::Profile(None)),
+        name if !name.is_empty() && name.chars().all(valid) => {
+            Some(Command::Profile(Some(name.to_string())))
+        }
+        _ => None,
+    }
+}
+
+/// How topics are laid out under the prefix.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// Everything side by side under `home/diald`.
+    Legacy,
+    /// `diald/<device id>/...`, with every subscribed topic under `command/`,
+    /// so a broker ACL can let dashboards read and only control clients
+    /// write (`diald/+/command/#`).
+    Acl,
+}
+
+/// `DIALD_TOPIC_LAYOUT`: `legacy` (the default) or `acl`.
+pub fn layout() -> Result<Layout, String> {
+    match config::var("DIALD_TOPIC_LAYOUT").as_deref() {
+        Err(_) | Ok("legacy") => Ok(Layout::Legacy),
+        Ok("acl") => Ok(Layout::Acl),
+        Ok(other) => Err(format!("DIALD_TOPIC_LAYOUT: expected legacy or acl, got '{}'", other)),
+    }
+}
+
+/// `DIALD_DEVICE_ID`, else the host name, made safe for a topic level.
+fn device_id() -> String {
+    let id = config::var("DIALD_DEVICE_ID").ok().unwrap_or_else(|| {
+        let mut name = [0u8; 256];
+        let len = if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } == 0 {
+            name.iter().position(|&b| b == 0).unwrap_or(name.len())
+        } else {
+            0
+        };
+        String::from_utf8_lossy(&name[..len]).into_owned()
+    });
+    let id: String = id.chars().map(|c| if "/+#".contains(c) { '_' } else { c }).collect();
+    if id.is_empty() { "diald".to_string() } else { id }
+}
+
+/// The topic prefix: `home/diald`, or `diald/<device id>` in the ACL layout,
+/// unless `DIALD_TOPIC_PREFIX` says otherwise.
+pub fn prefix() -> String {
+    match config::var("DIALD_TOPIC_PREFIX") {
+        Ok(prefix) if !prefix.trim_matches('/').is_empty() => {
+            prefix.trim_matches('/').to_string()
+        }
+        _ if layout() == Ok(Layout::Acl) => format!("diald/{}", device_id()),
+        _ => DEFAULT_PREFIX.to_string(),
+    }
+}
+
+/// Where the subscribed topics go under `prefix`.
+pub fn command_prefix(prefix: &str) -> String {
+    match layout() {
+        Ok(Layout::Acl) => format!("{}/command", prefix),
+        _ => prefix.to_string(),
+    }
+}
+
+/// The `value` of a `{"token": ..., "value": ...}` payload, which carries a
+/// plain one (`+5`, `boundary off`) along with the command token.
+fn wrapped(payload: &[u8]) -> Option<&str> {
+    field(&parse_object(std::str::from_utf8(payload).ok()?)?, "value")
+}
+
+/// `name` is the topic under the prefix, e.g. `volume/set`.
+pub fn parse(name: &str, payload: &[u8]) -> Option<Command> {
+    let payload = match name {
+        // JSON of their own
+        "reset" | "config/set" | "restart" | "request" => payload,
+        _ => wrapped(payload).map_or(payload, str::as_bytes),
+    };
+    match name {
+        "volume/set" => parse_number(std::str::from_utf8(payload).ok()?).map(Command::SetVolume),
+        "volume/adjust" => {
+            parse_number(std::str::from_utf8(payload).ok()?).map(Command::AdjustVolume)
+        }
+        "reset" => {
+            // Plain, or JSON for when the payload has to carry a token
+            let text = std::str::from_utf8(payload).ok()?.trim();
+            let volume = match parse_object(text) {
+                Some(fields) => field(&fields, "volume"),
+                None => (!text.is_empty()).then_some(text),
+            };
+            match volume {
+                Some(volume) => parse_number(volume).map(|volume| Command::Reset(Some(volume))),
+                None => Some(Command::Reset(None)),
+            }
+        }
+        "haptics/set" => {
+            let text = std::str::from_utf8(payload).ok()?;
+            match text.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [trigger, state] => parse_haptics(trigger, state),
+                _ => None,
+            }
+        }
+        "guest/set" => {
+            let text = std::str::from_utf8(payload).ok()?;
+            parse_guest(&text.split_whitespace().collect::<Vec<_>>())
+        }
+        "restart" => {
+            let text = std::str::from_utf8(payload).ok()?.trim();
+            let token = match parse_object(text) {
+                Some(fields) => field(&fields, "token")?,
+                None => text,
+            };
+            (!token.is_empty()).then(|| Command::Restart(token.to_string()))
+        }
+        "profile/set" => parse_profile(std::str::from_utf8(payload).ok()?.trim()),
+        "log_level/set" => {
+            Level::parse(std::str::from_utf8(payload).ok()?.trim()).map(Command::LogLevel)
+        }
+        "config/set" => parse_tuning(std::str::from_utf8(payload).ok()?).map(Command::Config),
+        // Without an id there is nowhere to send even an error
+        "request" => {
+            let fields = parse_object(std::str::from_utf8(payload).ok()?)?;
+            let id = field(&fields, "id").filter(|id| !id.is_empty())?.to_string();
+            let query = field(&fields, "query").and_then(Query::parse);
+            Some(Command::Request(Request { id, query }))
+        }
+        _ => None,
+    }
+}
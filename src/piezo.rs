@@ -0,0 +1,99 @@
+//! A piezo buzzer on a GPIO line, for DIY encoder builds without haptic
+//! hardware: it beeps whatever the haptics would play, and clicks at each
+//! volume step.
+//!
+//! `DIALD_PIEZO` = `<chip>:<line>`, e.g. `gpiochip0:18`. Passive buzzers get
+//! a square wave at each sound's pitch; for active buzzers (the kind with
+//! their own oscillator) add `:active` and the line is just held high.
+
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::gpio;
+use crate::haptics::Pattern;
+
+/// `(frequency in Hz, milliseconds)`; a frequency of 0 is a pause.
+type Sound = &'static [(u32, u64)];
+
+const DETENT: Sound = &[(3000, 3)];
+
+fn sound(pattern: Pattern) -> Sound {
+    match pattern {
+        Pattern::Chunky => &[(1800, 60)],
+        Pattern::Greeting => &[(1200, 60), (0, 40), (1600, 60), (0, 40), (2000, 80)],
+        Pattern::Tick => &[(2500, 8)],
+        Pattern::Error => &[(400, 120), (0, 60), (400, 120)],
+    }
+}
+
+pub struct Piezo {
+    queue: SyncSender<Sound>,
+}
+
+impl Piezo {
+    /// `None` when no piezo is configured or the line can't be claimed (which
+    /// is logged).
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(spec) = config::var("DIALD_PIEZO") else {
+            return Ok(None);
+        };
+        let (chip, line, flag) = gpio::parse_spec("DIALD_PIEZO", &spec)?;
+        let active = match flag {
+            None => false,
+            Some("active") => true,
+            Some(flag) => return Err(format!("DIALD_PIEZO: unknown flag '{}'", flag)),
+        };
+        let output = match gpio::Line::request(&chip, line, false) {
+            Ok(output) => output,
+            Err(err) => {
+                warn!("diald: can't claim {} line {} for the piezo ({})", chip, line, err);
+                return Ok(None);
+            }
+        };
+        // A short queue: sounds that can't keep up with the dial are dropped
+        // rather than played late
+        let (queue, sounds) = mpsc::sync_channel::<Sound>(2);
+        thread::spawn(move || {
+            for sound in sounds {
+                if let Err(err) = play(&output, sound, active) {
+                    warn!("diald: piezo stopped ({})", err);
+                    return;
+                }
+            }
+        });
+        Ok(Some(Self { queue }))
+    }
+
+    pub fn play(&self, pattern: Pattern) {
+        let _ = self.queue.try_send(sound(pattern));
+    }
+
+    pub fn detent(&self) {
+        let _ = self.queue.try_send(DETENT);
+    }
+}
+
+fn play(output: &gpio::Line, sound: Sound, active: bool) -> std::io::Result<()> {
+    for &(frequency, ms) in sound {
+        let duration = Duration::from_millis(ms);
+        if frequency == 0 {
+            thread::sleep(duration);
+        } else if active {
+            output.set(true)?;
+            thread::sleep(duration);
+            output.set(false)?;
+        } else {
+            let half_period = Duration::from_micros(500_000 / frequency as u64);
+            let start = Instant::now();
+            while start.elapsed() < duration {
+                output.set(true)?;
+                thread::sleep(half_period);
+                output.set(false)?;
+                thread::sleep(half_period);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! `diald record`: prompt for a series of labelled maneuvers ("turn right",
+//! "reverse quickly", ...) and save the raw events of each to a dataset, so
+//! backlash thresholds can be picked from how a dial actually behaves rather
+//! than guessed.
+//!
+//! The dataset is a trace like `replay:` takes, with each maneuver's events
+//! under a `# maneuver <label>` comment and times counted from the start of
+//! the session, so it can be replayed as a whole as well as split up by
+//! label.
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::input::{self, InputEvent, InputSource};
+
+pub const DEFAULT_PATH: &str = "diald-maneuvers.trace";
+
+/// A maneuver is over once the dial has been still this long.
+const SETTLE: Duration = Duration::from_secs(2);
+/// How long to wait for the user to start.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Each maneuver as `(label, instruction)`.
+pub const MANEUVERS: &[(&str, &str)] = &[
+    ("turn_right", "turn slowly clockwise, about a quarter turn"),
+    ("turn_left", "turn slowly counter-clockwise, about a quarter turn"),
+    ("spin_right", "spin quickly clockwise"),
+    ("spin_left", "spin quickly counter-clockwise"),
+    ("reverse_slowly", "turn clockwise, pause, then turn back"),
+    ("reverse_quickly", "turn clockwise and flick straight back"),
+    ("let_go", "turn clockwise and let go of the dial mid-turn"),
+    ("wiggle", "rest a hand on the dial and wiggle it without meaning to turn"),
+    ("press", "press and release without turning"),
+    ("press_turn", "hold the dial down and turn it"),
+];
+
+/// Enter to go, `s` to skip; `None` once stdin is closed.
+fn prompt(text: &str) -> io::Result<Option<bool>> {
+    print!("{}", text);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim() != "s"))
+}
+
+/// Every event from the first one until the dial has been still for
+/// `SETTLE`, with its time since `session`.
+fn capture(
+    device: &mut dyn InputSource,
+    session: Instant,
+) -> Result<Vec<(Duration, InputEvent)>, Box<dyn Error>> {
+    // Whatever happened before the prompt doesn't count
+    while device.fetch_events().is_ok_and(|events| !events.is_empty()) {}
+
+    let started = Instant::now();
+    let mut events = Vec::new();
+    loop {
+        let fetched = match device.fetch_events() {
+            Ok(fetched) => fetched,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let now = Instant::now();
+        events.extend(fetched.into_iter().map(|event| (now - session, event)));
+        match events.last() {
+            Some((at, _)) if now - session - *at >= SETTLE => return Ok(events),
+            None if started.elapsed() >= START_TIMEOUT => return Ok(events),
+            _ => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+fn write_events(
+    file: &mut File,
+    label: &str,
+    events: &[(Duration, InputEvent)],
+) -> io::Result<()> {
+    writeln!(file, "\n# maneuver {}", label)?;
+    for (at, event) in events {
+        let event = match event {
+            InputEvent::Rotate(value) => format!("rotate {}", value),
+            InputEvent::Detent(value) => format!("detent {}", value),
+            InputEvent::Press => "press".to_string(),
+            InputEvent::Release => "release".to_string(),
+        };
+        writeln!(file, "{} {}", at.as_millis(), event)?;
+    }
+    file.flush()
+}
+
+/// `device` is the one to record, else the first dial found. The settings
+/// the dataset is meant to tune, such as `backlash_threshold`, head it as
+/// comments. Each maneuver is written as soon as it is done, so an
+/// interrupted session keeps what it has.
+pub fn run(
+    device: Option<PathBuf>,
+    dataset: &Path,
+    settings: &[(&str, String)],
+) -> Result<(), Box<dyn Error>> {
+    let path = match device {
+        Some(path) => path,
+        None => match input::scan()?.into_iter().next() {
+            Some((path, _)) => path,
+            None => return Err("no dial found; pass its path with --device".into()),
+        },
+    };
+    let mut device = input::open(&path)?;
+    let name = device.name().unwrap_or_else(|| "unnamed".to_string());
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(dataset)
+        .map_err(|err| format!("{}: {}", dataset.display(), err))?;
+    writeln!(file, "# diald maneuvers from {} ({})", path.display(), name)?;
+    for (key, value) in settings {
+        writeln!(file, "# {} = {}", key, value)?;
+    }
+
+    println!("Recording {} ({}) to {}\n", path.display(), name, dataset.display());
+    println!("Each maneuver ends once the dial has been still for {} seconds.", SETTLE.as_secs());
+    println!("Enter s to skip one.\n");
+
+    let session = Instant::now();
+    let mut recorded = 0;
+    for (label, instruction) in MANEUVERS {
+        match prompt(&format!("{}: press Enter, then {}... ", label, instruction))? {
+            None => break,
+            Some(false) => continue,
+            Some(true) => {}
+        }
+        let events = capture(device.as_mut(), session)?;
+        if events.is_empty() {
+            println!("  nothing happened; skipped");
+            continue;
+        }
+        println!("  {} events", events.len());
+        write_events(&mut file, label, &events)?;
+        recorded += 1;
+    }
+    println!("\nSaved {} maneuvers to {}.", recorded, dataset.display());
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! User-defined HID output reports, for knobs with LEDs or small displays that
+//! diald doesn't know about.
+//!
+//! `DIALD_OUTPUT_REPORTS` holds `;`-separated templates of the form
+//! `<event>: <report id>, <byte expr>, ...`, for example
+//! `volume: 2, volume * 255 / 100; click: 3, value`. Byte expressions see
+//! `value` (the event's value) and `volume`; results are clamped to 0-255.
+
+use crate::config;
+use crate::expr::Expr;
+
+const VARIABLES: &[&str] = &["value", "volume"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReportEvent {
+    /// Published volume changed; `value` is the new volume.
+    Volume,
+    /// A click burst finished; `value` is the click count.
+    Click,
+}
+
+struct ReportTemplate {
+    event: ReportEvent,
+    report_id: u8,
+    bytes: Vec<Expr>,
+}
+
+impl ReportTemplate {
+    fn parse(source: &str) -> Result<Self, String> {
+        let (event, body) = source
+            .split_once(':')
+            .ok_or_else(|| format!("missing '<event>:' in '{}'", source))?;
+        let event = match event.trim() {
+            "volume" => ReportEvent::Volume,
+            "click" => ReportEvent::Click,
+            other => return Err(format!("unknown report event '{}'", other)),
+        };
+
+        let mut fields = body.split(',');
+        let report_id = fields
+            .next()
+            .and_then(|id| parse_u8(id.trim()))
+            .ok_or_else(|| format!("invalid report id in '{}'", source))?;
+        let bytes = fields
+            .map(|field| Expr::parse(field, VARIABLES).map_err(|err| err.to_string()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { event, report_id, bytes })
+    }
+
+    fn render(&self, value: i64, volume: i64) -> Vec<u8> {
+        let lookup = |name: &str| match name {
+            "value" => value,
+            "volume" => volume,
+            _ => 0,
+        };
+        let mut report = vec![self.report_id];
+        report.extend(
+            self.bytes
+                .iter()
+                .map(|expr| expr.eval(&lookup).clamp(0, 255) as u8),
+        );
+        report
+    }
+}
+
+fn parse_u8(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[derive(Default)]
+pub struct OutputReports {
+    templates: Vec<ReportTemplate>,
+}
+
+impl OutputReports {
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(spec) = config::var("DIALD_OUTPUT_REPORTS") else {
+            return Ok(Self::default());
+        };
+        let templates = spec
+            .split(';')
+            .filter(|t| !t.trim().is_empty())
+            .map(ReportTemplate::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|err| format!("DIALD_OUTPUT_REPORTS: {}", err))?;
+        Ok(Self { templates })
+    }
+
+    /// Reports to send for `event`.
+    pub fn render(&self, event: ReportEvent, value: i64, volume: i64) -> Vec<Vec<u8>> {
+        self.templates
+            .iter()
+            .filter(|t| t.event == event)
+            .map(|t| t.render(value, volume))
+            .collect()
+    }
+}
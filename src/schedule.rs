@@ -0,0 +1,487 @@
+//! Time-of-day behavior, evaluated locally: the night limiter.
+
+use std::cell::Cell;
+use std::f64::consts::PI;
+
+use crate::config;
+
+const MINUTES_PER_DAY: i32 = 24 * 60;
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const EVERY_DAY: u8 = 0x7f;
+
+/// The local wall-clock time, as much of it as schedules care about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalTime {
+    /// 0 is Sunday, as in `struct tm`.
+    pub weekday: i32,
+    /// Minutes since local midnight.
+    pub minute: i32,
+    /// 0 is January 1st.
+    pub year_day: i32,
+    /// Minutes ahead of UTC, including daylight saving time.
+    pub utc_offset: i32,
+}
+
+impl LocalTime {
+    pub fn now() -> Self {
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+            return Self { weekday: 0, minute: 0, year_day: 0, utc_offset: 0 };
+        }
+        Self {
+            weekday: tm.tm_wday,
+            minute: tm.tm_hour * 60 + tm.tm_min,
+            year_day: tm.tm_yday,
+            utc_offset: (tm.tm_gmtoff / 60) as i32,
+        }
+    }
+
+    /// The same time one day earlier, for windows that started last evening.
+    fn day_before(self) -> Self {
+        Self {
+            weekday: (self.weekday + 6) % 7,
+            year_day: (self.year_day + 364) % 365,
+            ..self
+        }
+    }
+}
+
+/// Where the dial is, for sunrise and sunset. Read from `DIALD_LOCATION`
+/// as `latitude,longitude` in degrees, east and north positive.
+#[derive(Clone, Copy)]
+pub struct Location {
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Location {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(spec) = config::var("DIALD_LOCATION") else {
+            return Ok(None);
+        };
+        let invalid = || format!("DIALD_LOCATION: expected 'LATITUDE,LONGITUDE', got '{}'", spec);
+        let (latitude, longitude) = spec.split_once(',').ok_or_else(invalid)?;
+        let latitude: f64 = latitude.trim().parse().map_err(|_| invalid())?;
+        let longitude: f64 = longitude.trim().parse().map_err(|_| invalid())?;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(invalid());
+        }
+        Ok(Some(Self { latitude, longitude }))
+    }
+
+    /// Sunrise and sunset in minutes after UTC midnight on `year_day`, using
+    /// NOAA's approximate equations (good to a minute or two). `None` during
+    /// polar day or night.
+    fn sun_times(&self, year_day: i32) -> Option<(f64, f64)> {
+        let gamma = 2.0 * PI / 365.0 * year_day as f64;
+        let equation_of_time = 229.18
+            * (0.000075 + 0.001868 * gamma.cos()
+                - 0.032077 * gamma.sin()
+                - 0.014615 * (2.0 * gamma).cos()
+                - 0.040849 * (2.0 * gamma).sin());
+        let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+            - 0.006758 * (2.0 * gamma).cos()
+            + 0.000907 * (2.0 * gamma).sin()
+            - 0.002697 * (3.0 * gamma).cos()
+            + 0.00148 * (3.0 * gamma).sin();
+        let latitude = self.latitude.to_radians();
+        // 90.833 degrees allows for refraction and the size of the sun's disc
+        let cos_hour_angle = 90.833f64.to_radians().cos() / (latitude.cos() * declination.cos())
+            - latitude.tan() * declination.tan();
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+        let hour_angle = cos_hour_angle.acos().to_degrees();
+        let noon = 720.0 - 4.0 * self.longitude - equation_of_time;
+        Some((noon - 4.0 * hour_angle, noon + 4.0 * hour_angle))
+    }
+}
+
+/// A time of day in a schedule: `HH:MM`, or `sunrise`/`sunset` with an
+/// optional offset in minutes such as `sunset-30`.
+#[derive(Clone, Copy)]
+enum TimeOfDay {
+    Fixed(i32),
+    Sunrise(i32),
+    Sunset(i32),
+}
+
+impl TimeOfDay {
+    fn parse(text: &str) -> Option<Self> {
+        let solar = |rest: &str| match rest {
+            "" => Some(0),
+            rest if rest.starts_with(['+', '-']) => rest.parse().ok(),
+            _ => None,
+        };
+        if let Some(rest) = text.strip_prefix("sunrise") {
+            return solar(rest).map(TimeOfDay::Sunrise);
+        }
+        if let Some(rest) = text.strip_prefix("sunset") {
+            return solar(rest).map(TimeOfDay::Sunset);
+        }
+        let (hours, minutes) = text.split_once(':')?;
+        let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        ((0..24).contains(&hours) && (0..60).contains(&minutes))
+            .then_some(TimeOfDay::Fixed(hours * 60 + minutes))
+    }
+
+    fn is_solar(self) -> bool {
+        !matches!(self, TimeOfDay::Fixed(_))
+    }
+
+    /// Minutes since local midnight on the day of `at`.
+    fn resolve(self, at: LocalTime, location: Option<&Location>) -> Option<i32> {
+        if let TimeOfDay::Fixed(minute) = self {
+            return Some(minute);
+        }
+        let (sunrise, sunset) = location?.sun_times(at.year_day)?;
+        let utc = match self {
+            TimeOfDay::Sunrise(offset) => sunrise.round() as i32 + offset,
+            TimeOfDay::Sunset(offset) => sunset.round() as i32 + offset,
+            TimeOfDay::Fixed(_) => unreachable!(),
+        };
+        Some((utc + at.utc_offset).rem_euclid(MINUTES_PER_DAY))
+    }
+}
+
+/// `start-end`, where either side may itself contain a `-` (`sunset-30`).
+fn parse_range(text: &str) -> Option<(TimeOfDay, TimeOfDay)> {
+    text.match_indices('-').find_map(|(index, _)| {
+        Some((TimeOfDay::parse(&text[..index])?, TimeOfDay::parse(&text[index + 1..])?))
+    })
+}
+
+fn parse_weekday(text: &str) -> Option<i32> {
+    WEEKDAYS.iter().position(|day| *day == text).map(|day| day as i32)
+}
+
+/// Cron-like weekday sets: `mon-fri`, `sat,sun`, `fri-mon`, `daily`. Bit 0 is
+/// Sunday.
+fn parse_weekdays(text: &str) -> Option<u8> {
+    if text == "daily" {
+        return Some(EVERY_DAY);
+    }
+    let mut days = 0;
+    for part in text.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (first, last) = (parse_weekday(first)?, parse_weekday(last)?);
+        let count = (last - first).rem_euclid(7) + 1;
+        for offset in 0..count {
+            days |= 1 << ((first + offset) % 7);
+        }
+    }
+    Some(days)
+}
+
+/// One evening window: the cap ramps from `from` at `start` to `to` at `end`
+/// and holds until `until`. `days` are the evenings it starts on.
+struct Rule {
+    spec: String,
+    days: u8,
+    start: TimeOfDay,
+    end: TimeOfDay,
+    until: TimeOfDay,
+    from: i32,
+    to: i32,
+    /// The last evening (`year_day`) skipped because the sun moved its start
+    /// past the end, so that is logged once a day.
+    skipped: Cell<Option<i32>>,
+}
+
+impl Rule {
+    fn parse(spec: &str, location: Option<&Location>) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "DIALD_NIGHT_LIMIT: expected '[DAYS] START-END FROM-TO [until TIME]', got '{}'",
+                spec
+            )
+        };
+        let mut parts: Vec<&str> = spec.split_whitespace().collect();
+        let days = match parts.first().and_then(|first| parse_weekdays(first)) {
+            Some(days) => {
+                parts.remove(0);
+                days
+            }
+            None => EVERY_DAY,
+        };
+        let (window, caps, until) = match parts.as_slice() {
+            [window, caps] => (*window, *caps, "06:00"),
+            [window, caps, "until", until] => (*window, *caps, *until),
+            _ => return Err(invalid()),
+        };
+        let (start, end) = parse_range(window).ok_or_else(invalid)?;
+        let (from, to) = caps.split_once('-').ok_or_else(invalid)?;
+        let rule = Rule {
+            spec: spec.trim().to_string(),
+            days,
+            start,
+            end,
+            until: TimeOfDay::parse(until).ok_or_else(invalid)?,
+            from: from.parse().map_err(|_| invalid())?,
+            to: to.parse().map_err(|_| invalid())?,
+            skipped: Cell::new(None),
+        };
+        let solar = [rule.start, rule.end, rule.until].iter().any(|time| time.is_solar());
+        if solar && location.is_none() {
+            return Err(format!(
+                "DIALD_NIGHT_LIMIT: sunrise and sunset need DIALD_LOCATION ('{}')",
+                spec
+            ));
+        }
+        if let (TimeOfDay::Fixed(start), TimeOfDay::Fixed(end), TimeOfDay::Fixed(until)) =
+            (rule.start, rule.end, rule.until)
+            && (until - start).rem_euclid(MINUTES_PER_DAY)
+                <= (end - start).rem_euclid(MINUTES_PER_DAY)
+        {
+            return Err(format!(
+                "DIALD_NIGHT_LIMIT: 'until' must come after the ramp ends ('{}')",
+                spec
+            ));
+        }
+        Ok(rule)
+    }
+
+    /// The cap at `now` if the window that started `days_ago` evenings ago is
+    /// still running. After midnight the window belongs to the evening before.
+    fn cap(&self, now: LocalTime, days_ago: i32, location: Option<&Location>) -> Option<i32> {
+        let evening = if days_ago == 0 { now } else { now.day_before() };
+        if self.days & (1 << evening.weekday) == 0 {
+            return None;
+        }
+        let start = self.start.resolve(evening, location)?;
+        let since_start = |minute: i32| (minute - start).rem_euclid(MINUTES_PER_DAY);
+        let ramp = since_start(self.end.resolve(evening, location)?);
+        let hold = since_start(self.until.resolve(evening, location)?);
+        // A solar start can pass a fixed end (sunset after 22:00 in summer),
+        // which would ramp all night and never lift; no window that evening
+        if hold <= ramp {
+            // Logged when that evening is today, not again after midnight
+            let day = Some(evening.year_day);
+            if days_ago == 0 && self.skipped.replace(day) != day {
+                log!("diald: night limit '{}' ends before it starts tonight, skipped", self.spec);
+            }
+            return None;
+        }
+        let elapsed = now.minute + days_ago * MINUTES_PER_DAY - start;
+        if !(0..MINUTES_PER_DAY).contains(&elapsed) {
+            None
+        } else if elapsed < ramp {
+            Some(self.from + (self.to - self.from) * elapsed / ramp)
+        } else if elapsed < hold {
+            Some(self.to)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lowers the maximum volume gradually over an evening window, holds it
+/// overnight and lifts it in the morning, with rules per weekday. A long press
+/// overrides it until the next morning.
+pub struct NightLimit {
+    rules: Vec<Rule>,
+    location: Option<Location>,
+    overridden: bool,
+}
+
+impl NightLimit {
+    /// `DIALD_NIGHT_LIMIT="20:00-23:00 100-40"` ramps the cap from 100 at
+    /// 20:00 down to 40 at 23:00; an optional `until 07:00` (default 06:00)
+    /// says when it lifts. Rules are separated by `;` and may start with the
+    /// evenings they apply to, e.g. `sun-thu 20:00-22:00 100-40; fri,sat ...`.
+    /// Times may be `sunset-30` or `sunrise+15` given `DIALD_LOCATION`.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(spec) = config::var("DIALD_NIGHT_LIMIT") else {
+            return Ok(None);
+        };
+        Self::parse(&spec, Location::from_env()?).map(Some)
+    }
+
+    fn parse(spec: &str, location: Option<Location>) -> Result<Self, String> {
+        let rules = spec
+            .split(';')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(|rule| Rule::parse(rule, location.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(NightLimit { rules, location, overridden: false })
+    }
+
+    /// The lowest cap of the rules in force at `now`.
+    fn scheduled_cap(&self, now: LocalTime) -> Option<i32> {
+        let location = self.location.as_ref();
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.cap(now, 0, location).or_else(|| rule.cap(now, 1, location)))
+            .min()
+    }
+
+    /// The cap in force at `now`; `None` outside every window or while
+    /// overridden. The override ends when the window does.
+    pub fn cap(&mut self, now: LocalTime) -> Option<i32> {
+        let cap = self.scheduled_cap(now);
+        if cap.is_none() {
+            self.overridden = false;
+        }
+        if self.overridden { None } else { cap }
+    }
+
+    /// Lift the limit until the window ends; returns false if it wasn't on.
+    pub fn override_tonight(&mut self, now: LocalTime) -> bool {
+        if self.scheduled_cap(now).is_none() || self.overridden {
+            return false;
+        }
+        self.overridden = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUN: i32 = 0;
+    const THU: i32 = 4;
+    const FRI: i32 = 5;
+    const SAT: i32 = 6;
+    /// June 21st.
+    const MIDSUMMER: i32 = 171;
+
+    const BERLIN: Location = Location { latitude: 52.52, longitude: 13.40 };
+    const HELSINKI: Location = Location { latitude: 60.17, longitude: 24.94 };
+
+    fn at(weekday: i32, time: &str) -> LocalTime {
+        let Some(TimeOfDay::Fixed(minute)) = TimeOfDay::parse(time) else {
+            panic!("bad time {}", time);
+        };
+        LocalTime { weekday, minute, year_day: 100, utc_offset: 0 }
+    }
+
+    fn limit(spec: &str) -> NightLimit {
+        NightLimit::parse(spec, None).unwrap()
+    }
+
+    #[test]
+    fn ramps_down_then_holds_until_morning() {
+        let mut limit = limit("20:00-23:00 100-40 until 07:00");
+        assert_eq!(limit.cap(at(THU, "19:59")), None);
+        assert_eq!(limit.cap(at(THU, "20:00")), Some(100));
+        assert_eq!(limit.cap(at(THU, "21:30")), Some(70));
+        assert_eq!(limit.cap(at(THU, "23:00")), Some(40));
+        // After midnight the window still belongs to Thursday evening
+        assert_eq!(limit.cap(at(FRI, "02:00")), Some(40));
+        assert_eq!(limit.cap(at(FRI, "06:59")), Some(40));
+        assert_eq!(limit.cap(at(FRI, "07:00")), None);
+        assert_eq!(limit.cap(at(FRI, "12:00")), None);
+    }
+
+    #[test]
+    fn a_ramp_past_midnight_keeps_going() {
+        let mut limit = limit("23:00-01:00 100-40");
+        assert_eq!(limit.cap(at(THU, "23:30")), Some(85));
+        assert_eq!(limit.cap(at(FRI, "00:00")), Some(70));
+        assert_eq!(limit.cap(at(FRI, "01:00")), Some(40));
+        assert_eq!(limit.cap(at(FRI, "06:00")), None);
+    }
+
+    #[test]
+    fn weekday_rules_start_on_their_evenings() {
+        let mut limit = limit("fri 20:00-22:00 100-40");
+        assert_eq!(limit.cap(at(THU, "21:00")), None);
+        // Thursday night, not Friday's
+        assert_eq!(limit.cap(at(FRI, "01:00")), None);
+        assert_eq!(limit.cap(at(FRI, "21:00")), Some(70));
+        assert_eq!(limit.cap(at(SAT, "01:00")), Some(40));
+        assert_eq!(limit.cap(at(SAT, "21:00")), None);
+    }
+
+    #[test]
+    fn weekday_ranges_wrap_around_the_week() {
+        assert_eq!(parse_weekdays("fri-mon"), Some(0b110_0011));
+        assert_eq!(parse_weekdays("mon-fri"), Some(0b011_1110));
+        assert_eq!(parse_weekdays("sat,sun"), Some(0b100_0001));
+        assert_eq!(parse_weekdays("daily"), Some(EVERY_DAY));
+        assert_eq!(parse_weekdays("fry"), None);
+    }
+
+    #[test]
+    fn the_lowest_cap_wins() {
+        let mut limit = limit("20:00-22:00 100-60; sun-thu 20:00-22:00 100-40");
+        assert_eq!(limit.cap(at(THU, "22:00")), Some(40));
+        assert_eq!(limit.cap(at(FRI, "22:00")), Some(60));
+        assert_eq!(limit.cap(at(SUN, "22:00")), Some(40));
+    }
+
+    #[test]
+    fn an_override_lasts_until_the_window_ends() {
+        let mut limit = limit("20:00-22:00 100-40");
+        assert!(!limit.override_tonight(at(THU, "19:00")));
+        assert!(limit.override_tonight(at(THU, "21:00")));
+        assert!(!limit.override_tonight(at(THU, "21:00")));
+        assert_eq!(limit.cap(at(THU, "23:00")), None);
+        assert_eq!(limit.cap(at(FRI, "05:59")), None);
+        assert_eq!(limit.cap(at(FRI, "06:00")), None);
+        assert_eq!(limit.cap(at(FRI, "21:00")), Some(70));
+    }
+
+    #[test]
+    fn until_must_come_after_the_ramp() {
+        assert!(NightLimit::parse("20:00-23:00 100-40 until 22:00", None).is_err());
+        assert!(NightLimit::parse("20:00-23:00 100-40 until 23:00", None).is_err());
+        assert!(NightLimit::parse("20:00-23:00 100-40 until 23:01", None).is_ok());
+        // Nor can the sun be placed without a location
+        assert!(NightLimit::parse("sunset-23:00 100-40", None).is_err());
+    }
+
+    #[test]
+    fn sun_times_at_a_known_place() {
+        // Sunset in Berlin at midsummer is 21:33 CEST, 19:33 UTC
+        let (sunrise, sunset) = BERLIN.sun_times(MIDSUMMER).unwrap();
+        assert!((sunset - (19.0 * 60.0 + 33.0)).abs() < 3.0, "sunset {}", sunset);
+        assert!((sunrise - (2.0 * 60.0 + 43.0)).abs() < 3.0, "sunrise {}", sunrise);
+        // Spitsbergen has no sunset then
+        let north = Location { latitude: 78.2, longitude: 15.6 };
+        assert_eq!(north.sun_times(MIDSUMMER), None);
+    }
+
+    #[test]
+    fn solar_rules_follow_the_sun() {
+        let spec = "sunset-sunset+60 100-40 until sunrise";
+        let mut limit = NightLimit::parse(spec, Some(BERLIN)).unwrap();
+        let (_, sunset) = BERLIN.sun_times(MIDSUMMER).unwrap();
+        let sunset = sunset.round() as i32 + 120;
+        let time = |weekday, minute| LocalTime {
+            weekday,
+            minute,
+            year_day: MIDSUMMER,
+            utc_offset: 120,
+        };
+        assert_eq!(limit.cap(time(THU, sunset - 1)), None);
+        assert_eq!(limit.cap(time(THU, sunset + 30)), Some(70));
+        assert_eq!(limit.cap(time(FRI, 60)), Some(40));
+        // Lifted by sunrise, around 04:43
+        assert_eq!(limit.cap(time(FRI, 6 * 60)), None);
+    }
+
+    #[test]
+    fn an_evening_whose_sunset_passes_the_end_is_skipped() {
+        let mut limit = NightLimit::parse("sunset-22:00 100-40", Some(HELSINKI)).unwrap();
+        // The sun sets around 22:50 EEST at midsummer
+        let summer = |weekday, time: &str| LocalTime {
+            year_day: MIDSUMMER,
+            utc_offset: 180,
+            ..at(weekday, time)
+        };
+        assert_eq!(limit.cap(summer(THU, "21:00")), None);
+        assert_eq!(limit.cap(summer(THU, "23:30")), None);
+        assert_eq!(limit.cap(summer(FRI, "03:00")), None);
+        // And around 15:15 EET in January, so the ramp is over by 22:00
+        let winter = |weekday, time: &str| LocalTime {
+            year_day: 0,
+            utc_offset: 120,
+            ..at(weekday, time)
+        };
+        assert_eq!(limit.cap(winter(THU, "22:00")), Some(40));
+        assert_eq!(limit.cap(winter(FRI, "03:00")), Some(40));
+    }
+}
@@ -0,0 +1,169 @@
+//! `diald config-schema`: print a JSON schema of the config file, with every
+//! setting, what it does and its default, so editors can complete and check
+//! it and provisioning tools can validate the files they generate.
+
+use toml::Value;
+
+use crate::config;
+use crate::payload::json_string as string;
+
+/// A TOML value as JSON.
+fn json(value: &Value) -> String {
+    match value {
+        Value::String(text) => string(text),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(json).collect();
+            format!("[{}]", items.join(", "))
+        }
+        other => other.to_string(),
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "number",
+        Value::Boolean(_) => "boolean",
+        Value::Array(_) => "array",
+        _ => "string",
+    }
+}
+
+/// An object `depth` levels in, its members already rendered.
+fn object(depth: usize, members: Vec<(&str, String)>) -> String {
+    let pad = "  ".repeat(depth + 1);
+    let members: Vec<String> =
+        members.iter().map(|(key, value)| format!("{}{}: {}", pad, string(key), value)).collect();
+    format!("{{\n{}\n{}}}", members.join(",\n"), "  ".repeat(depth))
+}
+
+fn reference(section: &str) -> String {
+    format!("{{\"$ref\": {}}}", string(&format!("#/definitions/{}", section)))
+}
+
+/// One setting, typed after its example in `config::EXAMPLES`.
+fn setting(depth: usize, env: &str) -> String {
+    let name = config::standard_name(env).unwrap_or_else(|| env.to_string());
+    let Some((_, example, help)) = config::EXAMPLES.iter().find(|(e, ..)| *e == env) else {
+        return object(depth, vec![("description", string(&name))]);
+    };
+    let mut members = vec![("description", string(&format!("{} ({})", help, name)))];
+    let example = format!("value = {}", example).parse::<toml::Table>().ok();
+    if let Some(example) = example.as_ref().and_then(|table| table.get("value")) {
+        match example {
+            // A list can also be one string, separated as in the environment
+            Value::Array(items) => {
+                let item = items.first().map_or("string", json_type);
+                members.push(("type", "[\"array\", \"string\"]".to_string()));
+                members.push(("items", format!("{{\"type\": {}}}", string(item))));
+            }
+            other => members.push(("type", string(json_type(other)))),
+        }
+        if config::DEFAULTS.contains(&env) {
+            members.push(("default", json(example)));
+        } else {
+            members.push(("examples", format!("[{}]", json(example))));
+        }
+    }
+    object(depth, members)
+}
+
+/// The settings of `section`, plus `extra` members.
+fn section(depth: usize, section: &str, extra: Vec<(&str, String)>) -> String {
+    let mut properties = extra;
+    for (_, key, env) in config::SETTINGS.iter().filter(|(s, ..)| *s == section) {
+        properties.push((key, setting(depth + 2, env)));
+    }
+    object(
+        depth,
+        vec![
+            ("type", string("object")),
+            ("properties", object(depth + 1, properties)),
+            ("additionalProperties", "false".to_string()),
+        ],
+    )
+}
+
+/// The schema for the current config file version.
+pub fn render() -> String {
+    let mut sections: Vec<&str> = config::SETTINGS.iter().map(|(section, ..)| *section).collect();
+    sections.dedup();
+
+    let name = |depth: usize, description: &str, taken: Option<&str>| {
+        let mut members = vec![
+            ("description", string(description)),
+            ("type", string("string")),
+            ("minLength", "1".to_string()),
+        ];
+        if let Some(taken) = taken {
+            members.push(("not", format!("{{\"const\": {}}}", string(taken))));
+        }
+        object(depth, members)
+    };
+    // In a `[[device]]` section, plain keys are device settings and the other
+    // sections are tables in it
+    let dial_name = name(7, "Name of the dial, in logs and its MQTT client id", None);
+    let mut dial = vec![("name", dial_name)];
+    dial.extend(sections.iter().filter(|s| **s != "device").map(|s| (*s, reference(s))));
+    let dial = section(5, "device", dial);
+    let mut profile = vec![("name", name(5, "Name to switch to over MQTT", Some("default")))];
+    profile.extend(sections.iter().map(|s| (*s, reference(s))));
+    let profile = object(
+        3,
+        vec![
+            ("type", string("object")),
+            ("required", "[\"name\"]".to_string()),
+            ("properties", object(4, profile)),
+            ("additionalProperties", "false".to_string()),
+        ],
+    );
+
+    let mut properties = vec![(
+        "version",
+        object(
+            2,
+            vec![
+                ("description", string("Layout of the file; older ones are migrated")),
+                ("type", string("integer")),
+                ("minimum", "1".to_string()),
+                ("maximum", config::VERSION.to_string()),
+                ("default", config::VERSION.to_string()),
+            ],
+        ),
+    )];
+    for name in &sections {
+        let schema = match *name {
+            "device" => object(
+                2,
+                vec![(
+                    "oneOf",
+                    format!(
+                        "[\n        {},\n        {}\n      ]",
+                        reference("device"),
+                        object(4, vec![("type", string("array")), ("items", dial.clone())])
+                    ),
+                )],
+            ),
+            _ => reference(name),
+        };
+        properties.push((name, schema));
+    }
+    properties.push((
+        "profile",
+        object(2, vec![("type", string("array")), ("items", profile)]),
+    ));
+    let definitions = sections.iter().map(|s| (*s, section(2, s, Vec::new()))).collect();
+
+    let schema = object(
+        0,
+        vec![
+            ("$schema", string("http://json-schema.org/draft-07/schema#")),
+            ("title", string("diald configuration")),
+            ("type", string("object")),
+            ("properties", object(1, properties)),
+            ("additionalProperties", "false".to_string()),
+            ("definitions", object(1, definitions)),
+        ],
+    );
+    format!("{}\n", schema)
+}
@@ -0,0 +1,223 @@
+//! `diald setup`: an interactive first-run wizard that finds the dial, tests
+//! haptics and the MQTT broker, and writes an environment file with the
+//! settings diald reads.
+
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Event, MqttOptions, Packet};
+
+use crate::haptics::HapticDevice;
+use crate::input;
+use crate::payload;
+
+const DEFAULT_PATH: &str = "/etc/diald/diald.env";
+
+fn ask(question: &str, default: &str) -> io::Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "setup cancelled"));
+    }
+    let answer = line.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+fn confirm(question: &str, default_yes: bool) -> io::Result<bool> {
+    let hint = if default_yes { "Y/n" } else { "y/N" };
+    let answer = ask(&format!("{} ({})", question, hint), "")?;
+    Ok(match answer.to_lowercase().as_str() {
+        "" => default_yes,
+        answer => answer.starts_with('y'),
+    })
+}
+
+fn choose_device() -> Result<PathBuf, Box<dyn Error>> {
+    println!("Looking for dials...");
+    let found = input::scan()?;
+    if found.is_empty() {
+        println!("No dial found. Is it paired and connected?");
+        return Ok(PathBuf::from(ask("Device path", "")?));
+    }
+    for (index, (path, name)) in found.iter().enumerate() {
+        println!("  {}) {} ({})", index + 1, path.display(), name);
+    }
+    let choice = ask("Which one", "1")?;
+    let (path, _) = choice
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| found.get(n.wrapping_sub(1)))
+        .ok_or("not one of the listed devices")?;
+    Ok(input::stable_path(path).unwrap_or_else(|| path.clone()))
+}
+
+fn test_haptics(device: &Path) -> io::Result<()> {
+    if !confirm("Test haptics now?", true)? {
+        return Ok(());
+    }
+    let mut haptic = HapticDevice::new(device.to_path_buf());
+    if !haptic.is_connected() {
+        println!("Could not open haptics. Check permissions on the hidraw node, or see");
+        println!("\"Haptics without hidraw access\" in the README.");
+        return Ok(());
+    }
+    haptic.send_chunky();
+    if !confirm("Did the dial buzz?", true)? {
+        println!("Try DIALD_HAPTIC_BACKEND=hidraw, hidapi or ff to pick another backend.");
+    }
+    Ok(())
+}
+
+/// Connect once and wait for the broker's CONNACK.
+pub fn test_broker(
+    client_id: &str,
+    host: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+) -> Result<(), String> {
+    let mut opts = MqttOptions::new(client_id, host, port);
+    if let Some((user, pass)) = credentials {
+        opts.set_credentials(user, pass);
+    }
+    let (_client, mut connection) = Client::new(opts, 10);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for event in connection.iter() {
+            let result = match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Ok(()),
+                Ok(_) => continue,
+                Err(err) => Err(err.to_string()),
+            };
+            let _ = tx.send(result);
+            return;
+        }
+    });
+    rx.recv_timeout(Duration::from_secs(5))
+        .unwrap_or_else(|_| Err("no answer within 5 seconds".to_string()))
+}
+
+struct Broker {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+}
+
+fn configure_broker() -> Result<Broker, Box<dyn Error>> {
+    loop {
+        let host = ask("MQTT host", "localhost")?;
+        let port = ask("MQTT port", "1883")?.parse::<u16>().map_err(|_| "invalid port")?;
+        let username = ask("MQTT username (blank for none)", "")?;
+        let password = if username.is_empty() {
+            String::new()
+        } else {
+            ask("MQTT password", "")?
+        };
+
+        let credentials =
+            (!username.is_empty()).then_some((username.as_str(), password.as_str()));
+        print!("Connecting to {}:{}... ", host, port);
+        io::stdout().flush()?;
+        match test_broker("diald-setup", &host, port, credentials) {
+            Ok(()) => println!("ok"),
+            Err(err) => {
+                println!("failed ({})", err);
+                if !confirm("Keep these settings anyway?", false)? {
+                    continue;
+                }
+            }
+        }
+        return Ok(Broker { host, port, username, password });
+    }
+}
+
+fn write_env_file(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // May hold the broker password, so never readable by others, not even briefly
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+pub fn run(path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    println!("diald setup\n");
+    let device = choose_device()?;
+    test_haptics(&device)?;
+    println!();
+    let broker = configure_broker()?;
+
+    println!("\ndiald will use these topics:");
+    let prefix = payload::prefix();
+    let command_prefix = payload::command_prefix(&prefix);
+    let topics = [
+        ("volume", "(published) volume, 0-100 or the configured range"),
+        ("clicks", "(published) clicks in a burst: 1, 2, 3..."),
+        ("long_press", "(published) button held for a second"),
+        ("state", "(published) ok / misconfigured"),
+        ("guest", "(published) guest mode on / off"),
+        ("profile", "(published) the profile in use, or default"),
+        ("throttled", "(published) host too hot or busy: on / off"),
+        ("info", "(published) version, and a newer one if checking"),
+        ("config", "(published) the settings config/set can change"),
+        ("response", "(published) answers to request, with its id"),
+        ("volume/set", "(subscribed) set the volume from elsewhere"),
+        ("volume/adjust", "(subscribed) nudge the volume, e.g. +5"),
+        ("reset", "(subscribed) clear a stuck gesture"),
+        ("haptics/set", "(subscribed) e.g. \"boundary off\""),
+        ("guest/set", "(subscribed) on, on 2h, off"),
+        ("profile/set", "(subscribed) a [[profile]] name, or default"),
+        ("log_level/set", "(subscribed) error, warn, info, debug or trace"),
+        ("config/set", "(subscribed) JSON of settings to change"),
+        ("restart", "(subscribed) restart diald; the payload is the token"),
+        ("request", "(subscribed) {\"id\": ..., \"query\": \"volume\"}"),
+    ];
+    for (name, description) in topics {
+        let prefix = if payload::SUBSCRIBED.contains(&name) { &command_prefix } else { &prefix };
+        println!("  {:<24} {}", format!("{}/{}", prefix, name), description);
+    }
+
+    let mut contents = String::from("# Written by `diald setup`\n");
+    contents.push_str(&format!("DIALD_DEVICE_PATH={}\n", device.display()));
+    contents.push_str(&format!("DIALD_MQTT_HOST={}\n", broker.host));
+    contents.push_str(&format!("DIALD_MQTT_PORT={}\n", broker.port));
+    if !broker.username.is_empty() {
+        contents.push_str(&format!("DIALD_MQTT_USERNAME={}\n", broker.username));
+        contents.push_str(&format!("DIALD_MQTT_PASSWORD={}\n", broker.password));
+    }
+
+    let path = PathBuf::from(ask("\nWrite settings to", path.unwrap_or(DEFAULT_PATH))?);
+    if path.exists() && !confirm(&format!("{} exists. Overwrite?", path.display()), false)? {
+        println!("\nNot written. The settings were:\n\n{}", contents);
+        return Ok(());
+    }
+    if let Err(err) = write_env_file(&path, &contents) {
+        println!("\nCould not write {} ({}). The settings were:\n", path.display(), err);
+        println!("{}", contents);
+        return Ok(());
+    }
+    println!("\nWrote {}. Point systemd at it with", path.display());
+    println!("  EnvironmentFile={}", path.display());
+    println!(
+        "or load it in a shell with `set -a; . {}; set +a` before running diald.",
+        path.display()
+    );
+    Ok(())
+}
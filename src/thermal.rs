@@ -0,0 +1,114 @@
+//! Load shedding for passively cooled boards: while the CPU is too hot or
+//! the host too busy, diald publishes intermediate volumes less often, polls
+//! the dial less eagerly and stops logging, until things cool down again.
+//!
+//! `DIALD_THROTTLE_TEMP` is the temperature in °C (default 80) and
+//! `DIALD_THROTTLE_LOAD` the 1-minute load average per CPU (default 2);
+//! either can be `off`. Hosts without the sensor are never throttled by it.
+
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+const DEFAULT_TEMP: f64 = 80.0;
+const DEFAULT_LOAD: f64 = 2.0;
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How far below the thresholds the host has to get before the throttle is
+/// lifted, so it doesn't flap around the limit.
+const TEMP_HYSTERESIS: f64 = 5.0;
+const LOAD_HYSTERESIS: f64 = 0.75;
+
+pub enum Change {
+    /// The host got too hot or busy, for the given reason (e.g. `83.5°C`).
+    Throttled(String),
+    Recovered,
+}
+
+pub struct Throttle {
+    max_temp: Option<f64>,
+    max_load: Option<f64>,
+    throttled: bool,
+    last_check: Option<Instant>,
+}
+
+fn threshold(name: &str, default: f64) -> Result<Option<f64>, String> {
+    match config::var(name).as_deref() {
+        Err(_) => Ok(Some(default)),
+        Ok("off") => Ok(None),
+        Ok(value) => value.parse().ok().filter(|value: &f64| *value > 0.0).map(Some).ok_or_else(
+            || format!("{}: expected a positive number or 'off', got '{}'", name, value),
+        ),
+    }
+}
+
+/// The hottest thermal zone, in °C.
+fn temperature() -> Option<f64> {
+    fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|entry| fs::read_to_string(entry.path().join("temp")).ok())
+        .filter_map(|millidegrees| millidegrees.trim().parse::<f64>().ok())
+        .map(|millidegrees| millidegrees / 1000.0)
+        .reduce(f64::max)
+}
+
+/// The 1-minute load average per CPU.
+fn load() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    Some(load / cpus as f64)
+}
+
+impl Throttle {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            max_temp: threshold("DIALD_THROTTLE_TEMP", DEFAULT_TEMP)?,
+            max_load: threshold("DIALD_THROTTLE_LOAD", DEFAULT_LOAD)?,
+            throttled: false,
+            last_check: None,
+        })
+    }
+
+    /// Take the thresholds of `other`, e.g. after a reload. The host is
+    /// checked again right away.
+    pub fn reconfigure(&mut self, other: Throttle) {
+        self.max_temp = other.max_temp;
+        self.max_load = other.max_load;
+        self.last_check = None;
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    /// Check the host every `CHECK_INTERVAL`; returns a change of state.
+    pub fn poll(&mut self, now: Instant) -> Option<Change> {
+        if self.last_check.is_some_and(|t| now.duration_since(t) < CHECK_INTERVAL) {
+            return None;
+        }
+        self.last_check = Some(now);
+
+        let temp = self.max_temp.and_then(|max| Some((temperature()?, max)));
+        let load = self.max_load.and_then(|max| Some((load()?, max)));
+        if !self.throttled {
+            let reason = match (temp, load) {
+                (Some((temp, max)), _) if temp >= max => format!("{:.1}°C", temp),
+                (_, Some((load, max))) if load >= max => format!("load {:.2} per CPU", load),
+                _ => return None,
+            };
+            self.throttled = true;
+            return Some(Change::Throttled(reason));
+        }
+        let cool = temp.is_none_or(|(temp, max)| temp < max - TEMP_HYSTERESIS);
+        let idle = load.is_none_or(|(load, max)| load < max * LOAD_HYSTERESIS);
+        if cool && idle {
+            self.throttled = false;
+            return Some(Change::Recovered);
+        }
+        None
+    }
+}
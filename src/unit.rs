@@ -0,0 +1,73 @@
+//! `diald systemd-unit` and `diald udev-rule`: a template unit that runs one
+//! diald per dial, and the udev rule that starts it, for letting systemd
+//! manage the dial's lifetime instead of diald's own reconnect loop. udev
+//! starts `diald@event5.service` when the dial appears, and `BindsTo=` on the
+//! device unit stops it when the dial goes away.
+
+use std::env;
+use std::fmt::Write;
+
+use crate::input;
+
+/// Where `diald setup` writes its settings; optional in the unit.
+const ENV_FILE: &str = "/etc/diald/diald.env";
+
+pub fn render_unit() -> String {
+    let exe = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.canonicalize().ok())
+        .map_or("/usr/bin/diald".to_string(), |exe| exe.display().to_string());
+    let mut out = String::new();
+    writeln!(out, "# /etc/systemd/system/diald@.service, started by `diald udev-rule`").unwrap();
+    writeln!(out, "[Unit]").unwrap();
+    writeln!(out, "Description=Surface Dial event daemon for /dev/input/%I").unwrap();
+    writeln!(out, "BindsTo=dev-input-%i.device").unwrap();
+    writeln!(out, "After=dev-input-%i.device").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "[Service]").unwrap();
+    writeln!(out, "ExecStart={} --device /dev/input/%I --exit-with-device", exe).unwrap();
+    writeln!(out, "ExecReload=/bin/kill -HUP $MAINPID").unwrap();
+    writeln!(out, "EnvironmentFile=-{}", ENV_FILE).unwrap();
+    writeln!(out, "Restart=on-failure").unwrap();
+    writeln!(out, "# A remote restart exits with EX_TEMPFAIL").unwrap();
+    writeln!(out, "RestartForceExitStatus=75").unwrap();
+    writeln!(out, "DynamicUser=yes").unwrap();
+    writeln!(out, "StateDirectory=diald").unwrap();
+    writeln!(out, "SupplementaryGroups=input").unwrap();
+    out
+}
+
+/// One rule per kind of dial connected now, by USB/Bluetooth id, or the
+/// Surface Dial's when none is, plus one giving the unit's `input` group the
+/// dial's hidraw node for haptics (root-only otherwise).
+pub fn render_rule() -> Result<String, String> {
+    let devices = input::list().map_err(|err| err.to_string())?;
+    let mut ids: Vec<(u16, u16)> =
+        devices.iter().filter(|device| device.matches).filter_map(|device| device.id).collect();
+    ids.sort();
+    ids.dedup();
+    if ids.is_empty() {
+        ids.push(input::SURFACE_DIAL_ID);
+    }
+    let mut out = String::new();
+    writeln!(out, "# /etc/udev/rules.d/70-diald.rules: run diald@.service for each dial").unwrap();
+    for (vendor, product) in ids {
+        writeln!(
+            out,
+            "ACTION==\"add\", SUBSYSTEM==\"input\", KERNEL==\"event*\", \
+             ATTRS{{id/vendor}}==\"{:04x}\", ATTRS{{id/product}}==\"{:04x}\", \
+             TAG+=\"systemd\", ENV{{SYSTEMD_WANTS}}+=\"diald@%k.service\"",
+            vendor, product
+        )
+        .unwrap();
+        // hidraw's parent is named bus:VENDOR:PRODUCT.instance, USB or Bluetooth
+        writeln!(
+            out,
+            "SUBSYSTEM==\"hidraw\", KERNELS==\"*:{:04X}:{:04X}.*\", \
+             GROUP=\"input\", MODE=\"0660\"",
+            vendor, product
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
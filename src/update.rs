@@ -0,0 +1,118 @@
+//! An opt-in check for newer releases. diald never installs anything; it
+//! only reports the newest version on the info topic, so a dashboard can
+//! show which dials need an upgrade.
+//!
+//! `DIALD_UPDATE_URL` is fetched with `curl` every `DIALD_UPDATE_INTERVAL`
+//! (default `24h`). It can be a GitHub "latest release" API URL, whose
+//! `tag_name` is used, or anything that answers with a bare version.
+
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::payload;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct UpdateCheck {
+    found: Receiver<String>,
+    available: Option<String>,
+}
+
+/// The version in a release feed: a JSON `tag_name`, else the first line.
+fn parse_version(body: &str) -> Option<String> {
+    let version = match body.find("\"tag_name\"") {
+        Some(at) => {
+            let rest = &body[at + "\"tag_name\"".len()..];
+            let rest = rest.trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+            &rest[..rest.find('"')?]
+        }
+        None => body.lines().next()?,
+    };
+    let version = version.trim().trim_start_matches('v');
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// `1.10.0` is newer than `1.9.2`. Pre-release suffixes are ignored.
+fn is_newer(candidate: &str, current: &str) -> bool {
+    let numbers = |version: &str| -> Vec<u64> {
+        let release = version.split(['-', '+']).next().unwrap_or("");
+        release.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+    };
+    numbers(candidate) > numbers(current)
+}
+
+fn fetch(url: &str) -> Result<String, String> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--max-time", "30"])
+        .args(["--user-agent", &format!("diald/{}", VERSION), url])
+        .output()
+        .map_err(|err| format!("curl: {}", err))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let body = String::from_utf8_lossy(&output.stdout);
+    parse_version(&body).ok_or_else(|| "no version in the answer".to_string())
+}
+
+impl UpdateCheck {
+    /// `None` unless `DIALD_UPDATE_URL` is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(url) = config::var("DIALD_UPDATE_URL") else {
+            return Ok(None);
+        };
+        let interval = match config::var("DIALD_UPDATE_INTERVAL") {
+            Ok(interval) => payload::parse_duration(&interval)
+                .filter(|interval| *interval >= Duration::from_secs(60))
+                .ok_or_else(|| {
+                    format!("DIALD_UPDATE_INTERVAL: expected e.g. 12h, got '{}'", interval)
+                })?,
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        let (tx, found) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                match fetch(&url) {
+                    Ok(latest) if is_newer(&latest, VERSION) => {
+                        if tx.send(latest).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("diald: update check failed ({})", err),
+                }
+                thread::sleep(interval);
+            }
+        });
+        Ok(Some(Self { found, available: None }))
+    }
+
+    /// Pick up the latest result; true when a newer version turned up.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(latest) = self.found.try_recv() {
+            if self.available.as_ref() != Some(&latest) {
+                log!("diald: version {} is available (running {})", latest, VERSION);
+                self.available = Some(latest);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn available(&self) -> Option<&str> {
+        self.available.as_deref()
+    }
+}
+
+/// The retained `<prefix>/info` payload, e.g.
+/// `{"version":"0.1.0","update_available":"0.2.0"}`.
+pub fn info(available: Option<&str>) -> String {
+    // Whatever the feed answered, so it may hold quotes
+    let available = available.map_or("null".to_string(), payload::json_string);
+    format!("{{\"version\":\"{}\",\"update_available\":{}}}", VERSION, available)
+}
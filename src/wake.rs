@@ -0,0 +1,148 @@
+//! Block the main loop until there is work: the device has events, another
+//! thread has queued something (an MQTT command, a control request), or a
+//! timeout runs out, rather than waking every 10ms to look.
+//!
+//! Other threads hold a [`Waker`] and call [`Waker::wake`] after sending on
+//! their channel. On Linux that writes an eventfd the loop polls along with
+//! the device; elsewhere, or for devices without a file descriptor (replayed
+//! traces), the wait is cut down to the old 10ms so nothing is held up.
+
+use std::io::ErrorKind;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The longest wait when something can't wake the loop itself.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wakes the [`Sleeper`] it came from, from any thread.
+#[derive(Clone)]
+pub struct Waker {
+    event: Arc<platform::Event>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        self.event.notify();
+    }
+}
+
+pub struct Sleeper {
+    event: Arc<platform::Event>,
+}
+
+impl Default for Sleeper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sleeper {
+    pub fn new() -> Self {
+        let event = match platform::Event::new() {
+            Ok(event) => event,
+            Err(err) if err.kind() == ErrorKind::Unsupported => platform::Event::none(),
+            Err(err) => {
+                warn!("diald: no event-driven wait, polling every 10ms ({})", err);
+                platform::Event::none()
+            }
+        };
+        Self { event: Arc::new(event) }
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker { event: self.event.clone() }
+    }
+
+    /// Wait up to `timeout` for `device` to have something to read or a
+    /// waker to fire.
+    pub fn sleep(&self, device: Option<RawFd>, timeout: Duration) {
+        match device.filter(|_| self.event.is_some()) {
+            Some(device) => self.event.wait(device, timeout),
+            None => thread::sleep(timeout.min(POLL_INTERVAL)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::time::Duration;
+
+    pub struct Event {
+        fd: Option<OwnedFd>,
+    }
+
+    impl Event {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd: Some(unsafe { OwnedFd::from_raw_fd(fd) }) })
+        }
+
+        pub fn none() -> Self {
+            Self { fd: None }
+        }
+
+        pub fn is_some(&self) -> bool {
+            self.fd.is_some()
+        }
+
+        pub fn notify(&self) {
+            if let Some(fd) = &self.fd {
+                let one: u64 = 1;
+                unsafe { libc::write(fd.as_raw_fd(), (&one as *const u64).cast(), 8) };
+            }
+        }
+
+        pub fn wait(&self, device: RawFd, timeout: Duration) {
+            let Some(fd) = &self.fd else {
+                return;
+            };
+            let mut fds = [
+                libc::pollfd { fd: device, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            ];
+            // Rounded up, so a deadline a fraction of a millisecond away
+            // isn't spun on
+            let timeout = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128);
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout as libc::c_int) };
+            if ready > 0 && fds[1].revents & libc::POLLIN != 0 {
+                // Reading resets the count, so the next wait blocks again
+                let mut count: u64 = 0;
+                unsafe { libc::read(fd.as_raw_fd(), (&mut count as *mut u64).cast(), 8) };
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+    use std::os::fd::RawFd;
+    use std::time::Duration;
+
+    pub struct Event;
+
+    impl Event {
+        pub fn new() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "needs Linux"))
+        }
+
+        pub fn none() -> Self {
+            Self
+        }
+
+        pub fn is_some(&self) -> bool {
+            false
+        }
+
+        pub fn notify(&self) {}
+
+        pub fn wait(&self, _device: RawFd, _timeout: Duration) {}
+    }
+}
@@ -0,0 +1,177 @@
+//! Reload the config file as soon as it changes, so settings like the
+//! backlash thresholds can be tuned while turning the dial, without a shell
+//! on the Pi to send SIGHUP from. The reload is the same one SIGHUP does.
+//!
+//! The directory is watched rather than the file: editors and NixOS save by
+//! writing a new file and renaming it over the old one. Changes are
+//! debounced, since one save can be several writes. On by default on Linux;
+//! `DIALD_WATCH_CONFIG=0` turns it off.
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::daemon;
+
+/// How long the file has to stay untouched before it is read.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watch `path` from a thread of its own, unless `DIALD_WATCH_CONFIG` turns
+/// it off.
+pub fn spawn(path: &Path) -> Result<(), String> {
+    if !config::flag("DIALD_WATCH_CONFIG", true)? {
+        return Ok(());
+    }
+    start(path);
+    Ok(())
+}
+
+fn start(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let Some(name) = path.file_name().map(|name| name.to_os_string()) else {
+        return;
+    };
+    let mut watcher = match platform::Watcher::new(&dir) {
+        Ok(watcher) => watcher,
+        // Nothing to say on other systems or without a config directory
+        Err(err) if matches!(err.kind(), ErrorKind::Unsupported | ErrorKind::NotFound) => return,
+        Err(err) => {
+            warn!("config: not watching {} ({})", dir.display(), err);
+            return;
+        }
+    };
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        loop {
+            match watcher.wait(&name, DEBOUNCE) {
+                Ok(()) => {
+                    log!("config: {} changed, reloading", path.display());
+                    daemon::request_reload();
+                }
+                Err(err) => {
+                    warn!("config: stopped watching {} ({})", path.display(), err);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::ffi::{CString, OsStr};
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    /// `struct inotify_event` without its name.
+    const EVENT_HEADER: usize = 16;
+
+    pub struct Watcher {
+        fd: OwnedFd,
+        buf: Vec<u8>,
+    }
+
+    impl Watcher {
+        pub fn new(dir: &Path) -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let dir = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd, buf: vec![0; 4096] })
+        }
+
+        /// The names of the files in the next batch of events.
+        fn read(&mut self) -> io::Result<Vec<Vec<u8>>> {
+            let read = unsafe {
+                libc::read(self.fd.as_raw_fd(), self.buf.as_mut_ptr().cast(), self.buf.len())
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let events = &self.buf[..read as usize];
+            let mut names = Vec::new();
+            let mut at = 0;
+            while at + EVENT_HEADER <= events.len() {
+                let len = u32::from_ne_bytes(events[at + 12..at + 16].try_into().unwrap());
+                let end = (at + EVENT_HEADER + len as usize).min(events.len());
+                let name = &events[at + EVENT_HEADER..end];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                names.push(name.to_vec());
+                at = end;
+            }
+            Ok(names)
+        }
+
+        /// Whether an event comes in within `timeout`.
+        fn ready(&self, timeout: Duration) -> io::Result<bool> {
+            let fd = self.fd.as_raw_fd();
+            let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            loop {
+                let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+                if ready >= 0 {
+                    return Ok(ready > 0);
+                }
+                let err = io::Error::last_os_error();
+                // SIGHUP cuts the wait short; unlike a read it isn't restarted
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+
+        fn changed(&mut self, name: &OsStr) -> io::Result<bool> {
+            Ok(self.read()?.iter().any(|changed| changed == name.as_bytes()))
+        }
+
+        /// Block until `name` has been written, then until it has been left
+        /// alone for `debounce`. Other files in the directory don't count.
+        pub fn wait(&mut self, name: &OsStr, debounce: Duration) -> io::Result<()> {
+            while !self.changed(name)? {}
+            let mut quiet_at = Instant::now() + debounce;
+            loop {
+                let left = quiet_at.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(());
+                }
+                if self.ready(left)? && self.changed(name)? {
+                    quiet_at = Instant::now() + debounce;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub struct Watcher;
+
+    impl Watcher {
+        pub fn new(_dir: &Path) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "watching files needs Linux"))
+        }
+
+        pub fn wait(&mut self, _name: &OsStr, _debounce: Duration) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "watching files needs Linux"))
+        }
+    }
+}
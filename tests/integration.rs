@@ -0,0 +1,1358 @@
+//! End-to-end tests: run the diald binary on a replayed trace against a
+//! minimal in-process MQTT broker and check what it publishes.
+//!
+//! Run with `cargo test --features integration-tests`.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+    let mut length = 0usize;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet)
+}
+
+fn read_string(body: &[u8]) -> (String, &[u8]) {
+    let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let string = String::from_utf8_lossy(&body[2..2 + length]).into_owned();
+    (string, &body[2 + length..])
+}
+
+#[derive(Default)]
+struct BrokerState {
+    published: Vec<(String, String)>,
+    subscriptions: Vec<String>,
+    retained: Vec<(String, String)>,
+    client: Option<TcpStream>,
+}
+
+/// Just enough of MQTT 3.1.1 for one client: CONNECT, SUBSCRIBE, PUBLISH at
+/// QoS 0/1 and PINGREQ. Everything the client publishes is recorded, and sent
+/// back if it subscribed to exactly that topic; retained messages are kept.
+struct Broker {
+    port: u16,
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl Broker {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(BrokerState::default()));
+
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, &shared);
+                });
+            }
+        });
+
+        Self { port, state }
+    }
+
+    /// Deliver a QoS 0 publish to the connected client.
+    fn publish(&self, topic: &str, payload: &str) {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic.as_bytes());
+        body.extend_from_slice(payload.as_bytes());
+        let mut state = self.state.lock().unwrap();
+        let client = state.client.as_mut().expect("no client connected");
+        write_packet(client, 0x30, &body).unwrap();
+    }
+
+    /// Keep `payload` on `topic`, as if an earlier client had retained it.
+    fn retain(&self, topic: &str, payload: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.retained.push((topic.to_string(), payload.to_string()));
+    }
+
+    /// Payloads published on `topic` so far.
+    fn values(&self, topic: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .published
+            .iter()
+            .filter(|(t, _)| t == topic)
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+
+    fn wait_until(&self, what: &str, condition: impl Fn(&BrokerState) -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if condition(&self.state.lock().unwrap()) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let state = self.state.lock().unwrap();
+        panic!("timed out waiting for {}; published: {:?}", what, state.published);
+    }
+
+    fn wait_for_value(&self, topic: &str, payload: &str) {
+        self.wait_until(&format!("{} = {}", topic, payload), |state| {
+            state.published.iter().any(|(t, p)| t == topic && p == payload)
+        });
+    }
+}
+
+fn serve(mut stream: TcpStream, state: &Mutex<BrokerState>) -> io::Result<()> {
+    loop {
+        let (header, body) = read_packet(&mut stream)?;
+        match header >> 4 {
+            // CONNECT -> CONNACK
+            1 => {
+                state.lock().unwrap().client = Some(stream.try_clone()?);
+                write_packet(&mut stream, 0x20, &[0, 0])?;
+            }
+            // PUBLISH
+            3 => {
+                let qos = (header >> 1) & 3;
+                let (topic, rest) = read_string(&body);
+                let (packet_id, payload) = if qos > 0 {
+                    (Some([rest[0], rest[1]]), &rest[2..])
+                } else {
+                    (None, rest)
+                };
+                let mut echo = (topic.len() as u16).to_be_bytes().to_vec();
+                echo.extend_from_slice(topic.as_bytes());
+                echo.extend_from_slice(payload);
+                let payload = String::from_utf8_lossy(payload).into_owned();
+                let subscribed = {
+                    let mut state = state.lock().unwrap();
+                    if header & 1 == 1 {
+                        state.retained.retain(|(t, _)| *t != topic);
+                        if !payload.is_empty() {
+                            state.retained.push((topic.clone(), payload.clone()));
+                        }
+                    }
+                    state.published.push((topic.clone(), payload));
+                    state.subscriptions.contains(&topic)
+                };
+                if let Some(packet_id) = packet_id {
+                    write_packet(&mut stream, 0x40, &packet_id)?;
+                }
+                if subscribed {
+                    write_packet(&mut stream, 0x30, &echo)?;
+                }
+            }
+            // SUBSCRIBE -> SUBACK, granting QoS 1 to every filter
+            8 => {
+                let mut ack = vec![body[0], body[1]];
+                let mut rest = &body[2..];
+                let mut filters = Vec::new();
+                while !rest.is_empty() {
+                    let (filter, after) = read_string(rest);
+                    state.lock().unwrap().subscriptions.push(filter.clone());
+                    filters.push(filter);
+                    rest = &after[1..];
+                    ack.push(1);
+                }
+                write_packet(&mut stream, 0x90, &ack)?;
+                let retained = state.lock().unwrap().retained.clone();
+                for (topic, payload) in retained.iter().filter(|(t, _)| filters.contains(t)) {
+                    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+                    body.extend_from_slice(topic.as_bytes());
+                    body.extend_from_slice(payload.as_bytes());
+                    write_packet(&mut stream, 0x31, &body)?;
+                }
+            }
+            // PINGREQ -> PINGRESP
+            12 => write_packet(&mut stream, 0xd0, &[])?,
+            // DISCONNECT
+            14 => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Kills diald when the test ends, pass or fail.
+struct Diald {
+    child: Child,
+    trace: PathBuf,
+    config: PathBuf,
+}
+
+impl Drop for Diald {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.trace);
+        let _ = fs::remove_file(&self.config);
+    }
+}
+
+fn spawn_diald(broker: &Broker, trace: &str) -> Diald {
+    spawn_diald_with(broker, trace, &[], &[])
+}
+
+fn spawn_diald_with(
+    broker: &Broker,
+    trace: &str,
+    args: &[&str],
+    vars: &[(&str, &str)],
+) -> Diald {
+    spawn_diald_with_config(broker, trace, "version = 2\n", args, vars)
+}
+
+/// `config` is the config file's contents; a file of its own keeps one on
+/// the host out of the tests.
+fn spawn_diald_with_config(
+    broker: &Broker,
+    trace: &str,
+    config: &str,
+    args: &[&str],
+    vars: &[(&str, &str)],
+) -> Diald {
+    static NEXT_TRACE: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "diald-trace-{}-{}",
+        std::process::id(),
+        NEXT_TRACE.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&path, trace).unwrap();
+    let config_path = path.with_extension("toml");
+    fs::write(&config_path, config).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_diald"))
+        .args(args)
+        .env("DIALD_DEVICE", format!("replay:{}", path.display()))
+        .env("MQTT_HOST", "127.0.0.1")
+        .env("MQTT_PORT", broker.port.to_string())
+        .env("DIALD_CONFIG", &config_path)
+        // One run's volume mustn't carry over into the next test
+        .env("DIALD_STATE_FILE", "off")
+        // A busy test machine mustn't slow publishing down
+        .env("DIALD_THROTTLE_TEMP", "off")
+        .env("DIALD_THROTTLE_LOAD", "off")
+        .env_remove("MQTT_USERNAME")
+        .env_remove("MQTT_PASSWORD")
+        .env_remove("DIALD_CONTROL_SOCKET")
+        .env_remove("DIALD_OUTPUT_REPORTS")
+        .env_remove("DIALD_TOOL")
+        .env_remove("DIALD_APP_TOOLS")
+        .env_remove("DIALD_MONITOR")
+        .env_remove("DIALD_DRY_RUN")
+        .env_remove("DIALD_EXIT_WHEN_IDLE")
+        .env_remove("DIALD_EXIT_WITH_DEVICE")
+        .env_remove("DIALD_WAIT_FOR_DEVICE")
+        .env_remove("DIALD_HOTPLUG")
+        .env_remove("DIALD_IDLE_SNAPSHOT")
+        .env_remove("DIALD_IDLE_EXEC")
+        .env_remove("DIALD_IDLE_PUBLISH")
+        .env_remove("DIALD_ACTIVE_PUBLISH")
+        .env_remove("DIALD_CLICK_WINDOW")
+        .env_remove("DIALD_CLICK_MAX")
+        .env_remove("DIALD_TOPIC_PREFIX")
+        .env_remove("DIALD_TOPIC_LAYOUT")
+        .env_remove("DIALD_DEVICE_ID")
+        .env_remove("DIALD_IDLE_TIMEOUT")
+        .env_remove("DIALD_STATUS_LED")
+        .env_remove("DIALD_HAPTIC_STRENGTH")
+        .env_remove("DIALD_HAPTIC_DEV")
+        .env_remove("DIALD_HAPTIC_BACKEND")
+        .env_remove("DIALD_COUNTS_PER_REV")
+        .env_remove("DIALD_BACKLASH_THRESHOLD")
+        .env_remove("DIALD_BACKLASH_CANCEL")
+        .env_remove("DIALD_BACKLASH_TIME")
+        .env_remove("DIALD_BACKLASH_FILTER")
+        .env_remove("DIALD_BACKLASH_VELOCITY")
+        .env_remove("DIALD_BACKLASH_DEADBAND")
+        .env_remove("DIALD_BACKLASH_RELEASE")
+        .env_remove("DIALD_PRESS_TURN")
+        .env_remove("DIALD_FAST")
+        .env_remove("DIALD_UPDATE_URL")
+        .env_remove("DIALD_COUNTS_PER_STEP")
+        .env_remove("DIALD_INVERT_DIRECTION")
+        .env_remove("DIALD_VOLUME_STEPS")
+        .env_remove("DIALD_RANGE")
+        .env_remove("DIALD_RANGE_STEP")
+        .env_remove("DIALD_CURVE")
+        .env_remove("DIALD_MIN_DELTA")
+        .env_remove("DIALD_MIN_DELTA_WINDOW")
+        .env_remove("DIALD_MIN_DELTA_RELEASE")
+        .env_remove("DIALD_RESTART_TOKEN")
+        .env_remove("DIALD_COMMAND_TOKEN")
+        .env_remove("DIALD_PIEZO")
+        .env_remove("DIALD_OLED")
+        .env_remove("DIALD_WATCH_CONFIG")
+        .env_remove("DIALD_LOG_LEVEL")
+        .envs(vars.iter().copied())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    Diald { child, trace: path, config: config_path }
+}
+
+/// `count` rotation events of `step` counts, 1ms apart, from `start_ms`.
+fn turn(start_ms: u64, count: u64, step: i32) -> String {
+    (0..count)
+        .map(|i| format!("{} rotate {}\n", start_ms + i, step))
+        .collect()
+}
+
+#[test]
+fn turning_publishes_volume() {
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, &turn(500, 200, 10));
+
+    broker.wait_for_value("home/diald/volume", "87");
+    thread::sleep(Duration::from_millis(300));
+    // The first 50 events stay in the backlash delay buffer; after that every
+    // 4 events is one volume step. Intermediate values are throttled, but
+    // crossing a multiple of ten always publishes, and the final value goes
+    // out once the throttle allows.
+    assert_eq!(broker.values("home/diald/volume"), ["51", "60", "70", "80", "87"]);
+}
+
+#[test]
+fn waking_publishes_the_starting_volume_first() {
+    let broker = Broker::start();
+    let vars = [("DIALD_ACTIVE_PUBLISH", "1")];
+    let _diald = spawn_diald_with(&broker, &turn(500, 60, 10), &[], &vars);
+
+    broker.wait_for_value("home/diald/volume", "52");
+    assert_eq!(broker.values("home/diald/volume"), ["50", "51", "52"]);
+}
+
+#[test]
+fn reversal_is_filtered_as_backlash() {
+    let broker = Broker::start();
+    let trace = turn(500, 100, 10) + &turn(600, 100, -10);
+    let _diald = spawn_diald(&broker, &trace);
+
+    broker.wait_for_value("home/diald/volume", "50");
+    thread::sleep(Duration::from_millis(300));
+    // Forward reaches 62 (60 published); the reversal only releases events in
+    // the new direction, landing back on 50 with no bounce in between
+    assert_eq!(broker.values("home/diald/volume"), ["51", "60", "50"]);
+}
+
+#[test]
+fn backlash_threshold_comes_from_the_config_file() {
+    let broker = Broker::start();
+    let config = "version = 2\n[backlash]\nthreshold = 10\n";
+    let _diald = spawn_diald_with_config(&broker, &turn(500, 60, 10), config, &[], &[]);
+
+    // Only the last 10 events are held back instead of 50
+    broker.wait_for_value("home/diald/volume", "62");
+    assert_eq!(broker.values("home/diald/volume"), ["51", "60", "62"]);
+}
+
+#[test]
+fn counts_per_step_sets_the_speed() {
+    let broker = Broker::start();
+    let vars = [("DIALD_COUNTS_PER_STEP", "20")];
+    let _diald = spawn_diald_with(&broker, &turn(500, 60, 10), &[], &vars);
+
+    // The 100 counts past the delay buffer are five steps instead of two
+    broker.wait_for_value("home/diald/volume", "55");
+    assert_eq!(broker.values("home/diald/volume"), ["51", "55"]);
+}
+
+#[test]
+fn invert_direction_turns_the_other_way() {
+    let broker = Broker::start();
+    let vars = [("DIALD_INVERT_DIRECTION", "1")];
+    let _diald = spawn_diald_with(&broker, &turn(500, 100, 10), &[], &vars);
+
+    // The same turn that takes the volume up to 62 takes it down to 38
+    broker.wait_for_value("home/diald/volume", "38");
+    let values = broker.values("home/diald/volume");
+    assert!(values.iter().all(|v| v.parse::<u32>().unwrap() < 50), "{:?}", values);
+}
+
+#[test]
+fn range_publishes_and_takes_values_in_it() {
+    let broker = Broker::start();
+    let vars = [("DIALD_RANGE", "-60,0"), ("DIALD_RANGE_STEP", "0.5")];
+    let _diald = spawn_diald_with(&broker, &turn(500, 100, 10), &[], &vars);
+
+    // 120 half-dB steps of 33 counts, starting halfway at -30
+    broker.wait_for_value("home/diald/volume", "-22.5");
+    broker.publish("home/diald/reset", "-40.5");
+    broker.wait_for_value("home/diald/volume", "-40.5");
+}
+
+#[test]
+fn log_curve_bends_published_values() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, &turn(500, 200, 10), &[], &[("DIALD_CURVE", "log")]);
+
+    // Position 87 as in turning_publishes_volume, 54.5% along the taper
+    broker.wait_for_value("home/diald/volume", "54.5");
+    // 25 is between positions; 71 is nearest
+    broker.publish("home/diald/reset", "25");
+    broker.wait_for_value("home/diald/volume", "25.6");
+}
+
+#[test]
+fn slow_reversals_are_confirmed_in_time() {
+    let broker = Broker::start();
+    // A quick turn, then 8 events back, 100ms apart: far fewer than the 50
+    // events that confirm a reversal, but 300ms in the new direction
+    let mut trace = turn(500, 60, 10);
+    trace.extend((0..8).map(|i| format!("{} rotate -10\n", 660 + 100 * i)));
+    let _diald = spawn_diald_with(&broker, &trace, &[], &[("DIALD_COUNTS_PER_STEP", "20")]);
+
+    // 55 as in counts_per_step_sets_the_speed, then the four events back
+    // until the fourth confirms it
+    broker.wait_for_value("home/diald/volume", "55");
+    broker.wait_for_value("home/diald/volume", "53");
+}
+
+#[test]
+fn backlash_decisions_are_published() {
+    let broker = Broker::start();
+    let mut trace = turn(500, 60, 10);
+    trace.push_str(&turn(700, 60, -10));
+    let _diald = spawn_diald(&broker, &trace);
+
+    broker.wait_for_value("home/diald/backlash", r#"{"event":"backlash_enter","from":1}"#);
+    // The 50 events up still held when it turned age out and are lost; the
+    // 50 events back that confirm it are released
+    broker.wait_for_value(
+        "home/diald/backlash",
+        r#"{"event":"backlash_exit","held":-500,"applied":-500,"dropped":500}"#,
+    );
+}
+
+#[test]
+fn velocity_filter_reverses_slow_turns() {
+    let broker = Broker::start();
+    // A quick turn, then 30 events back, 100ms apart: three seconds that the
+    // threshold filter would spend waiting for 50 events
+    let mut trace = turn(500, 60, 10);
+    trace.extend((0..30).map(|i| format!("{} rotate -10\n", 660 + 100 * i)));
+    let vars = [("DIALD_BACKLASH_FILTER", "velocity")];
+    let _diald = spawn_diald_with(&broker, &trace, &[], &vars);
+
+    // Nothing is held back on the way up: 600 counts are 15 steps
+    broker.wait_for_value("home/diald/volume", "65");
+    // The first events back only slow the turn down; once it is going the
+    // other way, all 300 counts back are released
+    broker.wait_for_value("home/diald/volume", "58");
+}
+
+#[test]
+fn deadband_filter_drops_the_gap_after_a_reversal() {
+    let broker = Broker::start();
+    let mut trace = turn(500, 60, 10);
+    trace.push_str(&turn(700, 10, -10));
+    let vars = [("DIALD_BACKLASH_FILTER", "deadband"), ("DIALD_COUNTS_PER_STEP", "20")];
+    let _diald = spawn_diald_with(&broker, &trace, &[], &vars);
+
+    // Nothing is held back: 600 counts are 30 steps
+    broker.wait_for_value("home/diald/volume", "80");
+    // Of the 100 counts back, the first 20 take up the play
+    broker.wait_for_value("home/diald/volume", "76");
+}
+
+#[test]
+fn backlash_release_halves_what_was_held_back() {
+    let broker = Broker::start();
+    let mut trace = turn(500, 60, 10);
+    trace.push_str(&turn(700, 60, -10));
+    let vars = [("DIALD_BACKLASH_RELEASE", "halve"), ("DIALD_COUNTS_PER_STEP", "20")];
+    let _diald = spawn_diald_with(&broker, &trace, &[], &vars);
+
+    // 55 as in counts_per_step_sets_the_speed. The 50 events back that
+    // confirm the reversal are 25 steps, of which half are applied; the last
+    // 10 stay in the delay buffer
+    broker.wait_for_value("home/diald/volume", "43");
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(broker.values("home/diald/volume").last().unwrap(), "43");
+}
+
+#[test]
+fn min_delta_keeps_chatter_from_waking_the_dial() {
+    let broker = Broker::start();
+    // ±1 every 10ms for five seconds, as a cheap encoder resting between detents
+    let chatter: String = (0..500)
+        .map(|i| format!("{} rotate {}\n", 10 * i, if i % 2 == 0 { 1 } else { -1 }))
+        .collect();
+    let _diald = spawn_diald_with(&broker, &chatter, &[], &[("DIALD_MIN_DELTA", "3")]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/volume/adjust")
+    });
+    thread::sleep(Duration::from_millis(300));
+    // Refused while the dial is active, so this only lands if it stayed idle
+    broker.publish("home/diald/volume/adjust", "+5");
+    broker.wait_for_value("home/diald/volume", "55");
+}
+
+#[test]
+fn min_delta_release_keeps_a_slow_turn_going() {
+    let broker = Broker::start();
+    // A quick turn, then 20 counts of 10 every 60ms: too slow to start a turn
+    // with a 30-count dead zone, but enough to keep one going
+    let mut trace = turn(500, 60, 10);
+    trace.extend((0..20).map(|i| format!("{} rotate 10\n", 620 + 60 * i)));
+    let vars = [
+        ("DIALD_MIN_DELTA", "30"),
+        ("DIALD_MIN_DELTA_RELEASE", "5"),
+        ("DIALD_COUNTS_PER_STEP", "20"),
+    ];
+    let _diald = spawn_diald_with(&broker, &trace, &[], &vars);
+
+    // 55 from the quick turn as in counts_per_step_sets_the_speed, and ten
+    // steps more from the slow one
+    broker.wait_for_value("home/diald/volume", "65");
+}
+
+#[test]
+fn fast_topic_gets_every_change() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, &turn(500, 90, 10), &[], &[("DIALD_FAST", "mqtt")]);
+
+    broker.wait_for_value("home/diald/volume", "60");
+    thread::sleep(Duration::from_millis(300));
+    // 40 events past the delay buffer are ten steps, each published at once
+    let fast: Vec<String> = (51..=60).map(|v| v.to_string()).collect();
+    assert_eq!(broker.values("home/diald/volume/fast"), fast);
+    assert_eq!(broker.values("home/diald/volume"), ["51", "60"]);
+}
+
+#[test]
+fn clicks_are_batched() {
+    let broker = Broker::start();
+    let trace = "500 press\n540 release\n600 press\n640 release\n";
+    let _diald = spawn_diald(&broker, trace);
+
+    broker.wait_for_value("home/diald/clicks", "2");
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(broker.values("home/diald/clicks"), ["2"]);
+    assert_eq!(broker.values("home/diald/click"), ["2"]);
+    assert!(broker.values("home/diald/volume").is_empty());
+}
+
+#[test]
+fn slow_triple_click_is_one_burst() {
+    let broker = Broker::start();
+    // 200ms apart: longer than the old fixed batch, within the click window
+    let trace = "500 press\n540 release\n740 press\n780 release\n980 press\n1020 release\n";
+    let _diald = spawn_diald(&broker, trace);
+
+    broker.wait_for_value("home/diald/clicks", "3");
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(broker.values("home/diald/clicks"), ["3"]);
+}
+
+#[test]
+fn click_window_and_max_are_configurable() {
+    let broker = Broker::start();
+    let trace = "500 press\n540 release\n600 press\n640 release\n";
+    let _diald = spawn_diald_with(&broker, trace, &[], &[("DIALD_CLICK_WINDOW", "0")]);
+    broker.wait_until("two clicks", |state| {
+        state.published.iter().filter(|(t, _)| t == "home/diald/clicks").count() == 2
+    });
+    assert_eq!(broker.values("home/diald/clicks"), ["1", "1"]);
+
+    let broker = Broker::start();
+    let trace = "500 press\n540 release\n600 press\n640 release\n700 press\n740 release\n";
+    let _diald = spawn_diald_with(&broker, trace, &[], &[("DIALD_CLICK_MAX", "2")]);
+    broker.wait_until("two bursts", |state| {
+        state.published.iter().filter(|(t, _)| t == "home/diald/clicks").count() == 2
+    });
+    assert_eq!(broker.values("home/diald/clicks"), ["2", "1"]);
+}
+
+#[test]
+fn turning_while_pressed_is_reported() {
+    // 200 counts with the button held are five volume steps, ignored
+    let trace = format!("500 press\n{}800 release\n", turn(520, 20, 10));
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, &trace);
+    broker.wait_for_value("home/diald/ignored_rotation", "5");
+    broker.wait_for_value("home/diald/clicks", "1");
+
+    // With press turns on they are one instead of a click
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, &trace, &[], &[("DIALD_PRESS_TURN", "1")]);
+    broker.wait_for_value("home/diald/press_turn", "5");
+    thread::sleep(Duration::from_millis(400));
+    assert!(broker.values("home/diald/clicks").is_empty());
+    assert!(broker.values("home/diald/ignored_rotation").is_empty());
+    assert!(broker.values("home/diald/volume").is_empty());
+}
+
+#[test]
+fn gesture_rules_add_effects_per_tool() {
+    let broker = Broker::start();
+    let config = "version = 2\n[gestures]\nrules = [\n\
+                  \"scroll double_click -> publish scene night\",\n\
+                  \"double_click -> tool scroll, publish scene movie\",\n]\n";
+    let trace = "500 press\n540 release\n600 press\n640 release\n\
+                 1500 press\n1540 release\n1600 press\n1640 release\n";
+    let _diald = spawn_diald_with_config(&broker, trace, config, &[], &[]);
+
+    // The first double click switches to scrolling, so the second one
+    // matches the scroll rule
+    broker.wait_for_value("home/diald/scene", "night");
+    assert_eq!(broker.values("home/diald/scene"), ["movie", "night"]);
+    assert_eq!(broker.values("home/diald/clicks"), ["2", "2"]);
+}
+
+#[test]
+fn volume_set_moves_the_starting_point() {
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, &turn(1500, 60, 10));
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/volume/set")
+    });
+    broker.publish("home/diald/volume/set", "20");
+
+    broker.wait_for_value("home/diald/volume", "22");
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(broker.values("home/diald/volume"), ["21", "22"]);
+}
+
+#[test]
+fn volume_adjust_is_applied_and_published() {
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, "");
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/volume/adjust")
+    });
+    broker.publish("home/diald/volume/adjust", "+5");
+    broker.wait_for_value("home/diald/volume", "55");
+    broker.publish("home/diald/volume/adjust", "-80");
+    broker.wait_for_value("home/diald/volume", "0");
+    assert_eq!(broker.values("home/diald/volume"), ["55", "0"]);
+}
+
+#[test]
+fn startup_takes_the_retained_volume() {
+    let broker = Broker::start();
+    broker.retain("home/diald/volume", "30");
+    let _diald = spawn_diald(&broker, &turn(1500, 60, 10));
+
+    broker.wait_for_value("home/diald/volume", "32");
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(broker.values("home/diald/volume"), ["31", "32"]);
+    let state = broker.state.lock().unwrap();
+    assert!(state.retained.contains(&("home/diald/volume".to_string(), "32".to_string())));
+}
+
+#[test]
+fn volume_and_tool_survive_a_restart() {
+    let broker = Broker::start();
+    let path = env::temp_dir().join(format!("diald-state-{}.toml", std::process::id()));
+    fs::write(&path, "volume = 30\ntool = \"volume\"\n").unwrap();
+    let vars = [("DIALD_STATE_FILE", path.to_str().unwrap()), ("DIALD_IDLE_TIMEOUT", "1s")];
+    let _diald = spawn_diald_with(&broker, &turn(1500, 60, 10), &[], &vars);
+
+    broker.wait_for_value("home/diald/volume", "32");
+    let deadline = Instant::now() + TIMEOUT;
+    while fs::read_to_string(&path).unwrap() != "volume = 32\ntool = \"volume\"\n" {
+        assert!(Instant::now() < deadline, "volume wasn't saved");
+        thread::sleep(Duration::from_millis(50));
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reset_resyncs_volume_mid_gesture() {
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, &turn(500, 100, 10));
+
+    broker.wait_for_value("home/diald/volume", "60");
+    // Still active, so a plain set would be ignored; reset isn't
+    broker.publish("home/diald/reset", "30");
+    broker.wait_for_value("home/diald/volume", "30");
+    assert_eq!(broker.values("home/diald/volume"), ["51", "60", "30"]);
+}
+
+#[test]
+fn reset_needs_the_command_token_when_set() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, "", &[], &[("DIALD_COMMAND_TOKEN", "s3cret")]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/reset")
+    });
+    broker.publish("home/diald/reset", "100");
+    broker.publish("home/diald/reset", r#"{"token": "guess", "volume": 100}"#);
+    broker.publish("home/diald/reset", r#"{"token": "s3cret", "volume": 30}"#);
+    broker.wait_for_value("home/diald/volume", "30");
+    assert_eq!(broker.values("home/diald/volume"), ["30"]);
+}
+
+#[test]
+fn every_command_needs_the_command_token_when_set() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, "", &[], &[("DIALD_COMMAND_TOKEN", "s3cret")]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/volume/set")
+    });
+    broker.publish("home/diald/volume/set", "100");
+    broker.publish("home/diald/volume/adjust", r#"{"token": "guess", "value": "+40"}"#);
+    broker.publish("home/diald/volume/adjust", r#"{"token": "s3cret", "value": "+5"}"#);
+    broker.wait_for_value("home/diald/volume", "55");
+    // A set isn't published itself, so show it took with a nudge
+    broker.publish("home/diald/volume/set", r#"{"token": "s3cret", "value": 30}"#);
+    broker.publish("home/diald/volume/adjust", r#"{"token": "s3cret", "value": "+5"}"#);
+    broker.wait_for_value("home/diald/volume", "35");
+    assert_eq!(broker.values("home/diald/volume"), ["55", "35"]);
+}
+
+#[test]
+fn monitor_publishes_under_its_own_prefix() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, &turn(500, 100, 10), &["--monitor"], &[]);
+
+    broker.wait_for_value("home/diald/monitor/volume", "60");
+    thread::sleep(Duration::from_millis(300));
+    assert!(broker.values("home/diald/volume").is_empty());
+    assert!(broker.state.lock().unwrap().subscriptions.is_empty());
+}
+
+#[test]
+fn dry_run_publishes_nothing() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, &turn(500, 100, 10), &["--dry-run"], &[]);
+
+    broker.wait_until("connection", |state| state.client.is_some());
+    // The turn is over by now, and would have been published
+    thread::sleep(Duration::from_millis(1000));
+    let state = broker.state.lock().unwrap();
+    assert!(state.published.is_empty(), "published {:?}", state.published);
+    assert!(state.subscriptions.is_empty());
+}
+
+#[test]
+fn config_file_moves_the_topics() {
+    let broker = Broker::start();
+    let config = "version = 2\n[topics]\nprefix = \"office/dial\"\n";
+    let _diald = spawn_diald_with_config(&broker, "", config, &[], &[]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "office/dial/volume/adjust")
+    });
+    broker.publish("office/dial/volume/adjust", "+5");
+    broker.wait_for_value("office/dial/volume", "55");
+    let state = broker.state.lock().unwrap();
+    assert!(state.subscriptions.iter().all(|s| s.starts_with("office/dial/")));
+}
+
+#[test]
+fn acl_layout_puts_commands_in_their_own_subtree() {
+    let broker = Broker::start();
+    let config = "version = 2\n[topics]\nlayout = \"acl\"\ndevice_id = \"den\"\n";
+    let _diald = spawn_diald_with_config(&broker, "", config, &[], &[]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "diald/den/command/volume/adjust")
+    });
+    broker.publish("diald/den/command/volume/adjust", "+5");
+    broker.wait_for_value("diald/den/volume", "55");
+    let state = broker.state.lock().unwrap();
+    // Besides reading back its own volume once, at startup
+    let commands = state.subscriptions.iter().filter(|s| *s != "diald/den/volume");
+    assert!(commands.clone().all(|s| s.starts_with("diald/den/command/")));
+    assert_eq!(commands.count(), state.subscriptions.len() - 1);
+}
+
+#[test]
+fn device_sections_run_several_dials_in_one_process() {
+    let broker = Broker::start();
+    let trace = env::temp_dir().join(format!("diald-trace-{}-dials", std::process::id()));
+    fs::write(&trace, turn(500, 60, 10)).unwrap();
+    let config = format!(
+        "version = 2\n\
+         [[device]]\nname = \"office\"\npath = \"replay:{0}\"\n\
+         [device.topics]\nprefix = \"office/dial\"\n\
+         [[device]]\nname = \"living\"\npath = \"replay:{0}\"\ncounts_per_step = 20\n\
+         [device.topics]\nprefix = \"living/dial\"\n",
+        trace.display()
+    );
+    let _diald = spawn_diald_with_config(&broker, "", &config, &[], &[]);
+
+    // The same turn, at each dial's own speed and under its own prefix
+    broker.wait_for_value("office/dial/volume", "52");
+    broker.wait_for_value("living/dial/volume", "55");
+    assert!(broker.values("home/diald/volume").is_empty());
+    let _ = fs::remove_file(&trace);
+}
+
+#[test]
+fn profile_set_switches_settings_at_runtime() {
+    let broker = Broker::start();
+    let config = "version = 2\n[[profile]]\nname = \"lights\"\n\
+                  [profile.topics]\nprefix = \"home/lights\"\n";
+    let _diald = spawn_diald_with_config(&broker, "", config, &[], &[]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/profile/set")
+    });
+    broker.wait_for_value("home/diald/profile", "default");
+    broker.publish("home/diald/profile/set", "nope");
+    broker.publish("home/diald/profile/set", "lights");
+    broker.wait_until("new subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/lights/volume/adjust")
+    });
+    broker.wait_for_value("home/lights/profile", "lights");
+    broker.publish("home/lights/volume/adjust", "+5");
+    broker.wait_for_value("home/lights/volume", "55");
+    assert_eq!(broker.values("home/diald/profile"), ["default"]);
+
+    broker.publish("home/lights/profile/set", "default");
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().filter(|s| *s == "home/diald/profile/set").count() == 2
+    });
+    broker.wait_until("default profile", |state| {
+        state.published.iter().filter(|(t, _)| t == "home/diald/profile").count() == 2
+    });
+}
+
+#[test]
+fn config_set_retunes_the_dial_and_publishes_it() {
+    let broker = Broker::start();
+    let _diald = spawn_diald_with(&broker, "", &[], &[("DIALD_COMMAND_TOKEN", "s3cret")]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/config/set")
+    });
+    let defaults = r#"{"counts_per_step":40,"click_window":300,"haptics":true}"#;
+    broker.wait_for_value("home/diald/config", defaults);
+    // Without the token, and with a setting that doesn't exist
+    broker.publish("home/diald/config/set", r#"{"haptics": false}"#);
+    broker.publish("home/diald/config/set", r#"{"token": "s3cret", "speed": 3}"#);
+    let tuning = r#"{"token": "s3cret", "counts_per_step": 20, "haptics": false}"#;
+    broker.publish("home/diald/config/set", tuning);
+    broker.wait_for_value(
+        "home/diald/config",
+        r#"{"counts_per_step":20,"click_window":300,"haptics":false}"#,
+    );
+    assert_eq!(broker.values("home/diald/config").len(), 2);
+}
+
+#[test]
+fn requests_are_answered_with_their_id() {
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, &turn(100, 100, 10));
+
+    broker.wait_for_value("home/diald/volume", "62");
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/request")
+    });
+    broker.publish("home/diald/request", r#"{"query": "volume"}"#);
+    broker.publish("home/diald/request", r#"{"id": "b2", "query": "weather"}"#);
+    broker.publish("home/diald/request", r#"{"id": "a1", "query": "volume"}"#);
+    broker.wait_for_value("home/diald/response", r#"{"id":"a1","volume":62}"#);
+    assert_eq!(
+        broker.values("home/diald/response"),
+        [r#"{"id":"b2","error":"unknown query"}"#, r#"{"id":"a1","volume":62}"#]
+    );
+}
+
+#[test]
+fn standard_names_and_env_files_are_read() {
+    let broker = Broker::start();
+    let env_file = env::temp_dir().join(format!("diald-{}.env", std::process::id()));
+    let lines = "# for development\nexport DIALD_TOPICS_PREFIX=\"dev/dial\"\nDIALD_MQTT_PORT=1\n";
+    fs::write(&env_file, lines).unwrap();
+    let env_file = env_file.to_str().unwrap();
+    // The standard name wins over the old one, and the environment (here
+    // MQTT_PORT) over the file, whichever name either uses
+    let vars = [("MQTT_HOST", "nowhere.invalid"), ("DIALD_MQTT_HOST", "127.0.0.1")];
+    let _diald = spawn_diald_with(&broker, "", &["--env-file", env_file], &vars);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "dev/dial/volume/adjust")
+    });
+    fs::remove_file(env_file).unwrap();
+}
+
+#[test]
+fn sighup_reloads_the_config_and_keeps_the_volume() {
+    let broker = Broker::start();
+    // Only the signal should reload it here
+    let diald = spawn_diald_with(&broker, "", &[], &[("DIALD_WATCH_CONFIG", "0")]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/volume/adjust")
+    });
+    broker.publish("home/diald/volume/adjust", "+5");
+    broker.wait_for_value("home/diald/volume", "55");
+
+    fs::write(&diald.config, "version = 2\n[topics]\nprefix = \"office/dial\"\n").unwrap();
+    let pid = diald.child.id().to_string();
+    assert!(Command::new("kill").args(["-HUP", &pid]).status().unwrap().success());
+    broker.wait_until("new subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "office/dial/volume/adjust")
+    });
+    broker.publish("office/dial/volume/adjust", "+5");
+    broker.wait_for_value("office/dial/volume", "60");
+}
+
+#[test]
+fn saving_the_config_reloads_it() {
+    let broker = Broker::start();
+    let diald = spawn_diald(&broker, "");
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/volume/adjust")
+    });
+
+    // Saved the way editors do, as a new file renamed over the old one
+    let saved = diald.config.with_extension("toml.new");
+    fs::write(&saved, "version = 2\n[topics]\nprefix = \"office/dial\"\n").unwrap();
+    fs::rename(&saved, &diald.config).unwrap();
+    broker.wait_until("new subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "office/dial/volume/adjust")
+    });
+    broker.publish("office/dial/volume/adjust", "+5");
+    broker.wait_for_value("office/dial/volume", "55");
+}
+
+#[test]
+fn check_config_exits_with_the_result() {
+    let broker = Broker::start();
+    let mut diald = spawn_diald_with(&broker, "", &["--check-config"], &[]);
+    assert!(diald.child.wait().unwrap().success());
+
+    let vars = [("DIALD_TOOL", "lasso")];
+    let mut diald = spawn_diald_with(&broker, "", &["--check-config"], &vars);
+    assert_eq!(diald.child.wait().unwrap().code(), Some(1));
+
+    let args = ["--check-config", "--log-level", "loud"];
+    let mut diald = spawn_diald_with(&broker, "", &args, &[]);
+    assert_eq!(diald.child.wait().unwrap().code(), Some(1));
+}
+
+#[test]
+fn without_a_device_diald_looks_for_one() {
+    let config = env::temp_dir().join(format!("diald-auto-{}.toml", std::process::id()));
+    fs::write(&config, "version = 2\n").unwrap();
+    // No input device reports REL_MISC, so there is nothing to find
+    let output = Command::new(env!("CARGO_BIN_EXE_diald"))
+        .arg("--check-config")
+        .env("DIALD_CONFIG", &config)
+        .env("DIALD_AXES", "REL_MISC=rotate")
+        .env("MQTT_HOST", "127.0.0.1")
+        .env("MQTT_PORT", "1")
+        .env_remove("DIALD_DEVICE")
+        .output()
+        .unwrap();
+    fs::remove_file(config).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("device    auto found no dial"), "{}", stdout);
+}
+
+#[test]
+fn restart_needs_the_token() {
+    let broker = Broker::start();
+    let mut diald = spawn_diald_with(&broker, "", &[], &[("DIALD_RESTART_TOKEN", "s3cret")]);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/restart")
+    });
+    broker.publish("home/diald/restart", "guess");
+    // Handled in order, so by the time the volume moves the guess was refused
+    broker.publish("home/diald/volume/adjust", "+5");
+    broker.wait_for_value("home/diald/volume", "55");
+    assert!(diald.child.try_wait().unwrap().is_none());
+
+    broker.publish("home/diald/restart", "s3cret");
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = diald.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "diald didn't exit");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(status.code(), Some(75));
+}
+
+#[test]
+fn exit_when_idle_waits_for_the_dial_to_rest() {
+    let broker = Broker::start();
+    let args = ["--exit-when-idle", "2s"];
+    let mut diald = spawn_diald_with(&broker, &turn(1500, 100, 10), &args, &[]);
+
+    // Turned before the two seconds are up, which starts them over. The
+    // final value, so the reset can't land mid-turn
+    broker.wait_for_value("home/diald/volume", "62");
+    let turned = Instant::now();
+    broker.publish("home/diald/reset", "");
+    let deadline = turned + TIMEOUT;
+    let status = loop {
+        if let Some(status) = diald.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "diald didn't exit");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success());
+    assert!(turned.elapsed() >= Duration::from_millis(1500), "exited after {:?}", turned.elapsed());
+}
+
+#[test]
+fn without_hotplug_a_missing_device_is_retried() {
+    let broker = Broker::start();
+    let late = env::temp_dir().join(format!("diald-late-{}.trace", std::process::id()));
+    let _ = fs::remove_file(&late);
+    let device = format!("replay:{}", late.display());
+    let vars = [("DIALD_DEVICE", device.as_str()), ("DIALD_HOTPLUG", "0")];
+    let _diald = spawn_diald_with(&broker, "", &[], &vars);
+
+    thread::sleep(Duration::from_millis(1500));
+    fs::write(&late, turn(100, 100, 10)).unwrap();
+    broker.wait_for_value("home/diald/volume", "62");
+    fs::remove_file(&late).unwrap();
+}
+
+#[test]
+fn exit_with_device_leaves_a_missing_device_to_systemd() {
+    let broker = Broker::start();
+    let missing = env::temp_dir().join(format!("diald-missing-{}", std::process::id()));
+    let vars = [("DIALD_DEVICE", missing.to_str().unwrap())];
+    let mut diald = spawn_diald_with(&broker, "", &["--exit-with-device"], &vars);
+
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = diald.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "diald didn't exit");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(status.code(), Some(1));
+
+    let unit = Command::new(env!("CARGO_BIN_EXE_diald")).arg("systemd-unit").output().unwrap();
+    let unit = String::from_utf8(unit.stdout).unwrap();
+    assert!(unit.contains("BindsTo=dev-input-%i.device\n"));
+    assert!(unit.contains(" --device /dev/input/%I --exit-with-device\n"));
+}
+
+#[test]
+fn wait_for_device_gives_up_with_its_own_status() {
+    let broker = Broker::start();
+    let missing = env::temp_dir().join(format!("diald-absent-{}", std::process::id()));
+    let vars = [("DIALD_DEVICE", missing.to_str().unwrap())];
+    let started = Instant::now();
+    let mut diald = spawn_diald_with(&broker, "", &["--wait-for-device", "1s"], &vars);
+
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = diald.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "diald didn't give up");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(status.code(), Some(69));
+    assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn haptics_state_is_published() {
+    let broker = Broker::start();
+    let _diald = spawn_diald(&broker, "");
+    broker.wait_for_value("home/diald/haptics", "unavailable");
+
+    let broker = Broker::start();
+    let node = env::temp_dir().join(format!("diald-hidraw-{}", std::process::id()));
+    fs::write(&node, "").unwrap();
+    let vars = [("DIALD_HAPTIC_DEV", node.to_str().unwrap())];
+    let _diald = spawn_diald_with(&broker, "", &[], &vars);
+    broker.wait_for_value("home/diald/haptics", "ok");
+    fs::remove_file(node).unwrap();
+}
+
+#[test]
+fn patterns_defined_over_mqtt_play_and_are_saved() {
+    let broker = Broker::start();
+    let node = env::temp_dir().join(format!("diald-pattern-{}", std::process::id()));
+    let state = node.with_extension("toml");
+    fs::write(&node, "").unwrap();
+    let vars = [
+        ("DIALD_HAPTIC_DEV", node.to_str().unwrap()),
+        ("DIALD_HAPTICS", "remote"),
+        ("DIALD_STATE_FILE", state.to_str().unwrap()),
+    ];
+    let _diald = spawn_diald_with(&broker, "", &[], &vars);
+
+    broker.wait_until("subscription", |state| {
+        state.subscriptions.iter().any(|s| s == "home/diald/config/set")
+    });
+    // Not the shape of a haptic report, so refused
+    broker.publish("home/diald/config/set", r#"{"pattern": "remote 2 1"}"#);
+    broker.publish("home/diald/config/set", r#"{"pattern": "remote 1 4 3 0x7f 0"}"#);
+    broker.publish("home/diald/volume/set", "20");
+
+    let deadline = Instant::now() + TIMEOUT;
+    while fs::read(&node).unwrap() != [1, 4, 3, 0x7f, 0] {
+        assert!(Instant::now() < deadline, "pattern wasn't played");
+        thread::sleep(Duration::from_millis(50));
+    }
+    while !fs::read_to_string(&state).is_ok_and(|s| s.contains("remote = [1, 4, 3, 127, 0]")) {
+        assert!(Instant::now() < deadline, "pattern wasn't saved");
+        thread::sleep(Duration::from_millis(50));
+    }
+    fs::remove_file(node).unwrap();
+    fs::remove_file(state).unwrap();
+}
+
+#[test]
+fn init_config_writes_a_file_diald_reads() {
+    let broker = Broker::start();
+    let path = env::temp_dir().join(format!("diald-init-{}.toml", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let init = |path: &PathBuf| {
+        Command::new(env!("CARGO_BIN_EXE_diald"))
+            .arg("init-config")
+            .arg(path)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+    };
+    assert!(init(&path).success());
+    assert!(fs::read_to_string(&path).unwrap().contains("# host = \"localhost\""));
+    // Never written over
+    assert!(!init(&path).success());
+
+    let config = fs::read_to_string(&path).unwrap();
+    let mut diald = spawn_diald_with_config(&broker, "", &config, &["--check-config"], &[]);
+    assert!(diald.child.wait().unwrap().success());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn oneshot_prints_the_first_gesture() {
+    let path = env::temp_dir().join(format!("diald-oneshot-{}", std::process::id()));
+    let config = path.with_extension("toml");
+    fs::write(&config, "version = 2\n").unwrap();
+    let oneshot = |trace: &str| {
+        fs::write(&path, trace).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_diald"))
+            .args(["--oneshot", "--timeout", "2s"])
+            .env("DIALD_DEVICE", format!("replay:{}", path.display()))
+            .env("DIALD_CONFIG", &config)
+            .env_remove("DIALD_CLICK_WINDOW")
+            .env_remove("DIALD_CLICK_MAX")
+            .env_remove("DIALD_COUNTS_PER_STEP")
+            .output()
+            .unwrap()
+    };
+
+    let trace = format!("100 press\n150 release\n300 press\n350 release\n{}", turn(900, 50, 10));
+    let output = oneshot(&trace);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "{\"gesture\":\"click\",\"count\":2}\n");
+
+    let output = oneshot(&turn(100, 100, -10));
+    assert!(output.status.success());
+    let turned = String::from_utf8(output.stdout).unwrap();
+    assert!(turned.starts_with("{\"gesture\":\"turn\",\"steps\":-"), "{}", turned);
+
+    let output = oneshot("");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "{\"gesture\":\"timeout\"}\n");
+    fs::remove_file(path).unwrap();
+    fs::remove_file(config).unwrap();
+}
+
+#[test]
+fn record_saves_labelled_maneuvers() {
+    let dir = env::temp_dir();
+    let trace = dir.join(format!("diald-record-in-{}.trace", std::process::id()));
+    let dataset = dir.join(format!("diald-record-{}.trace", std::process::id()));
+    let config = dir.join(format!("diald-record-{}.toml", std::process::id()));
+    fs::write(&trace, turn(300, 20, 5)).unwrap();
+    fs::write(&config, "version = 2\n").unwrap();
+    let _ = fs::remove_file(&dataset);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_diald"))
+        .arg("record")
+        .arg(&dataset)
+        .env("DIALD_DEVICE", format!("replay:{}", trace.display()))
+        .env("DIALD_CONFIG", &config)
+        .env_remove("DIALD_BACKLASH_THRESHOLD")
+        .env_remove("DIALD_BACKLASH_CANCEL")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Record the first maneuver, skip the second, then stop
+    child.stdin.take().unwrap().write_all(b"\ns\n").unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let text = fs::read_to_string(&dataset).unwrap();
+    fs::remove_file(&trace).unwrap();
+    fs::remove_file(&dataset).unwrap();
+    fs::remove_file(&config).unwrap();
+    assert!(text.contains("# backlash_threshold = 50\n"), "{}", text);
+    let maneuvers: Vec<&str> = text.lines().filter(|l| l.starts_with("# maneuver")).collect();
+    assert_eq!(maneuvers, ["# maneuver turn_right"]);
+    assert_eq!(text.lines().filter(|l| l.ends_with(" rotate 5")).count(), 20);
+}
+
+#[test]
+fn dump_fsm_shows_the_configured_thresholds() {
+    let config = env::temp_dir().join(format!("diald-fsm-{}.toml", std::process::id()));
+    fs::write(&config, "version = 2\n[backlash]\nthreshold = 80\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_diald"))
+        .args(["dump-fsm", "mermaid"])
+        .env("DIALD_CONFIG", &config)
+        .env_remove("DIALD_BACKLASH_THRESHOLD")
+        .env_remove("DIALD_BACKLASH_CANCEL")
+        .output()
+        .unwrap();
+    fs::remove_file(config).unwrap();
+    assert!(output.status.success());
+    let diagram = String::from_utf8(output.stdout).unwrap();
+    assert!(diagram.starts_with("stateDiagram-v2\n"));
+    assert!(diagram.contains("backlash --> active: 80 events or 300ms in the new direction"));
+    assert!(diagram.contains("backlash --> active: 16 events back"));
+}
+
+#[test]
+fn config_schema_describes_every_setting() {
+    let config = env::temp_dir().join(format!("diald-schema-{}.toml", std::process::id()));
+    fs::write(&config, "version = 2\n").unwrap();
+    let stdout = |command: &str, arg: Option<&str>| {
+        let output = Command::new(env!("CARGO_BIN_EXE_diald"))
+            .arg(command)
+            .args(arg)
+            .env("DIALD_CONFIG", &config)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let schema = stdout("config-schema", None);
+    let template = stdout("init-config", Some("-"));
+    fs::remove_file(config).unwrap();
+
+    assert!(schema.starts_with("{\n  \"$schema\": \"http://json-schema.org/draft-07/schema#\""));
+    for line in template.lines().filter(|line| line.contains(" = ")) {
+        let key = line.trim_start_matches("# ").split(" = ").next().unwrap();
+        assert!(schema.contains(&format!("\"{}\": {{", key)), "{} is missing", key);
+    }
+    assert!(schema.contains("\"description\": \"Broker port (DIALD_MQTT_PORT)\""));
+    assert!(schema.contains("\"type\": \"integer\",\n          \"default\": 1883"));
+    // Examples aren't passed off as defaults
+    assert!(schema.contains("\"examples\": [[\"/dev/input/event5\"]]"));
+}
+
+#[test]
+fn mqtt_test_reports_each_check() {
+    let broker = Broker::start();
+    let config = env::temp_dir().join(format!("diald-mqtt-test-{}.toml", std::process::id()));
+    fs::write(&config, "version = 2\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_diald"))
+        .arg("mqtt-test")
+        .env("MQTT_HOST", "127.0.0.1")
+        .env("MQTT_PORT", broker.port.to_string())
+        .env("DIALD_CONFIG", &config)
+        .env_remove("MQTT_USERNAME")
+        .env_remove("MQTT_PASSWORD")
+        .env_remove("DIALD_TOPIC_PREFIX")
+        .env_remove("DIALD_TOPIC_LAYOUT")
+        .output()
+        .unwrap();
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{}", report);
+    assert!(report.contains("ok    subscribe home/diald/volume/set (QoS 1)"), "{}", report);
+    assert!(report.contains("ok    echo      round trip"), "{}", report);
+    assert!(report.contains("ok    retain    retained messages are kept"), "{}", report);
+    // Cleared again afterwards
+    assert!(broker.state.lock().unwrap().retained.is_empty());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_diald"))
+        .arg("mqtt-test")
+        .env("MQTT_HOST", "127.0.0.1")
+        .env("MQTT_PORT", "1")
+        .env("DIALD_CONFIG", &config)
+        .output()
+        .unwrap();
+    fs::remove_file(config).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("FAIL  connect"));
+}
+
+#[test]
+fn info_reports_the_version_and_a_newer_release() {
+    let broker = Broker::start();
+    let feed = env::temp_dir().join(format!("diald-release-{}.json", std::process::id()));
+    fs::write(&feed, r#"{"tag_name": "v999.0.0", "name": "diald 999"}"#).unwrap();
+    let url = format!("file://{}", feed.display());
+    let _diald = spawn_diald_with(&broker, "", &[], &[("DIALD_UPDATE_URL", &url)]);
+
+    let version = env!("CARGO_PKG_VERSION");
+    broker.wait_for_value(
+        "home/diald/info",
+        &format!(r#"{{"version":"{}","update_available":"999.0.0"}}"#, version),
+    );
+    fs::remove_file(feed).unwrap();
+}
+
+#[test]
+fn going_idle_publishes_snapshot_and_message() {
+    let broker = Broker::start();
+    let vars = [
+        ("DIALD_IDLE_SNAPSHOT", "1"),
+        ("DIALD_IDLE_PUBLISH", "home/office/display off"),
+    ];
+    let _diald = spawn_diald_with(&broker, &turn(500, 100, 10), &[], &vars);
+
+    broker.wait_for_value("home/diald/volume", "60");
+    // A remote reset ends the gesture, which is also an idle edge
+    broker.publish("home/diald/reset", "30");
+    broker.wait_for_value("home/office/display", "off");
+    assert_eq!(
+        broker.values("home/diald/snapshot"),
+        [r#"{"volume":30,"tool":"volume","max_volume":100,"guest":false}"#]
+    );
+}